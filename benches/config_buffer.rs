@@ -0,0 +1,55 @@
+//! Measures [`Adapter::set_config`] with an interface with many peers, which packs the config
+//! into the buffer passed to `WireGuardSetConfiguration`. The adapter is a [`MockWireGuard`]
+//! adapter, so this does not need the driver or Administrator rights.
+//!
+//! The buffer is sized up front and allocated once, so the time is spent zeroing it and copying
+//! the peers in. The mock then decodes the buffer and replaces its peers, which the driver does in
+//! the kernel instead, so compare runs with each other rather than with the driver.
+//!
+//! Run with `cargo bench --bench config_buffer --features mock`
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wireguard_nt::{Adapter, AllowedIp, Interface, Key, MockWireGuard, Peer};
+
+/// Builds an interface with `count` peers, each with one to four allowed ips
+fn interface(count: usize) -> Interface {
+    let peers = (0..count).map(|i| {
+        let mut key = [0; 32];
+        key[..8].copy_from_slice(&(i as u64).to_le_bytes());
+        let mut peer = Peer::builder()
+            .public_key(Key::new(key))
+            .endpoint(([192, 0, 2, 1], 51820).into())
+            .build()
+            .unwrap();
+        for j in 0..=(i % 4) {
+            let address = Ipv4Addr::new(10, (i >> 8) as u8, i as u8, j as u8 * 64);
+            peer.add_allowed_ip(AllowedIp::new(IpAddr::V4(address), 26).unwrap());
+        }
+        peer
+    });
+    Interface::builder()
+        .private_key(Key::new([1; 32]))
+        .peers(peers)
+        .build()
+        .unwrap()
+}
+
+fn encode(c: &mut Criterion) {
+    let wireguard = Arc::new(MockWireGuard::new());
+    let adapter = Adapter::create(&wireguard, "WireGuard", "Bench", None).unwrap();
+    let mut group = c.benchmark_group("set_config");
+    for &count in &[100, 1000, 10_000] {
+        let config = interface(count);
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &config, |b, config| {
+            b.iter(|| adapter.set_config(config).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -0,0 +1,54 @@
+//! Creates an adapter, configures it with a single peer and brings it up.
+//!
+//! Must be run as Administrator because it creates a network adapter
+
+use std::time::Duration;
+
+use wireguard_nt::{AdapterState, AllowedIp, Interface, Key, Peer};
+
+fn main() {
+    env_logger::init();
+
+    //Unsafe because we are loading an arbitrary dll file
+    let wireguard =
+        unsafe { wireguard_nt::load_from_path("examples/wireguard_nt/bin/amd64/wireguard.dll") }
+            .expect("Failed to load wireguard dll");
+
+    //1. Create the adapter
+    let adapter = wireguard_nt::Adapter::create(&wireguard, "WireGuard", "BringUp", None)
+        .expect("Failed to create adapter");
+
+    //2. Give it a config. Replace the peer's key and endpoint with a real server in real code
+    let private_key = Key::generate();
+    let peer_key = Key::generate().public_key();
+    let peer = Peer::builder()
+        .public_key(peer_key)
+        .persistent_keepalive(Duration::from_secs(25))
+        .allowed_ip("10.8.0.0/24".parse::<AllowedIp>().unwrap())
+        .endpoint("203.0.113.1:51820".parse().unwrap())
+        .build()
+        .expect("Invalid peer config");
+    let interface = Interface::builder()
+        .private_key(private_key.clone())
+        .peer(peer)
+        .build()
+        .expect("Invalid interface config");
+    adapter
+        .set_config(&interface)
+        .expect("Failed to set config");
+
+    //3. Bring it up
+    adapter
+        .set_state(AdapterState::Up)
+        .expect("Failed to bring adapter up");
+    assert_eq!(adapter.get_state().unwrap(), AdapterState::Up);
+    println!(
+        "Adapter is up. Our public key is {}",
+        private_key.public_key()
+    );
+
+    //The adapter is removed when it is dropped
+    adapter
+        .set_state(AdapterState::Down)
+        .expect("Failed to bring adapter down");
+}
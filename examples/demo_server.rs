@@ -2,9 +2,9 @@ use std::io::{Read, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use ipnet::{Ipv4Net, Ipv6Net};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use log::*;
 
 fn main() {
@@ -24,17 +24,10 @@ fn main() {
         unsafe { wireguard_nt::load_from_path("examples/wireguard_nt/bin/amd64/wireguard.dll") }
             .expect("Failed to load wireguard dll");
 
-    //Try to open an adapter from the given pool with the name "Demo"
-    let adapter = match wireguard_nt::Adapter::open(wireguard, "Demo") {
-        Ok(a) => a,
-        Err((_, wireguard)) =>
-        //If loading failed (most likely it didn't exist), create a new one
-        {
-            wireguard_nt::Adapter::create(wireguard, "WireGuard", "Demo", None)
-                .map_err(|e| e.0)
-                .expect("Failed to create wireguard adapter!")
-        }
-    };
+    //Open the adapter with the name "Demo", or create it in the "WireGuard" pool if it doesn't
+    //exist yet
+    let adapter = wireguard_nt::Adapter::open_or_create(&wireguard, "WireGuard", "Demo", None)
+        .expect("Failed to open or create wireguard adapter!");
     let mut interface_private = [0; 32];
     let mut peer_pub = [0; 32];
 
@@ -42,33 +35,35 @@ fn main() {
     peer_pub.copy_from_slice(demo_pub.as_slice());
 
     //Only allow traffic going to the demo server to pass through the wireguard interface
-    let allowed_ip = match endpoint.ip() {
+    let allowed_ip: IpNet = match endpoint.ip() {
         IpAddr::V4(v4) => Ipv4Net::new(v4, 32).unwrap().into(),
         IpAddr::V6(v6) => Ipv6Net::new(v6, 128).unwrap().into(),
     };
 
-    let interface = wireguard_nt::SetInterface {
-        listen_port: None,
-        public_key: None,
-        private_key: Some(interface_private),
-        peers: vec![wireguard_nt::SetPeer {
-            public_key: Some(peer_pub),
-            preshared_key: None,
-            keep_alive: Some(21),
-            //Uncomment to tunnel all traffic
-            //allowed_ips: vec!["0.0.0.0/0".parse().unwrap()],
-            allowed_ips: vec![allowed_ip], //Only tunnel traffic bound for the demo server the wireguard interface
-            endpoint,
-        }],
-    };
-    assert!(adapter.set_logging(wireguard_nt::AdapterLoggingLevel::OnWithPrefix));
+    let peer = wireguard_nt::Peer::builder()
+        .public_key(wireguard_nt::Key::new(peer_pub))
+        .persistent_keepalive(Duration::from_secs(21))
+        //Uncomment to tunnel all traffic
+        //.allowed_ip(wireguard_nt::AllowedIp::ALL_V4)
+        .allowed_ip(allowed_ip) //Only tunnel traffic bound for the demo server the wireguard interface
+        .endpoint(endpoint)
+        .build()
+        .expect("Invalid peer config");
+    let interface = wireguard_nt::Interface::builder()
+        .private_key(wireguard_nt::Key::new(interface_private))
+        .peer(peer)
+        .build()
+        .expect("Invalid interface config");
+    adapter
+        .set_logging(wireguard_nt::AdapterLoggingLevel::OnWithPrefix)
+        .expect("Failed to set adapter logging");
 
     adapter.set_config(&interface).unwrap();
     match adapter.set_default_route(&[Ipv4Net::new(internal_ip, 24).unwrap().into()], &interface) {
         Ok(()) => {}
         Err(err) => panic!("Failed to set default route: {}", err),
     }
-    assert!(adapter.up());
+    adapter.up().expect("Failed to bring the adapter up");
 
     println!("Printing peer bandwidth statistics");
     println!("Press enter to exit");
@@ -82,15 +77,15 @@ fn main() {
                 }
                 std::thread::sleep(Duration::from_millis(100));
             }
-            let stats = adapter.get_config();
+            let stats = adapter.get_config().expect("Failed to get adapter config");
             for peer in stats.peers {
-                let handshake_age = Instant::now().duration_since(peer.last_handshake);
+                let handshake = match peer.last_handshake.map(|t| t.elapsed()) {
+                    Some(Ok(age)) => format!("{}s ago", age.as_secs_f32()),
+                    _ => "never".to_owned(),
+                };
                 println!(
-                    "  {:?}, up: {}, down: {}, handsake: {}s ago",
-                    peer.allowed_ips,
-                    peer.tx_bytes,
-                    peer.rx_bytes,
-                    handshake_age.as_secs_f32()
+                    "  {:?}, up: {}, down: {}, handshake: {}",
+                    peer.allowed_ips, peer.tx_bytes, peer.rx_bytes, handshake
                 );
             }
             // Go to 163.172.161.0 in your browser to see bandwidth numbers here change
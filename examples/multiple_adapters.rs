@@ -0,0 +1,75 @@
+//! Runs two tunnels side by side from one loaded wireguard.dll.
+//!
+//! Must be run as Administrator because it creates network adapters
+
+use std::time::Duration;
+
+use wireguard_nt::{Adapter, AdapterLoggingLevel, AllowedIp, Interface, Key, Peer};
+
+/// Creates an adapter named `name` with a single peer that routes `allowed_ip`
+fn tunnel(
+    wireguard: &std::sync::Arc<wireguard_nt::dll>,
+    name: &str,
+    allowed_ip: &str,
+    endpoint: &str,
+) -> Adapter {
+    let adapter =
+        Adapter::create(wireguard, "WireGuard", name, None).expect("Failed to create adapter");
+    //Both adapters log through the same process wide logger, the prefix tells them apart
+    adapter
+        .set_logging(AdapterLoggingLevel::OnWithPrefix)
+        .expect("Failed to enable logging");
+
+    //Replace the peer's key and endpoint with a real server in real code
+    let peer = Peer::builder()
+        .public_key(Key::generate().public_key())
+        .persistent_keepalive(Duration::from_secs(25))
+        .allowed_ip(allowed_ip.parse::<AllowedIp>().unwrap())
+        .endpoint(endpoint.parse().unwrap())
+        .build()
+        .expect("Invalid peer config");
+    let interface = Interface::builder()
+        .private_key(Key::generate())
+        .peer(peer)
+        .build()
+        .expect("Invalid interface config");
+    adapter
+        .set_config(&interface)
+        .expect("Failed to set config");
+    adapter.up().expect("Failed to bring adapter up");
+    adapter
+}
+
+fn main() {
+    env_logger::init();
+
+    //Load the dll once. Every adapter keeps a clone of the `Arc`, so it stays loaded until the
+    //last adapter is dropped
+    //Unsafe because we are loading an arbitrary dll file
+    let wireguard =
+        unsafe { wireguard_nt::load_from_path("examples/wireguard_nt/bin/amd64/wireguard.dll") }
+            .expect("Failed to load wireguard dll");
+
+    let office = tunnel(&wireguard, "Office", "10.8.0.0/24", "203.0.113.1:51820");
+    let lab = tunnel(&wireguard, "Lab", "10.9.0.0/24", "203.0.113.2:51820");
+    //The dll handle is not needed anymore, the adapters keep it loaded
+    drop(wireguard);
+
+    for adapter in [&office, &lab].iter() {
+        let config = adapter.get_config().expect("Failed to get config");
+        println!(
+            "{} listens on port {:?} and has peers {:?}",
+            adapter.name(),
+            config.listen_port,
+            config
+                .peers
+                .iter()
+                .map(|peer| peer.public_key.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    //Removing one tunnel leaves the other running
+    drop(office);
+    println!("{} is still {:?}", lab.name(), lab.get_state().unwrap());
+}
@@ -1,107 +1,677 @@
-use crate::log::AdapterLoggingLevel;
-use crate::util;
+use crate::log::{AdapterLoggingLevel, LogLevel};
 /// Representation of a wireGuard adapter with safe idiomatic bindings to the functionality provided by
 /// the WireGuard* C functions.
 ///
 /// The [`Adapter::create`] and [`Adapter::open`] functions serve as the entry point to using
 /// wireguard functionality
-use crate::util::{StructReader, UnsafeHandle};
+use crate::registry::RegKey;
+use crate::util;
+use crate::util::UnsafeHandle;
 use crate::wireguard_nt_raw;
-use crate::WireGuardError;
+use crate::{
+    AllowedIp, ConfigDelta, Error, Interface, InterfaceRef, Key, Peer, PeerStats, PublicKey,
+    SetConfigOptions, Status, WireGuardApi,
+};
 use std::mem::{align_of, size_of};
-use std::time::{Duration, Instant, SystemTime};
 
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ptr;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::wireguard_nt_raw::{WIREGUARD_ALLOWED_IP, WIREGUARD_INTERFACE, WIREGUARD_PEER};
-use ipnet::Ipv4Net;
-use ipnet::{IpNet, Ipv6Net};
-use rand::Rng;
-use widestring::U16CString;
-use winapi::shared::winerror::ERROR_MORE_DATA;
+#[cfg(feature = "ipnet")]
+use ipnet::IpNet;
+use winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED, ERROR_ADDRESS_ALREADY_ASSOCIATED, ERROR_DUP_NAME, ERROR_FILE_NOT_FOUND,
+    ERROR_NOT_FOUND, ERROR_SUCCESS, WSAEADDRINUSE,
+};
 use winapi::um::errhandlingapi::GetLastError;
 
 /// Wrapper around a `WIREGUARD_ADAPTER_HANDLE`
 ///
 /// Related functions from WireGuardXXX are functions with an adapter self parameter
-pub struct Adapter {
+///
+/// # Thread safety
+/// `Adapter` is `Send` and `Sync`, so it can be moved to another thread or shared through an
+/// `Arc`. Methods that take `&self` only pass the handle to wireguard.dll, whose functions may be
+/// called from several threads at once because the driver serializes access to each adapter. For
+/// example, one thread can read stats with [`Adapter::get_config`] while another calls
+/// [`Adapter::set_config`]. [`Adapter::rename`] takes `&mut self` because it also updates the
+/// cached name. The handle is closed when the `Adapter` is dropped, which the borrow checker only
+/// allows once no other thread is using it
+///
+/// # Dropping
+/// Dropping an `Adapter` calls `WireGuardCloseAdapter`. For an adapter obtained from
+/// [`Adapter::create`] this removes the network adapter from the system, so it can no longer be
+/// found by [`Adapter::open`]. For an adapter obtained from [`Adapter::open`] only the handle is
+/// closed, and the adapter stays installed for as long as its creator keeps it. Removing an
+/// adapter also removes the NRPT rule set by [`Adapter::set_nrpt_domains`]. A failure to remove the
+/// rule is sent to the logger as a warning, see [`Adapter::close`] for handling it instead.
+///
+/// WireGuard NT ties a created adapter to the process that created it, so an adapter is also
+/// removed when that process exits, even if it was never dropped. Use [`Adapter::persist`] to keep
+/// an adapter installed until then without holding on to the `Adapter`
+///
+/// # Testing
+/// The type parameter is the [`WireGuardApi`] the adapter calls, which is the loaded
+/// [`crate::dll`] unless another one is passed to [`Adapter::create`] or [`Adapter::open`]. With
+/// the `mock` feature, `Adapter<MockWireGuard>` keeps its config in memory, so code that is
+/// generic over `A: WireGuardApi` can be tested without the driver
+pub struct Adapter<A: WireGuardApi = crate::dll> {
     adapter: UnsafeHandle<wireguard_nt_raw::WIREGUARD_ADAPTER_HANDLE>,
-    wireguard: Arc<wireguard_nt_raw::wireguard>,
+    wireguard: Arc<A>,
+    name: String,
+    pool: String,
+    /// Whether this handle came from [`Adapter::create`], in which case dropping it removes the
+    /// adapter, or from [`Adapter::open`]
+    origin: AdapterOrigin,
+    /// The host names recorded with [`Adapter::track_endpoint_host`], by peer
+    endpoint_hosts: Mutex<HashMap<PublicKey, String>>,
+}
+
+/// The locally unique identifier (LUID) of a network adapter.
+///
+/// IP Helper functions such as `CreateUnicastIpAddressEntry` identify interfaces by their
+/// `NET_LUID`, whose 64 bit value is available from [`Luid::value`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Luid(u64);
+
+impl Luid {
+    /// Returns the raw 64 bit value of this LUID, the same value as `NET_LUID_LH::Value`
+    pub fn value(&self) -> u64 {
+        self.0
+    }
 }
 
-/// Representation of a WireGuard peer when setting the config
-#[derive(Clone)]
-pub struct SetPeer {
-    /// The peer's public key
-    pub public_key: Option<[u8; 32]>,
+impl From<Luid> for u64 {
+    fn from(luid: Luid) -> Self {
+        luid.0
+    }
+}
 
-    /// A preshared key used to symmetrically encrypt data with this peer
-    pub preshared_key: Option<[u8; 32]>,
+/// The GUID of a network adapter.
+///
+/// The `u128` value reads the same as the usual string form, so
+/// `Guid::from(0x6ba7b810_9dad_11d1_80b4_00c04fd430c8)` is `{6BA7B810-9DAD-11D1-80B4-00C04FD430C8}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid(u128);
+
+impl From<u128> for Guid {
+    fn from(value: u128) -> Self {
+        Guid(value)
+    }
+}
 
-    /// How often to send a keep alive packet to prevent NATs from blocking UDP packets
-    ///
-    /// Set to None if no keep alive behavior is wanted
-    pub keep_alive: Option<u16>,
+impl From<[u8; 16]> for Guid {
+    /// Creates a GUID from its bytes in the order they appear when written as a string
+    fn from(bytes: [u8; 16]) -> Self {
+        Guid(u128::from_be_bytes(bytes))
+    }
+}
+
+impl From<Guid> for u128 {
+    fn from(guid: Guid) -> Self {
+        guid.0
+    }
+}
+
+impl From<Guid> for wireguard_nt_raw::GUID {
+    fn from(guid: Guid) -> Self {
+        let bytes = guid.0.to_be_bytes();
+        let mut data4 = [0u8; 8];
+        data4.copy_from_slice(&bytes[8..]);
+        wireguard_nt_raw::GUID {
+            Data1: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            Data2: u16::from_be_bytes([bytes[4], bytes[5]]),
+            Data3: u16::from_be_bytes([bytes[6], bytes[7]]),
+            Data4: data4,
+        }
+    }
+}
+
+impl From<wireguard_nt_raw::GUID> for Guid {
+    fn from(guid: wireguard_nt_raw::GUID) -> Self {
+        let mut bytes = [0u8; 16];
+        bytes[..4].copy_from_slice(&guid.Data1.to_be_bytes());
+        bytes[4..6].copy_from_slice(&guid.Data2.to_be_bytes());
+        bytes[6..8].copy_from_slice(&guid.Data3.to_be_bytes());
+        bytes[8..].copy_from_slice(&guid.Data4);
+        Guid::from(bytes)
+    }
+}
+
+impl std::fmt::Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let v = self.0;
+        write!(
+            f,
+            "{{{:08X}-{:04X}-{:04X}-{:04X}-{:012X}}}",
+            v >> 96,
+            (v >> 80) & 0xFFFF,
+            (v >> 64) & 0xFFFF,
+            (v >> 48) & 0xFFFF,
+            v & 0xFFFF_FFFF_FFFF
+        )
+    }
+}
+
+impl Guid {
+    /// Returns a new random GUID from `CoCreateGuid`
+    fn random() -> Result<Guid, Error> {
+        //SAFETY: GUID only contains integers, so all zeroes is a valid value
+        let mut guid: winapi::shared::guiddef::GUID = unsafe { std::mem::zeroed() };
+        //SAFETY: the pointer is valid for the duration of the call
+        let result = unsafe { winapi::um::combaseapi::CoCreateGuid(&mut guid) };
+        if result != 0 {
+            return Err(Error::Win32(result as u32));
+        }
+        Ok(Guid::from(wireguard_nt_raw::GUID {
+            Data1: guid.Data1,
+            Data2: guid.Data2,
+            Data3: guid.Data3,
+            Data4: guid.Data4,
+        }))
+    }
+}
 
-    /// The address this peer is reachable from using UDP across the internet
-    pub endpoint: SocketAddr,
+/// An adapter returned by [`Adapter::create_resolving_guid`], together with its GUID
+pub struct CreatedAdapter<A: WireGuardApi = crate::dll> {
+    /// The new adapter
+    pub adapter: Adapter<A>,
 
-    /// The set of [`IpNet`]'s that dictate what packets are allowed to be sent of received from
-    /// this peer
-    pub allowed_ips: Vec<IpNet>,
+    /// The GUID the adapter was created with. Pass it to [`Adapter::create`] again later to reuse
+    /// the same device node, and with it the interface index, network profile and firewall rules
+    pub guid: Guid,
 }
 
+/// Whether a reboot was needed to finish installing the driver.
+///
+/// Older versions of the driver reported this when creating an adapter. Since WireGuard NT 0.10,
+/// `WireGuardCreateAdapter` installs the driver without needing a reboot and has no such output,
+/// so [`Adapter::create`] has nothing to report
+#[deprecated(note = "WireGuardCreateAdapter no longer reports whether a reboot is required")]
 pub type RebootRequired = bool;
 
-/// The data required when setting the config for an interface
-pub struct SetInterface {
-    /// The port this interface should listen on.
-    /// The default 51820 is used if this is set to `None`
-    pub listen_port: Option<u16>,
-
-    /// The public key of this interface.
-    /// If this is `None`, the public key is generated from the private key
-    pub public_key: Option<[u8; 32]>,
-
-    /// The private key of this interface
-    pub private_key: Option<[u8; 32]>,
-
-    /// The peers that this interface is allowed to communicate with
-    pub peers: Vec<SetPeer>,
-}
-
-fn encode_name(
-    name: &str,
-    wireguard: Arc<wireguard_nt_raw::wireguard>,
-) -> Result<
-    (U16CString, Arc<wireguard_nt_raw::wireguard>),
-    (crate::WireGuardError, Arc<wireguard_nt_raw::wireguard>),
-> {
-    let utf16 = match U16CString::from_str(name) {
-        Ok(u) => u,
-        Err(e) => return Err((e.into(), wireguard)),
+bitflags::bitflags! {
+    pub(crate) struct InterfaceFlags: i32 {
+        const HAS_PUBLIC_KEY =  1 << 0;
+        const HAS_PRIVATE_KEY = 1 << 1;
+        const HAS_LISTEN_PORT = 1 << 2;
+        const REPLACE_PEERS =  1 << 3;
+    }
+}
+
+bitflags::bitflags! {
+    pub(crate) struct PeerFlags: i32 {
+        const HAS_PUBLIC_KEY =  1 << 0;
+        const HAS_PRESHARED_KEY = 1 << 1;
+        const HAS_PERSISTENT_KEEPALIVE = 1 << 2;
+        const HAS_ENDPOINT = 1 << 3;
+        const REPLACE_ALLOWED_IPS = 1 << 5;
+        const REMOVE = 1 << 6;
+        const UPDATE = 1 << 7;
+    }
+}
+
+/// The registry key holding the driver settings of every network adapter
+const NET_CLASS_KEY: &str =
+    "SYSTEM\\CurrentControlSet\\Control\\Class\\{4d36e972-e325-11ce-bfc1-08002be10318}";
+
+/// The registry key holding the connection names of every network adapter
+const NET_CONNECTION_KEY: &str =
+    "SYSTEM\\CurrentControlSet\\Control\\Network\\{4d36e972-e325-11ce-bfc1-08002be10318}";
+
+/// The hardware id that WireGuard NT adapters are created with
+const WIREGUARD_HARDWARE_ID: &str = "wireguard";
+
+/// How often [`Adapter::wait_for_handshake`] and its async counterpart read the config
+pub(crate) const HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long [`Adapter::reset`] keeps an adapter down, so that the driver drops its sessions
+const RESET_DELAY: Duration = Duration::from_millis(100);
+
+/// Returns the interface and peer flags that `options` map to
+fn option_flags(options: SetConfigOptions) -> (InterfaceFlags, PeerFlags) {
+    let mut interface_flags = InterfaceFlags::empty();
+    if options.replace_peers {
+        interface_flags |= InterfaceFlags::REPLACE_PEERS;
+    }
+    let mut peer_flags = PeerFlags::empty();
+    if options.replace_allowed_ips {
+        peer_flags |= PeerFlags::REPLACE_ALLOWED_IPS;
+    }
+    (interface_flags, peer_flags)
+}
+
+/// Returns the `WIREGUARD_INTERFACE_FLAG`s for the fields that are set in `config`, combined with
+/// `extra`
+fn interface_flags_for(config: &Interface, extra: InterfaceFlags) -> InterfaceFlags {
+    let mut flags = extra;
+    if config.private_key.is_some() {
+        flags |= InterfaceFlags::HAS_PRIVATE_KEY;
+    }
+    if config.public_key.is_some() {
+        flags |= InterfaceFlags::HAS_PUBLIC_KEY;
+    }
+    if config.listen_port.is_some() {
+        flags |= InterfaceFlags::HAS_LISTEN_PORT;
+    }
+    flags
+}
+
+/// Returns the `WIREGUARD_PEER_FLAG`s for the fields that are set in `peer`, combined with
+/// `extra`. The public key is always sent because the driver uses it to identify the peer
+fn peer_flags_for(peer: &Peer, extra: PeerFlags) -> PeerFlags {
+    let mut flags = extra | PeerFlags::HAS_PUBLIC_KEY;
+    if peer.preshared_key.is_some() {
+        flags |= PeerFlags::HAS_PRESHARED_KEY;
+    }
+    if peer.persistent_keepalive.is_some() {
+        flags |= PeerFlags::HAS_PERSISTENT_KEEPALIVE;
+    }
+    if peer.endpoint.is_some() {
+        flags |= PeerFlags::HAS_ENDPOINT;
+    }
+    flags
+}
+
+/// Fills in `wg_peer` from `peer`. `wg_peer` must be zeroed, so that fields which are `None`
+/// in `peer` are left zeroed and their flags are not set
+fn write_peer(wg_peer: &mut WIREGUARD_PEER, peer: &Peer, extra: PeerFlags) -> Result<(), Error> {
+    wg_peer.Flags = peer_flags_for(peer, extra).bits;
+    wg_peer.PublicKey = *peer.public_key.as_bytes();
+    if let Some(preshared_key) = &peer.preshared_key {
+        wg_peer.PresharedKey = *preshared_key.as_bytes();
+    }
+    if let Some(keep_alive) = peer.persistent_keepalive {
+        wg_peer.PersistentKeepalive =
+            crate::config::keepalive_to_seconds(keep_alive).map_err(|e| match e {
+                Error::InvalidConfig(reason) => invalid_peer(peer, &reason),
+                e => e,
+            })?;
+    }
+    if let Some(endpoint) = &peer.endpoint {
+        check_endpoint(peer, endpoint)?;
+        wg_peer.Endpoint = endpoint_to_sockaddr_inet(endpoint);
+    }
+    wg_peer.AllowedIPsCount = peer.allowed_ips.len() as u32;
+    Ok(())
+}
+
+/// Returns an [`Error::InvalidConfig`] that names `peer` by its public key
+fn invalid_peer(peer: &Peer, reason: &str) -> Error {
+    Error::InvalidConfig(format!("Peer {}: {}", peer.public_key, reason))
+}
+
+/// Rejects endpoints that the driver would accept but could never send packets to. Keys and
+/// allowed ips need no checks here, their types only hold values the driver accepts
+fn check_endpoint(peer: &Peer, endpoint: &SocketAddr) -> Result<(), Error> {
+    if endpoint.port() == 0 {
+        return Err(invalid_peer(
+            peer,
+            &format!("endpoint {} has port 0", endpoint),
+        ));
+    }
+    if endpoint.ip().is_unspecified() {
+        return Err(invalid_peer(
+            peer,
+            &format!("endpoint {} has no address", endpoint),
+        ));
+    }
+    if let SocketAddr::V6(v6) = endpoint {
+        //The driver sends to IPv6 endpoints from its IPv6 socket, which cannot reach IPv4 hosts
+        if let [0, 0, 0, 0, 0, 0xffff, high, low] = v6.ip().segments() {
+            let v4 = Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+            return Err(invalid_peer(
+                peer,
+                &format!(
+                    "endpoint {} is an IPv4-mapped IPv6 address, use {}:{} instead",
+                    endpoint,
+                    v4,
+                    v6.port()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Converts a `WIREGUARD_PEER` read from the driver into a `Peer`, leaving out the allowed ips
+/// that follow it. Fields whose flag is not set are `None`
+pub(crate) fn read_peer(peer: &WIREGUARD_PEER) -> Result<Peer, Error> {
+    let flags = PeerFlags::from_bits_truncate(peer.Flags);
+    Ok(Peer {
+        public_key: Key::new(peer.PublicKey),
+        //An all zero preshared key is how WireGuard says that there is none
+        preshared_key: Some(Key::new(peer.PresharedKey)).filter(|key| {
+            flags.contains(PeerFlags::HAS_PRESHARED_KEY) && *key != Key::new([0; 32])
+        }),
+        persistent_keepalive: Some(peer.PersistentKeepalive)
+            .filter(|&secs| secs != 0 && flags.contains(PeerFlags::HAS_PERSISTENT_KEEPALIVE))
+            .map(|secs| Duration::from_secs(secs.into())),
+        endpoint: sockaddr_inet_to_endpoint(&peer.Endpoint)?,
+        tx_bytes: peer.TxBytes,
+        rx_bytes: peer.RxBytes,
+        last_handshake: util::filetime_to_system_time(peer.LastHandshake),
+        //The allowed ips are decoded by `PeerRef::to_peer`
+        allowed_ips: Vec::new(),
+    })
+}
+
+/// Builds the row that assigns `addr` to the interface `luid`, with `addr`'s prefix length as the
+/// on-link prefix
+#[cfg(feature = "ipnet")]
+fn unicast_address_row(
+    luid: Luid,
+    addr: &IpNet,
+) -> winapi::shared::netioapi::MIB_UNICASTIPADDRESS_ROW {
+    use winapi::shared::netioapi::{InitializeUnicastIpAddressEntry, MIB_UNICASTIPADDRESS_ROW};
+    use winapi::shared::nldef::IpDadStatePreferred;
+    use winapi::shared::ws2def::{AF_INET, AF_INET6};
+    use winapi::shared::{in6addr::IN6_ADDR, inaddr::IN_ADDR};
+
+    unsafe {
+        //SAFETY: the row only contains integers, so all zeroes is a valid value
+        let mut address_row: MIB_UNICASTIPADDRESS_ROW = std::mem::zeroed();
+        InitializeUnicastIpAddressEntry(&mut address_row);
+        address_row.InterfaceLuid.Value = luid.value();
+        address_row.OnLinkPrefixLength = addr.prefix_len();
+        address_row.DadState = IpDadStatePreferred;
+
+        match addr {
+            IpNet::V4(v4) => {
+                address_row.Address.Ipv4_mut().sin_family = AF_INET as u16;
+                address_row.Address.Ipv4_mut().sin_addr =
+                    std::mem::transmute::<[u8; 4], IN_ADDR>(v4.addr().octets());
+            }
+            IpNet::V6(v6) => {
+                address_row.Address.Ipv6_mut().sin6_family = AF_INET6 as u16;
+                address_row.Address.Ipv6_mut().sin6_addr =
+                    std::mem::transmute::<[u8; 16], IN6_ADDR>(v6.addr().octets());
+            }
+        }
+        address_row
+    }
+}
+
+/// Reads the IP interface settings of the interface `luid` for the address family `family`
+fn ip_interface_row(
+    luid: Luid,
+    family: i32,
+) -> Result<winapi::shared::netioapi::MIB_IPINTERFACE_ROW, Error> {
+    use winapi::shared::netioapi::{
+        GetIpInterfaceEntry, InitializeIpInterfaceEntry, MIB_IPINTERFACE_ROW,
+    };
+    use winapi::shared::winerror::ERROR_SUCCESS;
+
+    unsafe {
+        //SAFETY: the row only contains integers, so all zeroes is a valid value
+        let mut row: MIB_IPINTERFACE_ROW = std::mem::zeroed();
+        InitializeIpInterfaceEntry(&mut row);
+        row.InterfaceLuid.Value = luid.value();
+        row.Family = family as u16;
+        match GetIpInterfaceEntry(&mut row) {
+            ERROR_SUCCESS => Ok(row),
+            err => Err(Error::Win32(err)),
+        }
+    }
+}
+
+/// Builds the row for a route to `dest` through the interface `luid`. A `next_hop` of `None` is
+/// sent as the unspecified address of `dest`'s family, which makes the route on-link
+#[cfg(feature = "ipnet")]
+fn forward_row(
+    luid: Luid,
+    dest: &IpNet,
+    next_hop: Option<IpAddr>,
+    metric: u32,
+) -> Result<winapi::shared::netioapi::MIB_IPFORWARD_ROW2, Error> {
+    use winapi::shared::netioapi::{InitializeIpForwardEntry, MIB_IPFORWARD_ROW2};
+    use winapi::shared::ws2def::{AF_INET, AF_INET6};
+    use winapi::shared::{in6addr::IN6_ADDR, inaddr::IN_ADDR};
+
+    let dest = dest.trunc();
+    let next_hop = match (dest, next_hop) {
+        (IpNet::V4(_), None) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (IpNet::V6(_), None) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        (IpNet::V4(_), Some(hop @ IpAddr::V4(_))) | (IpNet::V6(_), Some(hop @ IpAddr::V6(_))) => {
+            hop
+        }
+        (_, Some(hop)) => {
+            return Err(Error::InvalidConfig(format!(
+                "Next hop {} is not in the same address family as {}",
+                hop, dest
+            )))
+        }
+    };
+
+    unsafe {
+        //SAFETY: the row only contains integers, so all zeroes is a valid value
+        let mut route: MIB_IPFORWARD_ROW2 = std::mem::zeroed();
+        InitializeIpForwardEntry(&mut route);
+        route.InterfaceLuid.Value = luid.value();
+        match dest {
+            IpNet::V4(v4) => {
+                *route.DestinationPrefix.Prefix.si_family_mut() = AF_INET as u16;
+                route.DestinationPrefix.Prefix.Ipv4_mut().sin_addr =
+                    std::mem::transmute::<[u8; 4], IN_ADDR>(v4.addr().octets());
+            }
+            IpNet::V6(v6) => {
+                *route.DestinationPrefix.Prefix.si_family_mut() = AF_INET6 as u16;
+                route.DestinationPrefix.Prefix.Ipv6_mut().sin6_addr =
+                    std::mem::transmute::<[u8; 16], IN6_ADDR>(v6.addr().octets());
+            }
+        }
+        route.DestinationPrefix.PrefixLength = dest.prefix_len();
+        match next_hop {
+            IpAddr::V4(v4) => {
+                *route.NextHop.si_family_mut() = AF_INET as u16;
+                route.NextHop.Ipv4_mut().sin_addr =
+                    std::mem::transmute::<[u8; 4], IN_ADDR>(v4.octets());
+            }
+            IpAddr::V6(v6) => {
+                *route.NextHop.si_family_mut() = AF_INET6 as u16;
+                route.NextHop.Ipv6_mut().sin6_addr =
+                    std::mem::transmute::<[u8; 16], IN6_ADDR>(v6.octets());
+            }
+        }
+        route.Metric = metric;
+        Ok(route)
+    }
+}
+
+/// The metric of the routes that send the allowed ips of peers through an adapter
+#[cfg(feature = "ipnet")]
+const ALLOWED_IP_ROUTE_METRIC: u32 = 5;
+
+/// Reads the address and on-link prefix length of a unicast address row. Returns `None` if the
+/// row is not an IPv4 or IPv6 address
+#[cfg(feature = "ipnet")]
+fn unicast_row_address(row: &winapi::shared::netioapi::MIB_UNICASTIPADDRESS_ROW) -> Option<IpNet> {
+    let addr = sockaddr_inet_address(&row.Address)?;
+    IpNet::new(addr, row.OnLinkPrefixLength).ok()
+}
+
+/// Reads the address of a `SOCKADDR_INET` from the IP Helper API. Returns `None` if it is not an
+/// IPv4 or IPv6 address
+#[cfg(feature = "ipnet")]
+fn sockaddr_inet_address(sockaddr: &winapi::shared::ws2ipdef::SOCKADDR_INET) -> Option<IpAddr> {
+    use winapi::shared::ws2def::{AF_INET, AF_INET6};
+
+    //SAFETY: the family says which member of the union is valid
+    unsafe {
+        match *sockaddr.si_family() as i32 {
+            AF_INET => {
+                let octets = sockaddr.Ipv4().sin_addr.S_un.S_un_b();
+                Some(IpAddr::V4(Ipv4Addr::new(
+                    octets.s_b1,
+                    octets.s_b2,
+                    octets.s_b3,
+                    octets.s_b4,
+                )))
+            }
+            AF_INET6 => Some(IpAddr::V6(Ipv6Addr::from(
+                *sockaddr.Ipv6().sin6_addr.u.Byte(),
+            ))),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the destination of `row` if it is an on-link route that [`Adapter::sync_routes`]
+/// manages, meaning one created through the IP Helper API rather than by Windows itself for the
+/// addresses of the interface
+#[cfg(feature = "ipnet")]
+fn managed_route_destination(row: &winapi::shared::netioapi::MIB_IPFORWARD_ROW2) -> Option<IpNet> {
+    use winapi::shared::nldef::MIB_IPPROTO_NETMGMT;
+
+    if row.Protocol != MIB_IPPROTO_NETMGMT || !sockaddr_inet_address(&row.NextHop)?.is_unspecified()
+    {
+        return None;
+    }
+    let destination = sockaddr_inet_address(&row.DestinationPrefix.Prefix)?;
+    IpNet::new(destination, row.DestinationPrefix.PrefixLength).ok()
+}
+
+/// Compares the routes that the allowed ips `wanted` need with the managed routes `existing`,
+/// returning the destinations to add and the existing routes to remove
+#[cfg(feature = "ipnet")]
+fn route_changes(wanted: &[IpNet], existing: &[IpNet]) -> (Vec<IpNet>, Vec<IpNet>) {
+    let mut add = Vec::new();
+    for dest in wanted.iter().map(IpNet::trunc) {
+        if !existing.contains(&dest) && !add.contains(&dest) {
+            add.push(dest);
+        }
+    }
+    let remove = existing
+        .iter()
+        .filter(|dest| !wanted.iter().any(|wanted| wanted.trunc() == **dest))
+        .copied()
+        .collect();
+    (add, remove)
+}
+
+/// Reads the name and pool of every WireGuard adapter from the network adapter registry keys.
+/// The pool is `None` if the adapter has no device description
+pub(crate) fn registry_adapters() -> Result<Vec<(String, Option<String>)>, Error> {
+    let class = match RegKey::open_local_machine(NET_CLASS_KEY)? {
+        Some(class) => class,
+        None => return Ok(Vec::new()),
+    };
+    let mut adapters = Vec::new();
+    for subkey in class.subkeys()? {
+        //The class key also holds a `Properties` subkey, which has no component id
+        match class.get_string(&subkey, "ComponentId")? {
+            Some(id) if id.eq_ignore_ascii_case(WIREGUARD_HARDWARE_ID) => {}
+            _ => continue,
+        }
+        let pool = match class.get_string(&subkey, "DeviceInstanceID")? {
+            Some(instance_id) => {
+                let enum_path = format!("SYSTEM\\CurrentControlSet\\Enum\\{}", instance_id);
+                match RegKey::open_local_machine(&enum_path)? {
+                    Some(device) => device
+                        .get_string("", "DeviceDesc")?
+                        .map(|description| pool_from_description(&description).to_owned()),
+                    None => None,
+                }
+            }
+            None => None,
+        };
+        let cfg_instance_id = match class.get_string(&subkey, "NetCfgInstanceId")? {
+            Some(id) => id,
+            None => continue,
+        };
+        let connection_path = format!("{}\\{}\\Connection", NET_CONNECTION_KEY, cfg_instance_id);
+        if let Some(connection) = RegKey::open_local_machine(&connection_path)? {
+            if let Some(name) = connection.get_string("", "Name")? {
+                adapters.push((name, pool));
+            }
+        }
+    }
+    Ok(adapters)
+}
+
+/// Opens the entry in [`NET_CLASS_KEY`] of the network adapter whose `NetCfgInstanceId` is `guid`
+/// for writing. Returns `None` if no adapter has that id
+fn net_class_key(guid: &Guid) -> Result<Option<RegKey>, Error> {
+    let class = match RegKey::open_local_machine(NET_CLASS_KEY)? {
+        Some(class) => class,
+        None => return Ok(None),
     };
+    let guid = guid.to_string();
+    for subkey in class.subkeys()? {
+        match class.get_string(&subkey, "NetCfgInstanceId")? {
+            Some(id) if id.eq_ignore_ascii_case(&guid) => {
+                let path = format!("{}\\{}", NET_CLASS_KEY, subkey);
+                return RegKey::open_local_machine_writeable(&path);
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the names of the WireGuard adapters in `pool`, or of every WireGuard adapter if `pool`
+/// is `None`, as listed by `wireguard`. For the real driver they come from the network adapter
+/// registry keys
+pub(crate) fn adapter_names(
+    wireguard: &impl WireGuardApi,
+    pool: Option<&str>,
+) -> Result<Vec<String>, Error> {
+    Ok(wireguard
+        .adapters()?
+        .into_iter()
+        .filter(|(_, adapter_pool)| match pool {
+            Some(pool) => adapter_pool.as_deref() == Some(pool),
+            None => true,
+        })
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Looks up the pool of the adapter named `name` in the adapters listed by `wireguard`. Adapter
+/// names are not case sensitive
+fn adapter_pool(wireguard: &impl WireGuardApi, name: &str) -> Result<Option<String>, Error> {
+    Ok(wireguard
+        .adapters()?
+        .into_iter()
+        .find(|(adapter_name, _)| adapter_name.eq_ignore_ascii_case(name))
+        .and_then(|(_, pool)| pool))
+}
+
+/// Returns the pool named by the `DeviceDesc` of an adapter.
+///
+/// Descriptions may be stored as an indirect string like `@oem1.inf,%desc%;WireGuard`, in which
+/// case the text after the last `;` is the pool
+fn pool_from_description(description: &str) -> &str {
+    match description.strip_prefix('@') {
+        Some(indirect) => indirect.rsplit(';').next().unwrap_or(indirect),
+        None => description,
+    }
+}
+
+/// Encodes an adapter or pool name for the driver, rejecting names that it would not accept with
+/// [`Error::InvalidName`]
+fn encode_name(name: &str) -> Result<Vec<u16>, Error> {
+    if name.is_empty() {
+        return Err(Error::InvalidName("Name cannot be empty".to_owned()));
+    }
+    let utf16 = util::to_wide(name)
+        .map_err(|_| Error::InvalidName(format!("Name {:?} contains a NUL character", name)))?;
     let max = crate::MAX_NAME;
-    if utf16.len() >= max {
-        //max_characters is the maximum number of characters including the null terminator. And .len() measures the
-        //number of characters (excluding the null terminator). Therefore we can hold a string with
-        //max_characters - 1 because the null terminator sits in the last element. A string
-        //of length max_characters needs max_characters + 1 to store the null terminator so the >=
-        //check holds
-        Err((
-            format!(
-                //TODO: Better error handling
-                "Length too large. Size: {}, Max: {}",
-                utf16.len(),
-                max,
-            )
-            .into(),
-            wireguard,
-        ))
+    //`utf16` and `max` both include the null terminator
+    if utf16.len() > max {
+        Err(Error::InvalidName(format!(
+            "Length too large. Size: {}, Max: {}",
+            utf16.len() - 1,
+            max - 1,
+        )))
     } else {
-        Ok((utf16, wireguard))
+        Ok(utf16)
     }
 }
 
@@ -111,552 +681,3223 @@ pub struct EnumeratedAdapter {
     pub name: String,
 }
 
-fn win_error(context: &str, error_code: u32) -> Result<(), Box<dyn std::error::Error>> {
-    let e = std::io::Error::from_raw_os_error(error_code as i32);
-    Err(format!("{} - {}", context, e).into())
+/// How an [`Adapter`] was obtained, see [`Adapter::origin`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdapterOrigin {
+    /// The adapter was created by [`Adapter::create`], so closing it removes the adapter
+    Created,
+
+    /// An existing adapter was opened by [`Adapter::open`], so closing it only closes the handle
+    Opened,
+}
+
+/// The state of an adapter. Maps to `WIREGUARD_ADAPTER_STATE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum AdapterState {
+    /// The adapter is down and does not send or receive packets
+    Down = wireguard_nt_raw::WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_DOWN,
+
+    /// The adapter is up
+    Up = wireguard_nt_raw::WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_UP,
+}
+
+impl From<AdapterState> for wireguard_nt_raw::WIREGUARD_ADAPTER_STATE {
+    fn from(state: AdapterState) -> Self {
+        state as wireguard_nt_raw::WIREGUARD_ADAPTER_STATE
+    }
 }
 
-const WIREGUARD_STATE_DOWN: i32 = 0;
-const WIREGUARD_STATE_UP: i32 = 1;
+impl TryFrom<wireguard_nt_raw::WIREGUARD_ADAPTER_STATE> for AdapterState {
+    type Error = wireguard_nt_raw::WIREGUARD_ADAPTER_STATE;
 
-impl Adapter {
-    //TODO: Call get last error for error information on failure and improve error types
+    /// Converts a raw `WIREGUARD_ADAPTER_STATE`, returning the value back if it is unknown
+    fn try_from(state: wireguard_nt_raw::WIREGUARD_ADAPTER_STATE) -> Result<Self, Self::Error> {
+        match state {
+            wireguard_nt_raw::WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_DOWN => {
+                Ok(AdapterState::Down)
+            }
+            wireguard_nt_raw::WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_UP => {
+                Ok(AdapterState::Up)
+            }
+            other => Err(other),
+        }
+    }
+}
 
+impl<A: WireGuardApi> Adapter<A> {
     /// Creates a new wireguard adapter inside the pool `pool` with name `name`
     ///
     /// Optionally a GUID can be specified that will become the GUID of this adapter once created.
+    /// Creating an adapter with a GUID that was used before reuses the same device node, so
+    /// Windows keeps the interface index, network profile and firewall rules of the earlier
+    /// adapter. If `guid` is `None` the driver picks a random GUID.
+    ///
+    /// `pool` and `name` must be non-empty and at most 127 characters long, otherwise
+    /// [`Error::InvalidName`] is returned without calling the driver.
+    ///
+    /// Creating adapters requires administrator privileges. If the driver denies access and the
+    /// process is not elevated, [`Error::NotElevated`] is returned
     pub fn create(
-        wireguard: Arc<wireguard_nt_raw::wireguard>,
+        wireguard: &Arc<A>,
         pool: &str,
         name: &str,
-        guid: Option<u128>,
-    ) -> Result<Adapter, (crate::WireGuardError, Arc<wireguard_nt_raw::wireguard>)> {
-        let (pool_utf16, wireguard) = encode_name(pool, wireguard)?;
-        let (name_utf16, wireguard) = encode_name(name, wireguard)?;
-
-        let guid = match guid {
-            Some(guid) => guid,
-            None => {
-                let mut guid_bytes: [u8; 16] = [0u8; 16];
-                rand::thread_rng().fill(&mut guid_bytes);
-                u128::from_ne_bytes(guid_bytes)
-            }
+        guid: Option<Guid>,
+    ) -> Result<Self, Error> {
+        let pool_utf16 = encode_name(pool)?;
+        let name_utf16 = encode_name(name)?;
+
+        let guid_struct = guid.map(wireguard_nt_raw::GUID::from);
+        let guid_ptr = match &guid_struct {
+            Some(guid) => guid as *const wireguard_nt_raw::GUID,
+            None => ptr::null(),
         };
-        //SAFETY: guid is a unique integer so transmuting either all zeroes or the user's preferred
-        //guid to the winapi guid type is safe and will allow the windows kernel to see our GUID
-        let guid_struct: wireguard_nt_raw::GUID = unsafe { std::mem::transmute(guid) };
-        //TODO: The guid of the adapter once created might differ from the one provided because of
-        //the byte order of the segments of the GUID struct that are larger than a byte. Verify
-        //that this works as expected
 
-        crate::log::set_default_logger_if_unset(&wireguard);
+        crate::log::set_default_logger_if_unset(wireguard);
 
         //SAFETY: the function is loaded from the wireguard dll properly, we are providing valid
         //pointers, and all the strings are correct null terminated UTF-16. This safety rationale
         //applies for all WireGuard* functions below
-        let result = unsafe {
-            wireguard.WireGuardCreateAdapter(
-                pool_utf16.as_ptr(),
-                name_utf16.as_ptr(),
-                &guid_struct as *const wireguard_nt_raw::GUID,
-            )
-        };
+        let result =
+            unsafe { wireguard.create_adapter(pool_utf16.as_ptr(), name_utf16.as_ptr(), guid_ptr) };
 
         if result.is_null() {
-            Err(("Failed to crate adapter".into(), wireguard))
+            match Error::last_win32() {
+                Error::Win32(ERROR_ACCESS_DENIED) if !crate::util::is_elevated() => {
+                    Err(Error::NotElevated)
+                }
+                err => Err(err),
+            }
         } else {
             Ok(Self {
                 adapter: UnsafeHandle(result),
-                wireguard,
+                wireguard: Arc::clone(wireguard),
+                name: name.to_owned(),
+                pool: pool.to_owned(),
+                origin: AdapterOrigin::Created,
+                endpoint_hosts: Mutex::default(),
             })
         }
     }
 
-    /// Attempts to open an existing wireguard with name `name`.
-    pub fn open(
-        wireguard: Arc<wireguard_nt_raw::wireguard>,
+    /// Like [`Adapter::create`], but gives up waiting after `timeout` and returns [`Error::Timeout`].
+    ///
+    /// The first adapter created on a system installs the driver, which can take tens of seconds,
+    /// so this lets a UI report progress or fail instead of freezing. The timeout only bounds the
+    /// wait, not the operation: `create` runs on a worker thread that is left to finish, so the
+    /// driver is never left half installed. An adapter that is created after the timeout is
+    /// removed again once the worker finishes. A later call, or [`Adapter::open_or_create`], can
+    /// then create the adapter with the driver already installed
+    pub fn create_with_timeout(
+        wireguard: &Arc<A>,
+        pool: &str,
+        name: &str,
+        guid: Option<Guid>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel();
+        let (wireguard, pool, name) = (Arc::clone(wireguard), pool.to_owned(), name.to_owned());
+        let worker = thread::spawn(move || {
+            //If the caller stopped waiting, sending fails and the adapter is dropped, which
+            //removes it
+            let _ = sender.send(Adapter::create(&wireguard, &pool, &name, guid));
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::Timeout),
+            //The worker only stops without sending if `create` panicked
+            Err(mpsc::RecvTimeoutError::Disconnected) => match worker.join() {
+                Err(panic) => std::panic::resume_unwind(panic),
+                Ok(()) => unreachable!("the worker always sends a result"),
+            },
+        }
+    }
+
+    /// Like [`Adapter::create`], but also returns the GUID of the new adapter, so that it can be
+    /// stored and passed to [`Adapter::create`] next time.
+    ///
+    /// If `guid` is `None`, a random GUID is generated with `CoCreateGuid` and requested from the
+    /// driver, instead of letting the driver pick one. The returned GUID is therefore the one the
+    /// adapter was created with, without looking it up through the LUID as [`Adapter::get_guid`]
+    /// does, which can fail while Windows is still setting up the interface
+    pub fn create_resolving_guid(
+        wireguard: &Arc<A>,
+        pool: &str,
         name: &str,
-    ) -> Result<Adapter, (crate::WireGuardError, Arc<wireguard_nt_raw::wireguard>)> {
-        let (name_utf16, wireguard) = encode_name(name, wireguard)?;
+        guid: Option<Guid>,
+    ) -> Result<CreatedAdapter<A>, Error> {
+        let guid = match guid {
+            Some(guid) => guid,
+            None => Guid::random()?,
+        };
+        let adapter = Adapter::create(wireguard, pool, name, Some(guid))?;
+        Ok(CreatedAdapter { adapter, guid })
+    }
+
+    /// Attempts to open an existing wireguard with name `name`.
+    ///
+    /// Returns [`Error::AdapterNotFound`] if no adapter with that name exists,
+    /// [`Error::AccessDenied`] if the process lacks the rights to open it, and
+    /// [`Error::InvalidName`] for names that [`Adapter::create`] would reject. The pool of the
+    /// adapter is read from the registry. If it cannot be found, [`Adapter::pool`] returns an empty
+    /// string
+    pub fn open(wireguard: &Arc<A>, name: &str) -> Result<Self, Error> {
+        let name_utf16 = encode_name(name)?;
 
-        crate::log::set_default_logger_if_unset(&wireguard);
+        crate::log::set_default_logger_if_unset(wireguard);
 
-        let result = unsafe { wireguard.WireGuardOpenAdapter(name_utf16.as_ptr()) };
+        let result = unsafe { wireguard.open_adapter(name_utf16.as_ptr()) };
 
         if result.is_null() {
-            Err(("WireGuardOpenAdapter failed".into(), wireguard))
+            Err(open_error(unsafe { GetLastError() }))
         } else {
-            Ok(Adapter {
+            Ok(Self {
                 adapter: UnsafeHandle(result),
-                wireguard,
+                wireguard: Arc::clone(wireguard),
+                name: name.to_owned(),
+                pool: adapter_pool(&**wireguard, name)?.unwrap_or_default(),
+                origin: AdapterOrigin::Opened,
+                endpoint_hosts: Mutex::default(),
             })
         }
     }
 
-    /// Sets the wireguard configuration of this adapter
-    pub fn set_config(&self, config: &SetInterface) -> Result<(), WireGuardError> {
-        use wireguard_nt_raw::*;
-
-        bitflags::bitflags! {
-            struct InterfaceFlags: i32 {
-                const HAS_PUBLIC_KEY =  1 << 0;
-                const HAS_PRIVATE_KEY = 1 << 1;
-                const HAS_LISTEN_PORT = 1 << 2;
-                const REPLACE_PEERS =  1 << 3;
-            }
+    /// Opens the adapter with name `name`, or creates it inside the pool `pool` if it does not
+    /// exist yet.
+    ///
+    /// Only [`Error::AdapterNotFound`] from [`Adapter::open`] leads to creating the adapter, any
+    /// other error such as [`Error::AccessDenied`] is returned as is. `guid` is only used when the
+    /// adapter is created, see [`Adapter::create`].
+    ///
+    /// If creating the adapter fails with a Win32 error and no driver is running afterwards,
+    /// [`Error::DriverNotInstalled`] is returned instead, see [`crate::ensure_driver_present`]
+    pub fn open_or_create(
+        wireguard: &Arc<A>,
+        pool: &str,
+        name: &str,
+        guid: Option<Guid>,
+    ) -> Result<Self, Error> {
+        match Adapter::open(wireguard, name) {
+            Err(Error::AdapterNotFound) => match Adapter::create(wireguard, pool, name, guid) {
+                Err(Error::Win32(code)) => match util::ensure_driver_present(wireguard) {
+                    Err(Error::DriverNotInstalled) => {
+                        log::warn!("Creating adapter {} failed with os error {}", name, code);
+                        Err(Error::DriverNotInstalled)
+                    }
+                    _ => Err(Error::Win32(code)),
+                },
+                result => result,
+            },
+            result => result,
         }
+    }
 
-        bitflags::bitflags! {
-            struct PeerFlags: i32 {
-                const HAS_PUBLIC_KEY =  1 << 0;
-                const HAS_PRESHARED_KEY = 1 << 1;
-                const HAS_PERSISTENT_KEEPALIVE = 1 << 2;
-                const HAS_ENDPOINT = 1 << 3;
-                const REPLACE_ALLOWED_IPS = 1 << 5;
-                const REMOVE = 1 << 6;
-                const UPDATE = 1 << 7;
+    /// Closes this handle, removing the adapter if it was created by [`Adapter::create`], and
+    /// returns whether the cleanup succeeded.
+    ///
+    /// The failure that can happen is removing the NRPT rule set by [`Adapter::set_nrpt_domains`],
+    /// which would otherwise keep sending DNS queries for its domains to the servers of an adapter
+    /// that no longer exists. `WireGuardCloseAdapter` itself cannot fail, so the handle is closed
+    /// and the adapter removed even when an error is returned.
+    ///
+    /// Dropping an `Adapter` does the same, but can only log a failure. The failure is logged as a
+    /// [`LogLevel::Warn`] message to the logger that receives the driver's messages:
+    /// [`crate::default_logger`], or the one set with [`crate::set_logger`] or its siblings.
+    /// Nothing is logged if logging was turned off
+    pub fn close(mut self) -> Result<(), Error> {
+        //Leaves a null handle, which `Drop` skips
+        self.close_handle()
+    }
+
+    /// Removes the NRPT rule of a created adapter and closes the handle
+    fn close_handle(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        if self.origin == AdapterOrigin::Created {
+            //Closing removes the adapter, so its GUID must be read first. Without a GUID no rule
+            //can have been set through this handle, so there is nothing to remove
+            if let Ok(guid) = self.get_guid() {
+                result = crate::dns::delete_nrpt_rule(guid);
             }
         }
+        unsafe { self.wireguard.close_adapter(self.adapter.0) };
+        self.adapter = UnsafeHandle(ptr::null_mut());
+        result
+    }
 
-        let peer_size: usize = config
-            .peers
-            .iter()
-            .map(|p| {
-                size_of::<WIREGUARD_PEER>()
-                    + p.allowed_ips.len() * size_of::<WIREGUARD_ALLOWED_IP>()
-            })
-            .sum();
+    /// Gives up this handle without closing it, so the adapter stays installed after this
+    /// `Adapter` is gone and can be reopened by name with [`Adapter::open`].
+    ///
+    /// The handle and the loaded wireguard.dll are leaked. WireGuard NT still removes the adapter
+    /// when the process that created it exits, so this keeps an adapter alive for the rest of the
+    /// process, for example across reconnects, but not across restarts of the process
+    pub fn persist(self) {
+        std::mem::forget(self);
+    }
 
-        let size: usize = size_of::<WIREGUARD_INTERFACE>() + peer_size;
-        let align = align_of::<WIREGUARD_INTERFACE>();
+    /// Returns the name this adapter was created or opened with.
+    ///
+    /// The name is updated by [`Adapter::rename`]. A rename made outside this `Adapter`, for
+    /// example in the Network Connections control panel, is not reflected here
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        let mut writer = util::StructWriter::new(size, align);
-        //Most of this function is writing data into `writer`, in a format that wireguard expects
-        //so that it can decode the data when we call WireGuardSetConfiguration
+    /// Returns the pool this adapter belongs to
+    pub fn pool(&self) -> &str {
+        &self.pool
+    }
 
-        // Safety:
-        // 1. `writer` has the correct alignment for a `WIREGUARD_INTERFACE`
-        // 2. Nothing has been written to writer so the internal pointer must be aligned
-        let interface: &mut WIREGUARD_INTERFACE = unsafe { writer.write() };
-        interface.Flags = {
-            let mut flags = InterfaceFlags::REPLACE_PEERS;
-            if let Some(private_key) = &config.private_key {
-                flags |= InterfaceFlags::HAS_PRIVATE_KEY;
-                interface.PrivateKey.copy_from_slice(private_key);
-            }
-            if let Some(pub_key) = &config.public_key {
-                flags |= InterfaceFlags::HAS_PUBLIC_KEY;
-                interface.PublicKey.copy_from_slice(pub_key);
-            }
+    /// Returns whether this adapter was created or opened by this process.
+    ///
+    /// An adapter from [`Adapter::open_or_create`] is [`AdapterOrigin::Opened`] if it already
+    /// existed. Services that attach to an adapter set up by someone else can check this before
+    /// cleanup that would disrupt the adapter, such as taking it down with [`Adapter::down`] or
+    /// removing its routes
+    pub fn origin(&self) -> AdapterOrigin {
+        self.origin
+    }
 
-            if let Some(listen_port) = config.listen_port {
-                flags |= InterfaceFlags::HAS_LISTEN_PORT;
-                interface.ListenPort = listen_port;
-            }
+    /// Changes the name of this adapter, as shown in the Network Connections control panel and
+    /// used by [`Adapter::open`].
+    ///
+    /// The name is written to the adapter's entry in the network connections registry key, which
+    /// requires administrator privileges. Empty names and names that are too long are rejected
+    /// with [`Error::InvalidName`] before the registry is changed
+    pub fn rename(&mut self, new_name: &str) -> Result<(), Error> {
+        //The registry takes any name, so check that the driver would accept it
+        encode_name(new_name)?;
+
+        let guid = self.get_guid()?;
+        let connection_path = format!("{}\\{}\\Connection", NET_CONNECTION_KEY, guid);
+        let connection = RegKey::open_local_machine_writeable(&connection_path)?
+            .ok_or(Error::AdapterNotFound)?;
+        connection.set_string("Name", new_name)?;
+        self.name = new_name.to_owned();
+        Ok(())
+    }
 
-            flags.bits
+    /// Changes the description of this adapter, which the Network Connections control panel shows
+    /// below its name and Device Manager lists it under. Giving each tunnel a description lets
+    /// users tell which application owns it.
+    ///
+    /// The description is written to the `DriverDesc` of the adapter's entry in the network adapter
+    /// registry key, which requires administrator privileges. Windows may only show the new
+    /// description after the adapter is disabled and enabled again. An empty `description` resets
+    /// it to the pool name, which the driver describes new adapters with. The pool is read from a
+    /// different value, so this changes neither [`Adapter::pool`] nor [`Adapter::enumerate`]
+    pub fn set_description(&self, description: &str) -> Result<(), Error> {
+        let guid = self.get_guid()?;
+        let device = net_class_key(&guid)?.ok_or(Error::AdapterNotFound)?;
+        let description = match description {
+            "" => self.pool(),
+            description => description,
         };
-        interface.PeersCount = config.peers.len() as u32;
+        device.set_string("DriverDesc", description)
+    }
 
-        for peer in &config.peers {
-            // Safety:
-            // `align_of::<WIREGUARD_INTERFACE` is 8, WIREGUARD_PEER has no special alignment
-            // requirements, and writer is already aligned to hold `WIREGUARD_INTERFACE` structs,
-            // therefore we uphold the alignment requirements of `write`
-            let mut wg_peer: &mut WIREGUARD_PEER = unsafe { writer.write() };
-
-            wg_peer.Flags = {
-                let mut flags = PeerFlags::HAS_ENDPOINT;
-                if let Some(pub_key) = &peer.public_key {
-                    flags |= PeerFlags::HAS_PUBLIC_KEY;
-                    wg_peer.PublicKey.copy_from_slice(pub_key);
-                }
-                if let Some(preshared_key) = &peer.preshared_key {
-                    flags |= PeerFlags::HAS_PRESHARED_KEY;
-                    wg_peer.PresharedKey.copy_from_slice(preshared_key);
-                }
-                if let Some(keep_alive) = peer.keep_alive {
-                    flags |= PeerFlags::HAS_PERSISTENT_KEEPALIVE;
-                    wg_peer.PersistentKeepalive = keep_alive;
-                }
-                flags.bits
-            };
+    /// Returns the names of all WireGuard adapters in the pool `pool`, including adapters created
+    /// by other processes or by earlier runs of this one.
+    ///
+    /// wireguard.dll has no function for listing adapters, so this reads the network adapter
+    /// entries from the registry instead. The pool of an adapter is the device description it was
+    /// created with
+    pub fn enumerate(wireguard: &Arc<A>, pool: &str) -> Result<Vec<String>, Error> {
+        adapter_names(&**wireguard, Some(pool))
+    }
 
-            log::info!("endpoint: {}", &peer.endpoint);
-            match peer.endpoint {
-                SocketAddr::V4(v4) => {
-                    let addr = unsafe { std::mem::transmute(v4.ip().octets()) };
-                    wg_peer.Endpoint.Ipv4.sin_family = winapi::shared::ws2def::AF_INET as u16;
-                    //Make sure to put the port in network byte order
-                    wg_peer.Endpoint.Ipv4.sin_port = u16::from_ne_bytes(v4.port().to_be_bytes());
-                    wg_peer.Endpoint.Ipv4.sin_addr = addr;
-                }
-                SocketAddr::V6(v6) => {
-                    let addr = unsafe { std::mem::transmute(v6.ip().octets()) };
-                    wg_peer.Endpoint.Ipv6.sin6_family = winapi::shared::ws2def::AF_INET6 as u16;
-                    wg_peer.Endpoint.Ipv4.sin_port = u16::from_ne_bytes(v6.port().to_be_bytes());
-                    wg_peer.Endpoint.Ipv6.sin6_addr = addr;
-                }
-            }
+    /// Sets the wireguard configuration of this adapter
+    ///
+    /// The peers of the adapter are replaced with the peers in `config`. Shorthand for
+    /// [`Adapter::set_config_with`] with `replace_peers` set.
+    ///
+    /// If the listen port of `config` is taken by another socket, this fails with
+    /// [`Error::PortInUse`] while the adapter is up. An adapter that is down binds the port when it
+    /// goes up, so then [`Adapter::up`] fails with it instead
+    pub fn set_config(&self, config: &Interface) -> Result<(), Error> {
+        self.set_config_with(
+            config,
+            SetConfigOptions {
+                replace_peers: true,
+                ..SetConfigOptions::default()
+            },
+        )
+    }
 
-            wg_peer.AllowedIPsCount = peer.allowed_ips.len() as u32;
+    /// Merges `config` into the wireguard configuration of this adapter, as controlled by
+    /// `options`.
+    ///
+    /// Peers in `config` that the adapter already has are updated, and the others are added
+    pub fn set_config_with(
+        &self,
+        config: &Interface,
+        options: SetConfigOptions,
+    ) -> Result<(), Error> {
+        let (interface_flags, peer_flags) = option_flags(options);
+        self.set_configuration(config, interface_flags, peer_flags)
+    }
 
-            for allowed_ip in &peer.allowed_ips {
-                // Safety:
-                // Same as above, `writer` is aligned because it was aligned before
-                let mut wg_allowed_ip: &mut WIREGUARD_ALLOWED_IP = unsafe { writer.write() };
-                match allowed_ip {
-                    IpNet::V4(v4) => {
-                        let addr = unsafe { std::mem::transmute(v4.addr().octets()) };
-                        wg_allowed_ip.Address.V4 = addr;
-                        wg_allowed_ip.AddressFamily = winapi::shared::ws2def::AF_INET as u16;
-                        wg_allowed_ip.Cidr = v4.prefix_len();
-                    }
-                    IpNet::V6(v6) => {
-                        let addr = unsafe { std::mem::transmute(v6.addr().octets()) };
-                        wg_allowed_ip.Address.V6 = addr;
-                        wg_allowed_ip.AddressFamily = winapi::shared::ws2def::AF_INET6 as u16;
-                        wg_allowed_ip.Cidr = v6.prefix_len();
-                    }
-                }
-            }
+    /// Adds `peers` to this adapter in a single call to the driver, leaving the interface and the
+    /// other peers untouched.
+    ///
+    /// Peers that the adapter already has are updated, and their allowed ips are added to the
+    /// current ones rather than replacing them. This suits controllers that push hundreds of peers
+    /// at once, where calling [`Adapter::update_peer`] for each would be slow
+    pub fn add_peers(&self, peers: &[Peer]) -> Result<(), Error> {
+        if peers.is_empty() {
+            return Ok(());
         }
+        let config = Interface {
+            peers: peers.to_vec(),
+            ..Default::default()
+        };
+        self.set_configuration(&config, InterfaceFlags::empty(), PeerFlags::empty())
+    }
 
-        //Make sure that our allocation math was correct and that we filled all of writer
-        debug_assert!(writer.is_full());
-
-        let result = unsafe {
-            self.wireguard.WireGuardSetConfiguration(
-                self.adapter.0,
-                writer.ptr().cast(),
-                size as u32,
-            )
+    /// Replaces the private key of this adapter, leaving its listen port and peers untouched.
+    ///
+    /// This is meant for rotating the key of a server without a full reconfig. The driver derives
+    /// the new public key from `key`, see [`Adapter::get_config`]. Existing sessions were
+    /// established with the old key, so every peer has to be told the new public key out of band,
+    /// for example in its own config, before it can complete a handshake again
+    pub fn set_private_key(&self, key: &Key) -> Result<(), Error> {
+        let config = Interface {
+            private_key: Some(key.clone()),
+            ..Default::default()
         };
+        self.set_configuration(&config, InterfaceFlags::empty(), PeerFlags::empty())
+    }
 
-        match result {
-            0 => Err("WireGuardSetConfiguration failed".into()),
-            _ => Ok(()),
-        }
+    /// Returns the public key of this adapter, derived from its private key, to hand to peers.
+    /// Fails with [`Error::InvalidConfig`] if no private key has been set yet.
+    ///
+    /// Requires the `x25519` feature
+    #[cfg(feature = "x25519")]
+    pub fn public_key(&self) -> Result<Key, Error> {
+        self.get_config()?
+            .public_key()
+            .ok_or_else(|| Error::InvalidConfig("The adapter has no private key".to_owned()))
     }
 
-    /// Assigns this adapter an ip address and adds route(s) so that packets sent
-    /// within the `interface_addr` ipnet will be sent across the WireGuard VPN
-    pub fn set_default_route(
+    /// Updates a peer of this adapter, matched by its public key, leaving the other peers
+    /// untouched. Nothing is changed if the adapter has no peer with that public key.
+    ///
+    /// The allowed ips of the peer are replaced with `peer.allowed_ips`. Fields of `peer` that
+    /// are `None` keep their current value, so this can be used to update the endpoint or
+    /// keepalive of a peer without interrupting the sessions of the other peers
+    pub fn update_peer(&self, peer: &Peer) -> Result<(), Error> {
+        let config = Interface {
+            peers: vec![peer.clone()],
+            ..Default::default()
+        };
+        self.set_configuration(
+            &config,
+            InterfaceFlags::empty(),
+            PeerFlags::UPDATE | PeerFlags::REPLACE_ALLOWED_IPS,
+        )
+    }
+
+    /// Changes the endpoint of the peer with the public key `public_key`, for example to fail over
+    /// to a backup server. Nothing is changed if the adapter has no peer with that public key.
+    ///
+    /// Unlike [`Adapter::update_peer`], only the endpoint is sent to the driver, so the allowed
+    /// ips, keepalive and preshared key of the peer are kept
+    pub fn set_peer_endpoint(&self, public_key: &Key, endpoint: SocketAddr) -> Result<(), Error> {
+        let config = Interface {
+            peers: vec![Peer {
+                endpoint: Some(endpoint),
+                ..key_only_peer(public_key)
+            }],
+            ..Default::default()
+        };
+        self.set_configuration(&config, InterfaceFlags::empty(), PeerFlags::UPDATE)
+    }
+
+    /// Removes the peer with the public key `public_key` from this adapter, leaving the other
+    /// peers untouched.
+    ///
+    /// Removing a peer that does not exist succeeds, so this is safe to call repeatedly
+    pub fn remove_peer(&self, public_key: &Key) -> Result<(), Error> {
+        let config = Interface {
+            peers: vec![key_only_peer(public_key)],
+            ..Default::default()
+        };
+        self.set_configuration(&config, InterfaceFlags::empty(), PeerFlags::REMOVE)?;
+        self.endpoint_hosts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&PublicKey::from(public_key.clone()));
+        Ok(())
+    }
+
+    /// Removes `allowed_ip` from the allowed ips of the peer with the public key `public_key`,
+    /// leaving its other settings, its session and the other peers untouched. Traffic to and from
+    /// `allowed_ip` is no longer routed to the peer, for example to revoke its access to a subnet.
+    ///
+    /// Allowed ips are compared by network, so `10.0.0.1/24` removes `10.0.0.0/24`. Nothing is
+    /// changed if the adapter has no such peer or the peer does not have `allowed_ip`, so this is
+    /// safe to call repeatedly. The driver can only replace all allowed ips of a peer, so they are
+    /// read with [`Adapter::get_config`] and sent back without `allowed_ip`. An allowed ip that is
+    /// added to the peer in between by another caller is lost
+    pub fn remove_allowed_ip(
         &self,
-        interface_addrs: &[IpNet],
-        config: &SetInterface,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let luid = self.get_luid();
-        unsafe {
-            use winapi::shared::netioapi::{
-                InitializeUnicastIpAddressEntry, MIB_UNICASTIPADDRESS_ROW,
+        public_key: &Key,
+        allowed_ip: impl Into<AllowedIp>,
+    ) -> Result<(), Error> {
+        let allowed_ip = allowed_ip.into();
+        let config = self.get_config()?;
+        let peer = match config.peers.iter().find(|p| p.public_key == *public_key) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let removed = allowed_ip.network();
+        let allowed_ips: Vec<AllowedIp> = peer
+            .allowed_ips
+            .iter()
+            .copied()
+            .filter(|ip| ip.network() != removed)
+            .collect();
+        if allowed_ips.len() == peer.allowed_ips.len() {
+            return Ok(());
+        }
+        let update = Interface {
+            peers: vec![Peer {
+                allowed_ips,
+                ..key_only_peer(public_key)
+            }],
+            ..Default::default()
+        };
+        //UPDATE keeps a peer that was removed in the meantime from being added back
+        self.set_configuration(
+            &update,
+            InterfaceFlags::empty(),
+            PeerFlags::UPDATE | PeerFlags::REPLACE_ALLOWED_IPS,
+        )
+    }
+
+    /// Remembers that the endpoint of the peer with the public key `public_key` was configured
+    /// from the host name and port `host`, such as `"home.example.com:51820"`, so that
+    /// [`Adapter::reresolve_endpoints`] resolves it again. Replaces the host recorded earlier for
+    /// the same peer.
+    ///
+    /// Host names are only kept by this `Adapter`, the driver only knows the resolved address. They
+    /// are forgotten when the peer is removed with [`Adapter::remove_peer`], or by
+    /// [`Adapter::reresolve_endpoints`] once the adapter no longer has the peer
+    pub fn track_endpoint_host(&self, public_key: &Key, host: &str) {
+        self.endpoint_hosts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(PublicKey::from(public_key.clone()), host.to_owned());
+    }
+
+    /// Resolves the host names recorded with [`Adapter::track_endpoint_host`] with `resolver`, and
+    /// updates the endpoint of every peer whose address changed. Returns how many peers were
+    /// updated.
+    ///
+    /// WireGuard NT never resolves host names itself, so peers behind dynamic DNS, such as home
+    /// servers, become unreachable when their address changes. Calling this periodically, or when
+    /// a peer goes stale, see [`PeerStats::is_stale`], keeps them reachable. Peers whose host
+    /// does not resolve keep their current endpoint. Only endpoints are sent to the driver, in a
+    /// single update that leaves the other settings and the sessions of unchanged peers alone.
+    ///
+    /// A resolver based on the system resolver is
+    /// `|host| host.to_socket_addrs().ok()?.next()`
+    pub fn reresolve_endpoints(
+        &self,
+        resolver: impl Fn(&str) -> Option<SocketAddr>,
+    ) -> Result<usize, Error> {
+        let config = self.get_config()?;
+        let hosts = {
+            let mut hosts = self
+                .endpoint_hosts
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            hosts.retain(|key, _| config.peers.iter().any(|p| p.public_key == *key.as_key()));
+            hosts.clone()
+        };
+        //Resolve without holding the lock, since resolving can block for a long time
+        let changed = endpoint_changes(&config, &hosts, resolver);
+        if changed.is_empty() {
+            return Ok(0);
+        }
+        let count = changed.len();
+        let update = Interface {
+            peers: changed,
+            ..Default::default()
+        };
+        self.set_configuration(&update, InterfaceFlags::empty(), PeerFlags::UPDATE)?;
+        Ok(count)
+    }
+
+    /// Changes the wireguard configuration of this adapter to `desired`, sending only what differs
+    /// from [`Adapter::get_config`], and returns what was changed.
+    ///
+    /// Unlike [`Adapter::set_config`], peers that already match are not sent to the driver, so
+    /// their sessions are not interrupted. This suits reconcile loops that push the same config
+    /// repeatedly. Peers that are not in `desired` are removed, and the allowed ips of changed
+    /// peers are replaced. A missing preshared key or keepalive in a peer of `desired` clears the
+    /// current one, but a missing endpoint keeps the endpoint the driver learned from the peer. A
+    /// listen port of zero is satisfied by any current port.
+    ///
+    /// Removed peers are sent to the driver before the other changes, so if the second call
+    /// fails, the peers are already removed
+    pub fn apply_config(&self, desired: &Interface) -> Result<ConfigDelta, Error> {
+        check_supported(desired)?;
+        let (delta, update) = config_changes(&self.get_config()?, desired);
+        if !delta.removed_peers.is_empty() {
+            let removals = Interface {
+                peers: delta.removed_peers.iter().map(key_only_peer).collect(),
+                ..Default::default()
             };
-            use winapi::shared::nldef::IpDadStatePreferred;
+            self.set_configuration(&removals, InterfaceFlags::empty(), PeerFlags::REMOVE)?;
+        }
+        if delta.interface_changed || !update.peers.is_empty() {
+            self.set_configuration(
+                &update,
+                InterfaceFlags::empty(),
+                PeerFlags::REPLACE_ALLOWED_IPS,
+            )?;
+        }
+        Ok(delta)
+    }
 
-            use winapi::shared::netioapi::{CreateIpForwardEntry2, CreateUnicastIpAddressEntry};
-            use winapi::shared::winerror::{ERROR_OBJECT_ALREADY_EXISTS, ERROR_SUCCESS};
-            use winapi::shared::ws2def::{AF_INET, AF_INET6};
-
-            for allowed_ip in config.peers.iter().map(|p| p.allowed_ips.iter()).flatten() {
-                use winapi::shared::netioapi::{InitializeIpForwardEntry, MIB_IPFORWARD_ROW2};
-                let mut default_route: MIB_IPFORWARD_ROW2 = std::mem::zeroed();
-                InitializeIpForwardEntry(&mut default_route);
-                default_route.InterfaceLuid = std::mem::transmute(luid);
-                match *allowed_ip {
-                    IpNet::V4(v4) => {
-                        *default_route.DestinationPrefix.Prefix.si_family_mut() = AF_INET as u16;
-                        default_route.DestinationPrefix.Prefix.Ipv4_mut().sin_addr =
-                            std::mem::transmute(v4.addr().octets());
-
-                        default_route.DestinationPrefix.PrefixLength = v4.prefix_len();
-
-                        //Next hop is 0.0.0.0/0, because it is the address of a local interface
-                        //(the wireguard interface). So because the struct is zeroed we don't need
-                        //to set anything except the address family
-                        *default_route.NextHop.si_family_mut() = AF_INET as u16;
-                    }
-                    IpNet::V6(v6) => {
-                        *default_route.DestinationPrefix.Prefix.si_family_mut() = AF_INET6 as u16;
-                        default_route.DestinationPrefix.Prefix.Ipv6_mut().sin6_addr =
-                            std::mem::transmute(v6.addr().octets());
+    /// Encodes `config` and passes it to `WireGuardSetConfiguration`. `interface_flags` and
+    /// `peer_flags` are added to the flags derived from the fields of `config` and its peers
+    fn set_configuration(
+        &self,
+        config: &Interface,
+        interface_flags: InterfaceFlags,
+        peer_flags: PeerFlags,
+    ) -> Result<(), Error> {
+        check_supported(config)?;
+        let writer = encode_config(config, interface_flags, peer_flags)?;
 
-                        default_route.DestinationPrefix.PrefixLength = v6.prefix_len();
+        let result = unsafe {
+            self.wireguard.set_configuration(
+                self.adapter.0,
+                writer.ptr().cast(),
+                writer.capacity() as u32,
+            )
+        };
 
-                        *default_route.NextHop.si_family_mut() = AF_INET6 as u16;
-                    }
-                }
-                default_route.Metric = 5;
+        match result {
+            0 => Err(listen_port_error(Error::last_win32(), config.listen_port)),
+            _ => Ok(()),
+        }
+    }
 
-                let err = CreateIpForwardEntry2(&default_route);
-                if err != ERROR_SUCCESS && err != ERROR_OBJECT_ALREADY_EXISTS {
-                    return win_error("Failed to set default route", err);
-                }
+    /// Assigns this adapter an ip address and adds route(s) so that packets sent
+    /// within the `interface_addr` ipnet will be sent across the WireGuard VPN
+    ///
+    /// Requires the `ipnet` feature
+    #[cfg(feature = "ipnet")]
+    pub fn set_default_route(
+        &self,
+        interface_addrs: &[IpNet],
+        config: &Interface,
+    ) -> Result<(), Error> {
+        let luid = self.get_luid()?;
+        unsafe {
+            use winapi::shared::netioapi::CreateUnicastIpAddressEntry;
+            use winapi::shared::winerror::{ERROR_OBJECT_ALREADY_EXISTS, ERROR_SUCCESS};
+            use winapi::shared::ws2def::AF_INET6;
+
+            for allowed_ip in config.peers.iter().flat_map(|p| p.allowed_ips.iter()) {
+                self.add_route(IpNet::from(*allowed_ip), None, ALLOWED_IP_ROUTE_METRIC)?;
             }
 
             for interface_addr in interface_addrs {
-                let mut address_row: MIB_UNICASTIPADDRESS_ROW = std::mem::zeroed();
-                InitializeUnicastIpAddressEntry(&mut address_row);
-                address_row.InterfaceLuid = std::mem::transmute(luid);
-                address_row.OnLinkPrefixLength = interface_addr.prefix_len();
-                address_row.DadState = IpDadStatePreferred;
-
-                match interface_addr {
-                    IpNet::V4(interface_addr_v4) => {
-                        address_row.Address.Ipv4_mut().sin_family = AF_INET as u16;
-                        address_row.Address.Ipv4_mut().sin_addr =
-                            std::mem::transmute(interface_addr_v4.addr().octets());
-                    }
-                    IpNet::V6(interface_addr_v6) => {
-                        address_row.Address.Ipv6_mut().sin6_family = AF_INET6 as u16;
-                        address_row.Address.Ipv6_mut().sin6_addr =
-                            std::mem::transmute(interface_addr_v6.addr().octets());
-                    }
-                }
-
+                let address_row = unicast_address_row(luid, interface_addr);
                 let err = CreateUnicastIpAddressEntry(&address_row);
                 if err != ERROR_SUCCESS && err != ERROR_OBJECT_ALREADY_EXISTS {
-                    return win_error("Failed to set IP interface", err);
+                    return Err(Error::Win32(err));
                 }
             }
 
-            use winapi::shared::netioapi::{InitializeIpInterfaceEntry, MIB_IPINTERFACE_ROW};
-            let mut ip_interface: MIB_IPINTERFACE_ROW = std::mem::zeroed();
-            InitializeIpInterfaceEntry(&mut ip_interface);
-            ip_interface.InterfaceLuid = std::mem::transmute(luid);
-            ip_interface.Family = AF_INET6 as u16;
-
-            use winapi::shared::netioapi::{GetIpInterfaceEntry, SetIpInterfaceEntry};
-            let err = GetIpInterfaceEntry(&mut ip_interface);
-            if err != ERROR_SUCCESS {
-                return win_error("Failed to get IP interface", err);
-            }
+            use winapi::shared::netioapi::SetIpInterfaceEntry;
+            let mut ip_interface = ip_interface_row(luid, AF_INET6)?;
             ip_interface.UseAutomaticMetric = 0;
             ip_interface.Metric = 0;
             ip_interface.NlMtu = 1420;
             ip_interface.SitePrefixLength = 0;
             let err = SetIpInterfaceEntry(&mut ip_interface);
             if err != ERROR_SUCCESS {
-                return win_error("Failed to set metric and MTU", err);
+                return Err(Error::Win32(err));
             }
 
             Ok(())
         }
     }
 
-    /// Puts this adapter into the up state
-    pub fn up(&self) -> bool {
-        unsafe {
-            self.wireguard
-                .WireGuardSetAdapterState(self.adapter.0, WIREGUARD_STATE_UP)
-                != 0
+    /// Makes the on-link routes through this adapter match the allowed ips of its peers, the way
+    /// wg-quick routes allowed ips through the tunnel.
+    ///
+    /// Routes are added for allowed ips that have none yet, with the same metric as
+    /// [`Adapter::set_default_route`], and routes through this adapter whose destination is no
+    /// longer an allowed ip of any peer are removed. Only routes with an on-link next hop that were
+    /// created through the IP Helper API, for example by [`Adapter::add_route`], are removed. The
+    /// routes Windows creates for the adapter's own addresses and all routes of other interfaces
+    /// are left alone.
+    ///
+    /// Requires the `ipnet` feature
+    #[cfg(feature = "ipnet")]
+    pub fn sync_routes(&self) -> Result<(), Error> {
+        use winapi::shared::netioapi::{
+            DeleteIpForwardEntry2, FreeMibTable, GetIpForwardTable2, MIB_IPFORWARD_TABLE2,
+        };
+        use winapi::shared::winerror::{ERROR_NOT_FOUND, ERROR_SUCCESS};
+        use winapi::shared::ws2def::AF_UNSPEC;
+
+        let config = self.get_config()?;
+        let wanted: Vec<IpNet> = config
+            .peers
+            .iter()
+            .flat_map(|peer| peer.allowed_ips.iter())
+            .map(|allowed_ip| IpNet::from(*allowed_ip))
+            .collect();
+
+        let luid = self.get_luid()?;
+        let mut table: *mut MIB_IPFORWARD_TABLE2 = ptr::null_mut();
+        //SAFETY: `table` is a valid out pointer. On success it points to a table that we free below
+        let err = unsafe { GetIpForwardTable2(AF_UNSPEC as u16, &mut table) };
+        let mut existing = Vec::new();
+        match err {
+            ERROR_SUCCESS => {
+                //SAFETY: the table holds `NumEntries` rows and stays valid until it is freed
+                let rows = unsafe {
+                    std::slice::from_raw_parts(
+                        (*table).Table.as_ptr(),
+                        (*table).NumEntries as usize,
+                    )
+                };
+                for row in rows
+                    .iter()
+                    .filter(|row| row.InterfaceLuid.Value == luid.value())
+                {
+                    if let Some(dest) = managed_route_destination(row) {
+                        existing.push((dest, *row));
+                    }
+                }
+                //SAFETY: `table` was allocated by GetIpForwardTable2 and is not used after this
+                unsafe { FreeMibTable(table.cast()) };
+            }
+            //There are no routes on any interface
+            ERROR_NOT_FOUND => {}
+            err => return Err(Error::Win32(err)),
+        }
+
+        let existing_dests: Vec<IpNet> = existing.iter().map(|(dest, _)| *dest).collect();
+        let (add, remove) = route_changes(&wanted, &existing_dests);
+        for (_, row) in existing.iter().filter(|(dest, _)| remove.contains(dest)) {
+            //SAFETY: `row` is a copy of a row returned by GetIpForwardTable2
+            let err = unsafe { DeleteIpForwardEntry2(row) };
+            if err != ERROR_SUCCESS && err != ERROR_NOT_FOUND {
+                return Err(Error::Win32(err));
+            }
         }
+        for dest in add {
+            self.add_route(dest, None, ALLOWED_IP_ROUTE_METRIC)?;
+        }
+        Ok(())
     }
 
-    /// Puts this adapter into the down state
-    pub fn down(&self) -> bool {
-        unsafe {
+    /// Adds a route that sends packets for `dest` through this adapter.
+    ///
+    /// When `next_hop` is `None` the route is on-link, meaning packets are handed straight to the
+    /// adapter, which is what tunnels such as `0.0.0.0/0` through WireGuard need. Otherwise
+    /// `next_hop` must be the same address family as `dest`. Host bits in `dest` are ignored.
+    /// Adding a route that already exists succeeds without changing its metric.
+    ///
+    /// Requires the `ipnet` feature
+    #[cfg(feature = "ipnet")]
+    pub fn add_route(
+        &self,
+        dest: IpNet,
+        next_hop: Option<IpAddr>,
+        metric: u32,
+    ) -> Result<(), Error> {
+        use winapi::shared::netioapi::CreateIpForwardEntry2;
+        use winapi::shared::winerror::{ERROR_OBJECT_ALREADY_EXISTS, ERROR_SUCCESS};
+
+        let route = forward_row(self.get_luid()?, &dest, next_hop, metric)?;
+        //SAFETY: `route` is initialized by `forward_row`
+        let err = unsafe { CreateIpForwardEntry2(&route) };
+        match err {
+            ERROR_SUCCESS | ERROR_OBJECT_ALREADY_EXISTS => Ok(()),
+            err => Err(Error::Win32(err)),
+        }
+    }
+
+    /// Removes a route added with [`Adapter::add_route`] with the same `dest` and `next_hop`.
+    /// Removing a route that does not exist succeeds.
+    ///
+    /// Requires the `ipnet` feature
+    #[cfg(feature = "ipnet")]
+    pub fn remove_route(&self, dest: IpNet, next_hop: Option<IpAddr>) -> Result<(), Error> {
+        use winapi::shared::netioapi::DeleteIpForwardEntry2;
+        use winapi::shared::winerror::{ERROR_NOT_FOUND, ERROR_SUCCESS};
+
+        let route = forward_row(self.get_luid()?, &dest, next_hop, 0)?;
+        //SAFETY: `route` is initialized by `forward_row`
+        let err = unsafe { DeleteIpForwardEntry2(&route) };
+        match err {
+            ERROR_SUCCESS | ERROR_NOT_FOUND => Ok(()),
+            err => Err(Error::Win32(err)),
+        }
+    }
+
+    /// Sets the ip addresses of this adapter to exactly `addrs`, which may mix IPv4 and IPv6
+    /// addresses. The prefix length of each address is the on-link prefix of the interface.
+    ///
+    /// Addresses of this adapter that are not in `addrs` are removed, including ones added by an
+    /// earlier call or by Windows itself, so calling this with an empty slice clears them all.
+    /// Addresses that are already assigned are left in place.
+    ///
+    /// Requires the `ipnet` feature
+    #[cfg(feature = "ipnet")]
+    pub fn set_addresses(&self, addrs: &[IpNet]) -> Result<(), Error> {
+        use winapi::shared::netioapi::{
+            CreateUnicastIpAddressEntry, DeleteUnicastIpAddressEntry, FreeMibTable,
+            GetUnicastIpAddressTable, MIB_UNICASTIPADDRESS_TABLE,
+        };
+        use winapi::shared::winerror::{
+            ERROR_NOT_FOUND, ERROR_OBJECT_ALREADY_EXISTS, ERROR_SUCCESS,
+        };
+        use winapi::shared::ws2def::AF_UNSPEC;
+
+        let luid = self.get_luid()?;
+        let mut table: *mut MIB_UNICASTIPADDRESS_TABLE = ptr::null_mut();
+        //SAFETY: `table` is a valid out pointer. On success it points to a table that we free below
+        let err = unsafe { GetUnicastIpAddressTable(AF_UNSPEC as u16, &mut table) };
+        let mut existing = Vec::new();
+        match err {
+            ERROR_SUCCESS => {
+                //SAFETY: the table holds `NumEntries` rows and stays valid until it is freed
+                let rows = unsafe {
+                    std::slice::from_raw_parts(
+                        (*table).Table.as_ptr(),
+                        (*table).NumEntries as usize,
+                    )
+                };
+                let mut result = Ok(());
+                for row in rows
+                    .iter()
+                    .filter(|row| row.InterfaceLuid.Value == luid.value())
+                {
+                    match unicast_row_address(row) {
+                        Some(addr) if addrs.contains(&addr) => existing.push(addr),
+                        _ => {
+                            //SAFETY: `row` was returned by GetUnicastIpAddressTable
+                            let err = unsafe { DeleteUnicastIpAddressEntry(row) };
+                            if err != ERROR_SUCCESS && err != ERROR_NOT_FOUND {
+                                result = Err(Error::Win32(err));
+                                break;
+                            }
+                        }
+                    }
+                }
+                //SAFETY: `table` was allocated by GetUnicastIpAddressTable and is not used after this
+                unsafe { FreeMibTable(table.cast()) };
+                result?;
+            }
+            //There are no addresses on any interface
+            ERROR_NOT_FOUND => {}
+            err => return Err(Error::Win32(err)),
+        }
+
+        for addr in addrs.iter().filter(|addr| !existing.contains(addr)) {
+            let address_row = unicast_address_row(luid, addr);
+            //SAFETY: `address_row` is initialized by `unicast_address_row`
+            let err = unsafe { CreateUnicastIpAddressEntry(&address_row) };
+            if err != ERROR_SUCCESS && err != ERROR_OBJECT_ALREADY_EXISTS {
+                return Err(Error::Win32(err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the MTU of this adapter for both IPv4 and IPv6. WireGuard adds 80 bytes of overhead
+    /// to every packet, so 1420 avoids fragmentation on a typical 1500 byte link.
+    ///
+    /// Address families that are disabled on the adapter are skipped. IPv6 requires an MTU of at
+    /// least 1280
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), Error> {
+        use winapi::shared::netioapi::SetIpInterfaceEntry;
+        use winapi::shared::winerror::{ERROR_NOT_FOUND, ERROR_SUCCESS};
+        use winapi::shared::ws2def::{AF_INET, AF_INET6};
+
+        let luid = self.get_luid()?;
+        for &family in [AF_INET, AF_INET6].iter() {
+            let mut ip_interface = match ip_interface_row(luid, family) {
+                Ok(row) => row,
+                Err(Error::Win32(ERROR_NOT_FOUND)) => continue,
+                Err(e) => return Err(e),
+            };
+            ip_interface.NlMtu = mtu;
+            //SetIpInterfaceEntry fails for IPv4 rows unless this is zero
+            ip_interface.SitePrefixLength = 0;
+            //SAFETY: `ip_interface` was filled in by GetIpInterfaceEntry
+            let err = unsafe { SetIpInterfaceEntry(&mut ip_interface) };
+            if err != ERROR_SUCCESS {
+                return Err(Error::Win32(err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the MTU of this adapter. The IPv4 MTU is returned, or the IPv6 MTU if IPv4 is
+    /// disabled on the adapter
+    pub fn get_mtu(&self) -> Result<u32, Error> {
+        use winapi::shared::winerror::ERROR_NOT_FOUND;
+        use winapi::shared::ws2def::{AF_INET, AF_INET6};
+
+        let luid = self.get_luid()?;
+        let row = match ip_interface_row(luid, AF_INET) {
+            Err(Error::Win32(ERROR_NOT_FOUND)) => ip_interface_row(luid, AF_INET6)?,
+            row => row?,
+        };
+        Ok(row.NlMtu)
+    }
+
+    /// Sets the state of this adapter. Sockets are owned by the process that brings the adapter up
+    pub fn set_state(&self, state: AdapterState) -> Result<(), Error> {
+        let result = unsafe {
             self.wireguard
-                .WireGuardSetAdapterState(self.adapter.0, WIREGUARD_STATE_DOWN)
-                != 0
+                .set_adapter_state(self.adapter.0, state.into())
+        };
+        match result {
+            //The driver binds the listen port when the adapter goes up
+            0 => match Error::last_win32() {
+                Error::Win32(code) if PORT_IN_USE_ERRORS.contains(&code) => Err(listen_port_error(
+                    Error::Win32(code),
+                    self.listen_port().ok(),
+                )),
+                e => Err(e),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Gets the current state of this adapter
+    pub fn get_state(&self) -> Result<AdapterState, Error> {
+        let mut state = wireguard_nt_raw::WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_DOWN;
+        let result = unsafe { self.wireguard.get_adapter_state(self.adapter.0, &mut state) };
+        if result == 0 {
+            return Err(Error::last_win32());
+        }
+        AdapterState::try_from(state)
+            .map_err(|state| Error::InvalidConfig(format!("Unknown adapter state {}", state)))
+    }
+
+    /// Puts this adapter into the up state. Shorthand for `set_state(AdapterState::Up)`.
+    ///
+    /// Fails with [`Error::PortInUse`] if the listen port is taken by another socket
+    pub fn up(&self) -> Result<(), Error> {
+        self.set_state(AdapterState::Up)
+    }
+
+    /// Puts this adapter into the down state. Shorthand for `set_state(AdapterState::Down)`
+    pub fn down(&self) -> Result<(), Error> {
+        self.set_state(AdapterState::Down)
+    }
+
+    /// Puts this adapter down and back up after a short delay. The driver drops the sessions of
+    /// all peers when the adapter goes down, so this forces new handshakes, for example after the
+    /// network changed. The adapter is up afterwards, even if it was down before.
+    ///
+    /// If bringing the adapter back up fails, the state it had before is set once more and the
+    /// error of the first attempt is returned
+    pub fn reset(&self) -> Result<(), Error> {
+        let prior = self.get_state()?;
+        self.down()?;
+        std::thread::sleep(RESET_DELAY);
+        if let Err(e) = self.up() {
+            let _ = self.set_state(prior);
+            return Err(e);
         }
+        Ok(())
     }
 
     /// Returns the adapter's LUID.
     /// This is a 64bit unique identifier that windows uses when referencing this adapter
-    pub fn get_luid(&self) -> u64 {
+    ///
+    /// Returns [`Error::InterfaceNotReady`] if the driver reports a zero LUID, which no interface
+    /// has, instead of handing it to IP Helper functions that would then fail or act on the wrong
+    /// interface
+    pub fn get_luid(&self) -> Result<Luid, Error> {
         let mut x = 0u64;
         unsafe {
             self.wireguard
-                .WireGuardGetAdapterLUID(self.adapter.0, std::mem::transmute(&mut x))
+                .get_adapter_luid(self.adapter.0, (&mut x as *mut u64).cast())
         };
-        x
+        checked_luid(x)
     }
 
-    /// Sets the logging level of this adapter
+    /// Returns the interface index of this adapter, which some IP Helper functions and tools such
+    /// as `netsh` use instead of the LUID.
     ///
-    /// Log messages will be sent to the current logger (set using [`crate::set_logger`]
-    pub fn set_logging(&self, level: AdapterLoggingLevel) -> bool {
-        let level = match level {
-            AdapterLoggingLevel::Off => 0,
-            AdapterLoggingLevel::On => 1,
-            AdapterLoggingLevel::OnWithPrefix => 2,
+    /// Returns [`Error::InterfaceNotReady`] if Windows has not assigned the adapter an index yet
+    pub fn get_index(&self) -> Result<u32, Error> {
+        use winapi::shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_NOT_FOUND, ERROR_SUCCESS};
+
+        let luid = winapi::shared::ifdef::NET_LUID {
+            Value: self.get_luid()?.value(),
         };
-        unsafe {
-            self.wireguard
-                .WireGuardSetAdapterLogging(self.adapter.0, level)
-                != 0
+        let mut index = 0;
+        //SAFETY: both pointers are valid for the duration of the call
+        let err =
+            unsafe { winapi::shared::netioapi::ConvertInterfaceLuidToIndex(&luid, &mut index) };
+        match err {
+            //0 is NET_IFINDEX_UNSPECIFIED
+            ERROR_SUCCESS if index != 0 => Ok(index),
+            ERROR_SUCCESS | ERROR_FILE_NOT_FOUND | ERROR_NOT_FOUND => Err(Error::InterfaceNotReady),
+            err => Err(Error::Win32(err)),
         }
     }
 
-    /// Gets the current configuration of this adapter
-    pub fn get_config(&self) -> WireguardInterface {
-        // calling wireguard.WireGuardGetConfiguration with Bytes = 0 returns ERROR_MORE_DATA
-        // and updates Bytes to the correct value
-        let mut size = 0u32;
-        let res = unsafe {
-            self.wireguard.WireGuardGetConfiguration(
-                self.adapter.0,
-                std::ptr::null_mut(),
-                &mut size as _,
-            )
+    /// Returns the adapter's GUID, which Windows uses to name its registry keys.
+    ///
+    /// The GUID is looked up from the adapter's LUID, so it is available for adapters returned by
+    /// [`Adapter::open`] as well as for those created with a random GUID
+    pub fn get_guid(&self) -> Result<Guid, Error> {
+        let luid = winapi::shared::ifdef::NET_LUID {
+            Value: self.get_luid()?.value(),
         };
-        assert_eq!(res, 0);
-        assert_eq!(unsafe { GetLastError() }, ERROR_MORE_DATA);
-        assert_ne!(size, 0); // size has been updated
-        let align = align_of::<WIREGUARD_INTERFACE>();
-        let mut reader = StructReader::new(size as usize, align);
-        let res = unsafe {
-            self.wireguard.WireGuardGetConfiguration(
-                self.adapter.0,
-                reader.ptr() as _,
-                &mut size as _,
-            )
+        //SAFETY: GUID only contains integers, so all zeroes is a valid value
+        let mut guid: winapi::shared::guiddef::GUID = unsafe { std::mem::zeroed() };
+        //SAFETY: both pointers are valid for the duration of the call
+        let err = unsafe { winapi::shared::netioapi::ConvertInterfaceLuidToGuid(&luid, &mut guid) };
+        if err != 0 {
+            return Err(Error::Win32(err));
+        }
+        Ok(Guid::from(wireguard_nt_raw::GUID {
+            Data1: guid.Data1,
+            Data2: guid.Data2,
+            Data3: guid.Data3,
+            Data4: guid.Data4,
+        }))
+    }
+
+    /// Returns the adapter's GUID in the braced form used by registry keys, such as
+    /// `{6BA7B810-9DAD-11D1-80B4-00C04FD430C8}`. See [`Adapter::get_guid`]
+    pub fn get_guid_string(&self) -> Result<String, Error> {
+        Ok(self.get_guid()?.to_string())
+    }
+
+    /// Sets the DNS servers of this adapter, replacing any that were set before. Passing an empty
+    /// slice clears them.
+    ///
+    /// `SetInterfaceDnsSettings` is used where it is available (Windows 10 2004 and later). On
+    /// older versions the servers are written to the adapter's TCP/IP registry keys instead
+    pub fn set_dns(&self, servers: &[IpAddr]) -> Result<(), Error> {
+        crate::dns::set_interface_dns(self.get_guid()?, servers)
+    }
+
+    /// Sets the DNS search domains of this adapter, which Windows appends to single label names
+    /// it resolves. Passing an empty slice clears them. Trailing dots are ignored
+    pub fn set_dns_search_domains(&self, domains: &[&str]) -> Result<(), Error> {
+        crate::dns::set_interface_search_list(self.get_guid()?, domains)
+    }
+
+    /// Routes DNS queries for `domains` and all of their subdomains to `servers`, using a rule in
+    /// the Name Resolution Policy Table (NRPT). This is how split DNS is set up: other names keep
+    /// using the DNS servers of the other network adapters.
+    ///
+    /// Each adapter has one rule, stored in the registry under
+    /// `HKLM\SYSTEM\CurrentControlSet\Services\Dnscache\Parameters\DnsPolicyConfig` and named
+    /// after its GUID, so calling this again replaces the domains and servers of the earlier call.
+    /// Passing no domains removes the rule. The rule is also removed when an adapter obtained from
+    /// [`Adapter::create`] is dropped. If the process exits without dropping it, the rule stays
+    /// until this is called again for an adapter with the same GUID.
+    ///
+    /// `servers` usually are the tunnel's DNS servers, as passed to [`Adapter::set_dns`]
+    pub fn set_nrpt_domains(&self, domains: &[&str], servers: &[IpAddr]) -> Result<(), Error> {
+        crate::dns::set_nrpt_rule(self.get_guid()?, domains, servers)
+    }
+
+    /// Sets the logging level of this adapter
+    ///
+    /// Log messages will be sent to the current logger (set using [`crate::set_logger`] or [`crate::set_raw_logger`])
+    pub fn set_logging(&self, level: AdapterLoggingLevel) -> Result<(), Error> {
+        let result = unsafe {
+            self.wireguard
+                .set_adapter_logging(self.adapter.0, level.into())
         };
-        assert_ne!(res, 0);
-
-        // # Safety:
-        // 1. `WireGuardGetConfiguration` writes a `WIREGUARD_INTERFACE` at offset 0 to the buffer we give it.
-        // 2. The buffer's alignment is set to be the proper alignment for a `WIREGUARD_INTERFACE` by the line above
-        // 3. We calculate the size of `reader` with the first call to `WireGuardGetConfiguration`. Wireguard writes at
-        //    least one `WIREGUARD_INTERFACE`, and size is updated accordingly, therefore `reader`'s allocation is at least
-        //    the size of a `WIREGUARD_INTERFACE`
-        let wireguard_interface: WIREGUARD_INTERFACE = unsafe { reader.read() };
-        let mut wg_interface = WireguardInterface {
-            flags: wireguard_interface.Flags as u32,
-            listen_port: wireguard_interface.ListenPort,
-            private_key: wireguard_interface.PrivateKey,
-            public_key: wireguard_interface.PublicKey,
-            peers: Vec::with_capacity(wireguard_interface.PeersCount as usize),
-        };
-
-        let now = SystemTime::now();
-        let now_instant = Instant::now();
-        let unix_duration = now
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Time set before unix epoch");
-
-        // The number of 100ns intervals between 1-1-1600 and 1-1-1970
-        const UNIX_EPOCH_FROM_1_1_1600: u64 = 116444736000000000;
-        //calculate now based on the number of 100ns intervals since 1-1-1600
-        let now_since_1600 = UNIX_EPOCH_FROM_1_1_1600 + (unix_duration.as_nanos() / 100u128) as u64;
-        for _ in 0..wireguard_interface.PeersCount {
-            // # Safety:
-            // 1. `WireGuardGetConfiguration` writes a `WIREGUARD_PEER` immediately after the WIREGUARD_INTERFACE we read above.
-            // 2. We rely on Wireguard-NT to specify the number of peers written, and therefore we never read too many times unless Wireguard-NT (wrongly) tells us to
-            let peer: WIREGUARD_PEER = unsafe { reader.read() };
-            let endpoint = peer.Endpoint;
-            let address_family = unsafe { endpoint.si_family } as i32;
-            let endpoint = match address_family {
-                winapi::shared::ws2def::AF_INET => {
-                    // #Safety
-                    // This enum is valid to access because the address is a [u8; 4] which is set properly by the call above,
-                    // and it can have any value.
-                    let octets = unsafe { endpoint.Ipv4.sin_addr.S_un.S_un_b };
-                    let address = Ipv4Addr::new(octets.s_b1, octets.s_b2, octets.s_b3, octets.s_b4);
-                    let port = u16::from_be(unsafe { endpoint.Ipv4.sin_port });
-                    SocketAddr::V4(SocketAddrV4::new(address, port))
-                }
-                winapi::shared::ws2def::AF_INET6 => {
-                    let octets = unsafe { endpoint.Ipv6.sin6_addr.u.Byte };
-                    let address = Ipv6Addr::from(octets);
-                    let port = u16::from_be(unsafe { endpoint.Ipv6.sin6_port });
-                    let flow_info = unsafe { endpoint.Ipv6.sin6_flowinfo };
-                    let scope_id = unsafe { endpoint.Ipv6.__bindgen_anon_1.sin6_scope_id };
-                    SocketAddr::V6(SocketAddrV6::new(address, port, flow_info, scope_id))
-                }
-                _ => {
-                    panic!("Illegal address family {}", address_family);
-                }
-            };
+        match result {
+            0 => Err(Error::last_win32()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Gets the current configuration of this adapter, including all of its peers and their
+    /// allowed ips
+    pub fn get_config(&self) -> Result<Interface, Error> {
+        let mut buf = Vec::new();
+        let config = self
+            .get_config_into(&mut buf)
+            .map(|config| config.to_interface());
+        util::zero_bytes(&mut buf);
+        config
+    }
 
-            //Calculate the difference in 100ns steps between the last handshake and now
-            let handshake_delta = now_since_1600 - peer.LastHandshake;
-
-            //The time of the lash handshake is now - the delta
-            let last_handshake = now_instant - Duration::from_nanos(handshake_delta * 100);
-
-            let mut wg_peer = WireguardPeer {
-                flags: peer.Flags as u32,
-                public_key: peer.PublicKey,
-                preshared_key: peer.PresharedKey,
-                persistent_keepalive: peer.PersistentKeepalive,
-                endpoint,
-                tx_bytes: peer.TxBytes,
-                rx_bytes: peer.RxBytes,
-                last_handshake,
-                allowed_ips: Vec::with_capacity(peer.AllowedIPsCount as usize),
+    /// Gets the current configuration of this adapter like [`Adapter::get_config`], but reads it
+    /// into `buf` and returns a view that decodes it from there, instead of allocating for the
+    /// config and each of its peers.
+    ///
+    /// `buf` only grows when the config no longer fits, so a loop that polls the config with the
+    /// same buffer stops allocating once it has seen the largest config. Anything already in `buf`
+    /// is overwritten. Once the view is dropped, `buf` still holds the private key of the adapter
+    /// and the preshared keys of its peers, so zero it when it is no longer needed, as
+    /// `get_config` does
+    pub fn get_config_into<'a>(&self, buf: &'a mut Vec<u8>) -> Result<InterfaceRef<'a>, Error> {
+        //Start with enough room for just the interface. If the buffer is too small,
+        //WireGuardGetConfiguration fails with ERROR_MORE_DATA and updates `size` to the required
+        //value. Peers can be added between the two calls, so `query_growing_into` keeps growing
+        //the buffer until the config fits
+        util::query_growing_into(buf, size_of::<WIREGUARD_INTERFACE>(), |buf, size| {
+            //SAFETY: `buf` is writeable for `size` bytes. The driver copies the config into it
+            //with DeviceIoControl, which has no alignment requirements, and `InterfaceRef` reads
+            //it unaligned
+            let res = unsafe {
+                self.wireguard.get_configuration(
+                    self.adapter.0,
+                    buf.as_mut_ptr() as *mut WIREGUARD_INTERFACE,
+                    size,
+                )
             };
-            for _ in 0..peer.AllowedIPsCount {
-                // # Safety:
-                // 1. `WireGuardGetConfiguration` writes zero or more `WIREGUARD_ALLOWED_IP`s immediately after the WIREGUARD_PEER we read above.
-                // 2. We rely on Wireguard-NT to specify the number of allowed ips written, and therefore we never read too many times unless Wireguard-NT (wrongly) tells us to
-                let allowed_ip: WIREGUARD_ALLOWED_IP = unsafe { reader.read() };
-                let prefix_length = allowed_ip.Cidr;
-                let allowed_ip = match allowed_ip.AddressFamily as i32 {
-                    winapi::shared::ws2def::AF_INET => {
-                        let octets = unsafe { allowed_ip.Address.V4.S_un.S_un_b };
-                        let address =
-                            Ipv4Addr::new(octets.s_b1, octets.s_b2, octets.s_b3, octets.s_b4);
-                        IpNet::V4(Ipv4Net::new(address, prefix_length).expect("prefix is valid"))
-                    }
-                    winapi::shared::ws2def::AF_INET6 => {
-                        let octets = unsafe { allowed_ip.Address.V6.u.Byte };
-                        let address = Ipv6Addr::from(octets);
-                        IpNet::V6(Ipv6Net::new(address, prefix_length).expect("prefix is valid"))
+            match res {
+                0 => unsafe { GetLastError() as i32 },
+                _ => ERROR_SUCCESS as i32,
+            }
+        })?;
+        InterfaceRef::parse(buf)
+    }
+
+    /// Returns an iterator over the peers of this adapter, as they are in [`Adapter::get_config`].
+    ///
+    /// The whole config is read and checked before the first peer is returned, so a malformed
+    /// buffer is reported here rather than in the middle of the iteration. The iterator owns the
+    /// peers and does not borrow the adapter
+    pub fn peers(&self) -> Result<impl Iterator<Item = Peer>, Error> {
+        Ok(self.get_config()?.peers.into_iter())
+    }
+
+    /// Returns the state, listen port and peers of this adapter. Its `Display` output is a summary
+    /// in the style of `wg show`, for command line tools
+    pub fn status(&self) -> Result<Status, Error> {
+        let config = self.get_config()?;
+        Ok(Status {
+            name: self.name.clone(),
+            state: self.get_state()?,
+            public_key: config.public_key,
+            listen_port: config.listen_port,
+            peers: config.peers,
+        })
+    }
+
+    /// Polls the config of this adapter every `interval` on a background thread, and calls
+    /// `on_change` with what changed since the previous poll: added and removed peers, peers whose
+    /// endpoint roamed or whose other settings changed, and peers that completed a new handshake.
+    /// Changes of the traffic counters alone are not reported.
+    ///
+    /// The thread polls through its own handle, opened with [`Adapter::open`], so this adapter can
+    /// be dropped while it runs. It stops when the returned [`Watcher`] is dropped. Failed polls
+    /// are logged and skipped. Intervals shorter than 1ms are raised to 1ms so that the thread
+    /// never spins
+    pub fn watch(
+        &self,
+        interval: Duration,
+        mut on_change: impl FnMut(ConfigDelta) + Send + 'static,
+    ) -> Result<Watcher, Error> {
+        let interval = interval.max(Duration::from_millis(1));
+        let adapter = Adapter::open(&self.wireguard, &self.name)?;
+        let mut previous = adapter.get_config()?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(format!("wireguard-watch-{}", self.name))
+            .spawn(move || {
+                //Dropping the `Watcher` disconnects the channel, which ends the wait early
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    match adapter.get_config() {
+                        Ok(current) => {
+                            let delta = observed_changes(&previous, &current);
+                            if !delta.is_empty() {
+                                on_change(delta);
+                            }
+                            previous = current;
+                        }
+                        Err(e) => log::warn!("Failed to poll adapter {}: {}", adapter.name, e),
                     }
-                    _ => {
-                        panic!("Illegal address family {}", allowed_ip.AddressFamily);
+                }
+            })?;
+        Ok(Watcher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the traffic counters, last handshake and endpoint of every peer of this adapter.
+    ///
+    /// This reads the same data as [`Adapter::get_config`] but skips over the allowed ips and keys
+    /// that a monitoring snapshot does not need
+    pub fn stats(&self) -> Result<Vec<PeerStats>, Error> {
+        let mut buf = Vec::new();
+        let stats = self.get_config_into(&mut buf).map(|config| {
+            config
+                .peers()
+                .map(|peer| PeerStats {
+                    public_key: peer.public_key().clone(),
+                    rx_bytes: peer.rx_bytes(),
+                    tx_bytes: peer.tx_bytes(),
+                    last_handshake: peer.last_handshake(),
+                    endpoint: peer.endpoint(),
+                })
+                .collect()
+        });
+        util::zero_bytes(&mut buf);
+        stats
+    }
+
+    /// Writes the traffic counters and last handshake time of every peer in the OpenMetrics text
+    /// format, so they can be served to Prometheus. The `wireguard_peer_rx_bytes` and
+    /// `wireguard_peer_tx_bytes` counters and the `wireguard_peer_last_handshake_seconds` gauge are
+    /// labeled with the adapter's name and the peer's base64 public key.
+    ///
+    /// Requires the `metrics` feature
+    #[cfg(feature = "metrics")]
+    pub fn write_openmetrics(&self, out: &mut impl std::fmt::Write) -> Result<(), Error> {
+        let stats = self.stats()?;
+        crate::metrics::write_openmetrics(out, &self.name, &stats)?;
+        Ok(())
+    }
+
+    /// Returns the configuration and peers of this adapter in the tab separated format of
+    /// `wg show <interface> dump`, with the same columns, order and placeholders, so tools that
+    /// parse `wg` dumps can read it unchanged. See `wg(8)` for the columns.
+    ///
+    /// Like the output of `wg`, this includes the private key of the adapter and the preshared
+    /// keys of its peers, so treat it as a secret. [`Adapter::status`] gives a summary without
+    /// them
+    pub fn dump(&self) -> Result<String, Error> {
+        let mut out = String::new();
+        crate::status::write_dump(&mut out, &self.get_config()?)?;
+        Ok(out)
+    }
+
+    /// Returns the UDP port this adapter listens on. If the port was left for the driver to pick,
+    /// this is the port it picked
+    pub fn listen_port(&self) -> Result<u16, Error> {
+        let mut buf = Vec::new();
+        let port = self
+            .get_config_into(&mut buf)
+            .map(|config| config.listen_port());
+        util::zero_bytes(&mut buf);
+        port?.ok_or_else(|| {
+            Error::InvalidConfig("The driver did not report a listen port".to_owned())
+        })
+    }
+
+    /// Waits until the peer with public key `peer` has completed a handshake, checking every
+    /// 100ms. Shorthand for [`Adapter::wait_for_handshake_with`]
+    pub fn wait_for_handshake(&self, peer: &Key, timeout: Duration) -> Result<bool, Error> {
+        self.wait_for_handshake_with(peer, timeout, HANDSHAKE_POLL_INTERVAL)
+    }
+
+    /// Waits until the peer with public key `peer` has completed a handshake, reading the config
+    /// every `poll_interval` and sleeping in between.
+    ///
+    /// Returns `true` as soon as the peer has a `last_handshake`, or `false` if `timeout` passes
+    /// first. A peer that is not configured yet counts as not having completed a handshake.
+    /// Intervals shorter than 1ms are raised to 1ms so that this never spins. A `timeout` too long
+    /// to be added to the current time, such as [`Duration::MAX`], waits without a deadline
+    pub fn wait_for_handshake_with(
+        &self,
+        peer: &Key,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<bool, Error> {
+        let poll_interval = poll_interval.max(Duration::from_millis(1));
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            if has_handshake(&self.get_config()?, peer) {
+                return Ok(true);
+            }
+            let sleep = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
                     }
-                };
-                wg_peer.allowed_ips.push(allowed_ip);
-            }
-            wg_interface.peers.push(wg_peer);
-        }
-        wg_interface
-    }
-}
-
-#[derive(Debug)]
-pub struct WireguardPeer {
-    /// Bitwise combination of flags
-    pub flags: u32,
-    /// Public key, the peer's primary identifier
-    pub public_key: [u8; 32usize],
-    /// Preshared key for additional layer of post-quantum resistance
-    pub preshared_key: [u8; 32usize],
-    /// Seconds interval, or 0 to disable
-    pub persistent_keepalive: u16,
-    /// Endpoint, with IP address and UDP port number
-    pub endpoint: SocketAddr,
-    /// Number of bytes transmitted
-    pub tx_bytes: u64,
-    /// Number of bytes received
-    pub rx_bytes: u64,
-    /// Time of the last handshake
-    pub last_handshake: Instant,
-    /// Number of allowed IP structs following this struct
-    pub allowed_ips: Vec<IpNet>,
-}
-
-#[derive(Debug)]
-pub struct WireguardInterface {
-    /// Bitwise combination of flags
-    pub flags: u32,
-    /// Port for UDP listen socket, or 0 to choose randomly
-    pub listen_port: u16,
-    /// Private key of interface
-    pub private_key: [u8; 32usize],
-    /// Corresponding public key of private key
-    pub public_key: [u8; 32usize],
-    /// Number of peer structs following this struct
-    pub peers: Vec<WireguardPeer>,
-}
-
-impl Drop for Adapter {
-    fn drop(&mut self) {
-        //Free adapter on drop
-        //This is why we need an Arc of wireguard, so we have access to it here
-        unsafe { self.wireguard.WireGuardCloseAdapter(self.adapter.0) };
-        self.adapter = UnsafeHandle(ptr::null_mut());
+                    poll_interval.min(deadline - now)
+                }
+                None => poll_interval,
+            };
+            std::thread::sleep(sleep);
+        }
+    }
+}
+
+/// Packs `config` into a single buffer in the layout `WireGuardSetConfiguration` expects: the
+/// interface, followed by each peer and then its allowed ips. The buffer is sized up front, so
+/// configs with many peers take one allocation
+pub(crate) fn encode_config(
+    config: &Interface,
+    interface_flags: InterfaceFlags,
+    peer_flags: PeerFlags,
+) -> Result<util::StructWriter, Error> {
+    use wireguard_nt_raw::*;
+
+    let peer_size: usize = config
+        .peers
+        .iter()
+        .map(|p| {
+            size_of::<WIREGUARD_PEER>() + p.allowed_ips.len() * size_of::<WIREGUARD_ALLOWED_IP>()
+        })
+        .sum();
+
+    let size: usize = size_of::<WIREGUARD_INTERFACE>() + peer_size;
+    let align = align_of::<WIREGUARD_INTERFACE>();
+
+    let mut writer = util::StructWriter::new(size, align);
+    //Most of this function is writing data into `writer`, in a format that wireguard expects
+    //so that it can decode the data when we call WireGuardSetConfiguration
+
+    // Safety:
+    // 1. `writer` has the correct alignment for a `WIREGUARD_INTERFACE`
+    // 2. Nothing has been written to writer so the internal pointer must be aligned
+    let interface: &mut WIREGUARD_INTERFACE = unsafe { writer.write() };
+    interface.Flags = interface_flags_for(config, interface_flags).bits;
+    if let Some(private_key) = &config.private_key {
+        interface.PrivateKey = *private_key.as_bytes();
+    }
+    if let Some(pub_key) = &config.public_key {
+        interface.PublicKey = *pub_key.as_bytes();
+    }
+    if let Some(listen_port) = config.listen_port {
+        interface.ListenPort = listen_port;
+    }
+    interface.PeersCount = config.peers.len() as u32;
+
+    for peer in &config.peers {
+        // Safety:
+        // `align_of::<WIREGUARD_INTERFACE` is 8, WIREGUARD_PEER has no special alignment
+        // requirements, and writer is already aligned to hold `WIREGUARD_INTERFACE` structs,
+        // therefore we uphold the alignment requirements of `write`
+        let wg_peer: &mut WIREGUARD_PEER = unsafe { writer.write() };
+
+        write_peer(wg_peer, peer, peer_flags)?;
+
+        for allowed_ip in &peer.allowed_ips {
+            // Safety:
+            // Same as above, `writer` is aligned because it was aligned before
+            let wg_allowed_ip: &mut WIREGUARD_ALLOWED_IP = unsafe { writer.write() };
+            match allowed_ip.address() {
+                IpAddr::V4(v4) => {
+                    let addr = unsafe { std::mem::transmute::<[u8; 4], IN_ADDR>(v4.octets()) };
+                    wg_allowed_ip.Address.V4 = addr;
+                    wg_allowed_ip.AddressFamily = winapi::shared::ws2def::AF_INET as u16;
+                }
+                IpAddr::V6(v6) => {
+                    let addr = unsafe { std::mem::transmute::<[u8; 16], IN6_ADDR>(v6.octets()) };
+                    wg_allowed_ip.Address.V6 = addr;
+                    wg_allowed_ip.AddressFamily = winapi::shared::ws2def::AF_INET6 as u16;
+                }
+            }
+            wg_allowed_ip.Cidr = allowed_ip.cidr();
+        }
+    }
+
+    //Make sure that our allocation math was correct and that we filled all of writer
+    debug_assert!(writer.is_full());
+    Ok(writer)
+}
+
+/// Resolves the `hosts` of the peers in `config` with `resolver`, and returns the peers to send to
+/// the driver, with only their public key and new endpoint, for those whose endpoint changed
+fn endpoint_changes(
+    config: &Interface,
+    hosts: &HashMap<PublicKey, String>,
+    resolver: impl Fn(&str) -> Option<SocketAddr>,
+) -> Vec<Peer> {
+    config
+        .peers
+        .iter()
+        .filter_map(|peer| {
+            let host = hosts.get(&PublicKey::from(peer.public_key.clone()))?;
+            let endpoint = resolver(host).filter(|e| peer.endpoint != Some(*e))?;
+            Some(Peer {
+                endpoint: Some(endpoint),
+                ..key_only_peer(&peer.public_key)
+            })
+        })
+        .collect()
+}
+
+/// Returns `raw` as a [`Luid`], or [`Error::InterfaceNotReady`] if it is zero
+fn checked_luid(raw: u64) -> Result<Luid, Error> {
+    match raw {
+        0 => Err(Error::InterfaceNotReady),
+        raw => Ok(Luid(raw)),
+    }
+}
+
+/// Maps the error code of a failed `WireGuardOpenAdapter` call, so that callers can tell a missing
+/// adapter from one they are not allowed to open
+fn open_error(code: u32) -> Error {
+    match code {
+        ERROR_NOT_FOUND | ERROR_FILE_NOT_FOUND => Error::AdapterNotFound,
+        ERROR_ACCESS_DENIED => Error::AccessDenied(std::io::Error::from_raw_os_error(
+            ERROR_ACCESS_DENIED as i32,
+        )),
+        code => Error::Win32(code),
+    }
+}
+
+/// The error codes that a failure to bind the listen port reaches `GetLastError` as. The driver
+/// binds its sockets in the kernel, where a taken port is `STATUS_ADDRESS_ALREADY_EXISTS` or
+/// `STATUS_ADDRESS_ALREADY_ASSOCIATED`, which Windows translates to `ERROR_DUP_NAME` and
+/// `ERROR_ADDRESS_ALREADY_ASSOCIATED`. `WSAEADDRINUSE` is what a bind in user mode fails with
+const PORT_IN_USE_ERRORS: [u32; 3] = [
+    ERROR_DUP_NAME,
+    ERROR_ADDRESS_ALREADY_ASSOCIATED,
+    WSAEADDRINUSE,
+];
+
+/// Maps the error of a failed `WireGuardSetConfiguration` or `WireGuardSetAdapterState` call to
+/// [`Error::PortInUse`] if it means that the fixed listen port `port` is taken. A port of zero is
+/// picked by the driver, so it cannot conflict
+fn listen_port_error(error: Error, port: Option<u16>) -> Error {
+    match (error, port) {
+        (Error::Win32(code), Some(port)) if port != 0 && PORT_IN_USE_ERRORS.contains(&code) => {
+            Error::PortInUse(port)
+        }
+        (error, _) => error,
+    }
+}
+
+/// Compares the `current` config of an adapter with the `desired` one. Returns what has to change,
+/// and the config to send to the driver for the interface and for the peers that are added or
+/// updated. Removed peers are only listed in the returned [`ConfigDelta`]
+fn config_changes(current: &Interface, desired: &Interface) -> (ConfigDelta, Interface) {
+    let mut delta = ConfigDelta::default();
+    let mut update = Interface::default();
+
+    if desired.private_key.is_some() && desired.private_key != current.private_key {
+        update.private_key = desired.private_key.clone();
+    }
+    //Zero asks the driver to pick a port, which any current port satisfies
+    update.listen_port = desired
+        .listen_port
+        .filter(|&port| port != 0 && Some(port) != current.listen_port);
+    delta.interface_changed = update.private_key.is_some() || update.listen_port.is_some();
+
+    for peer in &desired.peers {
+        match current
+            .peers
+            .iter()
+            .find(|current| current.public_key == peer.public_key)
+        {
+            None => {
+                delta.added_peers.push(peer.public_key.clone());
+                update.peers.push(peer.clone());
+            }
+            Some(current) => {
+                if let Some(changes) = peer_changes(current, peer) {
+                    delta.updated_peers.push(peer.public_key.clone());
+                    update.peers.push(changes);
+                }
+            }
+        }
+    }
+    for peer in &current.peers {
+        if !desired
+            .peers
+            .iter()
+            .any(|p| p.public_key == peer.public_key)
+        {
+            delta.removed_peers.push(peer.public_key.clone());
+        }
+    }
+    (delta, update)
+}
+
+/// Returns the peer to send to the driver to turn `current` into `desired`, or `None` if they
+/// already match. Fields that match are left unset, and a field that `desired` clears is sent as
+/// zero, which the driver treats as unset
+fn peer_changes(current: &Peer, desired: &Peer) -> Option<Peer> {
+    let zero_key = || Key::new([0; 32]);
+    let preshared_key = desired.preshared_key.clone().unwrap_or_else(zero_key);
+    let preshared_key_changed =
+        preshared_key != current.preshared_key.clone().unwrap_or_else(zero_key);
+
+    //The driver counts in whole seconds
+    let keepalive_secs = |peer: &Peer| peer.persistent_keepalive.map_or(0, |d| d.as_secs());
+    let keepalive_changed = keepalive_secs(desired) != keepalive_secs(current);
+
+    let endpoint_changed = desired.endpoint.is_some() && desired.endpoint != current.endpoint;
+
+    let networks = |peer: &Peer| -> HashSet<AllowedIp> {
+        peer.allowed_ips.iter().map(|ip| ip.network()).collect()
+    };
+    let allowed_ips_changed = networks(desired) != networks(current);
+
+    if !(preshared_key_changed || keepalive_changed || endpoint_changed || allowed_ips_changed) {
+        return None;
+    }
+    Some(Peer {
+        preshared_key: Some(preshared_key).filter(|_| preshared_key_changed),
+        persistent_keepalive: Some(desired.persistent_keepalive.unwrap_or_default())
+            .filter(|_| keepalive_changed),
+        endpoint: desired.endpoint.filter(|_| endpoint_changed),
+        //Allowed ips are replaced as a whole, which does not affect the peer's session
+        allowed_ips: desired.allowed_ips.clone(),
+        ..key_only_peer(&desired.public_key)
+    })
+}
+
+/// Compares two configs of an adapter read one after the other, for [`Adapter::watch`]
+fn observed_changes(previous: &Interface, current: &Interface) -> ConfigDelta {
+    let (mut delta, _) = config_changes(previous, current);
+    //A desired config differs from `previous` in everything it sets, but an observed one may also
+    //lose its private key or listen port
+    delta.interface_changed =
+        previous.private_key != current.private_key || previous.listen_port != current.listen_port;
+    for peer in &current.peers {
+        let previous_handshake = previous
+            .peers
+            .iter()
+            .find(|p| p.public_key == peer.public_key)
+            .and_then(|p| p.last_handshake);
+        if peer.last_handshake.is_some() && peer.last_handshake > previous_handshake {
+            delta.new_handshakes.push(peer.public_key.clone());
+        }
+    }
+    delta
+}
+
+/// Returns a peer with the public key `public_key` and every other field unset, so that only the
+/// public key is sent to the driver
+pub(crate) fn key_only_peer(public_key: &Key) -> Peer {
+    Peer {
+        public_key: public_key.clone(),
+        preshared_key: None,
+        persistent_keepalive: None,
+        endpoint: None,
+        allowed_ips: Vec::new(),
+        tx_bytes: 0,
+        rx_bytes: 0,
+        last_handshake: None,
+    }
+}
+
+/// Rejects settings of `config` that WireGuard NT has no equivalent for, rather than silently
+/// ignoring them
+fn check_supported(config: &Interface) -> Result<(), Error> {
+    match config.mark {
+        Some(mark) if mark != 0 => Err(Error::Unsupported(format!(
+            "WireGuard NT cannot mark packets, but mark {} was requested",
+            mark
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks if the peer with public key `peer` in `config` has completed a handshake
+pub(crate) fn has_handshake(config: &Interface, peer: &Key) -> bool {
+    config
+        .peers
+        .iter()
+        .any(|p| &p.public_key == peer && p.last_handshake.is_some())
+}
+
+/// Converts `endpoint` to the `SOCKADDR_INET` union used by the driver, with the port and address
+/// in network byte order
+fn endpoint_to_sockaddr_inet(endpoint: &SocketAddr) -> wireguard_nt_raw::SOCKADDR_INET {
+    use wireguard_nt_raw::{IN6_ADDR, IN_ADDR, SOCKADDR_IN, SOCKADDR_IN6, SOCKADDR_INET};
+
+    //SAFETY: SOCKADDR_INET only contains integers, so all zeroes is a valid (AF_UNSPEC) value
+    let mut sockaddr: SOCKADDR_INET = unsafe { std::mem::zeroed() };
+    match endpoint {
+        SocketAddr::V4(v4) => {
+            sockaddr.Ipv4 = SOCKADDR_IN {
+                sin_family: winapi::shared::ws2def::AF_INET as u16,
+                sin_port: v4.port().to_be(),
+                sin_addr: unsafe { std::mem::transmute::<[u8; 4], IN_ADDR>(v4.ip().octets()) },
+                sin_zero: [0; 8],
+            };
+        }
+        SocketAddr::V6(v6) => {
+            let mut ipv6: SOCKADDR_IN6 = unsafe { sockaddr.Ipv6 };
+            ipv6.sin6_family = winapi::shared::ws2def::AF_INET6 as u16;
+            ipv6.sin6_port = v6.port().to_be();
+            //Like the standard library, flow info and the scope id are passed through unchanged
+            ipv6.sin6_flowinfo = v6.flowinfo();
+            ipv6.sin6_addr = unsafe { std::mem::transmute::<[u8; 16], IN6_ADDR>(v6.ip().octets()) };
+            ipv6.__bindgen_anon_1.sin6_scope_id = v6.scope_id();
+            sockaddr.Ipv6 = ipv6;
+        }
+    }
+    sockaddr
+}
+
+/// Converts a `SOCKADDR_INET` returned by the driver into a [`SocketAddr`].
+///
+/// Returns `None` if the address family is `AF_UNSPEC`, which the driver uses for peers whose
+/// endpoint is not known yet
+fn sockaddr_inet_to_endpoint(
+    sockaddr: &wireguard_nt_raw::SOCKADDR_INET,
+) -> Result<Option<SocketAddr>, Error> {
+    // # Safety:
+    // Every variant of the union starts with the address family, and the fields read for each
+    // family only contain integers so any value is valid
+    let address_family = unsafe { sockaddr.si_family } as i32;
+    match address_family {
+        winapi::shared::ws2def::AF_UNSPEC => Ok(None),
+        winapi::shared::ws2def::AF_INET => {
+            let ipv4 = unsafe { sockaddr.Ipv4 };
+            let octets = unsafe { ipv4.sin_addr.S_un.S_un_b };
+            let address = Ipv4Addr::new(octets.s_b1, octets.s_b2, octets.s_b3, octets.s_b4);
+            let port = u16::from_be(ipv4.sin_port);
+            Ok(Some(SocketAddr::V4(SocketAddrV4::new(address, port))))
+        }
+        winapi::shared::ws2def::AF_INET6 => {
+            let ipv6 = unsafe { sockaddr.Ipv6 };
+            let address = Ipv6Addr::from(unsafe { ipv6.sin6_addr.u.Byte });
+            let port = u16::from_be(ipv6.sin6_port);
+            let scope_id = unsafe { ipv6.__bindgen_anon_1.sin6_scope_id };
+            Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+                address,
+                port,
+                ipv6.sin6_flowinfo,
+                scope_id,
+            ))))
+        }
+        _ => Err(Error::InvalidConfig(format!(
+            "Illegal endpoint address family {}",
+            address_family
+        ))),
+    }
+}
+
+/// Converts a `WIREGUARD_ALLOWED_IP` read from the driver into an [`AllowedIp`]
+pub(crate) fn read_allowed_ip(allowed_ip: &WIREGUARD_ALLOWED_IP) -> Result<AllowedIp, Error> {
+    let address = match allowed_ip.AddressFamily as i32 {
+        winapi::shared::ws2def::AF_INET => {
+            let octets = unsafe { allowed_ip.Address.V4.S_un.S_un_b };
+            IpAddr::V4(Ipv4Addr::new(
+                octets.s_b1,
+                octets.s_b2,
+                octets.s_b3,
+                octets.s_b4,
+            ))
+        }
+        winapi::shared::ws2def::AF_INET6 => {
+            let octets = unsafe { allowed_ip.Address.V6.u.Byte };
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => {
+            return Err(Error::InvalidConfig(format!(
+                "Illegal allowed ip address family {}",
+                allowed_ip.AddressFamily
+            )));
+        }
+    };
+    AllowedIp::new(address, allowed_ip.Cidr)
+}
+
+/// Stops the background thread of [`Adapter::watch`] when dropped. Dropping waits for a callback
+/// that is running to return
+pub struct Watcher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            //A panic in the callback already ended the thread, there is nothing left to stop
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<A: WireGuardApi> Drop for Adapter<A> {
+    fn drop(&mut self) {
+        //Free adapter on drop
+        //This is why we need an Arc of wireguard, so we have access to it here
+        if self.adapter.0.is_null() {
+            //Already closed by `Adapter::close`
+            return;
+        }
+        if let Err(e) = self.close_handle() {
+            let message = format!("Failed to clean up adapter {} on drop: {}", self.name, e);
+            crate::log::log_message(LogLevel::Warn, &message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapter_state_round_trip() {
+        for (state, raw) in [(AdapterState::Down, 0), (AdapterState::Up, 1)]
+            .iter()
+            .copied()
+        {
+            assert_eq!(wireguard_nt_raw::WIREGUARD_ADAPTER_STATE::from(state), raw);
+            assert_eq!(AdapterState::try_from(raw), Ok(state));
+        }
+        assert_eq!(AdapterState::try_from(2), Err(2));
+    }
+
+    /// Loads the wireguard.dll shipped with the examples, for the tests that need the driver
+    fn load_driver() -> Arc<crate::dll> {
+        unsafe { crate::load_from_path("examples/wireguard_nt/bin/amd64/wireguard.dll") }
+            .expect("Failed to load wireguard dll")
+    }
+
+    /// Creates an adapter named `name` in the `WireGuard` pool of the real driver. Tests of config
+    /// and state handling use [`crate::MockWireGuard`] instead, this is for the ones that go
+    /// through IP Helper, the registry or the sockets of the driver
+    fn driver_adapter(name: &str) -> (Arc<crate::dll>, Adapter) {
+        let wireguard = load_driver();
+        let adapter = Adapter::create(&wireguard, "WireGuard", name, None).unwrap();
+        (wireguard, adapter)
+    }
+
+    /// Creates an adapter named `name` on a new [`crate::MockWireGuard`]
+    #[cfg(feature = "mock")]
+    fn mock_adapter(name: &str) -> (Arc<crate::MockWireGuard>, Adapter<crate::MockWireGuard>) {
+        let wireguard = Arc::new(crate::MockWireGuard::new());
+        let adapter = Adapter::create(&wireguard, "WireGuard", name, None).unwrap();
+        (wireguard, adapter)
+    }
+
+    fn peer() -> Peer {
+        Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .build()
+            .unwrap()
+    }
+
+    fn current_config() -> Interface {
+        let mut first = peer();
+        first.preshared_key = Some(Key::new([9; 32]));
+        first.persistent_keepalive = Some(Duration::from_secs(25));
+        first.endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        first.allowed_ips = vec!["10.0.0.0/24".parse().unwrap(), "fd00::/64".parse().unwrap()];
+        let mut second = first.clone();
+        second.public_key = Key::new([2; 32]);
+        second.allowed_ips = vec!["10.0.1.0/24".parse().unwrap()];
+        Interface {
+            listen_port: Some(51820),
+            private_key: Some(Key::new([3; 32])),
+            peers: vec![first, second],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unchanged_config() {
+        let current = current_config();
+        let mut desired = current.clone();
+        //Learned endpoints are kept, any port satisfies zero, and allowed ips compare as networks
+        desired.peers[0].endpoint = None;
+        desired.peers[0].allowed_ips = vec![
+            "fd00::1/64".parse().unwrap(),
+            "10.0.0.7/24".parse().unwrap(),
+        ];
+        desired.listen_port = Some(0);
+        desired.peers.reverse();
+
+        let (delta, update) = config_changes(&current, &desired);
+        assert!(delta.is_empty(), "{:?}", delta);
+        assert!(update.peers.is_empty());
+        assert_eq!(update.private_key, None);
+        assert_eq!(update.listen_port, None);
+    }
+
+    #[test]
+    fn changed_config() {
+        let current = current_config();
+        let mut desired = current.clone();
+        desired.listen_port = Some(51821);
+        desired.peers[0].preshared_key = None;
+        desired.peers[0].persistent_keepalive = None;
+        desired.peers.remove(1);
+        let mut added = peer();
+        added.public_key = Key::new([4; 32]);
+        desired.peers.push(added.clone());
+
+        let (delta, update) = config_changes(&current, &desired);
+        assert!(delta.interface_changed);
+        assert_eq!(delta.added_peers, vec![Key::new([4; 32])]);
+        assert_eq!(delta.removed_peers, vec![Key::new([2; 32])]);
+        assert_eq!(delta.updated_peers, vec![Key::new([1; 32])]);
+        assert_eq!(update.listen_port, Some(51821));
+        assert_eq!(update.private_key, None);
+
+        //The cleared fields are sent as zero, and the unchanged endpoint is left out
+        let updated = &update.peers[0];
+        assert_eq!(updated.preshared_key, Some(Key::new([0; 32])));
+        assert_eq!(updated.persistent_keepalive, Some(Duration::from_secs(0)));
+        assert_eq!(updated.endpoint, None);
+        assert_eq!(updated.allowed_ips, current.peers[0].allowed_ips);
+        assert_eq!(update.peers[1], added);
+    }
+
+    #[test]
+    fn changed_endpoint_and_allowed_ips() {
+        let current = current_config();
+        let mut desired = current.clone();
+        let endpoint: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        desired.peers[0].endpoint = Some(endpoint);
+        desired.peers[1]
+            .allowed_ips
+            .push("10.0.2.0/24".parse().unwrap());
+
+        let (delta, update) = config_changes(&current, &desired);
+        assert!(!delta.interface_changed);
+        assert_eq!(
+            delta.updated_peers,
+            vec![Key::new([1; 32]), Key::new([2; 32])]
+        );
+        assert_eq!(update.peers[0].endpoint, Some(endpoint));
+        assert_eq!(update.peers[0].preshared_key, None);
+        assert_eq!(update.peers[0].persistent_keepalive, None);
+        assert_eq!(update.peers[1].endpoint, None);
+        assert_eq!(update.peers[1].allowed_ips.len(), 2);
+    }
+
+    #[test]
+    fn name_validation() {
+        let longest = "a".repeat(crate::MAX_NAME - 1);
+        assert_eq!(encode_name(&longest).unwrap().len(), crate::MAX_NAME);
+        assert!(encode_name("WireGuard").is_ok());
+
+        let too_long = "a".repeat(crate::MAX_NAME);
+        assert!(matches!(encode_name(&too_long), Err(Error::InvalidName(_))));
+        assert!(matches!(
+            encode_name("Wire\0Guard"),
+            Err(Error::InvalidName(_))
+        ));
+        assert!(matches!(encode_name(""), Err(Error::InvalidName(_))));
+    }
+
+    #[test]
+    fn changed_endpoints() {
+        let home = Key::new([1; 32]);
+        let office = Key::new([2; 32]);
+        let untracked = Key::new([3; 32]);
+        let old_home: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let new_home: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let office_addr: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+        let config = Interface {
+            peers: [
+                (&home, old_home),
+                (&office, office_addr),
+                (&untracked, old_home),
+            ]
+            .iter()
+            .map(|(key, endpoint)| Peer {
+                endpoint: Some(*endpoint),
+                ..key_only_peer(key)
+            })
+            .collect(),
+            ..Default::default()
+        };
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            PublicKey::from(home.clone()),
+            "home.example.com:51820".to_owned(),
+        );
+        hosts.insert(
+            PublicKey::from(office.clone()),
+            "office.example.com:51820".to_owned(),
+        );
+
+        let resolver = |host: &str| match host {
+            "home.example.com:51820" => Some(new_home),
+            "office.example.com:51820" => Some(office_addr),
+            _ => None,
+        };
+        let changed = endpoint_changes(&config, &hosts, resolver);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].public_key, home);
+        assert_eq!(changed[0].endpoint, Some(new_home));
+        assert!(changed[0].allowed_ips.is_empty());
+
+        //Hosts that fail to resolve keep their endpoint
+        assert!(endpoint_changes(&config, &hosts, |_| None).is_empty());
+    }
+
+    #[test]
+    fn observed_config_changes() {
+        let previous = current_config();
+        assert!(observed_changes(&previous, &previous).is_empty());
+
+        let mut current = previous.clone();
+        //Traffic alone is not a change
+        current.peers[0].rx_bytes += 100;
+        assert!(observed_changes(&previous, &current).is_empty());
+
+        let handshake = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        current.peers[0].last_handshake = Some(handshake);
+        current.peers[1].endpoint = Some("198.51.100.7:40000".parse().unwrap());
+        let delta = observed_changes(&previous, &current);
+        assert_eq!(delta.new_handshakes, vec![Key::new([1; 32])]);
+        assert_eq!(delta.updated_peers, vec![Key::new([2; 32])]);
+        assert!(delta.added_peers.is_empty() && delta.removed_peers.is_empty());
+        assert!(!delta.interface_changed);
+
+        //The same handshake is only reported once
+        let previous = current.clone();
+        current.peers.remove(1);
+        current.listen_port = None;
+        let delta = observed_changes(&previous, &current);
+        assert!(delta.new_handshakes.is_empty());
+        assert_eq!(delta.removed_peers, vec![Key::new([2; 32])]);
+        assert!(delta.interface_changed);
+    }
+
+    #[test]
+    fn zero_luid() {
+        assert!(matches!(checked_luid(0), Err(Error::InterfaceNotReady)));
+        assert_eq!(checked_luid(1 << 32).unwrap().value(), 1 << 32);
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn luid_of_new_adapter() {
+        let (_, adapter) = driver_adapter("LuidTest");
+        //The adapter is still down, so it either has a usable LUID or says it is not ready
+        match adapter.get_luid() {
+            Ok(luid) => assert_ne!(luid.value(), 0),
+            Err(e) => assert!(matches!(e, Error::InterfaceNotReady), "{}", e),
+        }
+    }
+
+    #[test]
+    fn open_errors() {
+        assert!(matches!(
+            open_error(ERROR_FILE_NOT_FOUND),
+            Error::AdapterNotFound
+        ));
+        assert!(matches!(
+            open_error(ERROR_NOT_FOUND),
+            Error::AdapterNotFound
+        ));
+        match open_error(ERROR_ACCESS_DENIED) {
+            Error::AccessDenied(e) => {
+                assert_eq!(e.raw_os_error(), Some(ERROR_ACCESS_DENIED as i32))
+            }
+            other => panic!("expected AccessDenied, got {:?}", other),
+        }
+        assert!(matches!(open_error(1), Error::Win32(1)));
+    }
+
+    #[test]
+    fn listen_port_errors() {
+        for &code in PORT_IN_USE_ERRORS.iter() {
+            assert!(matches!(
+                listen_port_error(Error::Win32(code), Some(51820)),
+                Error::PortInUse(51820)
+            ));
+            assert!(matches!(
+                listen_port_error(Error::Win32(code), Some(0)),
+                Error::Win32(c) if c == code
+            ));
+            assert!(matches!(
+                listen_port_error(Error::Win32(code), None),
+                Error::Win32(c) if c == code
+            ));
+        }
+        assert!(matches!(
+            listen_port_error(Error::Win32(ERROR_ACCESS_DENIED), Some(51820)),
+            Error::Win32(ERROR_ACCESS_DENIED)
+        ));
+        assert!(matches!(
+            listen_port_error(Error::Timeout, Some(51820)),
+            Error::Timeout
+        ));
+    }
+
+    #[test]
+    fn adapter_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Adapter>();
+        //Adapters on different threads can share one loaded dll
+        assert_send_sync::<Arc<wireguard_nt_raw::wireguard>>();
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn watch() {
+        let (_, adapter) = mock_adapter("WatchTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let watcher = adapter
+            .watch(Duration::from_millis(50), move |delta| {
+                let _ = sender.send(delta);
+            })
+            .unwrap();
+        adapter
+            .set_config_with(
+                &Interface {
+                    peers: vec![peer()],
+                    ..config
+                },
+                SetConfigOptions::default(),
+            )
+            .unwrap();
+        let delta = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(delta.added_peers, vec![Key::new([1; 32])]);
+
+        //Dropping the watcher stops the thread, which drops the callback and its sender
+        drop(watcher);
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        ));
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn create_with_timeout() {
+        let wireguard = load_driver();
+        let adapter = Adapter::create_with_timeout(
+            &wireguard,
+            "WireGuard",
+            "TimeoutTest",
+            None,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert_eq!(adapter.name(), "TimeoutTest");
+        assert!(adapter.get_index().is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn set_private_key() {
+        let (wireguard, adapter) = mock_adapter("PrivateKeyTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .listen_port(51830)
+            .peer(peer())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        wireguard.clear_calls();
+
+        adapter.set_private_key(&Key::new([4; 32])).unwrap();
+        //Only the private key is sent, so the listen port and peers are left as they are
+        match &wireguard.calls()[0] {
+            crate::MockCall::SetConfiguration { config: sent, .. } => {
+                assert_eq!(sent.private_key, Some(Key::new([4; 32])));
+                assert_eq!(sent.listen_port, None);
+                assert!(sent.peers.is_empty());
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+        #[cfg(feature = "x25519")]
+        assert_eq!(
+            adapter.public_key().unwrap(),
+            Key::new([4; 32]).public_key()
+        );
+        let rotated = adapter.get_config().unwrap();
+        assert_eq!(rotated.private_key, Some(Key::new([4; 32])));
+        assert_eq!(rotated.listen_port, Some(51830));
+        assert_eq!(rotated.peers.len(), 1);
+        assert_eq!(rotated.peers[0].public_key, Key::new([1; 32]));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn add_peers() {
+        let (_, adapter) = mock_adapter("AddPeersTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(peer())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let added: Vec<Peer> = (2..200u8)
+            .map(|i| {
+                Peer::builder()
+                    .public_key(Key::new([i; 32]))
+                    .allowed_ip(AllowedIp::new(IpAddr::V4(Ipv4Addr::new(10, 1, i, 0)), 24).unwrap())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        adapter.add_peers(&added).unwrap();
+        let peers = adapter.get_config().unwrap().peers;
+        assert_eq!(peers.len(), added.len() + 1);
+        assert!(peers.iter().any(|p| p.public_key == Key::new([1; 32])));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn status() {
+        let (_, adapter) = mock_adapter("StatusTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .listen_port(crate::ListenPort::ephemeral())
+            .peer(peer())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        adapter.up().unwrap();
+
+        let status = adapter.status().unwrap();
+        assert_eq!(status.name, "StatusTest");
+        assert_eq!(status.state, AdapterState::Up);
+        assert_ne!(status.listen_port, None);
+        assert_eq!(status.peers.len(), 1);
+        let summary = status.to_string();
+        assert!(summary.starts_with("interface: StatusTest\n  state: up\n"));
+        assert!(summary.contains(&format!("peer: {}", Key::new([1; 32]))));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn dump() {
+        let (_, adapter) = mock_adapter("DumpTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .listen_port(51831)
+            .peer(peer())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let dump = adapter.dump().unwrap();
+        let lines: Vec<Vec<&str>> = dump.lines().map(|l| l.split('\t').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), 4);
+        assert_eq!(lines[0][0], Key::new([3; 32]).to_base64());
+        assert_eq!(lines[0][2], "51831");
+        assert_eq!(lines[1].len(), 8);
+        assert_eq!(lines[1][0], Key::new([1; 32]).to_base64());
+        assert_eq!(lines[1][7], "off");
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn reset() {
+        let (wireguard, adapter) = mock_adapter("ResetTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        adapter.up().unwrap();
+        adapter.reset().unwrap();
+        assert_eq!(adapter.get_state().unwrap(), AdapterState::Up);
+
+        //A down adapter is brought up as well
+        adapter.down().unwrap();
+        adapter.reset().unwrap();
+        assert_eq!(adapter.get_state().unwrap(), AdapterState::Up);
+
+        //If the adapter does not come back up, the state it had before is restored
+        wireguard.fail_next_up(ERROR_ACCESS_DENIED);
+        assert!(matches!(
+            adapter.reset(),
+            Err(Error::Win32(ERROR_ACCESS_DENIED))
+        ));
+        assert_eq!(adapter.get_state().unwrap(), AdapterState::Up);
+
+        adapter.down().unwrap();
+        wireguard.fail_next_up(ERROR_ACCESS_DENIED);
+        assert!(adapter.reset().is_err());
+        assert_eq!(adapter.get_state().unwrap(), AdapterState::Down);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn adapters_share_wireguard() {
+        let wireguard = Arc::new(crate::MockWireGuard::new());
+        let first = Adapter::create(&wireguard, "WireGuard", "SharedDllFirst", None).unwrap();
+        let second = Adapter::create(&wireguard, "WireGuard", "SharedDllSecond", None).unwrap();
+        assert_eq!(Arc::strong_count(&wireguard), 3);
+        drop(wireguard);
+
+        let config = |key: u8| {
+            Interface::builder()
+                .private_key(Key::new([key; 32]))
+                .peer(
+                    Peer::builder()
+                        .public_key(Key::new([key + 1; 32]))
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap()
+        };
+        first.set_config(&config(3)).unwrap();
+        second.set_config(&config(5)).unwrap();
+        first.up().unwrap();
+
+        //Each adapter only sees its own config and state
+        assert_eq!(
+            first.get_config().unwrap().peers[0].public_key,
+            Key::new([4; 32])
+        );
+        assert_eq!(
+            second.get_config().unwrap().peers[0].public_key,
+            Key::new([6; 32])
+        );
+        assert_eq!(first.get_state().unwrap(), AdapterState::Up);
+        assert_eq!(second.get_state().unwrap(), AdapterState::Down);
+
+        //Removing one adapter leaves the other working, and the api stays alive for it
+        drop(first);
+        second.up().unwrap();
+        assert_eq!(second.get_config().unwrap().peers.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn get_config_from_other_thread() {
+        let adapter = Arc::new(mock_adapter("ThreadTest").1);
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(peer())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let reader = {
+            let adapter = Arc::clone(&adapter);
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    adapter.get_config().unwrap();
+                }
+            })
+        };
+        for _ in 0..100 {
+            adapter.set_config(&config).unwrap();
+        }
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn interface_flags() {
+        let mut config = Interface::default();
+        assert_eq!(
+            interface_flags_for(&config, InterfaceFlags::REPLACE_PEERS),
+            InterfaceFlags::REPLACE_PEERS
+        );
+        config.private_key = Some(Key::new([2; 32]));
+        config.listen_port = Some(51820);
+        assert_eq!(
+            interface_flags_for(&config, InterfaceFlags::empty()),
+            InterfaceFlags::HAS_PRIVATE_KEY | InterfaceFlags::HAS_LISTEN_PORT
+        );
+    }
+
+    #[test]
+    fn unsupported_mark() {
+        let mut config = Interface {
+            mark: Some(0),
+            ..Default::default()
+        };
+        assert!(check_supported(&config).is_ok());
+        config.mark = Some(51820);
+        assert!(matches!(
+            check_supported(&config),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn set_config_options() {
+        assert_eq!(
+            option_flags(SetConfigOptions::default()),
+            (InterfaceFlags::empty(), PeerFlags::empty())
+        );
+        assert_eq!(
+            option_flags(SetConfigOptions {
+                replace_peers: true,
+                replace_allowed_ips: true,
+            }),
+            (
+                InterfaceFlags::REPLACE_PEERS,
+                PeerFlags::REPLACE_ALLOWED_IPS
+            )
+        );
+    }
+
+    #[test]
+    fn update_peer_flags() {
+        let update = PeerFlags::UPDATE | PeerFlags::REPLACE_ALLOWED_IPS;
+        let mut peer = peer();
+        assert_eq!(
+            peer_flags_for(&peer, update),
+            update | PeerFlags::HAS_PUBLIC_KEY
+        );
+
+        peer.endpoint = Some("203.0.113.1:51820".parse().unwrap());
+        peer.persistent_keepalive = Some(Duration::from_secs(25));
+        let flags = peer_flags_for(&peer, update);
+        assert!(flags.contains(PeerFlags::UPDATE | PeerFlags::HAS_ENDPOINT));
+        assert!(flags.contains(PeerFlags::HAS_PERSISTENT_KEEPALIVE));
+        assert!(!flags.contains(PeerFlags::REMOVE));
+    }
+
+    fn zeroed_peer() -> WIREGUARD_PEER {
+        //SAFETY: `WIREGUARD_PEER` is a plain C struct for which all zeroes is a valid value
+        unsafe { std::mem::zeroed() }
+    }
+
+    /// Builds a buffer like the one filled by `WireGuardGetConfiguration`, with one peer followed
+    /// by two IPv4 allowed ips, but with the given counts. Returns the first `len` bytes of it
+    fn config_buffer(peers_count: u32, allowed_ips_count: u32, len: usize) -> Vec<u8> {
+        let size = size_of::<WIREGUARD_INTERFACE>()
+            + size_of::<WIREGUARD_PEER>()
+            + 2 * size_of::<WIREGUARD_ALLOWED_IP>();
+        let align = align_of::<WIREGUARD_INTERFACE>();
+        let mut writer = util::StructWriter::new(size, align);
+        //SAFETY: the structs are written in the order `InterfaceRef::parse` reads them, and
+        //`size` leaves room for all of them
+        unsafe {
+            writer.write::<WIREGUARD_INTERFACE>().PeersCount = peers_count;
+            writer.write::<WIREGUARD_PEER>().AllowedIPsCount = allowed_ips_count;
+            for _ in 0..2 {
+                writer.write::<WIREGUARD_ALLOWED_IP>().AddressFamily =
+                    winapi::shared::ws2def::AF_INET as u16;
+            }
+        }
+        //SAFETY: the writer's buffer is `size` bytes long
+        let bytes = unsafe { std::slice::from_raw_parts(writer.ptr(), len.min(size)) };
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn parse_config_buffer() {
+        let buf = config_buffer(1, 2, usize::MAX);
+        let interface = InterfaceRef::parse(&buf).unwrap();
+        assert_eq!(interface.peers().len(), 1);
+        let peer = interface.peers().next().unwrap();
+        assert_eq!(peer.allowed_ips().len(), 2);
+        assert!(peer
+            .allowed_ips()
+            .all(|allowed_ip| allowed_ip == AllowedIp::ALL_V4));
+        assert_eq!(
+            interface.to_interface().peers[0].allowed_ips,
+            vec![AllowedIp::ALL_V4; 2]
+        );
+    }
+
+    #[test]
+    fn parse_unaligned_config_buffer() {
+        //A `Vec<u8>` gives no alignment guarantees, so the view must not rely on one
+        let mut buf = vec![0];
+        buf.extend(config_buffer(1, 2, usize::MAX));
+        let interface = InterfaceRef::parse(&buf[1..]).unwrap();
+        assert_eq!(interface.to_interface().peers[0].allowed_ips.len(), 2);
+    }
+
+    #[test]
+    fn encode_many_peers() {
+        let peers: Vec<Peer> = (0..300u32)
+            .map(|i| {
+                let mut peer = Peer::builder()
+                    .public_key(Key::new([(i % 256) as u8; 32]))
+                    .persistent_keepalive(Duration::from_secs(u64::from(i % 60 + 1)))
+                    .build()
+                    .unwrap();
+                if i % 2 == 0 {
+                    peer.endpoint = Some(SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::new(192, 0, 2, (i % 256) as u8)),
+                        51820,
+                    ));
+                }
+                //Zero to six allowed ips, alternating between families
+                for j in 0..(i % 7) {
+                    let allowed_ip = if j % 2 == 0 {
+                        AllowedIp::new(
+                            IpAddr::V4(Ipv4Addr::new(10, (i / 256) as u8, i as u8, 0)),
+                            24,
+                        )
+                    } else {
+                        AllowedIp::new(
+                            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, i as u16, j as u16, 0, 0, 0)),
+                            80,
+                        )
+                    };
+                    peer.add_allowed_ip(allowed_ip.unwrap());
+                }
+                peer
+            })
+            .collect();
+        let config = Interface::builder()
+            .private_key(Key::new([1; 32]))
+            .peers(peers.clone())
+            .build()
+            .unwrap();
+
+        let writer = encode_config(&config, InterfaceFlags::empty(), PeerFlags::empty()).unwrap();
+        let allowed_ips: usize = peers.iter().map(|p| p.allowed_ips.len()).sum();
+        assert_eq!(
+            writer.capacity(),
+            size_of::<WIREGUARD_INTERFACE>()
+                + peers.len() * size_of::<WIREGUARD_PEER>()
+                + allowed_ips * size_of::<WIREGUARD_ALLOWED_IP>()
+        );
+
+        //SAFETY: the writer's buffer is `capacity` bytes long
+        let bytes = unsafe { std::slice::from_raw_parts(writer.ptr(), writer.capacity()) };
+        let decoded = InterfaceRef::parse(bytes).unwrap().to_interface();
+        //The config fills the whole buffer
+        assert!(InterfaceRef::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert_eq!(decoded.private_key, config.private_key);
+        assert_eq!(decoded.peers.len(), peers.len());
+        for (decoded, peer) in decoded.peers.iter().zip(&peers) {
+            assert_eq!(decoded.public_key, peer.public_key);
+            assert_eq!(decoded.endpoint, peer.endpoint);
+            assert_eq!(decoded.persistent_keepalive, peer.persistent_keepalive);
+            assert_eq!(decoded.allowed_ips, peer.allowed_ips);
+        }
+    }
+
+    #[test]
+    fn parse_truncated_config_buffer() {
+        let size = size_of::<WIREGUARD_INTERFACE>()
+            + size_of::<WIREGUARD_PEER>()
+            + 2 * size_of::<WIREGUARD_ALLOWED_IP>();
+        for len in 0..size {
+            assert!(
+                matches!(
+                    InterfaceRef::parse(&config_buffer(1, 2, len)),
+                    Err(Error::InvalidConfig(_))
+                ),
+                "buffer truncated to {} bytes was accepted",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn parse_corrupt_counts() {
+        for &(peers_count, allowed_ips_count) in &[(2, 2), (1, 3), (u32::MAX, 2), (1, u32::MAX)] {
+            assert!(
+                matches!(
+                    InterfaceRef::parse(&config_buffer(peers_count, allowed_ips_count, usize::MAX)),
+                    Err(Error::InvalidConfig(_))
+                ),
+                "{} peers with {} allowed ips were accepted",
+                peers_count,
+                allowed_ips_count
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_peers() {
+        let cases = [
+            ("192.0.2.1:0", "port 0"),
+            ("0.0.0.0:51820", "no address"),
+            ("[::]:51820", "no address"),
+            ("[::ffff:192.0.2.1]:51820", "use 192.0.2.1:51820 instead"),
+        ];
+        for &(endpoint, reason) in cases.iter() {
+            let mut peer = peer();
+            peer.endpoint = Some(endpoint.parse().unwrap());
+            match write_peer(&mut zeroed_peer(), &peer, PeerFlags::empty()) {
+                Err(Error::InvalidConfig(message)) => {
+                    assert!(message.starts_with(&format!("Peer {}:", peer.public_key)));
+                    assert!(message.ends_with(reason), "{}", message);
+                }
+                result => panic!("{} was accepted: {:?}", endpoint, result.err()),
+            }
+        }
+
+        let mut peer = peer();
+        peer.endpoint = Some("[2001:db8::1]:51820".parse().unwrap());
+        assert!(write_peer(&mut zeroed_peer(), &peer, PeerFlags::empty()).is_ok());
+        peer.persistent_keepalive = Some(Duration::from_secs(1 << 16));
+        let config = Interface {
+            peers: vec![peer.clone()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            encode_config(&config, InterfaceFlags::empty(), PeerFlags::empty()),
+            Err(Error::InvalidConfig(message)) if message.contains(&peer.public_key.to_string())
+        ));
+    }
+
+    #[test]
+    fn preshared_key() {
+        let mut peer = peer();
+        let mut wg_peer = zeroed_peer();
+        write_peer(&mut wg_peer, &peer, PeerFlags::empty()).unwrap();
+        assert!(
+            !PeerFlags::from_bits_truncate(wg_peer.Flags).contains(PeerFlags::HAS_PRESHARED_KEY)
+        );
+        assert_eq!(wg_peer.PresharedKey, [0; 32]);
+        assert_eq!(read_peer(&wg_peer).unwrap().preshared_key, None);
+
+        peer.preshared_key = Some(Key::new([7; 32]));
+        let mut wg_peer = zeroed_peer();
+        write_peer(&mut wg_peer, &peer, PeerFlags::empty()).unwrap();
+        assert!(PeerFlags::from_bits_truncate(wg_peer.Flags).contains(PeerFlags::HAS_PRESHARED_KEY));
+        assert_eq!(wg_peer.PresharedKey, [7; 32]);
+        assert_eq!(
+            read_peer(&wg_peer).unwrap().preshared_key,
+            peer.preshared_key
+        );
+
+        //A flagged zero key is how a preshared key is cleared, and reads back as none
+        wg_peer.PresharedKey = [0; 32];
+        assert_eq!(read_peer(&wg_peer).unwrap().preshared_key, None);
+    }
+
+    #[test]
+    fn unflagged_preshared_key_is_none() {
+        let mut wg_peer = zeroed_peer();
+        wg_peer.Flags = PeerFlags::HAS_PUBLIC_KEY.bits;
+        wg_peer.PresharedKey = [7; 32];
+        assert_eq!(read_peer(&wg_peer).unwrap().preshared_key, None);
+    }
+
+    #[test]
+    fn handshake_of_peer() {
+        let mut config = Interface {
+            peers: vec![peer()],
+            ..Default::default()
+        };
+        let key = Key::new([1; 32]);
+        assert!(!has_handshake(&config, &key));
+        config.peers[0].last_handshake = Some(std::time::SystemTime::now());
+        assert!(has_handshake(&config, &key));
+        assert!(!has_handshake(&config, &Key::new([2; 32])));
+    }
+
+    #[test]
+    fn set_peer_endpoint_flags() {
+        let mut peer = peer();
+        peer.endpoint = Some("203.0.113.1:51820".parse().unwrap());
+        assert_eq!(
+            peer_flags_for(&peer, PeerFlags::UPDATE),
+            PeerFlags::UPDATE | PeerFlags::HAS_PUBLIC_KEY | PeerFlags::HAS_ENDPOINT
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn stats() {
+        let (wireguard, adapter) = mock_adapter("StatsTest");
+        let endpoint = "203.0.113.1:51820".parse().unwrap();
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([1; 32]))
+                    .endpoint(endpoint)
+                    .allowed_ip(AllowedIp::ALL_V4)
+                    .allowed_ip(AllowedIp::ALL_V6)
+                    .build()
+                    .unwrap(),
+            )
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([2; 32]))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        let handshake = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let roamed = "198.51.100.1:51820".parse().unwrap();
+        assert!(wireguard.set_peer_stats(
+            "StatsTest",
+            &PeerStats {
+                public_key: Key::new([2; 32]),
+                rx_bytes: 1024,
+                tx_bytes: 2048,
+                last_handshake: Some(handshake),
+                endpoint: Some(roamed),
+            }
+        ));
+
+        let stats = adapter.stats().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].public_key, Key::new([1; 32]));
+        assert_eq!(stats[0].endpoint, Some(endpoint));
+        assert_eq!((stats[0].rx_bytes, stats[0].tx_bytes), (0, 0));
+        assert_eq!(stats[0].last_handshake, None);
+        assert_eq!(stats[1].public_key, Key::new([2; 32]));
+        assert_eq!((stats[1].rx_bytes, stats[1].tx_bytes), (1024, 2048));
+        assert_eq!(stats[1].last_handshake, Some(handshake));
+        assert_eq!(stats[1].endpoint, Some(roamed));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn link_local_endpoint() {
+        let (_, adapter) = mock_adapter("LinkLocalTest");
+        let endpoint: SocketAddr = "[fe80::1%12]:51820".parse().unwrap();
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([1; 32]))
+                    .endpoint(endpoint)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let endpoint = adapter.get_config().unwrap().peers[0].endpoint;
+        match endpoint {
+            Some(SocketAddr::V6(v6)) => assert_eq!(v6.scope_id(), 12),
+            other => panic!("expected an IPv6 endpoint, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn set_peer_endpoint() {
+        let (_, adapter) = mock_adapter("EndpointTest");
+
+        let key = Key::new([1; 32]);
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(
+                Peer::builder()
+                    .public_key(key.clone())
+                    .endpoint("203.0.113.1:51820".parse().unwrap())
+                    .allowed_ip(AllowedIp::ALL_V4)
+                    .persistent_keepalive(Duration::from_secs(25))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let backup = "198.51.100.1:51820".parse().unwrap();
+        adapter.set_peer_endpoint(&key, backup).unwrap();
+        let peer = &adapter.get_config().unwrap().peers[0];
+        assert_eq!(peer.endpoint, Some(backup));
+        assert_eq!(peer.allowed_ips, vec![AllowedIp::ALL_V4]);
+        assert_eq!(peer.persistent_keepalive, Some(Duration::from_secs(25)));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn reresolve_endpoints() {
+        let (_, adapter) = mock_adapter("ReresolveTest");
+        let key = Key::new([1; 32]);
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(
+                Peer::builder()
+                    .public_key(key.clone())
+                    .endpoint("203.0.113.1:51820".parse().unwrap())
+                    .allowed_ip(AllowedIp::ALL_V4)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        adapter.track_endpoint_host(&key, "home.example.com:51820");
+
+        let moved: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+        assert_eq!(adapter.reresolve_endpoints(|_| Some(moved)).unwrap(), 1);
+        assert_eq!(adapter.reresolve_endpoints(|_| Some(moved)).unwrap(), 0);
+        let peer = &adapter.get_config().unwrap().peers[0];
+        assert_eq!(peer.endpoint, Some(moved));
+        assert_eq!(peer.allowed_ips, vec![AllowedIp::ALL_V4]);
+
+        //Removing the peer forgets its host
+        adapter.remove_peer(&key).unwrap();
+        adapter.set_config(&config).unwrap();
+        assert_eq!(adapter.reresolve_endpoints(|_| Some(moved)).unwrap(), 0);
+    }
+
+    #[test]
+    fn remove_peer_flags() {
+        assert_eq!(
+            peer_flags_for(&peer(), PeerFlags::REMOVE),
+            PeerFlags::REMOVE | PeerFlags::HAS_PUBLIC_KEY
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn remove_peer() {
+        let (_, adapter) = mock_adapter("RemovePeerTest");
+
+        let first = Key::new([1; 32]);
+        let second = Key::new([2; 32]);
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(Peer::builder().public_key(first.clone()).build().unwrap())
+            .peer(Peer::builder().public_key(second.clone()).build().unwrap())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        assert_eq!(adapter.get_config().unwrap().peers.len(), 2);
+
+        adapter.remove_peer(&first).unwrap();
+        let peers = adapter.get_config().unwrap().peers;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].public_key, second);
+
+        //Removing a peer that is already gone succeeds
+        adapter.remove_peer(&first).unwrap();
+        assert_eq!(adapter.get_config().unwrap().peers.len(), 1);
+    }
+
+    #[test]
+    fn guid() {
+        let guid = Guid::from(0x6ba7b810_9dad_11d1_80b4_00c04fd430c8);
+        assert_eq!(guid.to_string(), "{6BA7B810-9DAD-11D1-80B4-00C04FD430C8}");
+        assert_eq!(
+            Guid::from([
+                0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4,
+                0x30, 0xc8
+            ]),
+            guid
+        );
+
+        let raw = wireguard_nt_raw::GUID::from(guid);
+        assert_eq!(Guid::from(raw), guid);
+        assert_eq!(raw.Data1, 0x6ba7b810);
+        assert_eq!(raw.Data2, 0x9dad);
+        assert_eq!(raw.Data3, 0x11d1);
+        assert_eq!(raw.Data4, [0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8]);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn name_and_pool() {
+        let (wireguard, adapter) = mock_adapter("NamePoolTest");
+        assert_eq!(adapter.name(), "NamePoolTest");
+        assert_eq!(adapter.pool(), "WireGuard");
+
+        let opened = Adapter::open(&wireguard, "NamePoolTest").unwrap();
+        assert_eq!(opened.name(), "NamePoolTest");
+        assert_eq!(opened.pool(), "WireGuard");
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn persist() {
+        let wireguard = Arc::new(crate::MockWireGuard::new());
+        Adapter::create(&wireguard, "WireGuard", "PersistTest", None)
+            .unwrap()
+            .persist();
+        //Dropping an opened adapter only closes its handle
+        drop(Adapter::open(&wireguard, "PersistTest").unwrap());
+        let opened = Adapter::open(&wireguard, "PersistTest").unwrap();
+        assert_eq!(opened.name(), "PersistTest");
+
+        drop(Adapter::create(&wireguard, "WireGuard", "DroppedTest", None).unwrap());
+        assert!(matches!(
+            Adapter::open(&wireguard, "DroppedTest"),
+            Err(Error::AdapterNotFound)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn open_or_create() {
+        let wireguard = Arc::new(crate::MockWireGuard::new());
+        assert!(matches!(
+            Adapter::open(&wireguard, "OpenOrCreateTest"),
+            Err(Error::AdapterNotFound)
+        ));
+
+        let created =
+            Adapter::open_or_create(&wireguard, "WireGuard", "OpenOrCreateTest", None).unwrap();
+        let opened =
+            Adapter::open_or_create(&wireguard, "WireGuard", "OpenOrCreateTest", None).unwrap();
+        assert_eq!(opened.get_luid().unwrap(), created.get_luid().unwrap());
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn rename() {
+        let (wireguard, mut adapter) = driver_adapter("RenameTest");
+        assert!(matches!(adapter.rename(""), Err(Error::InvalidName(_))));
+        let long = "a".repeat(crate::MAX_NAME);
+        assert!(matches!(adapter.rename(&long), Err(Error::InvalidName(_))));
+        assert_eq!(adapter.name(), "RenameTest");
+
+        adapter.rename("RenamedTest").unwrap();
+        assert_eq!(adapter.name(), "RenamedTest");
+        assert!(adapter_names(&*wireguard, None)
+            .unwrap()
+            .contains(&"RenamedTest".to_owned()));
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn set_description() {
+        let (wireguard, adapter) = driver_adapter("DescriptionTest");
+        let guid = adapter.get_guid().unwrap();
+        let description = || {
+            net_class_key(&guid)
+                .unwrap()
+                .unwrap()
+                .get_string("", "DriverDesc")
+                .unwrap()
+        };
+
+        adapter.set_description("Example App tunnel").unwrap();
+        assert_eq!(description().as_deref(), Some("Example App tunnel"));
+        assert_eq!(
+            adapter_pool(&*wireguard, "DescriptionTest")
+                .unwrap()
+                .as_deref(),
+            Some("WireGuard")
+        );
+        adapter.set_description("").unwrap();
+        assert_eq!(description().as_deref(), Some("WireGuard"));
+    }
+
+    #[test]
+    #[cfg(feature = "ipnet")]
+    fn unicast_address_row_round_trip() {
+        let luid = Luid(0x0006_0000_0100_0000);
+        for addr in ["10.8.0.2/24", "fd00::2/64"].iter() {
+            let addr: IpNet = addr.parse().unwrap();
+            let row = unicast_address_row(luid, &addr);
+            assert_eq!(row.InterfaceLuid.Value, luid.value());
+            assert_eq!(unicast_row_address(&row), Some(addr));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "ipnet")]
+    fn route_changes_diff() {
+        let nets =
+            |nets: &[&str]| -> Vec<IpNet> { nets.iter().map(|n| n.parse().unwrap()).collect() };
+        let (add, remove) = route_changes(
+            &nets(&["10.0.0.7/24", "10.0.0.0/24", "0.0.0.0/0", "fd00::/64"]),
+            &nets(&["0.0.0.0/0", "10.1.0.0/16", "fd00::/48"]),
+        );
+        assert_eq!(add, nets(&["10.0.0.0/24", "fd00::/64"]));
+        assert_eq!(remove, nets(&["10.1.0.0/16", "fd00::/48"]));
+
+        let (add, remove) = route_changes(&[], &[]);
+        assert!(add.is_empty() && remove.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "ipnet")]
+    fn forward_row_next_hop() {
+        let luid = Luid(0x0006_0000_0100_0000);
+        let route = forward_row(luid, &"10.8.0.7/24".parse().unwrap(), None, 5).unwrap();
+        assert_eq!(route.InterfaceLuid.Value, luid.value());
+        assert_eq!(route.Metric, 5);
+        assert_eq!(route.DestinationPrefix.PrefixLength, 24);
+        unsafe {
+            assert_eq!(
+                *route.DestinationPrefix.Prefix.si_family(),
+                winapi::shared::ws2def::AF_INET as u16
+            );
+            //Host bits are cleared
+            assert_eq!(
+                *route.DestinationPrefix.Prefix.Ipv4().sin_addr.S_un.S_addr(),
+                u32::from_ne_bytes([10, 8, 0, 0])
+            );
+            //On-link routes use the unspecified address of the same family
+            assert_eq!(
+                *route.NextHop.si_family(),
+                winapi::shared::ws2def::AF_INET as u16
+            );
+            assert_eq!(*route.NextHop.Ipv4().sin_addr.S_un.S_addr(), 0);
+        }
+
+        let hop = "fd00::1".parse().unwrap();
+        let route = forward_row(luid, &"::/0".parse().unwrap(), Some(hop), 0).unwrap();
+        unsafe {
+            assert_eq!(
+                *route.NextHop.si_family(),
+                winapi::shared::ws2def::AF_INET6 as u16
+            );
+            assert_eq!(
+                Ipv6Addr::from(*route.NextHop.Ipv6().sin6_addr.u.Byte()),
+                hop
+            );
+        }
+
+        assert!(matches!(
+            forward_row(luid, &"0.0.0.0/0".parse().unwrap(), Some(hop), 0),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "ipnet")]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn set_addresses() {
+        let (_, adapter) = driver_adapter("AddressTest");
+        let first: Vec<IpNet> = vec![
+            "10.8.0.2/24".parse().unwrap(),
+            "fd00::2/64".parse().unwrap(),
+        ];
+        adapter.set_addresses(&first).unwrap();
+        adapter.set_addresses(&first[..1]).unwrap();
+        adapter.set_addresses(&[]).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "ipnet")]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn sync_routes() {
+        let (_, adapter) = driver_adapter("SyncRoutesTest");
+        adapter
+            .set_addresses(&["10.9.0.2/24".parse().unwrap()])
+            .unwrap();
+        let stale: IpNet = "10.10.0.0/16".parse().unwrap();
+        adapter.add_route(stale, None, 5).unwrap();
+
+        let mut peer = peer();
+        peer.add_allowed_ip("10.11.0.0/16".parse::<AllowedIp>().unwrap());
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(peer)
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        adapter.sync_routes().unwrap();
+        //Syncing again finds every route in place
+        adapter.sync_routes().unwrap();
+
+        //The stale route is gone, so removing it finds nothing to delete
+        adapter.remove_route(stale, None).unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn listen_port_in_use() {
+        let wireguard = load_driver();
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let adapter = Adapter::create(&wireguard, "WireGuard", "PortTest", None).unwrap();
+        let config = Interface::builder()
+            .private_key(Key::new([9; 32]))
+            .listen_port(port)
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        match adapter.up() {
+            Err(Error::PortInUse(p)) => assert_eq!(p, port),
+            result => panic!("expected PortInUse, got {:?}", result),
+        }
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn guid_of_created_adapter() {
+        let wireguard = load_driver();
+        let guid = Guid::from(0x6ba7b810_9dad_11d1_80b4_00c04fd430c8);
+        let adapter = Adapter::create(&wireguard, "WireGuard", "GuidTest", Some(guid)).unwrap();
+        assert_eq!(adapter.get_guid().unwrap(), guid);
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn guid_of_adapter_created_without_guid() {
+        let wireguard = load_driver();
+        let created =
+            Adapter::create_resolving_guid(&wireguard, "WireGuard", "ResolvedGuidTest", None)
+                .unwrap();
+        assert_eq!(created.adapter.get_guid().unwrap(), created.guid);
+    }
+
+    #[test]
+    fn random_guids() {
+        let a = u128::from(Guid::random().unwrap());
+        let b = u128::from(Guid::random().unwrap());
+        assert_ne!(a, b);
+        //CoCreateGuid returns version 4 GUIDs
+        assert_eq!((a >> 76) & 0xf, 4);
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn nrpt_rule_removed_on_drop() {
+        let (_, adapter) = driver_adapter("NrptTest");
+        let rule = format!(
+            "SYSTEM\\CurrentControlSet\\Services\\Dnscache\\Parameters\\DnsPolicyConfig\\wireguard-nt-{}",
+            adapter.get_guid().unwrap()
+        );
+        let servers = ["10.0.0.1".parse().unwrap()];
+        adapter
+            .set_nrpt_domains(&["corp.example.com"], &servers)
+            .unwrap();
+        adapter
+            .set_dns_search_domains(&["corp.example.com"])
+            .unwrap();
+        assert!(crate::registry::RegKey::open_local_machine(&rule)
+            .unwrap()
+            .is_some());
+
+        drop(adapter);
+        assert!(crate::registry::RegKey::open_local_machine(&rule)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn apply_config() {
+        let (_, adapter) = mock_adapter("ApplyConfigTest");
+        let mut config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .listen_port(crate::ListenPort::ephemeral())
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([1; 32]))
+                    .allowed_ip("10.0.0.0/24".parse::<AllowedIp>().unwrap())
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+
+        let delta = adapter.apply_config(&config).unwrap();
+        assert!(delta.interface_changed);
+        assert_eq!(delta.added_peers, vec![Key::new([1; 32])]);
+        //The adapter now has the desired config, so applying it again changes nothing
+        assert!(adapter.apply_config(&config).unwrap().is_empty());
+
+        config.peers[0].public_key = Key::new([2; 32]);
+        let delta = adapter.apply_config(&config).unwrap();
+        assert_eq!(delta.added_peers, vec![Key::new([2; 32])]);
+        assert_eq!(delta.removed_peers, vec![Key::new([1; 32])]);
+        assert_eq!(adapter.get_config().unwrap().peers.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn apply_config_clears_preshared_key() {
+        let (wireguard, adapter) = mock_adapter("ClearPresharedKeyTest");
+        let mut config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([1; 32]))
+                    .preshared_key(Key::new([7; 32]))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        adapter.apply_config(&config).unwrap();
+        assert_eq!(
+            adapter.get_config().unwrap().peers[0].preshared_key,
+            Some(Key::new([7; 32]))
+        );
+
+        config.peers[0].preshared_key = None;
+        let delta = adapter.apply_config(&config).unwrap();
+        assert_eq!(delta.updated_peers, vec![Key::new([1; 32])]);
+        assert_eq!(adapter.get_config().unwrap().peers[0].preshared_key, None);
+
+        //The cleared key reads back as none, so the clear is not sent again
+        wireguard.clear_calls();
+        assert!(adapter.apply_config(&config).unwrap().is_empty());
+        assert!(!wireguard
+            .calls()
+            .iter()
+            .any(|call| matches!(call, crate::MockCall::SetConfiguration { .. })));
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn wait_for_handshake_without_deadline() {
+        let (wireguard, adapter) = mock_adapter("HandshakeTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(peer())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        assert!(!adapter
+            .wait_for_handshake(&Key::new([1; 32]), Duration::from_millis(10))
+            .unwrap());
+
+        assert!(wireguard.set_peer_stats(
+            "HandshakeTest",
+            &PeerStats {
+                public_key: Key::new([1; 32]),
+                rx_bytes: 0,
+                tx_bytes: 0,
+                last_handshake: Some(std::time::SystemTime::now()),
+                endpoint: None,
+            }
+        ));
+        //A timeout that overflows the deadline waits for the handshake instead of panicking
+        assert!(adapter
+            .wait_for_handshake(&Key::new([1; 32]), Duration::MAX)
+            .unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "mock")]
+    fn peers() {
+        let (_, adapter) = mock_adapter("PeersTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(peer())
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([2; 32]))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let peers = adapter.peers().unwrap();
+        //The iterator outlives the adapter
+        drop(adapter);
+        let keys: Vec<Key> = peers.map(|peer| peer.public_key).collect();
+        assert_eq!(keys, vec![Key::new([1; 32]), Key::new([2; 32])]);
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn guid_of_opened_adapter() {
+        let wireguard = load_driver();
+        let created = Adapter::create(&wireguard, "WireGuard", "OpenGuidTest", None).unwrap();
+        let opened = Adapter::open(&wireguard, "OpenGuidTest").unwrap();
+        assert_eq!(opened.get_guid().unwrap(), created.get_guid().unwrap());
+        assert_eq!(
+            opened.get_guid_string().unwrap(),
+            created.get_guid().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn picked_listen_port() {
+        let (_, adapter) = driver_adapter("ListenPortTest");
+        let config = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .listen_port(crate::ListenPort::ephemeral())
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+        adapter.up().unwrap();
+
+        let port = adapter.listen_port().unwrap();
+        assert_ne!(port, 0);
+        assert_eq!(adapter.get_config().unwrap().listen_port, Some(port));
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn interface_index() {
+        let (_, adapter) = driver_adapter("IndexTest");
+        assert_ne!(adapter.get_index().unwrap(), 0);
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn mtu() {
+        let (_, adapter) = driver_adapter("MtuTest");
+        adapter.set_mtu(1420).unwrap();
+        assert_eq!(adapter.get_mtu().unwrap(), 1420);
+        adapter.set_mtu(1380).unwrap();
+        assert_eq!(adapter.get_mtu().unwrap(), 1380);
+    }
+
+    #[test]
+    fn device_description() {
+        assert_eq!(pool_from_description("WireGuard"), "WireGuard");
+        assert_eq!(
+            pool_from_description("@oem42.inf,%wireguard.devicedesc%;WireGuard"),
+            "WireGuard"
+        );
+        assert_eq!(
+            pool_from_description("@oem42.inf,%wireguard.devicedesc%;Demo"),
+            "Demo"
+        );
+    }
+
+    #[test]
+    fn ipv4_endpoint_round_trip() {
+        let endpoint: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        let sockaddr = endpoint_to_sockaddr_inet(&endpoint);
+        let ipv4 = unsafe { sockaddr.Ipv4 };
+        assert_eq!(ipv4.sin_family, winapi::shared::ws2def::AF_INET as u16);
+        //Network byte order
+        assert_eq!(ipv4.sin_port.to_ne_bytes(), 51820u16.to_be_bytes());
+        assert_eq!(
+            unsafe { ipv4.sin_addr.S_un.S_addr }.to_ne_bytes(),
+            [203, 0, 113, 7]
+        );
+        assert_eq!(
+            sockaddr_inet_to_endpoint(&sockaddr).unwrap(),
+            Some(endpoint)
+        );
+    }
+
+    #[test]
+    fn ipv6_endpoint_round_trip() {
+        let endpoint = SocketAddr::V6(SocketAddrV6::new(
+            "fe80::1:2".parse().unwrap(),
+            51820,
+            0x12345,
+            7,
+        ));
+        let sockaddr = endpoint_to_sockaddr_inet(&endpoint);
+        let ipv6 = unsafe { sockaddr.Ipv6 };
+        assert_eq!(ipv6.sin6_family, winapi::shared::ws2def::AF_INET6 as u16);
+        assert_eq!(ipv6.sin6_port.to_ne_bytes(), 51820u16.to_be_bytes());
+        assert_eq!(unsafe { ipv6.__bindgen_anon_1.sin6_scope_id }, 7);
+        assert_eq!(
+            sockaddr_inet_to_endpoint(&sockaddr).unwrap(),
+            Some(endpoint)
+        );
+    }
+
+    #[test]
+    fn link_local_endpoint_through_peer() {
+        let mut peer = peer();
+        peer.endpoint = Some("[fe80::1%12]:51820".parse().unwrap());
+        let mut wg_peer = zeroed_peer();
+        write_peer(&mut wg_peer, &peer, PeerFlags::empty()).unwrap();
+        assert_eq!(
+            unsafe { wg_peer.Endpoint.Ipv6.__bindgen_anon_1.sin6_scope_id },
+            12
+        );
+        match read_peer(&wg_peer).unwrap().endpoint {
+            Some(SocketAddr::V6(v6)) => {
+                assert_eq!(v6.scope_id(), 12);
+                assert_eq!(v6.to_string(), "[fe80::1%12]:51820");
+            }
+            other => panic!("expected an IPv6 endpoint, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unspecified_endpoint_is_none() {
+        let sockaddr: wireguard_nt_raw::SOCKADDR_INET = unsafe { std::mem::zeroed() };
+        assert_eq!(sockaddr_inet_to_endpoint(&sockaddr).unwrap(), None);
     }
 }
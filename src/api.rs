@@ -0,0 +1,175 @@
+use crate::wireguard_nt_raw::{
+    wireguard, BOOL, DWORD, GUID, LPCWSTR, NET_LUID, WIREGUARD_ADAPTER_HANDLE,
+    WIREGUARD_ADAPTER_LOG_STATE, WIREGUARD_ADAPTER_STATE, WIREGUARD_INTERFACE,
+    WIREGUARD_LOGGER_CALLBACK,
+};
+use crate::Error;
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for crate::dll {}
+
+    #[cfg(feature = "mock")]
+    impl Sealed for crate::MockWireGuard {}
+}
+
+/// The functions of wireguard.dll that [`crate::Adapter`] and the driver functions of this crate
+/// call.
+///
+/// [`crate::dll`] implements this by calling the loaded functions, and is the default type
+/// parameter of [`crate::Adapter`] and [`crate::WireGuard`], so code that does not name another
+/// implementation is unchanged. Calls are dispatched statically, so this costs nothing over
+/// calling the dll directly. With the `mock` feature, [`crate::MockWireGuard`] implements it in
+/// memory, so code that is generic over `WireGuardApi` can be tested without the driver.
+///
+/// The trait is sealed. Its methods mirror the C functions, take raw pointers and report errors
+/// through `GetLastError`, so they are hidden and not part of the stable API
+pub trait WireGuardApi: sealed::Sealed + Send + Sync + 'static {
+    #[doc(hidden)]
+    unsafe fn create_adapter(
+        &self,
+        pool: LPCWSTR,
+        name: LPCWSTR,
+        guid: *const GUID,
+    ) -> WIREGUARD_ADAPTER_HANDLE;
+
+    #[doc(hidden)]
+    unsafe fn open_adapter(&self, name: LPCWSTR) -> WIREGUARD_ADAPTER_HANDLE;
+
+    #[doc(hidden)]
+    unsafe fn close_adapter(&self, adapter: WIREGUARD_ADAPTER_HANDLE);
+
+    #[doc(hidden)]
+    unsafe fn get_adapter_luid(&self, adapter: WIREGUARD_ADAPTER_HANDLE, luid: *mut NET_LUID);
+
+    #[doc(hidden)]
+    unsafe fn get_running_driver_version(&self) -> DWORD;
+
+    #[doc(hidden)]
+    unsafe fn delete_driver(&self) -> BOOL;
+
+    #[doc(hidden)]
+    unsafe fn set_logger(&self, logger: WIREGUARD_LOGGER_CALLBACK);
+
+    #[doc(hidden)]
+    unsafe fn set_adapter_logging(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: WIREGUARD_ADAPTER_LOG_STATE,
+    ) -> BOOL;
+
+    #[doc(hidden)]
+    unsafe fn get_adapter_state(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: *mut WIREGUARD_ADAPTER_STATE,
+    ) -> BOOL;
+
+    #[doc(hidden)]
+    unsafe fn set_adapter_state(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: WIREGUARD_ADAPTER_STATE,
+    ) -> BOOL;
+
+    #[doc(hidden)]
+    unsafe fn get_configuration(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        config: *mut WIREGUARD_INTERFACE,
+        bytes: *mut DWORD,
+    ) -> BOOL;
+
+    #[doc(hidden)]
+    unsafe fn set_configuration(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        config: *const WIREGUARD_INTERFACE,
+        bytes: DWORD,
+    ) -> BOOL;
+
+    /// Returns the name and pool of every adapter. wireguard.dll cannot list adapters, so for the
+    /// real driver they are read from the registry
+    #[doc(hidden)]
+    fn adapters(&self) -> Result<Vec<(String, Option<String>)>, Error> {
+        crate::adapter::registry_adapters()
+    }
+}
+
+impl WireGuardApi for wireguard {
+    unsafe fn create_adapter(
+        &self,
+        pool: LPCWSTR,
+        name: LPCWSTR,
+        guid: *const GUID,
+    ) -> WIREGUARD_ADAPTER_HANDLE {
+        self.WireGuardCreateAdapter(pool, name, guid)
+    }
+
+    unsafe fn open_adapter(&self, name: LPCWSTR) -> WIREGUARD_ADAPTER_HANDLE {
+        self.WireGuardOpenAdapter(name)
+    }
+
+    unsafe fn close_adapter(&self, adapter: WIREGUARD_ADAPTER_HANDLE) {
+        self.WireGuardCloseAdapter(adapter)
+    }
+
+    unsafe fn get_adapter_luid(&self, adapter: WIREGUARD_ADAPTER_HANDLE, luid: *mut NET_LUID) {
+        self.WireGuardGetAdapterLUID(adapter, luid)
+    }
+
+    unsafe fn get_running_driver_version(&self) -> DWORD {
+        self.WireGuardGetRunningDriverVersion()
+    }
+
+    unsafe fn delete_driver(&self) -> BOOL {
+        self.WireGuardDeleteDriver()
+    }
+
+    unsafe fn set_logger(&self, logger: WIREGUARD_LOGGER_CALLBACK) {
+        self.WireGuardSetLogger(logger)
+    }
+
+    unsafe fn set_adapter_logging(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: WIREGUARD_ADAPTER_LOG_STATE,
+    ) -> BOOL {
+        self.WireGuardSetAdapterLogging(adapter, state)
+    }
+
+    unsafe fn get_adapter_state(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: *mut WIREGUARD_ADAPTER_STATE,
+    ) -> BOOL {
+        self.WireGuardGetAdapterState(adapter, state)
+    }
+
+    unsafe fn set_adapter_state(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: WIREGUARD_ADAPTER_STATE,
+    ) -> BOOL {
+        self.WireGuardSetAdapterState(adapter, state)
+    }
+
+    unsafe fn get_configuration(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        config: *mut WIREGUARD_INTERFACE,
+        bytes: *mut DWORD,
+    ) -> BOOL {
+        self.WireGuardGetConfiguration(adapter, config, bytes)
+    }
+
+    unsafe fn set_configuration(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        config: *const WIREGUARD_INTERFACE,
+        bytes: DWORD,
+    ) -> BOOL {
+        self.WireGuardSetConfiguration(adapter, config, bytes)
+    }
+}
@@ -0,0 +1,184 @@
+use crate::adapter::{has_handshake, HANDSHAKE_POLL_INTERVAL};
+use crate::{dll, Adapter, Error, Guid, Interface, Key, WireGuardApi};
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An [`Adapter`] whose slow calls run on tokio's blocking thread pool, for use from async code.
+///
+/// Calls into wireguard.dll block the calling thread, creating an adapter for several seconds
+/// while the driver installs, so calling them from an async task stalls every other task on the
+/// same worker thread. Each async method here moves the call of the [`Adapter`] method of the same
+/// name to [`tokio::task::spawn_blocking`], and otherwise behaves like it. Everything else is
+/// available through [`AsyncAdapter::adapter`].
+///
+/// The adapter is shared with the blocking tasks, so if a future is dropped before its call
+/// finishes, the call still completes in the background, and the adapter is only closed once it
+/// has. Must be used from within a tokio runtime. Requires the `tokio` feature
+pub struct AsyncAdapter<A: WireGuardApi = dll> {
+    adapter: Arc<Adapter<A>>,
+}
+
+impl<A: WireGuardApi> AsyncAdapter<A> {
+    /// Creates a new adapter, see [`Adapter::create`]
+    pub async fn create(
+        wireguard: &Arc<A>,
+        pool: &str,
+        name: &str,
+        guid: Option<Guid>,
+    ) -> Result<Self, Error> {
+        let (wireguard, pool, name) = (Arc::clone(wireguard), pool.to_owned(), name.to_owned());
+        let adapter = blocking(move || Adapter::create(&wireguard, &pool, &name, guid)).await?;
+        Ok(AsyncAdapter::from(adapter))
+    }
+
+    /// Opens an existing adapter, see [`Adapter::open`]
+    pub async fn open(wireguard: &Arc<A>, name: &str) -> Result<Self, Error> {
+        let (wireguard, name) = (Arc::clone(wireguard), name.to_owned());
+        let adapter = blocking(move || Adapter::open(&wireguard, &name)).await?;
+        Ok(AsyncAdapter::from(adapter))
+    }
+
+    /// Returns the wrapped adapter, for calling its other methods synchronously
+    pub fn adapter(&self) -> &Adapter<A> {
+        &self.adapter
+    }
+
+    /// Sets the wireguard configuration of this adapter, see [`Adapter::set_config`]
+    pub async fn set_config(&self, config: &Interface) -> Result<(), Error> {
+        let (adapter, config) = (Arc::clone(&self.adapter), config.clone());
+        blocking(move || adapter.set_config(&config)).await
+    }
+
+    /// Returns the current wireguard configuration of this adapter, see [`Adapter::get_config`]
+    pub async fn get_config(&self) -> Result<Interface, Error> {
+        let adapter = Arc::clone(&self.adapter);
+        blocking(move || adapter.get_config()).await
+    }
+
+    /// Waits until the peer with public key `peer` has completed a handshake, checking every
+    /// 100ms. Shorthand for [`AsyncAdapter::wait_for_handshake_with`]
+    pub async fn wait_for_handshake(&self, peer: &Key, timeout: Duration) -> Result<bool, Error> {
+        self.wait_for_handshake_with(peer, timeout, HANDSHAKE_POLL_INTERVAL)
+            .await
+    }
+
+    /// Waits until the peer with public key `peer` has completed a handshake, reading the config
+    /// every `poll_interval`, see [`Adapter::wait_for_handshake_with`].
+    ///
+    /// Each read is a round trip to the blocking thread pool that copies the whole config, so
+    /// short intervals cost more on adapters with many peers. The time in between is spent in
+    /// [`tokio::time::sleep`], so no thread is blocked while waiting. Intervals shorter than 1ms
+    /// are raised to 1ms, and a `timeout` too long to be added to the current time waits without a
+    /// deadline
+    pub async fn wait_for_handshake_with(
+        &self,
+        peer: &Key,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<bool, Error> {
+        let poll_interval = poll_interval.max(Duration::from_millis(1));
+        let deadline = tokio::time::Instant::now().checked_add(timeout);
+        loop {
+            if has_handshake(&self.get_config().await?, peer) {
+                return Ok(true);
+            }
+            let sleep = match deadline {
+                Some(deadline) => {
+                    let now = tokio::time::Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    poll_interval.min(deadline - now)
+                }
+                None => poll_interval,
+            };
+            tokio::time::sleep(sleep).await;
+        }
+    }
+}
+
+impl<A: WireGuardApi> From<Adapter<A>> for AsyncAdapter<A> {
+    fn from(adapter: Adapter<A>) -> Self {
+        AsyncAdapter {
+            adapter: Arc::new(adapter),
+        }
+    }
+}
+
+/// Runs `f` with [`tokio::task::spawn_blocking`]. A panic in `f` is resumed in the caller
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        //Only happens when the runtime shuts down before the task started
+        Err(e) => Err(Error::Io(io::Error::new(io::ErrorKind::Interrupted, e))),
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::{MockWireGuard, Peer, PeerStats};
+
+    #[tokio::test]
+    async fn config_round_trip() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let adapter = AsyncAdapter::create(&wireguard, "WireGuard", "Async", None)
+            .await
+            .unwrap();
+        let peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .build()
+            .unwrap();
+        let config = Interface::builder()
+            .private_key(Key::new([9; 32]))
+            .peer(peer)
+            .build()
+            .unwrap();
+        adapter.set_config(&config).await.unwrap();
+        assert_eq!(adapter.get_config().await.unwrap().peers, config.peers);
+        assert_eq!(adapter.adapter().name(), "Async");
+        assert!(AsyncAdapter::open(&wireguard, "Async").await.is_ok());
+
+        let waited = adapter
+            .wait_for_handshake(&Key::new([1; 32]), Duration::from_millis(10))
+            .await;
+        assert!(!waited.unwrap());
+    }
+
+    #[tokio::test]
+    async fn handshake_poll_interval() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let adapter = AsyncAdapter::create(&wireguard, "WireGuard", "AsyncHandshake", None)
+            .await
+            .unwrap();
+        let peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .build()
+            .unwrap();
+        let config = Interface::builder()
+            .private_key(Key::new([9; 32]))
+            .peer(peer)
+            .build()
+            .unwrap();
+        adapter.set_config(&config).await.unwrap();
+        assert!(wireguard.set_peer_stats(
+            "AsyncHandshake",
+            &PeerStats {
+                public_key: Key::new([1; 32]),
+                rx_bytes: 0,
+                tx_bytes: 0,
+                last_handshake: Some(std::time::SystemTime::now()),
+                endpoint: None,
+            }
+        ));
+        let waited = adapter
+            .wait_for_handshake_with(&Key::new([1; 32]), Duration::MAX, Duration::from_millis(1))
+            .await;
+        assert!(waited.unwrap());
+    }
+}
@@ -0,0 +1,1555 @@
+use crate::{Error, Key, PublicKey};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// An ip range that a peer is allowed to send traffic from, and that traffic to is routed to the peer.
+///
+/// The prefix length is validated on construction, so an `AllowedIp` always converts to an
+/// [`ipnet::IpNet`] when the `ipnet` feature is enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AllowedIp {
+    address: IpAddr,
+    cidr: u8,
+}
+
+impl AllowedIp {
+    /// All IPv4 addresses, `0.0.0.0/0`
+    pub const ALL_V4: AllowedIp = AllowedIp {
+        address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        cidr: 0,
+    };
+
+    /// All IPv6 addresses, `::/0`
+    pub const ALL_V6: AllowedIp = AllowedIp {
+        address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        cidr: 0,
+    };
+
+    /// Creates an allowed ip from a network address and prefix length.
+    ///
+    /// Returns [`Error::InvalidConfig`] if `cidr` is longer than 32 bits for an IPv4 address or
+    /// 128 bits for an IPv6 address
+    pub fn new(address: IpAddr, cidr: u8) -> Result<AllowedIp, Error> {
+        let max = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if cidr > max {
+            return Err(Error::InvalidConfig(format!(
+                "Prefix length {} of {} is longer than {}",
+                cidr, address, max
+            )));
+        }
+        Ok(AllowedIp { address, cidr })
+    }
+
+    /// The network address
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// The prefix length of the network
+    pub fn cidr(&self) -> u8 {
+        self.cidr
+    }
+
+    /// Returns `true` if `address` is in this range. Addresses of the other family never are
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let (network, width) = self.to_bits();
+        let (address, address_width) = address_bits(address);
+        width == address_width && (network ^ address) & !host_mask(width, self.cidr) == 0
+    }
+
+    /// Returns the address as an integer, together with the number of bits in an address of its
+    /// family
+    fn to_bits(self) -> (u128, u8) {
+        address_bits(self.address)
+    }
+
+    /// Builds an allowed ip from an address returned by [`AllowedIp::to_bits`]
+    fn from_bits(address: u128, width: u8, cidr: u8) -> AllowedIp {
+        let address = match width {
+            32 => IpAddr::V4(Ipv4Addr::from(address as u32)),
+            _ => IpAddr::V6(Ipv6Addr::from(address)),
+        };
+        AllowedIp { address, cidr }
+    }
+
+    /// Returns this range with the host bits of the address cleared, as the driver stores it
+    pub(crate) fn network(self) -> AllowedIp {
+        let (address, width) = self.to_bits();
+        AllowedIp::from_bits(address & !host_mask(width, self.cidr), width, self.cidr)
+    }
+}
+
+/// Returns `address` as an integer, together with the number of bits in an address of its family
+fn address_bits(address: IpAddr) -> (u128, u8) {
+    match address {
+        IpAddr::V4(address) => (u32::from(address).into(), 32),
+        IpAddr::V6(address) => (address.into(), 128),
+    }
+}
+
+/// Returns the mask of the host bits of a network with prefix length `cidr` in an address family
+/// with `width` bit addresses
+fn host_mask(width: u8, cidr: u8) -> u128 {
+    match width - cidr {
+        128 => u128::MAX,
+        bits => (1 << bits) - 1,
+    }
+}
+
+/// Merges the networks of `ips`, which must all have `width` bit addresses, into the smallest set
+/// of networks covering the same addresses. The result is sorted by address
+fn coalesce_family(ips: impl Iterator<Item = AllowedIp>, width: u8) -> Vec<AllowedIp> {
+    let mut networks: Vec<(u128, u8)> = ips.map(|ip| (ip.network().to_bits().0, ip.cidr)).collect();
+    loop {
+        networks.sort_unstable();
+        let mut merged: Vec<(u128, u8)> = Vec::with_capacity(networks.len());
+        let mut changed = false;
+        for (address, cidr) in networks {
+            if let Some(&(last_address, last_cidr)) = merged.last() {
+                let last_end = last_address | host_mask(width, last_cidr);
+                //Sorting puts a network right after any network containing it
+                if address <= last_end {
+                    changed = true;
+                    continue;
+                }
+                //Two halves of the same parent network become the parent
+                if cidr == last_cidr
+                    && cidr > 0
+                    && last_address & host_mask(width, cidr - 1) == 0
+                    && address == last_end + 1
+                {
+                    merged.pop();
+                    merged.push((last_address, cidr - 1));
+                    changed = true;
+                    continue;
+                }
+            }
+            merged.push((address, cidr));
+        }
+        networks = merged;
+        if !changed {
+            break;
+        }
+    }
+    networks
+        .into_iter()
+        .map(|(address, cidr)| AllowedIp::from_bits(address, width, cidr))
+        .collect()
+}
+
+#[cfg(feature = "ipnet")]
+impl From<ipnet::IpNet> for AllowedIp {
+    fn from(net: ipnet::IpNet) -> Self {
+        AllowedIp {
+            address: net.addr(),
+            cidr: net.prefix_len(),
+        }
+    }
+}
+
+#[cfg(feature = "ipnet")]
+impl From<AllowedIp> for ipnet::IpNet {
+    fn from(allowed_ip: AllowedIp) -> Self {
+        //The prefix length was checked when `allowed_ip` was created
+        ipnet::IpNet::new(allowed_ip.address, allowed_ip.cidr).unwrap()
+    }
+}
+
+impl FromStr for AllowedIp {
+    type Err = Error;
+
+    /// Parses an address in CIDR notation, such as `10.0.0.0/24`. An address without a prefix
+    /// length covers only that address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfig(format!("Invalid allowed ip {:?}", s));
+        match s.split_once('/') {
+            Some((address, cidr)) => {
+                let address = address.parse().map_err(|_| invalid())?;
+                let cidr = cidr.parse().map_err(|_| invalid())?;
+                AllowedIp::new(address, cidr)
+            }
+            None => {
+                let address: IpAddr = s.parse().map_err(|_| invalid())?;
+                let cidr = if address.is_ipv4() { 32 } else { 128 };
+                AllowedIp::new(address, cidr)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for AllowedIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.cidr)
+    }
+}
+
+/// Allowed ips are serialized in CIDR notation, such as `"10.0.0.0/24"`
+#[cfg(feature = "serde")]
+impl serde::Serialize for AllowedIp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AllowedIp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A WireGuard peer of an [`Interface`].
+///
+/// The `Debug` output shows the public key in base64 and hides the preshared key, so peers can be
+/// logged without leaking secrets. With the `serde` feature, keys are serialized as base64
+/// strings, the endpoint as `"ip:port"` and allowed ips in CIDR notation. Fields other than the
+/// public key may be omitted when deserializing
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Peer {
+    /// The peer's public key, its primary identifier
+    pub public_key: Key,
+
+    /// A preshared key used to symmetrically encrypt data with this peer, for an additional layer
+    /// of post-quantum resistance
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub preshared_key: Option<Key>,
+
+    /// How often to send a keep alive packet to prevent NATs from blocking UDP packets.
+    ///
+    /// `None` or zero disables keep alive packets. The driver counts in whole seconds, so the
+    /// interval is truncated to seconds and must be no longer than `u16::MAX` seconds. With the
+    /// `serde` feature, it is serialized as a number of seconds
+    #[cfg_attr(feature = "serde", serde(default, with = "keepalive_seconds"))]
+    pub persistent_keepalive: Option<Duration>,
+
+    /// The address this peer is reachable from using UDP across the internet.
+    ///
+    /// `None` if the peer's address is not known yet, in which case the driver learns it from the
+    /// first authenticated packet the peer sends. The scope id of IPv6 link-local addresses, as in
+    /// `[fe80::1%12]:51820`, is kept
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub endpoint: Option<SocketAddr>,
+
+    /// The ip ranges that dictate what packets are allowed to be sent to or received from this peer
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub allowed_ips: Vec<AllowedIp>,
+
+    /// Number of bytes transmitted. Only filled in by [`crate::Adapter::get_config`]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tx_bytes: u64,
+
+    /// Number of bytes received. Only filled in by [`crate::Adapter::get_config`]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rx_bytes: u64,
+
+    /// Time of the last handshake, or `None` if no handshake has completed yet.
+    /// Only filled in by [`crate::Adapter::get_config`]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_handshake: Option<SystemTime>,
+}
+
+impl Peer {
+    /// Returns a builder for constructing a peer
+    pub fn builder() -> PeerBuilder {
+        PeerBuilder::default()
+    }
+
+    /// Adds `allowed_ip` to [`Peer::allowed_ips`] unless the same network is already in the list.
+    ///
+    /// Only exact duplicates are skipped, so this stays cheap for long lists. Call
+    /// [`Peer::coalesce_allowed_ips`] afterwards to also merge overlapping and adjacent ranges
+    pub fn add_allowed_ip(&mut self, allowed_ip: impl Into<AllowedIp>) {
+        let allowed_ip = allowed_ip.into();
+        let network = allowed_ip.network();
+        if !self.allowed_ips.iter().any(|ip| ip.network() == network) {
+            self.allowed_ips.push(allowed_ip);
+        }
+    }
+
+    /// Replaces [`Peer::allowed_ips`] with the smallest list of ranges that covers the same
+    /// addresses. Ranges inside other ranges are removed and adjacent ranges are merged, for
+    /// example `10.0.0.0/25` and `10.0.0.128/25` become `10.0.0.0/24`.
+    ///
+    /// The host bits of every range are cleared, and the result is sorted with IPv4 ranges first
+    pub fn coalesce_allowed_ips(&mut self) {
+        let v4 = self
+            .allowed_ips
+            .iter()
+            .copied()
+            .filter(|ip| ip.address.is_ipv4());
+        let v6 = self
+            .allowed_ips
+            .iter()
+            .copied()
+            .filter(|ip| ip.address.is_ipv6());
+        let mut coalesced = coalesce_family(v4, 32);
+        coalesced.extend(coalesce_family(v6, 128));
+        self.allowed_ips = coalesced;
+    }
+
+    /// Returns the networks that traffic is routed to this peer for: its allowed ips with the host
+    /// bits of their addresses cleared, as the driver stores them
+    pub fn routes(&self) -> impl Iterator<Item = AllowedIp> + '_ {
+        self.allowed_ips
+            .iter()
+            .map(|allowed_ip| allowed_ip.network())
+    }
+
+    /// Returns `true` if one of the allowed ips of this peer contains `address`
+    pub fn allowed_ips_contains(&self, address: IpAddr) -> bool {
+        self.allowed_ips
+            .iter()
+            .any(|allowed_ip| allowed_ip.contains(address))
+    }
+
+    /// Returns `true` if `self` and `other` have the same configuration. Unlike `==`, this ignores
+    /// the traffic counters and the last handshake, which change on their own while the peer is
+    /// connected, and the order of the allowed ips
+    pub fn config_eq(&self, other: &Peer) -> bool {
+        self.public_key == other.public_key
+            && self.preshared_key == other.preshared_key
+            && self.persistent_keepalive == other.persistent_keepalive
+            && self.endpoint == other.endpoint
+            && self.allowed_ips.iter().collect::<HashSet<_>>()
+                == other.allowed_ips.iter().collect::<HashSet<_>>()
+    }
+
+    /// Resolves `host`, a host name or address with a port such as `"vpn.example.com:51820"`, and
+    /// sets [`Peer::endpoint`] to the result. When the host has addresses of both families,
+    /// `preference` picks which one is used.
+    ///
+    /// WireGuard NT only accepts resolved addresses, so the host is resolved once per call and
+    /// nothing is cached. If the address of the host can change, for example with dynamic DNS,
+    /// call this again when the peer goes stale, see [`PeerStats::is_stale`], and send the new
+    /// endpoint with [`crate::Adapter::set_peer_endpoint`]
+    pub fn set_endpoint_host(
+        &mut self,
+        host: &str,
+        preference: AddressFamilyPreference,
+    ) -> Result<(), Error> {
+        self.endpoint = Some(preference.resolve(host).map_err(Error::InvalidConfig)?);
+        Ok(())
+    }
+}
+
+/// Which address is used when a host name resolves to both IPv4 and IPv6 addresses, for example
+/// by [`Peer::set_endpoint_host`] and [`crate::WgQuickConfig::parse_with`].
+///
+/// The default is [`AddressFamilyPreference::V4First`]. The `Only` variants suit networks where
+/// one of the families is broken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamilyPreference {
+    /// Only IPv4 addresses are used. Resolving fails if the host has none
+    V4Only,
+
+    /// Only IPv6 addresses are used. Resolving fails if the host has none
+    V6Only,
+
+    /// The first IPv4 address, or the first IPv6 address if there is none
+    #[default]
+    V4First,
+
+    /// The first IPv6 address, or the first IPv4 address if there is none
+    V6First,
+}
+
+impl AddressFamilyPreference {
+    /// Resolves `host` with the system resolver and picks an address according to this preference
+    pub(crate) fn resolve(self, host: &str) -> Result<SocketAddr, String> {
+        let addrs = host
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve {}: {}", host, e))?;
+        self.pick(addrs).ok_or_else(|| match self {
+            AddressFamilyPreference::V4Only => format!("{} has no IPv4 address", host),
+            AddressFamilyPreference::V6Only => format!("{} has no IPv6 address", host),
+            _ => format!("{} did not resolve to any address", host),
+        })
+    }
+
+    /// Picks an address from `addrs` according to this preference
+    fn pick(self, addrs: impl Iterator<Item = SocketAddr>) -> Option<SocketAddr> {
+        let prefer_v4 = match self {
+            AddressFamilyPreference::V4Only | AddressFamilyPreference::V4First => true,
+            AddressFamilyPreference::V6Only | AddressFamilyPreference::V6First => false,
+        };
+        let fallback = matches!(
+            self,
+            AddressFamilyPreference::V4First | AddressFamilyPreference::V6First
+        );
+        let mut other = None;
+        for addr in addrs {
+            if addr.is_ipv4() == prefer_v4 {
+                return Some(addr);
+            }
+            other = other.or(Some(addr));
+        }
+        other.filter(|_| fallback)
+    }
+}
+
+/// A snapshot of the traffic counters and connection state of a peer, returned by
+/// [`crate::Adapter::stats`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerStats {
+    /// The peer's public key
+    pub public_key: Key,
+
+    /// Number of bytes received from the peer
+    pub rx_bytes: u64,
+
+    /// Number of bytes transmitted to the peer
+    pub tx_bytes: u64,
+
+    /// Time of the last handshake, or `None` if no handshake has completed yet
+    pub last_handshake: Option<SystemTime>,
+
+    /// The current endpoint of the peer, or `None` if it is not known yet
+    pub endpoint: Option<SocketAddr>,
+}
+
+impl PeerStats {
+    /// Returns `true` if the last handshake is older than `max_age`, or if no handshake has
+    /// completed yet.
+    ///
+    /// WireGuard renews sessions every two minutes while traffic flows, so a handshake older than
+    /// about three minutes usually means that the peer is unreachable
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.is_stale_at(SystemTime::now(), max_age)
+    }
+
+    /// Like [`PeerStats::is_stale`], with `now` as the current time. A handshake after `now`, as
+    /// seen when the system clock is set back, is not stale
+    fn is_stale_at(&self, now: SystemTime, max_age: Duration) -> bool {
+        match self.last_handshake {
+            None => true,
+            Some(handshake) => matches!(now.duration_since(handshake), Ok(age) if age > max_age),
+        }
+    }
+}
+
+/// The configuration of a WireGuard interface.
+///
+/// The `Debug` output shows the public key in base64 and hides the private key, as well as the
+/// preshared keys of the peers. With the `serde` feature, this serializes the same way as
+/// [`Peer`] and every field may be omitted when deserializing
+#[derive(Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Interface {
+    /// The port this interface listens on.
+    /// When setting the config, the driver picks a port if this is `None` or `Some(0)`. The config
+    /// returned by [`crate::Adapter::get_config`] holds the port that was actually picked
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub listen_port: Option<u16>,
+
+    /// The private key of this interface
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub private_key: Option<Key>,
+
+    /// The public key of this interface.
+    /// When setting the config, the public key is generated from the private key if this is `None`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub public_key: Option<Key>,
+
+    /// The mark applied to outgoing packets, known as fwmark on Linux, for policy based routing.
+    ///
+    /// WireGuard NT has no packet marks, so [`crate::Adapter::set_config`] returns
+    /// [`Error::Unsupported`] if this is set to anything other than `None` or `Some(0)`, which
+    /// both mean no mark. It is always `None` in configs returned by the driver
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mark: Option<u32>,
+
+    /// The peers that this interface is allowed to communicate with
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub peers: Vec<Peer>,
+}
+
+impl Interface {
+    /// Returns a builder for constructing an interface config
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder::default()
+    }
+
+    /// Returns `true` if `self` and `other` have the same configuration, comparing peers with
+    /// [`Peer::config_eq`] and ignoring their order.
+    ///
+    /// Reconcile loops can keep a clone of the last config they applied and skip applying a new one
+    /// that is `config_eq` to it. Fields are compared as they are, so a config returned by
+    /// [`crate::Adapter::get_config`], which holds the listen port and public key filled in by the
+    /// driver, is not `config_eq` to a config without them. See [`crate::Adapter::apply_config`]
+    /// for comparing a desired config with the running one
+    pub fn config_eq(&self, other: &Interface) -> bool {
+        if self.listen_port != other.listen_port
+            || self.private_key != other.private_key
+            || self.public_key != other.public_key
+            || self.mark != other.mark
+            || self.peers.len() != other.peers.len()
+        {
+            return false;
+        }
+        let (ours, theirs) = (peers_by_key(self), peers_by_key(other));
+        //Peers with the same public key are one peer to the driver, so duplicates never match
+        ours.len() == self.peers.len()
+            && theirs.len() == other.peers.len()
+            && ours
+                .iter()
+                .all(|(key, p)| matches!(theirs.get(key), Some(o) if p.config_eq(o)))
+    }
+
+    /// Checks this config for mistakes that the driver accepts silently, but that leave the tunnel
+    /// not working as intended, and returns every one that was found, in the order of the peers.
+    ///
+    /// Only the same network on two peers is reported as an overlap, since the driver keeps it for
+    /// one of the peers only. A network inside a larger one of another peer, such as `10.0.0.0/24`
+    /// next to `0.0.0.0/0`, is routed by longest prefix match and is a common setup
+    pub fn validate(&self) -> Result<(), Vec<ConfigWarning>> {
+        let mut warnings = Vec::new();
+        let mut keys = HashSet::new();
+        let mut networks: HashMap<AllowedIp, &Key> = HashMap::new();
+        for peer in &self.peers {
+            if !keys.insert(PublicKey::from(peer.public_key.clone())) {
+                warnings.push(ConfigWarning::DuplicatePeer {
+                    public_key: peer.public_key.clone(),
+                });
+                continue;
+            }
+            if peer.allowed_ips.is_empty() {
+                warnings.push(ConfigWarning::NoAllowedIps {
+                    public_key: peer.public_key.clone(),
+                });
+            }
+            for allowed_ip in peer.routes() {
+                match networks.get(&allowed_ip) {
+                    Some(&first) if *first != peer.public_key => {
+                        warnings.push(ConfigWarning::OverlappingAllowedIp {
+                            allowed_ip,
+                            first: first.clone(),
+                            second: peer.public_key.clone(),
+                        })
+                    }
+                    Some(_) => {}
+                    None => {
+                        networks.insert(allowed_ip, &peer.public_key);
+                    }
+                }
+            }
+            //The driver truncates the interval to seconds, so less than a second disables it
+            let keepalive =
+                matches!(peer.persistent_keepalive, Some(interval) if interval.as_secs() != 0);
+            if keepalive && peer.endpoint.is_none() {
+                warnings.push(ConfigWarning::KeepaliveWithoutEndpoint {
+                    public_key: peer.public_key.clone(),
+                });
+            }
+        }
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+
+    /// Returns the peer that packets to `address` are sent to: the peer with the longest allowed ip
+    /// that contains `address`, which is how WireGuard routes packets between its peers. When
+    /// several peers list the same network, the last of them has it, as the driver keeps it for
+    /// the peer that was configured last. Returns `None` if no allowed ip contains `address`.
+    ///
+    /// Every allowed ip is checked, so for many lookups build a [`RoutingTable`] once with
+    /// [`Interface::routing_table`]
+    pub fn peer_for_ip(&self, address: IpAddr) -> Option<&Peer> {
+        let mut best: Option<(u8, &Peer)> = None;
+        for peer in &self.peers {
+            for allowed_ip in peer.allowed_ips.iter().filter(|ip| ip.contains(address)) {
+                if !matches!(best, Some((cidr, _)) if cidr > allowed_ip.cidr) {
+                    best = Some((allowed_ip.cidr, peer));
+                }
+            }
+        }
+        best.map(|(_, peer)| peer)
+    }
+
+    /// Indexes the allowed ips of the peers for looking up the peer of many addresses, see
+    /// [`RoutingTable::peer_for_ip`]
+    pub fn routing_table(&self) -> RoutingTable<'_> {
+        let mut by_prefix: HashMap<(u8, u8), HashMap<u128, &Peer>> = HashMap::new();
+        for peer in &self.peers {
+            for allowed_ip in &peer.allowed_ips {
+                let (network, width) = allowed_ip.network().to_bits();
+                //Later peers replace earlier ones with the same network, as in `peer_for_ip`
+                by_prefix
+                    .entry((width, allowed_ip.cidr))
+                    .or_default()
+                    .insert(network, peer);
+            }
+        }
+        let mut prefixes: Vec<_> = by_prefix
+            .into_iter()
+            .map(|((width, cidr), networks)| (width, cidr, networks))
+            .collect();
+        prefixes.sort_unstable_by_key(|&(_, cidr, _)| std::cmp::Reverse(cidr));
+        RoutingTable { prefixes }
+    }
+
+    /// Derives the public key from [`Interface::private_key`], or returns `None` if no private key
+    /// is set. Unlike the `public_key` field, which only holds a key when one was set or reported
+    /// by the driver, this always matches the private key.
+    ///
+    /// Requires the `x25519` feature
+    #[cfg(feature = "x25519")]
+    pub fn public_key(&self) -> Option<Key> {
+        self.private_key.as_ref().map(Key::public_key)
+    }
+}
+
+/// The allowed ips of the peers of an [`Interface`], indexed for finding the peer that packets to
+/// an address are sent to. Built by [`Interface::routing_table`].
+///
+/// A lookup checks one hash table for each prefix length in use, so it takes the same time
+/// however many peers and allowed ips there are
+#[derive(Debug, Clone)]
+pub struct RoutingTable<'a> {
+    /// The networks of each address width and prefix length, with the longest prefixes first
+    prefixes: Vec<(u8, u8, HashMap<u128, &'a Peer>)>,
+}
+
+impl<'a> RoutingTable<'a> {
+    /// Returns the peer with the longest allowed ip that contains `address`, like
+    /// [`Interface::peer_for_ip`]
+    pub fn peer_for_ip(&self, address: IpAddr) -> Option<&'a Peer> {
+        let (address, width) = address_bits(address);
+        self.prefixes
+            .iter()
+            .filter(|(prefix_width, _, _)| *prefix_width == width)
+            .find_map(|(_, cidr, networks)| {
+                networks.get(&(address & !host_mask(width, *cidr))).copied()
+            })
+    }
+}
+
+/// Maps the peers of `config` by their public key. Of peers with the same key, only the last is kept
+fn peers_by_key(config: &Interface) -> HashMap<PublicKey, &Peer> {
+    config
+        .peers
+        .iter()
+        .map(|p| (PublicKey::from(p.public_key.clone()), p))
+        .collect()
+}
+
+/// Converts a persistent keepalive interval to the whole seconds used by the driver
+pub(crate) fn keepalive_to_seconds(interval: Duration) -> Result<u16, Error> {
+    u16::try_from(interval.as_secs()).map_err(|_| {
+        Error::InvalidConfig(format!(
+            "Persistent keepalive {:?} is longer than {} seconds",
+            interval,
+            u16::MAX
+        ))
+    })
+}
+
+/// Serializes a keepalive interval as its number of seconds
+#[cfg(feature = "serde")]
+mod keepalive_seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(
+        interval: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match interval {
+            Some(interval) => serializer.serialize_some(&interval.as_secs()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+    }
+}
+
+/// Controls how [`crate::Adapter::set_config_with`] merges a config into the current config of
+/// an adapter. The default merges everything, keeping peers and allowed ips that are not in the
+/// new config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetConfigOptions {
+    /// Remove the peers of the adapter that are not in the new config.
+    /// Maps to `WIREGUARD_INTERFACE_REPLACE_PEERS`
+    pub replace_peers: bool,
+
+    /// Replace the allowed ips of each peer in the new config instead of adding to them.
+    /// Maps to `WIREGUARD_PEER_REPLACE_ALLOWED_IPS`
+    pub replace_allowed_ips: bool,
+}
+
+/// The changes made by [`crate::Adapter::apply_config`], or observed by [`crate::Adapter::watch`].
+/// Peers are identified by their public key
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConfigDelta {
+    /// The private key or listen port of the interface was changed
+    pub interface_changed: bool,
+
+    /// Peers that were in the desired config, but not on the adapter. For
+    /// [`crate::Adapter::watch`], peers that appeared since the previous poll
+    pub added_peers: Vec<Key>,
+
+    /// Peers that were on the adapter, but not in the desired config. For
+    /// [`crate::Adapter::watch`], peers that disappeared since the previous poll
+    pub removed_peers: Vec<Key>,
+
+    /// Peers whose preshared key, keepalive, endpoint or allowed ips were changed
+    pub updated_peers: Vec<Key>,
+
+    /// Peers that completed a handshake. Only filled in by [`crate::Adapter::watch`]
+    pub new_handshakes: Vec<Key>,
+}
+
+impl ConfigDelta {
+    /// Returns `true` if the adapter already had the desired config, so nothing was changed
+    pub fn is_empty(&self) -> bool {
+        !self.interface_changed
+            && self.added_peers.is_empty()
+            && self.removed_peers.is_empty()
+            && self.updated_peers.is_empty()
+            && self.new_handshakes.is_empty()
+    }
+}
+
+/// A likely mistake in an [`Interface`], found by [`Interface::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigWarning {
+    /// A peer has the same public key as an earlier peer. The driver merges them into one peer
+    DuplicatePeer {
+        /// The public key of the peers
+        public_key: Key,
+    },
+
+    /// Two peers have the same network in their allowed ips. The driver routes it to `second`
+    /// only, so `first` never receives traffic for it
+    OverlappingAllowedIp {
+        /// The network, with the host bits cleared
+        allowed_ip: AllowedIp,
+        /// The earlier peer with the network
+        first: Key,
+        /// The later peer with the network
+        second: Key,
+    },
+
+    /// A peer has no allowed ips, so no traffic is sent to it and all traffic from it is dropped
+    NoAllowedIps {
+        /// The public key of the peer
+        public_key: Key,
+    },
+
+    /// A peer has a persistent keepalive but no endpoint. Keepalive packets are only sent once
+    /// the peer has contacted this interface, so they cannot open a path through a NAT
+    KeepaliveWithoutEndpoint {
+        /// The public key of the peer
+        public_key: Key,
+    },
+}
+
+impl std::fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigWarning::DuplicatePeer { public_key } => {
+                write!(f, "peer {} is configured more than once", public_key)
+            }
+            ConfigWarning::OverlappingAllowedIp {
+                allowed_ip,
+                first,
+                second,
+            } => write!(
+                f,
+                "allowed ip {} of peer {} is also an allowed ip of peer {}",
+                allowed_ip, first, second
+            ),
+            ConfigWarning::NoAllowedIps { public_key } => {
+                write!(f, "peer {} has no allowed ips", public_key)
+            }
+            ConfigWarning::KeepaliveWithoutEndpoint { public_key } => write!(
+                f,
+                "peer {} has a persistent keepalive but no endpoint",
+                public_key
+            ),
+        }
+    }
+}
+
+/// The UDP port an [`Interface`] listens on, as passed to [`InterfaceBuilder::listen_port`].
+///
+/// Port 0 makes the driver pick a free port, which is easy to pass by accident where a fixed port
+/// was meant, for example from a setting that defaults to zero. So a `ListenPort` converted from
+/// `0` with `From<u16>` is rejected by [`InterfaceBuilder::build`], and asking for a picked port
+/// takes [`ListenPort::ephemeral`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ListenPort {
+    port: u16,
+    /// Whether this came from `ListenPort::ephemeral`, the only way to ask for port 0 on purpose
+    ephemeral: bool,
+}
+
+impl ListenPort {
+    /// Returns the fixed port `port`, or [`Error::InvalidConfig`] if it is 0
+    pub fn new(port: u16) -> Result<Self, Error> {
+        ListenPort::from(port).check()
+    }
+
+    /// Returns a listen port for the driver to pick. The config returned by
+    /// [`crate::Adapter::get_config`] holds the port it picked
+    pub const fn ephemeral() -> Self {
+        ListenPort {
+            port: 0,
+            ephemeral: true,
+        }
+    }
+
+    /// Returns the port number, which is 0 for [`ListenPort::ephemeral`]
+    pub fn port(self) -> u16 {
+        self.port
+    }
+
+    /// Returns true if this is [`ListenPort::ephemeral`]
+    pub fn is_ephemeral(self) -> bool {
+        self.ephemeral
+    }
+
+    /// Rejects port 0 unless it came from `ListenPort::ephemeral`
+    fn check(self) -> Result<Self, Error> {
+        if self.port == 0 && !self.ephemeral {
+            return Err(Error::InvalidConfig(
+                "Listen port 0 makes the driver pick a port, use ListenPort::ephemeral() for that"
+                    .to_owned(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Converts a port number without checking it. A port of 0 is rejected later by
+/// [`InterfaceBuilder::build`]
+impl From<u16> for ListenPort {
+    fn from(port: u16) -> Self {
+        ListenPort {
+            port,
+            ephemeral: false,
+        }
+    }
+}
+
+impl std::fmt::Display for ListenPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.port)
+    }
+}
+
+/// Builds an [`Interface`], validating that required fields are present in [`InterfaceBuilder::build`]
+#[derive(Clone, Default)]
+pub struct InterfaceBuilder {
+    private_key: Option<Key>,
+    listen_port: Option<ListenPort>,
+    peers: Vec<Peer>,
+}
+
+impl InterfaceBuilder {
+    /// Creates an empty builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the private key of the interface. Required
+    pub fn private_key(mut self, key: Key) -> Self {
+        self.private_key = Some(key);
+        self
+    }
+
+    /// Sets the UDP port the interface listens on, which takes a port number or a [`ListenPort`].
+    /// If unset the driver picks a port. Port 0 is rejected by [`InterfaceBuilder::build`], pass
+    /// [`ListenPort::ephemeral`] to have the driver pick one on purpose
+    pub fn listen_port(mut self, port: impl Into<ListenPort>) -> Self {
+        self.listen_port = Some(port.into());
+        self
+    }
+
+    /// Adds a peer to the interface
+    pub fn peer(mut self, peer: Peer) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    /// Adds every peer in `peers` to the interface
+    pub fn peers(mut self, peers: impl IntoIterator<Item = Peer>) -> Self {
+        self.peers.extend(peers);
+        self
+    }
+
+    /// Validates the config and builds the [`Interface`]. Fails with [`Error::InvalidConfig`] if the
+    /// private key is missing, or if the listen port is 0 but not [`ListenPort::ephemeral`]
+    pub fn build(self) -> Result<Interface, Error> {
+        let private_key = self
+            .private_key
+            .ok_or_else(|| Error::InvalidConfig("Interface is missing a private key".to_owned()))?;
+        let listen_port = match self.listen_port {
+            Some(port) => Some(port.check()?.port()),
+            None => None,
+        };
+        Ok(Interface {
+            listen_port,
+            private_key: Some(private_key),
+            peers: self.peers,
+            ..Default::default()
+        })
+    }
+}
+
+/// Builds a [`Peer`], validating that required fields are present in [`PeerBuilder::build`]
+#[derive(Clone, Default)]
+pub struct PeerBuilder {
+    public_key: Option<Key>,
+    preshared_key: Option<Key>,
+    persistent_keepalive: Option<Duration>,
+    endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<AllowedIp>,
+}
+
+impl PeerBuilder {
+    /// Creates an empty builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the public key of the peer. Required
+    pub fn public_key(mut self, key: Key) -> Self {
+        self.public_key = Some(key);
+        self
+    }
+
+    /// Sets the preshared key used with this peer
+    pub fn preshared_key(mut self, key: Key) -> Self {
+        self.preshared_key = Some(key);
+        self
+    }
+
+    /// Sets how often a keep alive packet is sent to the peer. The interval is truncated to whole
+    /// seconds and [`PeerBuilder::build`] fails if it is longer than `u16::MAX` seconds
+    pub fn persistent_keepalive(mut self, interval: Duration) -> Self {
+        self.persistent_keepalive = Some(interval);
+        self
+    }
+
+    /// Sets the address the peer is reachable at
+    pub fn endpoint(mut self, endpoint: SocketAddr) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Adds an ip range that traffic is allowed to be sent to and received from the peer
+    pub fn allowed_ip(mut self, allowed_ip: impl Into<AllowedIp>) -> Self {
+        self.allowed_ips.push(allowed_ip.into());
+        self
+    }
+
+    /// Validates the config and builds the [`Peer`]
+    pub fn build(self) -> Result<Peer, Error> {
+        let public_key = self
+            .public_key
+            .ok_or_else(|| Error::InvalidConfig("Peer is missing a public key".to_owned()))?;
+        if let Some(interval) = self.persistent_keepalive {
+            keepalive_to_seconds(interval)?;
+        }
+        Ok(Peer {
+            public_key,
+            preshared_key: self.preshared_key,
+            persistent_keepalive: self.persistent_keepalive,
+            endpoint: self.endpoint,
+            allowed_ips: self.allowed_ips,
+            tx_bytes: 0,
+            rx_bytes: 0,
+            last_handshake: None,
+        })
+    }
+}
+
+/// Shows a key that is not secret as its base64 encoding in `Debug` output
+struct DebugPublic<'a>(&'a Key);
+
+impl std::fmt::Debug for DebugPublic<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.0.to_base64(), f)
+    }
+}
+
+/// Stands in for a secret key in `Debug` output
+struct Redacted;
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+fn debug_public(key: &Option<Key>) -> Option<DebugPublic<'_>> {
+    key.as_ref().map(DebugPublic)
+}
+
+fn redact(key: &Option<Key>) -> Option<Redacted> {
+    key.as_ref().map(|_| Redacted)
+}
+
+impl std::fmt::Debug for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Peer")
+            .field("public_key", &DebugPublic(&self.public_key))
+            .field("preshared_key", &redact(&self.preshared_key))
+            .field("persistent_keepalive", &self.persistent_keepalive)
+            .field("endpoint", &self.endpoint)
+            .field("allowed_ips", &self.allowed_ips)
+            .field("tx_bytes", &self.tx_bytes)
+            .field("rx_bytes", &self.rx_bytes)
+            .field("last_handshake", &self.last_handshake)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Interface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Interface")
+            .field("listen_port", &self.listen_port)
+            .field("private_key", &redact(&self.private_key))
+            .field("public_key", &debug_public(&self.public_key))
+            .field("mark", &self.mark)
+            .field("peers", &self.peers)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for InterfaceBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InterfaceBuilder")
+            .field("private_key", &redact(&self.private_key))
+            .field("listen_port", &self.listen_port)
+            .field("peers", &self.peers)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for PeerBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerBuilder")
+            .field("public_key", &debug_public(&self.public_key))
+            .field("preshared_key", &redact(&self.preshared_key))
+            .field("persistent_keepalive", &self.persistent_keepalive)
+            .field("endpoint", &self.endpoint)
+            .field("allowed_ips", &self.allowed_ips)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint() -> SocketAddr {
+        "203.0.113.1:51820".parse().unwrap()
+    }
+
+    #[test]
+    fn interface_builder_requires_private_key() {
+        assert!(matches!(
+            Interface::builder().listen_port(51820).build(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn listen_ports() {
+        let builder = || Interface::builder().private_key(Key::new([1; 32]));
+        assert_eq!(
+            builder().listen_port(51820).build().unwrap().listen_port,
+            Some(51820)
+        );
+        let ephemeral = builder()
+            .listen_port(ListenPort::ephemeral())
+            .build()
+            .unwrap();
+        assert_eq!(ephemeral.listen_port, Some(0));
+        assert_eq!(builder().build().unwrap().listen_port, None);
+
+        //Port 0 without `ephemeral` is taken as a mistake
+        assert!(matches!(
+            builder().listen_port(0).build(),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(ListenPort::new(0).is_err());
+        let fixed = ListenPort::new(51820).unwrap();
+        assert_eq!(fixed, ListenPort::from(51820));
+        assert!(!fixed.is_ephemeral());
+        assert!(ListenPort::ephemeral().is_ephemeral());
+        assert_eq!(ListenPort::ephemeral().port(), 0);
+        assert_eq!(fixed.to_string(), "51820");
+    }
+
+    fn ips(ips: &[&str]) -> Vec<AllowedIp> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn add_allowed_ip() {
+        let mut peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .build()
+            .unwrap();
+        peer.add_allowed_ip("10.0.0.0/24".parse::<AllowedIp>().unwrap());
+        peer.add_allowed_ip("10.0.0.1/24".parse::<AllowedIp>().unwrap());
+        peer.add_allowed_ip("10.0.0.0/25".parse::<AllowedIp>().unwrap());
+        assert_eq!(peer.allowed_ips, ips(&["10.0.0.0/24", "10.0.0.0/25"]));
+    }
+
+    #[test]
+    fn coalesce_allowed_ips() {
+        let mut peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .build()
+            .unwrap();
+        peer.allowed_ips = ips(&[
+            "fd00::1/64",
+            "10.0.1.0/24",
+            "10.0.0.128/25",
+            "10.0.0.0/25",
+            "10.0.0.7",
+            "192.168.0.0/24",
+            "fd00:0:0:1::/64",
+        ]);
+        peer.coalesce_allowed_ips();
+        assert_eq!(
+            peer.allowed_ips,
+            ips(&["10.0.0.0/23", "192.168.0.0/24", "fd00::/63"])
+        );
+
+        //Unaligned neighbours are not merged
+        peer.allowed_ips = ips(&["10.0.1.0/24", "10.0.2.0/24"]);
+        peer.coalesce_allowed_ips();
+        assert_eq!(peer.allowed_ips, ips(&["10.0.1.0/24", "10.0.2.0/24"]));
+
+        peer.allowed_ips = ips(&["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1", "::1"]);
+        peer.coalesce_allowed_ips();
+        assert_eq!(peer.allowed_ips, vec![AllowedIp::ALL_V4, AllowedIp::ALL_V6]);
+    }
+
+    #[test]
+    #[cfg(feature = "x25519")]
+    fn derived_public_key() {
+        let mut interface = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .build()
+            .unwrap();
+        assert_eq!(interface.public_key(), Some(Key::new([3; 32]).public_key()));
+        interface.private_key = None;
+        assert_eq!(interface.public_key(), None);
+    }
+
+    #[test]
+    fn address_preference() {
+        use AddressFamilyPreference::*;
+
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let both = [v6, v4];
+        assert_eq!(AddressFamilyPreference::default(), V4First);
+        assert_eq!(V4First.pick(both.iter().copied()), Some(v4));
+        assert_eq!(V4Only.pick(both.iter().copied()), Some(v4));
+        assert_eq!(V6First.pick(both.iter().rev().copied()), Some(v6));
+        assert_eq!(V6Only.pick(both.iter().copied()), Some(v6));
+        //Without an address of the preferred family, only the `First` variants fall back
+        assert_eq!(V6First.pick(std::iter::once(v4)), Some(v4));
+        assert_eq!(V4First.pick(std::iter::once(v6)), Some(v6));
+        assert_eq!(V6Only.pick(std::iter::once(v4)), None);
+        assert_eq!(V4Only.pick(std::iter::once(v6)), None);
+        assert_eq!(V4First.pick(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn endpoint_host() {
+        let mut peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .build()
+            .unwrap();
+        peer.set_endpoint_host("[2001:db8::1]:51820", AddressFamilyPreference::V4First)
+            .unwrap();
+        assert_eq!(peer.endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+        //A missing port fails before anything is resolved, and keeps the current endpoint
+        assert!(matches!(
+            peer.set_endpoint_host("192.0.2.1", AddressFamilyPreference::V4First),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            peer.set_endpoint_host("192.0.2.1:51820", AddressFamilyPreference::V6Only),
+            Err(Error::InvalidConfig(reason)) if reason == "192.0.2.1:51820 has no IPv6 address"
+        ));
+        assert_eq!(peer.endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+    }
+
+    #[test]
+    fn config_equality() {
+        let peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .allowed_ip(AllowedIp::ALL_V4)
+            .allowed_ip(AllowedIp::ALL_V6)
+            .build()
+            .unwrap();
+        let mut running = peer.clone();
+        running.rx_bytes = 100;
+        running.tx_bytes = 200;
+        running.last_handshake = Some(SystemTime::now());
+        running.allowed_ips.reverse();
+        assert_ne!(peer, running);
+        assert!(peer.config_eq(&running));
+        running.endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        assert!(!peer.config_eq(&running));
+
+        let other = Peer::builder()
+            .public_key(Key::new([2; 32]))
+            .build()
+            .unwrap();
+        let applied = Interface::builder()
+            .private_key(Key::new([3; 32]))
+            .peer(peer.clone())
+            .peer(other.clone())
+            .build()
+            .unwrap();
+        let mut desired = applied.clone();
+        desired.peers.reverse();
+        desired.peers[1].rx_bytes = 5;
+        assert!(applied.config_eq(&desired));
+        desired.listen_port = Some(51820);
+        assert!(!applied.config_eq(&desired));
+
+        //The same number of peers, but one of them twice
+        let mut duplicated = applied.clone();
+        duplicated.peers[1] = peer;
+        assert!(!applied.config_eq(&duplicated));
+        assert!(!duplicated.config_eq(&applied));
+    }
+
+    #[test]
+    fn longest_prefix_match() {
+        let peer = |byte: u8, allowed_ips: &[&str]| {
+            let mut peer = Peer::builder()
+                .public_key(Key::new([byte; 32]))
+                .build()
+                .unwrap();
+            for allowed_ip in allowed_ips {
+                peer.add_allowed_ip(allowed_ip.parse::<AllowedIp>().unwrap());
+            }
+            peer
+        };
+        let config = Interface::builder()
+            .private_key(Key::new([9; 32]))
+            .peer(peer(1, &["0.0.0.0/0"]))
+            .peer(peer(2, &["10.0.0.0/8"]))
+            .peer(peer(3, &["10.1.2.3/16", "192.0.2.7/32"]))
+            .peer(peer(4, &["fd00::/64"]))
+            //Takes 10.0.0.0/8 from the second peer
+            .peer(peer(5, &["10.0.0.0/8"]))
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.peers[2].routes().collect::<Vec<_>>(),
+            vec![
+                "10.1.0.0/16".parse::<AllowedIp>().unwrap(),
+                "192.0.2.7/32".parse().unwrap()
+            ]
+        );
+        assert!(config.peers[2].allowed_ips_contains("10.1.255.1".parse().unwrap()));
+        assert!(!config.peers[2].allowed_ips_contains("10.2.0.1".parse().unwrap()));
+        assert!(!AllowedIp::ALL_V4.contains("::1".parse().unwrap()));
+        assert!(AllowedIp::ALL_V6.contains("::1".parse().unwrap()));
+
+        let table = config.routing_table();
+        let cases = [
+            ("10.1.2.3", Some(3)),
+            ("10.2.0.1", Some(5)),
+            ("192.0.2.7", Some(3)),
+            ("192.0.2.8", Some(1)),
+            ("8.8.8.8", Some(1)),
+            ("fd00::1", Some(4)),
+            ("2001:db8::1", None),
+        ];
+        for &(address, expected) in cases.iter() {
+            let address: IpAddr = address.parse().unwrap();
+            let expected = expected.map(|byte| Key::new([byte; 32]));
+            let found = config.peer_for_ip(address).map(|p| p.public_key.clone());
+            assert_eq!(found, expected, "{}", address);
+            let indexed = table.peer_for_ip(address).map(|p| p.public_key.clone());
+            assert_eq!(indexed, expected, "{}", address);
+        }
+    }
+
+    #[test]
+    fn validate_warnings() {
+        let peer = |byte: u8, allowed_ip: Option<&str>| {
+            let mut peer = Peer::builder()
+                .public_key(Key::new([byte; 32]))
+                .build()
+                .unwrap();
+            peer.allowed_ips
+                .extend(allowed_ip.map(|ip| ip.parse::<AllowedIp>().unwrap()));
+            peer
+        };
+        let mut keepalive = peer(4, Some("10.0.4.0/24"));
+        keepalive.persistent_keepalive = Some(Duration::from_secs(25));
+        let mut connected = keepalive.clone();
+        connected.public_key = Key::new([5; 32]);
+        connected.allowed_ips = vec!["10.0.5.0/24".parse().unwrap()];
+        connected.endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        let mut config = Interface::builder()
+            .private_key(Key::new([9; 32]))
+            .peer(peer(1, Some("0.0.0.0/0")))
+            //Nested in the first peer's network, which is fine
+            .peer(peer(2, Some("10.0.0.0/8")))
+            .peer(peer(3, Some("10.1.2.3/8")))
+            .peer(peer(1, None))
+            .peer(peer(6, None))
+            .peer(keepalive)
+            .peer(connected)
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ConfigWarning::OverlappingAllowedIp {
+                    allowed_ip: "10.0.0.0/8".parse().unwrap(),
+                    first: Key::new([2; 32]),
+                    second: Key::new([3; 32]),
+                },
+                ConfigWarning::DuplicatePeer {
+                    public_key: Key::new([1; 32])
+                },
+                ConfigWarning::NoAllowedIps {
+                    public_key: Key::new([6; 32])
+                },
+                ConfigWarning::KeepaliveWithoutEndpoint {
+                    public_key: Key::new([4; 32])
+                },
+            ])
+        );
+
+        config.peers.truncate(2);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn stale_handshakes() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let max_age = Duration::from_secs(180);
+        let stats = |last_handshake| PeerStats {
+            public_key: Key::new([1; 32]),
+            rx_bytes: 0,
+            tx_bytes: 0,
+            last_handshake,
+            endpoint: None,
+        };
+        assert!(stats(None).is_stale_at(now, max_age));
+        assert!(stats(Some(now - Duration::from_secs(181))).is_stale_at(now, max_age));
+        assert!(!stats(Some(now - max_age)).is_stale_at(now, max_age));
+        assert!(!stats(Some(now - Duration::from_secs(5))).is_stale_at(now, max_age));
+        assert!(!stats(Some(now + Duration::from_secs(5))).is_stale_at(now, max_age));
+        assert!(stats(None).is_stale(max_age));
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let private_key = Key::new([0xab; 32]);
+        let preshared_key = Key::new([0xcd; 32]);
+        let public_key = Key::new([0x12; 32]);
+        let peer_builder = Peer::builder()
+            .public_key(public_key.clone())
+            .preshared_key(preshared_key.clone());
+        let interface_builder = Interface::builder()
+            .private_key(private_key.clone())
+            .peer(peer_builder.clone().build().unwrap());
+        let mut interface = interface_builder.clone().build().unwrap();
+        interface.public_key = Some(public_key.clone());
+
+        for output in &[
+            format!("{:?}", interface),
+            format!("{:#?}", interface),
+            format!("{:?}", interface_builder),
+            format!("{:?}", peer_builder),
+        ] {
+            for secret in &[&private_key, &preshared_key] {
+                assert!(!output.contains(&secret.to_base64()), "{}", output);
+                assert!(
+                    !output.contains(&format!("{:?}", secret.as_bytes())),
+                    "{}",
+                    output
+                );
+                //The bytes of the keys are not printed individually either
+                assert!(
+                    !output.contains("171") && !output.contains("205"),
+                    "{}",
+                    output
+                );
+            }
+            assert!(output.contains("<redacted>"), "{}", output);
+        }
+        assert!(format!("{:?}", interface).contains(&public_key.to_base64()));
+        assert!(format!("{:?}", peer_builder).contains(&public_key.to_base64()));
+    }
+
+    #[test]
+    fn builders() {
+        let net: AllowedIp = "10.0.0.0/24".parse().unwrap();
+        let peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .endpoint(endpoint())
+            .allowed_ip(net)
+            .persistent_keepalive(Duration::from_secs(25))
+            .build()
+            .unwrap();
+        assert_eq!(peer.persistent_keepalive, Some(Duration::from_secs(25)));
+        assert_eq!(peer.endpoint, Some(endpoint()));
+        assert_eq!(peer.allowed_ips, vec![net]);
+
+        let interface = Interface::builder()
+            .private_key(Key::new([2; 32]))
+            .listen_port(51820)
+            .peer(peer)
+            .build()
+            .unwrap();
+        assert_eq!(interface.listen_port, Some(51820));
+        assert_eq!(interface.peers.len(), 1);
+    }
+
+    #[test]
+    fn peer_builder_requires_public_key() {
+        assert!(matches!(
+            Peer::builder().endpoint(endpoint()).build(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn peer_builder_rejects_large_keepalive() {
+        let result = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .endpoint(endpoint())
+            .persistent_keepalive(Duration::from_secs(u16::MAX as u64 + 1))
+            .build();
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn allowed_ip_parse() {
+        let v4: AllowedIp = "10.0.0.0/24".parse().unwrap();
+        assert_eq!(v4.address(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)));
+        assert_eq!(v4.cidr(), 24);
+        assert_eq!(v4.to_string(), "10.0.0.0/24");
+
+        let single: AllowedIp = "fd00::1".parse().unwrap();
+        assert_eq!(single.cidr(), 128);
+
+        assert!("10.0.0.0/33".parse::<AllowedIp>().is_err());
+        assert!("::/129".parse::<AllowedIp>().is_err());
+        assert!("10.0.0.0/".parse::<AllowedIp>().is_err());
+        assert!("example.com/24".parse::<AllowedIp>().is_err());
+    }
+
+    #[test]
+    fn allowed_ip_bare_address() {
+        //wg-quick treats an address without a prefix length as a host route
+        let v4: AllowedIp = "10.0.0.5".parse().unwrap();
+        assert_eq!(v4.address(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)));
+        assert_eq!(v4.cidr(), 32);
+        let v6: AllowedIp = "fd00::5".parse().unwrap();
+        assert_eq!(v6.cidr(), 128);
+
+        //Displayed with the prefix length, which parses back to the same allowed ip
+        assert_eq!(v4.to_string(), "10.0.0.5/32");
+        assert_eq!(v6.to_string(), "fd00::5/128");
+        for allowed_ip in [v4, v6].iter() {
+            assert_eq!(
+                allowed_ip.to_string().parse::<AllowedIp>().unwrap(),
+                *allowed_ip
+            );
+        }
+        assert!("10.0.0.256".parse::<AllowedIp>().is_err());
+        assert!("fd00::5%1".parse::<AllowedIp>().is_err());
+    }
+
+    #[test]
+    fn allowed_ip_default_routes() {
+        assert_eq!("0.0.0.0/0".parse::<AllowedIp>().unwrap(), AllowedIp::ALL_V4);
+        assert_eq!("::/0".parse::<AllowedIp>().unwrap(), AllowedIp::ALL_V6);
+
+        let peer = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .allowed_ip(AllowedIp::ALL_V4)
+            .allowed_ip(AllowedIp::ALL_V6)
+            .build()
+            .unwrap();
+        assert_eq!(peer.allowed_ips, vec![AllowedIp::ALL_V4, AllowedIp::ALL_V6]);
+    }
+
+    #[test]
+    #[cfg(feature = "ipnet")]
+    fn allowed_ip_ipnet_round_trip() {
+        for net in ["0.0.0.0/0", "::/0", "192.168.1.0/24", "fd00::/64"].iter() {
+            let net: ipnet::IpNet = net.parse().unwrap();
+            let allowed_ip = AllowedIp::from(net);
+            assert_eq!(allowed_ip.to_string(), net.to_string());
+            assert_eq!(ipnet::IpNet::from(allowed_ip), net);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() {
+        let interface = Interface::builder()
+            .private_key(Key::new([2; 32]))
+            .listen_port(51820)
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([1; 32]))
+                    .endpoint(endpoint())
+                    .allowed_ip(AllowedIp::ALL_V4)
+                    .persistent_keepalive(Duration::from_secs(25))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(&interface).unwrap();
+        let peer = &json["peers"][0];
+        assert_eq!(
+            peer["public_key"],
+            "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+        );
+        assert_eq!(peer["endpoint"], "203.0.113.1:51820");
+        assert_eq!(peer["allowed_ips"][0], "0.0.0.0/0");
+        assert_eq!(peer["persistent_keepalive"], 25);
+        assert_eq!(
+            serde_json::from_value::<Interface>(json).unwrap(),
+            interface
+        );
+
+        let minimal: Interface = serde_json::from_str(
+            r#"{"peers": [{"public_key": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="}]}"#,
+        )
+        .unwrap();
+        assert_eq!(minimal.peers[0].endpoint, None);
+        assert!(serde_json::from_str::<AllowedIp>(r#""10.0.0.0/40""#).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+#[cfg(feature = "mock")]
+use crate::adapter::PeerFlags;
+use crate::adapter::{read_allowed_ip, read_peer, InterfaceFlags};
+use crate::wireguard_nt_raw::{WIREGUARD_ALLOWED_IP, WIREGUARD_INTERFACE, WIREGUARD_PEER};
+use crate::{AllowedIp, Error, Interface, Key, Peer};
+
+use std::fmt;
+use std::mem::size_of;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+/// A config returned by [`crate::Adapter::get_config_into`], which decodes its fields from the
+/// buffer filled by `WireGuardGetConfiguration` when they are accessed, instead of copying them
+/// into an [`Interface`].
+///
+/// The layout of the whole buffer is checked when the view is created, so its accessors and
+/// iterators never fail. [`InterfaceRef::to_interface`] copies it into an owned `Interface`, as
+/// [`crate::Adapter::get_config`] does. Like `Interface`, the `Debug` output hides the private
+/// and preshared keys
+#[derive(Clone, Copy)]
+pub struct InterfaceRef<'a> {
+    bytes: &'a [u8],
+    peers_count: usize,
+}
+
+impl<'a> InterfaceRef<'a> {
+    /// Checks that `bytes` holds a config in the layout `WireGuardGetConfiguration` writes, and
+    /// returns a view over it.
+    ///
+    /// The buffer starts with a `WIREGUARD_INTERFACE`, followed by `PeersCount` peers. Each
+    /// `WIREGUARD_PEER` is immediately followed by its own `AllowedIPsCount`
+    /// `WIREGUARD_ALLOWED_IP`s, so the next peer starts after the last allowed ip of the previous
+    /// one. Counts that do not fit in the buffer, and address families other than IPv4 and IPv6,
+    /// are rejected with [`Error::InvalidConfig`]
+    pub(crate) fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        check_struct::<WIREGUARD_INTERFACE>(bytes, 0, "the interface")?;
+        //SAFETY: checked above that the interface is in bounds
+        let interface: WIREGUARD_INTERFACE = unsafe { read_at(bytes, 0) };
+        let mut offset = size_of::<WIREGUARD_INTERFACE>();
+        //Reject a corrupt count up front, rather than after reading peers until the buffer ends
+        check_count::<WIREGUARD_PEER>(bytes, offset, interface.PeersCount, "peers")?;
+        for _ in 0..interface.PeersCount {
+            offset = check_peer(bytes, offset)?;
+        }
+        Ok(InterfaceRef {
+            bytes: &bytes[..offset],
+            peers_count: interface.PeersCount as usize,
+        })
+    }
+
+    fn raw(&self) -> WIREGUARD_INTERFACE {
+        //SAFETY: `parse` checked that the interface is in bounds
+        unsafe { read_at(self.bytes, 0) }
+    }
+
+    /// Returns the flags of the interface, which tell which of its fields are set
+    pub(crate) fn flags(&self) -> InterfaceFlags {
+        InterfaceFlags::from_bits_truncate(self.raw().Flags)
+    }
+
+    /// Returns the flags of each peer, in the order of [`InterfaceRef::peers`]. The buffer passed to
+    /// `WireGuardSetConfiguration` has the same layout, so `MockWireGuard` uses this to apply a
+    /// config the way the driver does
+    #[cfg(feature = "mock")]
+    pub(crate) fn peer_flags(&self) -> Vec<PeerFlags> {
+        let mut peers = self.peers();
+        std::iter::from_fn(|| peers.next_raw())
+            .map(|(raw, _)| PeerFlags::from_bits_truncate(raw.Flags))
+            .collect()
+    }
+
+    /// Returns the port the interface listens on, see [`Interface::listen_port`]
+    pub fn listen_port(&self) -> Option<u16> {
+        Some(self.raw().ListenPort)
+            .filter(|_| self.flags().contains(InterfaceFlags::HAS_LISTEN_PORT))
+    }
+
+    /// Returns the private key of the interface, see [`Interface::private_key`]
+    pub fn private_key(&self) -> Option<Key> {
+        Some(Key::new(self.raw().PrivateKey))
+            .filter(|_| self.flags().contains(InterfaceFlags::HAS_PRIVATE_KEY))
+    }
+
+    /// Returns the public key of the interface, see [`Interface::public_key`]
+    pub fn public_key(&self) -> Option<Key> {
+        Some(Key::new(self.raw().PublicKey))
+            .filter(|_| self.flags().contains(InterfaceFlags::HAS_PUBLIC_KEY))
+    }
+
+    /// Returns an iterator over the peers of the interface, in the order the driver returned them
+    pub fn peers(&self) -> PeerRefs<'a> {
+        PeerRefs {
+            bytes: self.bytes,
+            offset: size_of::<WIREGUARD_INTERFACE>(),
+            remaining: self.peers_count,
+        }
+    }
+
+    /// Copies the config into an owned [`Interface`]
+    pub fn to_interface(&self) -> Interface {
+        Interface {
+            listen_port: self.listen_port(),
+            private_key: self.private_key(),
+            public_key: self.public_key(),
+            peers: self.peers().map(|peer| peer.to_peer()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Debug for InterfaceRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_interface(), f)
+    }
+}
+
+/// A peer of an [`InterfaceRef`], returned by [`InterfaceRef::peers`]. The fields match those of
+/// [`Peer`]
+#[derive(Clone)]
+pub struct PeerRef<'a> {
+    /// The peer with everything but its allowed ips, which decoding does not allocate for
+    peer: Peer,
+    allowed_ips: &'a [u8],
+}
+
+impl<'a> PeerRef<'a> {
+    /// Returns the public key of the peer
+    pub fn public_key(&self) -> &Key {
+        &self.peer.public_key
+    }
+
+    /// Returns the preshared key of the peer, if it has one
+    pub fn preshared_key(&self) -> Option<&Key> {
+        self.peer.preshared_key.as_ref()
+    }
+
+    /// Returns the persistent keepalive interval of the peer, if keepalives are enabled
+    pub fn persistent_keepalive(&self) -> Option<Duration> {
+        self.peer.persistent_keepalive
+    }
+
+    /// Returns the endpoint of the peer, if it is known
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        self.peer.endpoint
+    }
+
+    /// Returns the number of bytes sent to the peer
+    pub fn tx_bytes(&self) -> u64 {
+        self.peer.tx_bytes
+    }
+
+    /// Returns the number of bytes received from the peer
+    pub fn rx_bytes(&self) -> u64 {
+        self.peer.rx_bytes
+    }
+
+    /// Returns the time of the last handshake, or `None` if no handshake has completed yet
+    pub fn last_handshake(&self) -> Option<SystemTime> {
+        self.peer.last_handshake
+    }
+
+    /// Returns an iterator over the allowed ips of the peer
+    pub fn allowed_ips(&self) -> AllowedIpRefs<'a> {
+        AllowedIpRefs {
+            bytes: self.allowed_ips,
+        }
+    }
+
+    /// Copies the peer into an owned [`Peer`]
+    pub fn to_peer(&self) -> Peer {
+        Peer {
+            allowed_ips: self.allowed_ips().collect(),
+            ..self.peer.clone()
+        }
+    }
+}
+
+impl fmt::Debug for PeerRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_peer(), f)
+    }
+}
+
+/// The iterator returned by [`InterfaceRef::peers`]
+#[derive(Debug, Clone)]
+pub struct PeerRefs<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> PeerRefs<'a> {
+    /// Returns the next `WIREGUARD_PEER`, and the bytes of the allowed ips that follow it
+    fn next_raw(&mut self) -> Option<(WIREGUARD_PEER, &'a [u8])> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        //SAFETY: `InterfaceRef::parse` checked that the peer is in bounds
+        let raw: WIREGUARD_PEER = unsafe { read_at(self.bytes, self.offset) };
+        let start = self.offset + size_of::<WIREGUARD_PEER>();
+        self.offset = start + raw.AllowedIPsCount as usize * size_of::<WIREGUARD_ALLOWED_IP>();
+        Some((raw, &self.bytes[start..self.offset]))
+    }
+}
+
+impl<'a> Iterator for PeerRefs<'a> {
+    type Item = PeerRef<'a>;
+
+    fn next(&mut self) -> Option<PeerRef<'a>> {
+        let (raw, allowed_ips) = self.next_raw()?;
+        let peer = read_peer(&raw)
+            .unwrap_or_else(|_| unreachable!("the endpoint is checked by InterfaceRef::parse"));
+        Some(PeerRef { peer, allowed_ips })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for PeerRefs<'_> {}
+
+/// The iterator returned by [`PeerRef::allowed_ips`]
+#[derive(Debug, Clone)]
+pub struct AllowedIpRefs<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for AllowedIpRefs<'_> {
+    type Item = AllowedIp;
+
+    fn next(&mut self) -> Option<AllowedIp> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        //SAFETY: `InterfaceRef::parse` checked that the allowed ips fill these bytes
+        let raw: WIREGUARD_ALLOWED_IP = unsafe { read_at(self.bytes, 0) };
+        self.bytes = &self.bytes[size_of::<WIREGUARD_ALLOWED_IP>()..];
+        Some(
+            read_allowed_ip(&raw)
+                .unwrap_or_else(|_| unreachable!("allowed ips are checked by InterfaceRef::parse")),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.bytes.len() / size_of::<WIREGUARD_ALLOWED_IP>();
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for AllowedIpRefs<'_> {}
+
+/// Checks the peer at `offset` of `bytes` and its allowed ips, and returns the offset of the next
+/// peer
+fn check_peer(bytes: &[u8], offset: usize) -> Result<usize, Error> {
+    check_struct::<WIREGUARD_PEER>(bytes, offset, "a peer")?;
+    //SAFETY: checked above that the peer is in bounds
+    let peer: WIREGUARD_PEER = unsafe { read_at(bytes, offset) };
+    read_peer(&peer)?;
+    let start = offset + size_of::<WIREGUARD_PEER>();
+    let end =
+        check_count::<WIREGUARD_ALLOWED_IP>(bytes, start, peer.AllowedIPsCount, "allowed ips")?;
+    for offset in (start..end).step_by(size_of::<WIREGUARD_ALLOWED_IP>()) {
+        //SAFETY: `check_count` checked that the allowed ips are in bounds
+        read_allowed_ip(&unsafe { read_at(bytes, offset) })?;
+    }
+    Ok(end)
+}
+
+/// Checks that a `T` fits in `bytes` at `offset`
+fn check_struct<T>(bytes: &[u8], offset: usize, what: &str) -> Result<(), Error> {
+    if bytes.len() - offset < size_of::<T>() {
+        return Err(Error::InvalidConfig(format!(
+            "The {} byte config ends in the middle of {}, which starts at byte {}",
+            bytes.len(),
+            what,
+            offset
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `count` structs of type `T`, as reported by the driver, fit in `bytes` after
+/// `offset`, and returns the offset after them
+fn check_count<T>(bytes: &[u8], offset: usize, count: u32, what: &str) -> Result<usize, Error> {
+    let remaining = bytes.len() - offset;
+    match (count as usize).checked_mul(size_of::<T>()) {
+        Some(size) if size <= remaining => Ok(offset + size),
+        _ => Err(Error::InvalidConfig(format!(
+            "The driver reported {} {}, which do not fit in the remaining {} bytes",
+            count, what, remaining
+        ))),
+    }
+}
+
+/// Reads the `T` at `offset` of `bytes`. The bytes come from a `Vec<u8>`, so they are read
+/// unaligned.
+///
+/// # Safety
+/// `T` must be one of the raw structs of the driver, for which any bytes are a valid value
+///
+/// # Panics
+/// If `offset + size_of::<T>()` exceeds the length of `bytes`
+unsafe fn read_at<T>(bytes: &[u8], offset: usize) -> T {
+    let bytes = &bytes[offset..offset + size_of::<T>()];
+    unsafe { bytes.as_ptr().cast::<T>().read_unaligned() }
+}
@@ -0,0 +1,263 @@
+use crate::registry::RegKey;
+use crate::util::to_wide;
+use crate::{wireguard_nt_raw, Error, Guid};
+
+use std::net::IpAddr;
+use std::ptr;
+
+/// The parameters of every adapter that has IPv4 enabled, keyed by adapter GUID
+const TCPIP_INTERFACES_KEY: &str =
+    "SYSTEM\\CurrentControlSet\\Services\\Tcpip\\Parameters\\Interfaces";
+
+/// The parameters of every adapter that has IPv6 enabled, keyed by adapter GUID
+const TCPIP6_INTERFACES_KEY: &str =
+    "SYSTEM\\CurrentControlSet\\Services\\Tcpip6\\Parameters\\Interfaces";
+
+/// The local Name Resolution Policy Table. Each subkey is one rule
+const DNS_POLICY_CONFIG_KEY: &str =
+    "SYSTEM\\CurrentControlSet\\Services\\Dnscache\\Parameters\\DnsPolicyConfig";
+
+/// The version of the rule format written by [`set_nrpt_rule`]
+const NRPT_RULE_VERSION: u32 = 2;
+/// Rule option that sends the matching queries to the servers in `GenericDNSServers`
+const NRPT_CONFIG_OPTIONS_GENERIC_DNS: u32 = 0x8;
+
+const DNS_INTERFACE_SETTINGS_VERSION1: u32 = 1;
+const DNS_SETTING_IPV6: u64 = 0x0001;
+const DNS_SETTING_NAMESERVER: u64 = 0x0002;
+const DNS_SETTING_SEARCHLIST: u64 = 0x0004;
+
+/// `DNS_INTERFACE_SETTINGS` from netioapi.h, which winapi does not have
+#[repr(C)]
+#[allow(non_snake_case)]
+struct DNS_INTERFACE_SETTINGS {
+    Version: u32,
+    Flags: u64,
+    Domain: *mut u16,
+    NameServer: *mut u16,
+    SearchList: *mut u16,
+    RegistrationEnabled: u32,
+    RegisterAdapterName: u32,
+    EnableLLMNR: u32,
+    QueryAdapterName: u32,
+    ProfileNameServer: *mut u16,
+}
+
+type SetInterfaceDnsSettings =
+    unsafe extern "system" fn(wireguard_nt_raw::GUID, *const DNS_INTERFACE_SETTINGS) -> u32;
+
+/// Joins the servers of one address family into the comma separated list Windows expects
+fn name_server_list(servers: &[IpAddr], ipv6: bool) -> String {
+    servers
+        .iter()
+        .filter(|server| server.is_ipv6() == ipv6)
+        .map(|server| server.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Checks that every domain is a plain DNS name and returns them without trailing dots
+fn normalize_domains<'a>(domains: &[&'a str]) -> Result<Vec<&'a str>, Error> {
+    domains
+        .iter()
+        .map(|domain| {
+            let trimmed = domain.trim_end_matches('.');
+            let valid = !trimmed.is_empty()
+                && !trimmed.starts_with('.')
+                && trimmed
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.');
+            if valid {
+                Ok(trimmed)
+            } else {
+                Err(Error::InvalidConfig(format!(
+                    "Invalid DNS domain {:?}",
+                    domain
+                )))
+            }
+        })
+        .collect()
+}
+
+/// Joins the search domains into the comma separated list Windows expects
+fn search_list(domains: &[&str]) -> Result<String, Error> {
+    Ok(normalize_domains(domains)?.join(","))
+}
+
+/// Returns the NRPT namespaces matching `domains` and all of their subdomains. A leading dot makes
+/// a rule match by suffix
+fn nrpt_names(domains: &[&str]) -> Result<Vec<String>, Error> {
+    Ok(normalize_domains(domains)?
+        .into_iter()
+        .map(|domain| format!(".{}", domain))
+        .collect())
+}
+
+/// The key of the NRPT rule owned by the adapter `guid`
+fn nrpt_rule_path(guid: Guid) -> String {
+    format!("{}\\wireguard-nt-{}", DNS_POLICY_CONFIG_KEY, guid)
+}
+
+/// Calls `SetInterfaceDnsSettings` for the adapter `guid`. Returns `false` if Windows does not
+/// have the function, which was added in Windows 10 2004
+fn apply_dns_settings(guid: Guid, settings: &DNS_INTERFACE_SETTINGS) -> Result<bool, Error> {
+    //SAFETY: iphlpapi.dll is a system library whose initialization has no preconditions
+    let iphlpapi = unsafe { libloading::Library::new("iphlpapi.dll") }?;
+    //SAFETY: the signature matches the declaration in netioapi.h
+    let set_dns_settings =
+        unsafe { iphlpapi.get::<SetInterfaceDnsSettings>(b"SetInterfaceDnsSettings\0") };
+    let set_dns_settings = match set_dns_settings {
+        Ok(f) => f,
+        Err(_) => return Ok(false),
+    };
+    //SAFETY: the callers only point `settings` to strings that outlive the call. Fields that are
+    //not named in its flags are ignored
+    let err = unsafe { set_dns_settings(wireguard_nt_raw::GUID::from(guid), settings) };
+    if err != 0 {
+        return Err(Error::Win32(err));
+    }
+    Ok(true)
+}
+
+/// Sets the DNS servers of the adapter `guid` to `servers`, clearing them if it is empty
+pub(crate) fn set_interface_dns(guid: Guid, servers: &[IpAddr]) -> Result<(), Error> {
+    for &ipv6 in [false, true].iter() {
+        let name_server = to_wide(&name_server_list(servers, ipv6))?;
+        let mut flags = DNS_SETTING_NAMESERVER;
+        if ipv6 {
+            flags |= DNS_SETTING_IPV6;
+        }
+        let settings = DNS_INTERFACE_SETTINGS {
+            Version: DNS_INTERFACE_SETTINGS_VERSION1,
+            Flags: flags,
+            Domain: ptr::null_mut(),
+            //The field is only read, despite not being const in netioapi.h
+            NameServer: name_server.as_ptr() as *mut u16,
+            SearchList: ptr::null_mut(),
+            RegistrationEnabled: 0,
+            RegisterAdapterName: 0,
+            EnableLLMNR: 0,
+            QueryAdapterName: 0,
+            ProfileNameServer: ptr::null_mut(),
+        };
+        if !apply_dns_settings(guid, &settings)? {
+            //Windows versions before 10 2004 only have the registry
+            return set_registry_dns(guid, servers);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the DNS search domains of the adapter `guid` to `domains`, clearing them if it is empty
+pub(crate) fn set_interface_search_list(guid: Guid, domains: &[&str]) -> Result<(), Error> {
+    let search_list = search_list(domains)?;
+    let search_list_wide = to_wide(&search_list)?;
+    let settings = DNS_INTERFACE_SETTINGS {
+        Version: DNS_INTERFACE_SETTINGS_VERSION1,
+        Flags: DNS_SETTING_SEARCHLIST,
+        Domain: ptr::null_mut(),
+        NameServer: ptr::null_mut(),
+        //The field is only read, despite not being const in netioapi.h
+        SearchList: search_list_wide.as_ptr() as *mut u16,
+        RegistrationEnabled: 0,
+        RegisterAdapterName: 0,
+        EnableLLMNR: 0,
+        QueryAdapterName: 0,
+        ProfileNameServer: ptr::null_mut(),
+    };
+    if apply_dns_settings(guid, &settings)? {
+        return Ok(());
+    }
+    for &key in [TCPIP_INTERFACES_KEY, TCPIP6_INTERFACES_KEY].iter() {
+        let path = format!("{}\\{}", key, guid);
+        if let Some(interface) = RegKey::open_local_machine_writeable(&path)? {
+            interface.set_string("SearchList", &search_list)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the NRPT rule of the adapter `guid`, which sends queries for `domains` and their
+/// subdomains to `servers`. Removes the rule if `domains` is empty
+pub(crate) fn set_nrpt_rule(guid: Guid, domains: &[&str], servers: &[IpAddr]) -> Result<(), Error> {
+    if domains.is_empty() {
+        return delete_nrpt_rule(guid);
+    }
+    if servers.is_empty() {
+        return Err(Error::InvalidConfig(
+            "An NRPT rule needs at least one DNS server".to_owned(),
+        ));
+    }
+    let names = nrpt_names(domains)?;
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let servers = servers
+        .iter()
+        .map(|server| server.to_string())
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let rule = RegKey::create_local_machine(&nrpt_rule_path(guid))?;
+    rule.set_dword("Version", NRPT_RULE_VERSION)?;
+    rule.set_multi_string("Name", &names)?;
+    rule.set_string("GenericDNSServers", &servers)?;
+    rule.set_dword("ConfigOptions", NRPT_CONFIG_OPTIONS_GENERIC_DNS)?;
+    rule.set_string("IPSECCARestriction", "")?;
+    rule.set_string("Comment", "")?;
+    Ok(())
+}
+
+/// Removes the NRPT rule of the adapter `guid`, if there is one
+pub(crate) fn delete_nrpt_rule(guid: Guid) -> Result<(), Error> {
+    RegKey::delete_local_machine_tree(&nrpt_rule_path(guid))
+}
+
+/// Writes the DNS servers of the adapter `guid` to its `NameServer` registry values
+fn set_registry_dns(guid: Guid, servers: &[IpAddr]) -> Result<(), Error> {
+    for &(key, ipv6) in [(TCPIP_INTERFACES_KEY, false), (TCPIP6_INTERFACES_KEY, true)].iter() {
+        let path = format!("{}\\{}", key, guid);
+        //The key is missing when the address family is disabled on the adapter
+        if let Some(interface) = RegKey::open_local_machine_writeable(&path)? {
+            interface.set_string("NameServer", &name_server_list(servers, ipv6))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_servers_by_family() {
+        let servers: Vec<IpAddr> = ["1.1.1.1", "2606:4700:4700::1111", "8.8.8.8"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(name_server_list(&servers, false), "1.1.1.1,8.8.8.8");
+        assert_eq!(name_server_list(&servers, true), "2606:4700:4700::1111");
+        assert_eq!(name_server_list(&[], false), "");
+    }
+
+    #[test]
+    fn search_domains() {
+        assert_eq!(
+            search_list(&["corp.example.com.", "example.org"]).unwrap(),
+            "corp.example.com,example.org"
+        );
+        assert_eq!(search_list(&[]).unwrap(), "");
+        for invalid in &["", ".", ".example.com", "a,b", "a;b", "a b"] {
+            assert!(search_list(&[invalid]).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn nrpt_rule() {
+        assert_eq!(
+            nrpt_names(&["corp.example.com", "internal."]).unwrap(),
+            vec![".corp.example.com", ".internal"]
+        );
+        let guid = Guid::from(0x6ba7b810_9dad_11d1_80b4_00c04fd430c8);
+        assert!(nrpt_rule_path(guid)
+            .ends_with("\\DnsPolicyConfig\\wireguard-nt-{6BA7B810-9DAD-11D1-80B4-00C04FD430C8}"));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::DriverVersion;
+
+use std::fmt;
+
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::winbase::{
+    FormatMessageW, FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+};
+
+/// The error type returned by fallible operations in this crate
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// No adapter with the requested name exists
+    AdapterNotFound,
+
+    /// The calling process lacks the rights to perform the operation.
+    /// Most WireGuard NT operations must be run as Administrator
+    AccessDenied(std::io::Error),
+
+    /// The operation was denied because the process is not elevated. Run it as Administrator,
+    /// see [`crate::is_elevated`]
+    NotElevated,
+
+    /// An adapter or pool name is empty, too long or contains a NUL character. Checked before the
+    /// name is passed to the driver
+    InvalidName(String),
+
+    /// A configuration or argument was rejected before being passed to the driver, or the driver
+    /// returned data that could not be understood
+    InvalidConfig(String),
+
+    /// The requested setting has no equivalent in WireGuard NT, for example
+    /// [`crate::Interface::mark`]
+    Unsupported(String),
+
+    /// The running WireGuard NT driver is older than required, see
+    /// [`crate::require_driver_version`]
+    DriverVersionUnsupported {
+        /// The version of the running driver
+        found: DriverVersion,
+
+        /// The oldest version that is supported
+        required: DriverVersion,
+    },
+
+    /// The WireGuard NT kernel driver is not running, so it was not installed or could not be
+    /// started. See [`crate::ensure_driver_present`]
+    DriverNotInstalled,
+
+    /// The driver cannot be deleted because WireGuard adapters still exist
+    DriverInUse,
+
+    /// The UDP listen port is already used by another socket, so the driver could not bind it.
+    /// Contains the port, see [`crate::Adapter::set_config`]
+    PortInUse(u16),
+
+    /// Windows has not finished setting up the network interface of the adapter, so it has no LUID
+    /// or interface index yet
+    InterfaceNotReady,
+
+    /// The operation did not finish in time, see [`crate::Adapter::create_with_timeout`]
+    Timeout,
+
+    /// A Win32 call failed. Contains the raw code returned by `GetLastError`
+    Win32(u32),
+
+    /// The wireguard.dll file passed to [`crate::load_from_path`] or [`crate::load`] does not exist
+    DllNotFound,
+
+    /// The loaded dll does not export the named function, so it is not a wireguard.dll that this
+    /// crate supports. This usually means a different dll with the same name was shipped
+    DllMissingSymbol(&'static str),
+
+    /// Loading wireguard.dll failed for another reason, for example because it was built for a
+    /// different architecture
+    Load(libloading::Error),
+
+    /// Writing formatted output failed
+    Format(fmt::Error),
+
+    /// Reading or writing a file failed
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// Builds an error from the calling thread's last Win32 error code
+    pub(crate) fn last_win32() -> Self {
+        Error::Win32(unsafe { GetLastError() })
+    }
+}
+
+/// Looks up the system message for a Win32 error code using `FormatMessageW`
+fn format_message(code: u32) -> Option<String> {
+    let mut buf = [0u16; 512];
+    //SAFETY: `buf` is writeable for `buf.len()` wide characters and we pass no inserts
+    let len = unsafe {
+        FormatMessageW(
+            FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS,
+            std::ptr::null(),
+            code,
+            0,
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+            std::ptr::null_mut(),
+        )
+    };
+    if len == 0 {
+        return None;
+    }
+    let message = String::from_utf16_lossy(&buf[..len as usize]);
+    //System messages end with "\r\n"
+    Some(message.trim_end().to_owned())
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AdapterNotFound => write!(f, "adapter not found"),
+            Error::AccessDenied(e) => write!(f, "access denied: {}", e),
+            Error::NotElevated => write!(f, "the process must be run as Administrator"),
+            Error::InvalidName(reason) => write!(f, "invalid name: {}", reason),
+            Error::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            Error::Unsupported(reason) => write!(f, "unsupported by WireGuard NT: {}", reason),
+            Error::DriverVersionUnsupported { found, required } => write!(
+                f,
+                "the running WireGuard NT driver version {} is unsupported, version {} or newer is required",
+                found, required
+            ),
+            Error::DriverNotInstalled => write!(f, "the WireGuard NT driver is not installed"),
+            Error::DriverInUse => write!(f, "the driver is in use by existing adapters"),
+            Error::PortInUse(port) => write!(f, "UDP port {} is already in use", port),
+            Error::InterfaceNotReady => write!(f, "the network interface is not ready yet"),
+            Error::Timeout => write!(f, "the operation timed out"),
+            Error::Win32(code) => match format_message(*code) {
+                Some(message) => write!(f, "{} (os error {})", message, code),
+                None => write!(f, "unknown error (os error {})", code),
+            },
+            Error::DllNotFound => write!(f, "wireguard.dll was not found"),
+            Error::DllMissingSymbol(name) => write!(
+                f,
+                "the loaded dll does not export {}, it is not a supported wireguard.dll",
+                name
+            ),
+            Error::Load(e) => write!(f, "failed to load wireguard dll: {}", e),
+            Error::Format(e) => write!(f, "failed to write output: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::AccessDenied(e) => Some(e),
+            Error::Load(e) => Some(e),
+            Error::Format(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<libloading::Error> for Error {
+    fn from(e: libloading::Error) -> Self {
+        Error::Load(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(e: fmt::Error) -> Self {
+        Error::Format(e)
+    }
+}
@@ -0,0 +1,136 @@
+use crate::util;
+use crate::{
+    dll, Adapter, CreatedAdapter, DriverCapabilities, DriverVersion, Error, Guid, WireGuardApi,
+};
+
+use std::sync::Arc;
+
+/// A loaded wireguard.dll, as an entry point for creating and opening adapters and for querying
+/// the driver.
+///
+/// This is a thin wrapper around the `Arc<dll>` returned by [`crate::load`] and the other load
+/// functions, so cloning it is cheap and every clone shares the same loaded dll. Each method
+/// delegates to the [`Adapter`] constructor or free function of the same name, so both styles can
+/// be mixed. [`WireGuard::raw`] returns the `Arc<dll>` for functions that take it directly.
+///
+/// Like [`Adapter`], the handle can wrap another [`WireGuardApi`] than the loaded dll, such as
+/// `crate::MockWireGuard` with the `mock` feature
+pub struct WireGuard<A: WireGuardApi = dll> {
+    dll: Arc<A>,
+}
+
+impl<A: WireGuardApi> WireGuard<A> {
+    /// Wraps a loaded wireguard.dll
+    pub fn new(dll: Arc<A>) -> Self {
+        Self { dll }
+    }
+
+    /// Returns the loaded wireguard.dll
+    pub fn raw(&self) -> &Arc<A> {
+        &self.dll
+    }
+
+    /// Creates a new adapter, see [`Adapter::create`]
+    pub fn create(&self, pool: &str, name: &str, guid: Option<Guid>) -> Result<Adapter<A>, Error> {
+        Adapter::create(&self.dll, pool, name, guid)
+    }
+
+    /// Creates a new adapter and returns its GUID, see [`Adapter::create_resolving_guid`]
+    pub fn create_resolving_guid(
+        &self,
+        pool: &str,
+        name: &str,
+        guid: Option<Guid>,
+    ) -> Result<CreatedAdapter<A>, Error> {
+        Adapter::create_resolving_guid(&self.dll, pool, name, guid)
+    }
+
+    /// Opens an existing adapter, see [`Adapter::open`]
+    pub fn open(&self, name: &str) -> Result<Adapter<A>, Error> {
+        Adapter::open(&self.dll, name)
+    }
+
+    /// Opens an adapter, or creates it if it does not exist yet, see [`Adapter::open_or_create`]
+    pub fn open_or_create(
+        &self,
+        pool: &str,
+        name: &str,
+        guid: Option<Guid>,
+    ) -> Result<Adapter<A>, Error> {
+        Adapter::open_or_create(&self.dll, pool, name, guid)
+    }
+
+    /// Returns the names of the adapters in the pool `pool`, see [`Adapter::enumerate`]
+    pub fn enumerate(&self, pool: &str) -> Result<Vec<String>, Error> {
+        Adapter::enumerate(&self.dll, pool)
+    }
+
+    /// Returns the version of the running driver, see [`crate::get_running_driver_version_typed`]
+    pub fn running_version(&self) -> Result<DriverVersion, Error> {
+        util::get_running_driver_version_typed(&self.dll)
+    }
+
+    /// Returns which optional features the running driver supports, see
+    /// [`crate::driver_capabilities`]
+    pub fn capabilities(&self) -> DriverCapabilities {
+        util::driver_capabilities(&self.dll)
+    }
+
+    /// Checks that the running driver has at least version `min`, see
+    /// [`crate::require_driver_version`]
+    pub fn require_version(&self, min: DriverVersion) -> Result<(), Error> {
+        util::require_driver_version(&self.dll, min)
+    }
+
+    /// Checks that the driver is running, see [`crate::ensure_driver_present`]
+    pub fn ensure_driver_present(&self) -> Result<(), Error> {
+        util::ensure_driver_present(&self.dll)
+    }
+
+    /// Uninstalls the driver, see [`crate::delete_driver`]
+    pub fn delete_driver(&self) -> Result<(), Error> {
+        util::delete_driver(&self.dll)
+    }
+}
+
+//Derived `Clone` would require `A: Clone`, but only the `Arc` is cloned
+impl<A: WireGuardApi> Clone for WireGuard<A> {
+    fn clone(&self) -> Self {
+        Self {
+            dll: Arc::clone(&self.dll),
+        }
+    }
+}
+
+impl<A: WireGuardApi> From<Arc<A>> for WireGuard<A> {
+    fn from(dll: Arc<A>) -> Self {
+        WireGuard::new(dll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<WireGuard>();
+    }
+
+    #[test]
+    #[ignore = "requires administrator privileges and wireguard.dll"]
+    fn create_through_handle() {
+        let wireguard = WireGuard::new(
+            unsafe { crate::load_from_path("examples/wireguard_nt/bin/amd64/wireguard.dll") }
+                .expect("Failed to load wireguard dll"),
+        );
+        let adapter = wireguard.create("WireGuard", "HandleTest", None).unwrap();
+        assert!(wireguard.running_version().is_ok());
+        assert!(wireguard.capabilities().adapter_api);
+        let opened = wireguard.open("HandleTest").unwrap();
+        assert_eq!(opened.get_luid().unwrap(), adapter.get_luid().unwrap());
+        //Both adapters hold a clone of the dll, as does the handle
+        assert_eq!(Arc::strong_count(wireguard.raw()), 3);
+    }
+}
@@ -0,0 +1,388 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// The length in bytes of WireGuard's private, public and preshared keys
+pub const KEY_LENGTH: usize = 32;
+
+/// A 32 byte Curve25519 key, as used by WireGuard for private, public and preshared keys
+///
+/// With the `zeroize` feature, the bytes of a key are overwritten with zeroes when it is dropped.
+/// Every clone owns its own copy of the bytes and is zeroed separately when it is dropped, so key
+/// material stays in memory until the last clone is gone. Copies taken with [`Key::as_bytes`] or
+/// [`Key::to_base64`] are not zeroed.
+///
+/// Keys compare in constant time, so comparing a secret key does not reveal how many of its
+/// leading bytes matched. `Key` deliberately implements neither `Ord` nor `Hash`, since both would
+/// have to inspect the raw bytes in a data dependent way. To look up peers by their public key in a
+/// map, wrap the key in a [`PublicKey`].
+///
+/// A `Key` may be secret, so its `Debug` output never shows the bytes. [`PublicKey`] shows its key
+/// in base64
+#[derive(Clone)]
+pub struct Key([u8; KEY_LENGTH]);
+
+impl Key {
+    /// Creates a key from its raw bytes
+    pub fn new(bytes: [u8; KEY_LENGTH]) -> Self {
+        Key(bytes)
+    }
+
+    /// Returns the raw bytes of this key
+    pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+        &self.0
+    }
+
+    /// Decodes a key from the standard base64 encoding used by `wg` and wg-quick config files
+    pub fn from_base64(s: &str) -> Result<Key, KeyParseError> {
+        let bytes = base64::decode(s).map_err(|e| match e {
+            base64::DecodeError::InvalidByte(offset, _)
+            | base64::DecodeError::InvalidLastSymbol(offset, _) => {
+                KeyParseError::InvalidCharacter { offset }
+            }
+            base64::DecodeError::InvalidLength => KeyParseError::InvalidLength,
+        })?;
+        let key = if bytes.len() == KEY_LENGTH {
+            let mut key = [0u8; KEY_LENGTH];
+            key.copy_from_slice(&bytes);
+            Ok(Key(key))
+        } else {
+            Err(KeyParseError::InvalidLength)
+        };
+        //A decoded buffer of the wrong length may still hold most of a key
+        #[cfg(feature = "zeroize")]
+        {
+            let mut bytes = bytes;
+            zeroize::Zeroize::zeroize(&mut bytes);
+        }
+        key
+    }
+
+    /// Encodes this key using the standard base64 encoding used by `wg` and wg-quick config files
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+
+    /// Generates a new random X25519 private key using the operating system's random number
+    /// generator.
+    ///
+    /// The key is clamped the same way `wg genkey` clamps keys.
+    #[cfg(feature = "x25519")]
+    pub fn generate() -> Key {
+        use rand::RngCore;
+
+        let mut bytes = [0u8; KEY_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        bytes[0] &= 248;
+        bytes[31] &= 127;
+        bytes[31] |= 64;
+        Key(bytes)
+    }
+
+    /// Treats this key as a private key and derives the matching X25519 public key
+    #[cfg(feature = "x25519")]
+    pub fn public_key(&self) -> Key {
+        Key(x25519_dalek::x25519(
+            self.0,
+            x25519_dalek::X25519_BASEPOINT_BYTES,
+        ))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        subtle::ConstantTimeEq::ct_eq(&self.0[..], &other.0[..]).into()
+    }
+}
+
+impl Eq for Key {}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(<redacted>)")
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Key {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+impl From<[u8; KEY_LENGTH]> for Key {
+    fn from(bytes: [u8; KEY_LENGTH]) -> Self {
+        Key(bytes)
+    }
+}
+
+/// Converts an `x25519-dalek` private key. Requires the `x25519` feature
+#[cfg(feature = "x25519")]
+impl From<x25519_dalek::StaticSecret> for Key {
+    fn from(secret: x25519_dalek::StaticSecret) -> Self {
+        Key(secret.to_bytes())
+    }
+}
+
+/// Converts an `x25519-dalek` public key. Requires the `x25519` feature
+#[cfg(feature = "x25519")]
+impl From<x25519_dalek::PublicKey> for Key {
+    fn from(key: x25519_dalek::PublicKey) -> Self {
+        Key(key.to_bytes())
+    }
+}
+
+impl FromStr for Key {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Key::from_base64(s)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_base64())
+    }
+}
+
+/// A [`Key`] that is known to be public, such as the public key of a peer.
+///
+/// Public keys are not secret, so unlike `Key` this implements `Hash` and `Ord` on the raw bytes and
+/// can be used as the key of a `HashMap` or `BTreeMap`, for example to look up the state of a peer
+/// by [`crate::Peer::public_key`]. Never wrap a private or preshared key in a `PublicKey`
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct PublicKey(Key);
+
+impl PublicKey {
+    /// Returns the wrapped key
+    pub fn as_key(&self) -> &Key {
+        &self.0
+    }
+
+    /// Unwraps the key
+    pub fn into_key(self) -> Key {
+        self.0
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PublicKey")
+            .field(&self.0.to_base64())
+            .finish()
+    }
+}
+
+impl std::hash::Hash for PublicKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.as_bytes().hash(state);
+    }
+}
+
+impl PartialOrd for PublicKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PublicKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.as_bytes().cmp(other.0.as_bytes())
+    }
+}
+
+impl From<Key> for PublicKey {
+    fn from(key: Key) -> Self {
+        PublicKey(key)
+    }
+}
+
+impl From<PublicKey> for Key {
+    fn from(key: PublicKey) -> Self {
+        key.0
+    }
+}
+
+/// Converts an `x25519-dalek` public key. Requires the `x25519` feature
+#[cfg(feature = "x25519")]
+impl From<x25519_dalek::PublicKey> for PublicKey {
+    fn from(key: x25519_dalek::PublicKey) -> Self {
+        PublicKey(Key::from(key))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Key::from_base64(s).map(PublicKey)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The error returned when decoding a base64 [`Key`] fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyParseError {
+    /// The string is valid base64 but does not decode to exactly 32 bytes
+    InvalidLength,
+
+    /// The string contains a character that is not valid base64
+    InvalidCharacter {
+        /// The byte offset of the offending character
+        offset: usize,
+    },
+}
+
+impl fmt::Display for KeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyParseError::InvalidLength => {
+                write!(f, "key must decode to exactly {} bytes", KEY_LENGTH)
+            }
+            KeyParseError::InvalidCharacter { offset } => {
+                write!(f, "invalid base64 character at offset {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyParseError {}
+
+/// Keys are serialized as base64 strings so that stored configs stay readable
+#[cfg(feature = "serde")]
+impl serde::Serialize for Key {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Key {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Key::from_base64(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Key {
+        let mut bytes = [0u8; KEY_LENGTH];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        Key(bytes)
+    }
+
+    #[test]
+    fn equality() {
+        let key = Key::new([7; KEY_LENGTH]);
+        assert_eq!(key, key.clone());
+        let mut last_differs = [7; KEY_LENGTH];
+        last_differs[KEY_LENGTH - 1] = 8;
+        assert_ne!(key, Key::new(last_differs));
+        assert_ne!(key, Key::new([0; KEY_LENGTH]));
+    }
+
+    #[test]
+    fn public_key_map() {
+        use std::collections::HashMap;
+
+        let mut peers = HashMap::new();
+        peers.insert(PublicKey::from(Key::new([1; KEY_LENGTH])), "first");
+        peers.insert(PublicKey::from(Key::new([2; KEY_LENGTH])), "second");
+        assert_eq!(peers[&PublicKey::from(Key::new([1; KEY_LENGTH]))], "first");
+        assert_eq!(peers[&PublicKey::from(Key::new([2; KEY_LENGTH]))], "second");
+
+        assert!(PublicKey::from(Key::new([1; KEY_LENGTH])) < Key::new([2; KEY_LENGTH]).into());
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        let key = from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let encoded = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=";
+        assert_eq!(key.to_base64(), encoded);
+        assert_eq!(Key::from_base64(encoded), Ok(key.clone()));
+        assert_eq!(encoded.parse::<Key>(), Ok(key.clone()));
+        assert_eq!(key.to_string(), encoded);
+    }
+
+    #[test]
+    fn base64_wrong_length() {
+        //31 zero bytes
+        let short = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==";
+        assert_eq!(Key::from_base64(short), Err(KeyParseError::InvalidLength));
+        assert_eq!(Key::from_base64(""), Err(KeyParseError::InvalidLength));
+        assert_eq!(Key::from_base64("AAAAA"), Err(KeyParseError::InvalidLength));
+    }
+
+    #[test]
+    fn debug_hides_key() {
+        let key = Key::new([7; 32]);
+        let debug = format!("{:?}", key);
+        assert_eq!(debug, "Key(<redacted>)");
+        assert!(!debug.contains(&key.to_base64()));
+        assert_eq!(
+            format!("{:?}", PublicKey::from(key.clone())),
+            format!("PublicKey({:?})", key.to_base64())
+        );
+    }
+
+    #[test]
+    fn base64_invalid_character() {
+        let invalid = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25L!o=";
+        assert_eq!(
+            Key::from_base64(invalid),
+            Err(KeyParseError::InvalidCharacter { offset: 41 })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "x25519")]
+    fn public_key_test_vector() {
+        //Alice's key pair from RFC 7748 section 6.1, the Curve25519 function WireGuard is built on
+        let private = from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let public = from_hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        assert_eq!(private.public_key(), public);
+    }
+
+    #[test]
+    #[cfg(feature = "x25519")]
+    fn from_x25519_dalek() {
+        let private = from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let secret = x25519_dalek::StaticSecret::from(*private.as_bytes());
+        let public = x25519_dalek::PublicKey::from(&secret);
+        assert_eq!(Key::from(public), private.public_key());
+        assert_eq!(PublicKey::from(public).as_key(), &private.public_key());
+        assert_eq!(Key::from(secret), private);
+    }
+
+    #[test]
+    #[cfg(feature = "x25519")]
+    fn generate_is_clamped() {
+        let key = Key::generate();
+        assert_eq!(key.as_bytes()[0] & 7, 0);
+        assert_eq!(key.as_bytes()[31] & 128, 0);
+        assert_eq!(key.as_bytes()[31] & 64, 64);
+        assert_ne!(key, Key::generate());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_base64() {
+        let key = from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(json, "\"dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=\"");
+        assert_eq!(serde_json::from_str::<Key>(&json).unwrap(), key);
+        assert!(serde_json::from_str::<Key>("\"AAAA\"").is_err());
+    }
+}
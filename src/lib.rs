@@ -15,7 +15,8 @@
 //! Inside your code load the wireguard.dll signed driver file, downloaded from <https://git.zx2c4.com/wireguard-nt/about>
 //!
 //! Then either call [`Adapter::create`] or [`Adapter::open`] to obtain a wireguard
-//! adapter. Start by setting its config with [`Adapter::set_config`].
+//! adapter. Start by setting its config with [`Adapter::set_config`]. The [`WireGuard`] handle
+//! wraps the loaded dll and offers the same entry points and driver queries as methods.
 //!
 //! # Example
 //! ```no_run
@@ -23,39 +24,30 @@
 //! //Load the wireguard dll file so that we can call the underlying C functions
 //! //Unsafe because we are loading an arbitrary dll file
 //! let wireguard = unsafe { wireguard_nt::load_from_path("path/to/wireguard.dll") }.expect("Failed to load wireguard dll");
-//! //Try to open an adapter with the name "Demo"
-//! let adapter = match wireguard_nt::Adapter::open(wireguard, "Demo") {
-//!     Ok(a) => a,
-//!     Err((_, wireguard)) => {
-//!         //If loading failed (most likely it didn't exist), create a new one
-//!         match wireguard_nt::Adapter::create(wireguard, "WireGuard", "Demo", None) {
-//!             Ok(a) => a,
-//!             Err((e, _)) => panic!("Failed to create adapter: {:?}", e),
-//!         }
-//!     }
-//! };
-//!
-//! let interface = wireguard_nt::SetInterface {
-//!     //Let the OS pick a port for us
-//!     listen_port: None,
-//!     //Generated from the private key if not specified
-//!     public_key: None,
-//!     //Fill in private keys in real code
-//!     private_key: None,
+//! //Open the adapter with the name "Demo", or create it if it doesn't exist yet
+//! let adapter = wireguard_nt::Adapter::open_or_create(&wireguard, "WireGuard", "Demo", None)
+//!     .expect("Failed to open or create adapter");
+//!
+//! let interface = wireguard_nt::Interface::builder()
+//!     //Fill in real keys in real code
+//!     .private_key(wireguard_nt::Key::new([0; 32]))
 //!     //Add a peer
-//!     peers: vec![wireguard_nt::SetPeer {
-//!         //Provide a public key so that we can communicate with them
-//!         public_key: None,
-//!         //Disable additional AES encryption
-//!         preshared_key: None,
-//!         //Send a keepalive packet every 21 seconds
-//!         keep_alive: Some(21),
-//!         //Route all traffic through the WireGuard interface
-//!         allowed_ips: vec!["0.0.0.0/0".parse().unwrap()],
-//!         //The peer's ip address
-//!         endpoint: "1.2.3.4".parse().unwrap(),
-//!     }],
-//! };
+//!     .peer(
+//!         wireguard_nt::Peer::builder()
+//!             //Provide a public key so that we can communicate with them
+//!             .public_key(wireguard_nt::Key::new([0; 32]))
+//!             //Send a keepalive packet every 21 seconds
+//!             .persistent_keepalive(std::time::Duration::from_secs(21))
+//!             //Route all traffic through the WireGuard interface
+//!             .allowed_ip(wireguard_nt::AllowedIp::ALL_V4)
+//!             //The peer's ip address
+//!             .endpoint("1.2.3.4:51820".parse().unwrap())
+//!             .build()
+//!             .unwrap(),
+//!     )
+//!     //The OS picks a port for us and the public key is generated from the private key
+//!     .build()
+//!     .unwrap();
 //!
 //! //Set the config our adapter will use
 //! //This lets it know about the peers and keys
@@ -74,14 +66,57 @@
 //!    
 //! See `examples/demo_server.rs` that connects to the wireguard demo server
 //!
+//! # Running several adapters
+//! One loaded wireguard.dll serves any number of adapters, so load it once and pass the same
+//! `Arc<dll>` to every [`Adapter::create`] and [`Adapter::open`]. Each [`Adapter`] holds a clone of
+//! the `Arc`, which keeps the dll loaded until the last adapter is dropped. The config, state,
+//! addresses, routes and logging level of each adapter are independent of the others.
+//!
+//! The logger is the exception: it is global to the process. [`set_logger`], [`set_raw_logger`]
+//! and [`set_logger_tracing`] replace the logger for all adapters, and it receives the messages of
+//! every adapter. Enable [`AdapterLoggingLevel::OnWithPrefix`] to tell them apart. See
+//! `examples/multiple_adapters.rs`
+//!
 //! # Version compatibility
 //! Wireguard NT versions 0.10 and above are supported. Versions < 0.10 have breaking changes that
 //! make interoperability hard. Please file an issue if this effects your use case.
 //!
+//! # Features
+//! - `ipnet` (default): conversions between [`AllowedIp`] and `ipnet::IpNet`, and
+//!   [`Adapter::set_default_route`], [`Adapter::set_addresses`] and [`Adapter::sync_routes`]
+//! - `x25519`: [`Key::generate`], [`Key::public_key`], [`Interface::public_key()`] and
+//!   [`Adapter::public_key`], and conversions from the `x25519-dalek` key types
+//! - `serde`: `Serialize` and `Deserialize` for [`Interface`], [`Peer`], [`AllowedIp`] and [`Key`]
+//! - `tracing`: [`set_logger_tracing`], which sends driver log messages to `tracing`
+//! - `metrics`: [`Adapter::write_openmetrics`] for exporting peer statistics to Prometheus
+//! - `zeroize`: overwrites the bytes of a [`Key`] with zeroes when it is dropped
+//! - `tokio`: [`AsyncAdapter`], which runs the blocking calls of an [`Adapter`] on tokio's blocking
+//!   thread pool
+//! - `setup`: [`install_driver`], which adds a separately shipped driver package to the driver
+//!   store
+//! - `mock`: `MockWireGuard`, an in-memory [`WireGuardApi`] for testing code that uses [`Adapter`]
+//!   without the driver
+//!
 
 mod adapter;
+mod api;
+#[cfg(feature = "tokio")]
+mod async_adapter;
+mod config;
+mod config_ref;
+mod dns;
+mod error;
+mod handle;
+mod key;
 mod log;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "mock")]
+mod mock;
+mod registry;
+mod status;
 mod util;
+mod wg_quick;
 
 //Generated by bingen, so ignore lints
 #[allow(
@@ -94,11 +129,33 @@ mod util;
 )]
 mod wireguard_nt_raw;
 
-pub(crate) const MAX_NAME: usize = 256;
+/// `MAX_ADAPTER_NAME` from iptypes.h. wireguard.h limits adapter and pool names to this many
+/// characters, including the null terminator
+pub(crate) const MAX_NAME: usize = 128;
 
 pub use crate::adapter::*;
+pub use crate::api::WireGuardApi;
+#[cfg(feature = "tokio")]
+pub use crate::async_adapter::AsyncAdapter;
+pub use crate::config::*;
+pub use crate::config_ref::{AllowedIpRefs, InterfaceRef, PeerRef, PeerRefs};
+pub use crate::error::Error;
+pub use crate::handle::WireGuard;
+pub use crate::key::*;
 pub use crate::log::*;
+#[cfg(feature = "mock")]
+pub use crate::mock::{MockCall, MockWireGuard};
+pub use crate::status::Status;
+#[allow(deprecated)]
 pub use crate::util::get_running_driver_version;
+#[cfg(feature = "setup")]
+pub use crate::util::install_driver;
+pub use crate::util::{
+    check_raw_bindings_version, delete_driver, driver_capabilities, ensure_driver_present,
+    get_running_driver_version_typed, is_elevated, require_driver_version, DriverCapabilities,
+    DriverVersion, RAW_BINDINGS_VERSION,
+};
+pub use crate::wg_quick::*;
 
 pub use wireguard_nt_raw::wireguard as dll;
 
@@ -118,12 +175,16 @@ use std::sync::Arc;
 /// Hoverer one can never be too cautious when loading a dll file.
 ///
 /// For more information see [`libloading`]'s dynamic library safety guarantees: [`libloading`][`libloading::Library::new`]
-pub unsafe fn load() -> Result<Arc<dll>, libloading::Error> {
+pub unsafe fn load() -> Result<Arc<dll>, Error> {
     load_from_path("wireguard")
 }
 
 /// Attempts to load the wireguard library as a dynamic library from the given path.
 ///
+/// Returns [`Error::DllNotFound`] if there is no file at `path`, and [`Error::DllMissingSymbol`]
+/// if the file is a dll that lacks a function of wireguard.dll, such as a different dll that was
+/// shipped under the same name.
+///
 ///
 /// # Safety
 /// This function loads a dll file with the path provided.
@@ -134,15 +195,47 @@ pub unsafe fn load() -> Result<Arc<dll>, libloading::Error> {
 /// Hoverer one can never be too cautious when loading a dll file.
 ///
 /// For more information see [`libloading`]'s dynamic library safety guarantees: [`libloading`][`libloading::Library::new`]
-pub unsafe fn load_from_path<P>(path: P) -> Result<Arc<dll>, libloading::Error>
+pub unsafe fn load_from_path<P>(path: P) -> Result<Arc<dll>, Error>
 where
     P: AsRef<::std::ffi::OsStr>,
 {
-    Ok(Arc::new(wireguard_nt_raw::wireguard::new(path)?))
+    let library = libloading::Library::new(path).map_err(util::open_library_error)?;
+    load_from_library(library)
+}
+
+/// The directory below [`std::env::temp_dir`] that [`load_from_memory`] writes wireguard.dll to
+const EMBEDDED_DLL_DIR: &str = "wireguard-nt-rs";
+
+/// Attempts to load the wireguard library from `bytes`, the contents of a wireguard.dll file,
+/// typically embedded in the executable with `include_bytes!`.
+///
+/// Windows can only load dlls from files, so `bytes` is written to a new file in the
+/// `wireguard-nt-rs` directory of [`std::env::temp_dir`] and then loaded with [`load_from_path`].
+/// A loaded dll cannot be deleted, so the file stays until the dll is unloaded. Instead, every
+/// call first deletes the files that earlier processes left behind and that are no longer in use.
+///
+///
+/// # Safety
+/// The same caveats as for [`load_from_path`] apply to whatever `bytes` contains. Additionally,
+/// the temporary directory is writeable by every process of the current user. Another process of
+/// the same user can replace the file after it is written and before it is loaded, and have its
+/// own code run with the privileges of this process. For an elevated process started by a user
+/// without administrator rights, prefer [`load_from_path`] with a file in a directory that only
+/// administrators can write to, such as the installation directory of the program.
+///
+/// For more information see [`libloading`]'s dynamic library safety guarantees: [`libloading`][`libloading::Library::new`]
+pub unsafe fn load_from_memory(bytes: &[u8]) -> Result<Arc<dll>, Error> {
+    let dir = std::env::temp_dir().join(EMBEDDED_DLL_DIR);
+    std::fs::create_dir_all(&dir)?;
+    util::remove_embedded_dlls(&dir);
+    let path = util::write_embedded_dll(&dir, bytes)?;
+    load_from_path(path)
 }
 
 /// Attempts to load the WireGuard NT library from an existing [`libloading::Library`].
 ///
+/// Returns [`Error::DllMissingSymbol`] if `library` lacks a function of wireguard.dll.
+///
 ///
 /// # Safety
 /// This function loads the required WireGuard NT functions using the provided library. Reading a symbol table
@@ -151,14 +244,16 @@ where
 /// is inherently unsafe.
 ///
 /// For more information see [`libloading`]'s dynamic library safety guarantees: [`libloading::Library::new`]
-pub unsafe fn load_from_library<L>(library: L) -> Result<Arc<dll>, libloading::Error>
+pub unsafe fn load_from_library<L>(library: L) -> Result<Arc<dll>, Error>
 where
     L: Into<libloading::Library>,
 {
+    let library = library.into();
+    util::check_exports(&library)?;
     Ok(Arc::new(wireguard_nt_raw::wireguard::from_library(
         library,
     )?))
 }
 
-/// The error type
-pub type WireGuardError = Box<dyn std::error::Error>;
+/// The error type returned by this crate. Alias of [`Error`]
+pub type WireGuardError = Error;
@@ -1,28 +1,236 @@
+use crate::util;
 use crate::wireguard_nt_raw;
+use crate::WireGuardApi;
 use log::*;
 use widestring::U16CStr;
 
+use std::convert::TryFrom;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
-/// Sets the logger wireguard will use when logging. Maps to the wireguardSetLogger C function
-pub fn set_logger(
-    wireguard: &Arc<wireguard_nt_raw::wireguard>,
+/// Sets the raw callback wireguard will use when logging. Maps to the wireguardSetLogger C function.
+///
+/// Once a logger has been set, opening or creating an adapter no longer installs [`default_logger`].
+///
+/// The logger is global to the process: it receives the messages of every adapter, and setting it
+/// replaces the logger of all of them, even when they were created from different `Arc<dll>`s
+pub fn set_raw_logger<A: WireGuardApi>(
+    wireguard: &Arc<A>,
     f: wireguard_nt_raw::WIREGUARD_LOGGER_CALLBACK,
 ) {
-    unsafe { wireguard.WireGuardSetLogger(f) };
+    let mut logger = RAW_LOGGER.write().unwrap_or_else(|e| e.into_inner());
+    *logger = f;
+    unsafe { wireguard.set_logger(f) };
+    SET_LOGGER.store(true, Ordering::Relaxed);
 }
 
-/// What level of logging this adapter is using
+/// The callback last passed to [`set_raw_logger`], for messages from this crate itself
+static RAW_LOGGER: RwLock<wireguard_nt_raw::WIREGUARD_LOGGER_CALLBACK> = RwLock::new(None);
+
+/// Passes a message from this crate to the logger that was given to wireguard, as if the driver
+/// had logged it at `level`, so that it shows up wherever the driver's messages go. Does nothing
+/// if no logger is set, or if logging was turned off by passing `None` to [`set_raw_logger`]
+pub(crate) fn log_message(level: LogLevel, message: &str) {
+    let logger = *RAW_LOGGER.read().unwrap_or_else(|e| e.into_inner());
+    log_to(logger, level, message, SystemTime::now());
+}
+
+/// Calls `logger` the way wireguard does, see [`log_message`]
+fn log_to(
+    logger: wireguard_nt_raw::WIREGUARD_LOGGER_CALLBACK,
+    level: LogLevel,
+    message: &str,
+    time: SystemTime,
+) {
+    if let Some(logger) = logger {
+        let message: Vec<u16> = message.encode_utf16().chain(Some(0)).collect();
+        let timestamp = util::system_time_to_filetime(time);
+        //SAFETY: `message` is null terminated and outlives the call, like the messages wireguard
+        //passes to loggers
+        unsafe {
+            logger(
+                level as wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL,
+                timestamp,
+                message.as_ptr(),
+            )
+        };
+    }
+}
+
+/// The severity of a message logged by wireguard. Maps to `WIREGUARD_LOGGER_LEVEL`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(i32)]
+pub enum LogLevel {
+    /// Informational message
+    Info = wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_INFO,
+
+    /// Warning message
+    Warn = wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_WARN,
+
+    /// Error message
+    Err = wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_ERR,
+}
+
+impl TryFrom<wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL> for LogLevel {
+    type Error = wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL;
+
+    /// Converts a raw `WIREGUARD_LOGGER_LEVEL`, returning the value back if it is unknown
+    fn try_from(level: wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL) -> Result<Self, Self::Error> {
+        match level {
+            wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_INFO => Ok(LogLevel::Info),
+            wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_WARN => Ok(LogLevel::Warn),
+            wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_ERR => Ok(LogLevel::Err),
+            other => Err(other),
+        }
+    }
+}
+
+/// Converts the timestamp that wireguard passes to a logger callback, the number of 100ns
+/// intervals since 1601-01-01 UTC, into a [`SystemTime`].
+///
+/// [`set_logger`] does this before calling its closure. It is public for callbacks installed with
+/// [`set_raw_logger`] and for the `timestamp` field of [`set_logger_tracing`] events. Returns
+/// `None` for a zero timestamp, which the driver does not send, and for one that `SystemTime`
+/// cannot represent
+pub fn log_timestamp_to_system_time(timestamp: wireguard_nt_raw::DWORD64) -> Option<SystemTime> {
+    util::filetime_to_system_time(timestamp)
+}
+
+type LoggerFn = dyn Fn(LogLevel, SystemTime, &str) + Send + Sync;
+
+/// The closure passed to [`set_logger`], called by [`closure_logger`]
+static LOGGER: RwLock<Option<Arc<LoggerFn>>> = RwLock::new(None);
+
+/// Sets a closure that is called with the level, time and text of every message wireguard logs.
+/// This replaces the current logger, including one set by an earlier call. Like
+/// [`set_raw_logger`], the logger is shared by every adapter in the process.
+///
+/// The closure may be called from driver threads at the same time as other code is running.
+/// Messages with an unknown level are passed as [`LogLevel::Err`]. If the closure panics, the panic
+/// is caught and the message is dropped, so that it does not unwind into wireguard.dll
+pub fn set_logger<A: WireGuardApi>(
+    wireguard: &Arc<A>,
+    f: impl Fn(LogLevel, SystemTime, &str) + Send + Sync + 'static,
+) {
+    let mut logger = LOGGER.write().unwrap_or_else(|e| e.into_inner());
+    *logger = Some(Arc::new(f));
+    //Install the callback while holding the lock, so that it never sees an earlier closure
+    set_raw_logger(wireguard, Some(closure_logger));
+}
+
+/// Dispatches wireguard's log messages to the closure passed to [`set_logger`]
+extern "C" fn closure_logger(
+    level: wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL,
+    timestamp: wireguard_nt_raw::DWORD64,
+    message: *const wireguard_nt_raw::WCHAR,
+) {
+    if message.is_null() {
+        return;
+    }
+    //Clone the closure out so that the lock is not held while it runs,
+    //which would deadlock if it called `set_logger` itself
+    let logger = match LOGGER.read() {
+        Ok(logger) => logger.clone(),
+        Err(e) => e.into_inner().clone(),
+    };
+    let logger = match logger {
+        Some(logger) => logger,
+        None => return,
+    };
+    //Unwinding out of an `extern "C"` function aborts the process
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        //WireGuard will always give us a valid UTF16 null terminated string
+        let msg = unsafe { U16CStr::from_ptr_str(message) };
+        let level = LogLevel::try_from(level).unwrap_or(LogLevel::Err);
+        let time = log_timestamp_to_system_time(timestamp).unwrap_or_else(SystemTime::now);
+        logger(level, time, &msg.to_string_lossy());
+    }));
+}
+
+/// Sets a logger that emits wireguard's log messages as `tracing` events with the `wireguard`
+/// target. Each event has a `timestamp` field holding the time that the driver supplied, in 100ns
+/// intervals since 1601-01-01 UTC, which [`log_timestamp_to_system_time`] converts.
+///
+/// Requires the `tracing` feature
+#[cfg(feature = "tracing")]
+pub fn set_logger_tracing<A: WireGuardApi>(wireguard: &Arc<A>) {
+    set_raw_logger(wireguard, Some(tracing_logger));
+}
+
+/// The logger installed by [`set_logger_tracing`]
+#[cfg(feature = "tracing")]
+extern "C" fn tracing_logger(
+    level: wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL,
+    timestamp: wireguard_nt_raw::DWORD64,
+    message: *const wireguard_nt_raw::WCHAR,
+) {
+    if message.is_null() {
+        return;
+    }
+    //WireGuard will always give us a valid UTF16 null terminated string
+    let msg = unsafe { U16CStr::from_ptr_str(message) };
+    let utf8_msg = msg.to_string_lossy();
+    match level {
+        wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_INFO => {
+            tracing::info!(target: "wireguard", timestamp, "{}", utf8_msg)
+        }
+        wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_WARN => {
+            tracing::warn!(target: "wireguard", timestamp, "{}", utf8_msg)
+        }
+        wireguard_nt_raw::WIREGUARD_LOGGER_LEVEL_WIREGUARD_LOG_ERR => {
+            tracing::error!(target: "wireguard", timestamp, "{}", utf8_msg)
+        }
+        _ => tracing::error!(
+            target: "wireguard",
+            timestamp,
+            "{} (with invalid log level {})",
+            utf8_msg,
+            level
+        ),
+    }
+}
+
+/// What level of logging this adapter is using. Maps to `WIREGUARD_ADAPTER_LOG_STATE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
 pub enum AdapterLoggingLevel {
     /// No messages are logged
-    Off,
+    Off = wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE_WIREGUARD_ADAPTER_LOG_OFF,
 
     /// All messages are logged
-    On,
+    On = wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE_WIREGUARD_ADAPTER_LOG_ON,
 
     /// All messaged are logged and the adapter id prefixes the log message
-    OnWithPrefix,
+    OnWithPrefix =
+        wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE_WIREGUARD_ADAPTER_LOG_ON_WITH_PREFIX,
+}
+
+impl From<AdapterLoggingLevel> for wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE {
+    fn from(level: AdapterLoggingLevel) -> Self {
+        level as wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE
+    }
+}
+
+impl TryFrom<wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE> for AdapterLoggingLevel {
+    type Error = wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE;
+
+    /// Converts a raw `WIREGUARD_ADAPTER_LOG_STATE`, returning the value back if it is unknown
+    fn try_from(state: wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE) -> Result<Self, Self::Error> {
+        match state {
+            wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE_WIREGUARD_ADAPTER_LOG_OFF => {
+                Ok(AdapterLoggingLevel::Off)
+            }
+            wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE_WIREGUARD_ADAPTER_LOG_ON => {
+                Ok(AdapterLoggingLevel::On)
+            }
+            wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE_WIREGUARD_ADAPTER_LOG_ON_WITH_PREFIX => {
+                Ok(AdapterLoggingLevel::OnWithPrefix)
+            }
+            other => Err(other),
+        }
+    }
 }
 
 static SET_LOGGER: AtomicBool = AtomicBool::new(false);
@@ -54,9 +262,93 @@ pub extern "C" fn default_logger(
     }
 }
 
-pub(crate) fn set_default_logger_if_unset(wireguard: &Arc<wireguard_nt_raw::wireguard>) {
+pub(crate) fn set_default_logger_if_unset<A: WireGuardApi>(wireguard: &Arc<A>) {
     if !SET_LOGGER.load(Ordering::Relaxed) {
-        set_logger(wireguard, Some(default_logger));
-        SET_LOGGER.store(true, Ordering::Relaxed);
+        set_raw_logger(wireguard, Some(default_logger));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapter_logging_level_round_trip() {
+        let levels = [
+            (AdapterLoggingLevel::Off, 0),
+            (AdapterLoggingLevel::On, 1),
+            (AdapterLoggingLevel::OnWithPrefix, 2),
+        ];
+        for (level, raw) in levels.iter().copied() {
+            assert_eq!(
+                wireguard_nt_raw::WIREGUARD_ADAPTER_LOG_STATE::from(level),
+                raw
+            );
+            assert_eq!(AdapterLoggingLevel::try_from(raw), Ok(level));
+        }
+        assert_eq!(AdapterLoggingLevel::try_from(3), Err(3));
+    }
+
+    #[test]
+    fn log_level_from_raw() {
+        assert_eq!(LogLevel::try_from(0), Ok(LogLevel::Info));
+        assert_eq!(LogLevel::try_from(1), Ok(LogLevel::Warn));
+        assert_eq!(LogLevel::try_from(2), Ok(LogLevel::Err));
+        assert_eq!(LogLevel::try_from(3), Err(3));
+    }
+
+    #[test]
+    fn log_timestamps() {
+        use std::time::Duration;
+
+        //2021-11-23T00:00:00Z
+        assert_eq!(
+            log_timestamp_to_system_time(132_820_992_000_000_000),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_637_625_600))
+        );
+        assert_eq!(log_timestamp_to_system_time(0), None);
+    }
+
+    #[test]
+    fn closure_logger_dispatch() {
+        use std::sync::Mutex;
+        use widestring::U16CString;
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logged = Arc::clone(&messages);
+        *LOGGER.write().unwrap() = Some(Arc::new(move |level, time, msg: &str| {
+            if msg == "panic" {
+                panic!("logger panicked");
+            }
+            logged.lock().unwrap().push((level, time, msg.to_owned()));
+        }));
+
+        let message = U16CString::from_str("hello").unwrap();
+        //1970-01-01 00:00:01 UTC
+        closure_logger(1, 116_444_736_010_000_000, message.as_ptr());
+        closure_logger(7, 0, message.as_ptr());
+        closure_logger(0, 0, std::ptr::null());
+        let panic = U16CString::from_str("panic").unwrap();
+        closure_logger(0, 0, panic.as_ptr());
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(2);
+        log_to(Some(closure_logger), LogLevel::Warn, "from the crate", time);
+        log_to(None, LogLevel::Warn, "dropped", time);
+        *LOGGER.write().unwrap() = None;
+
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[2],
+            (LogLevel::Warn, time, "from the crate".to_owned())
+        );
+        assert_eq!(
+            messages[0],
+            (
+                LogLevel::Warn,
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1),
+                "hello".to_owned()
+            )
+        );
+        assert_eq!(messages[1].0, LogLevel::Err);
     }
 }
@@ -0,0 +1,172 @@
+use crate::PeerStats;
+
+use std::fmt::{self, Write};
+use std::time::SystemTime;
+
+/// The metrics written for each peer, as (name, type, unit, help text, value)
+type Metric = (
+    &'static str,
+    MetricType,
+    &'static str,
+    &'static str,
+    fn(&PeerStats) -> Value,
+);
+
+#[derive(Clone, Copy)]
+enum MetricType {
+    /// A value that only goes up, apart from resets when the adapter is recreated. Its samples
+    /// are named with a `_total` suffix
+    Counter,
+    Gauge,
+}
+
+impl MetricType {
+    fn name(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+
+    fn sample_suffix(self) -> &'static str {
+        match self {
+            MetricType::Counter => "_total",
+            MetricType::Gauge => "",
+        }
+    }
+}
+
+enum Value {
+    Integer(u64),
+    Float(f64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(v) => write!(f, "{}", v),
+            //`{:?}` always includes a decimal point or exponent, as OpenMetrics floats require
+            Value::Float(v) => write!(f, "{:?}", v),
+        }
+    }
+}
+
+const METRICS: [Metric; 3] = [
+    (
+        "wireguard_peer_rx_bytes",
+        MetricType::Counter,
+        "bytes",
+        "Number of bytes received from the peer.",
+        |peer| Value::Integer(peer.rx_bytes),
+    ),
+    (
+        "wireguard_peer_tx_bytes",
+        MetricType::Counter,
+        "bytes",
+        "Number of bytes transmitted to the peer.",
+        |peer| Value::Integer(peer.tx_bytes),
+    ),
+    (
+        "wireguard_peer_last_handshake_seconds",
+        MetricType::Gauge,
+        "seconds",
+        "Unix time of the last handshake with the peer, or 0 if there has been none.",
+        |peer| {
+            let since_epoch = peer
+                .last_handshake
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            Value::Float(since_epoch.as_secs_f64())
+        },
+    ),
+];
+
+/// Writes `s` as an OpenMetrics label value, escaping backslashes, double quotes and newlines
+fn write_label_value(out: &mut impl Write, s: &str) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            '"' => out.write_str("\\\"")?,
+            '\n' => out.write_str("\\n")?,
+            c => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the stats of the peers of the adapter `adapter` in the OpenMetrics text format,
+/// ending with the `# EOF` marker
+pub(crate) fn write_openmetrics(
+    out: &mut impl Write,
+    adapter: &str,
+    peers: &[PeerStats],
+) -> fmt::Result {
+    for (name, kind, unit, help, value) in METRICS.iter() {
+        writeln!(out, "# TYPE {} {}", name, kind.name())?;
+        writeln!(out, "# UNIT {} {}", name, unit)?;
+        writeln!(out, "# HELP {} {}", name, help)?;
+        for peer in peers {
+            write!(out, "{}{}{{adapter=\"", name, kind.sample_suffix())?;
+            write_label_value(out, adapter)?;
+            write!(out, "\",public_key=\"")?;
+            write_label_value(out, &peer.public_key.to_base64())?;
+            writeln!(out, "\"}} {}", value(peer))?;
+        }
+    }
+    writeln!(out, "# EOF")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use std::time::Duration;
+
+    #[test]
+    fn openmetrics() {
+        let peers = [
+            PeerStats {
+                public_key: Key::new([0; 32]),
+                rx_bytes: 1024,
+                tx_bytes: 2048,
+                last_handshake: Some(
+                    SystemTime::UNIX_EPOCH + Duration::from_millis(1_637_625_600_500),
+                ),
+                endpoint: None,
+            },
+            PeerStats {
+                public_key: Key::new([0xff; 32]),
+                rx_bytes: 0,
+                tx_bytes: 0,
+                last_handshake: None,
+                endpoint: None,
+            },
+        ];
+        let mut out = String::new();
+        write_openmetrics(&mut out, "wg\"0", &peers).unwrap();
+        let zero = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let ones = "//////////////////////////////////////////8=";
+        let expected = format!(
+            "# TYPE wireguard_peer_rx_bytes counter
+# UNIT wireguard_peer_rx_bytes bytes
+# HELP wireguard_peer_rx_bytes Number of bytes received from the peer.
+wireguard_peer_rx_bytes_total{{adapter=\"wg\\\"0\",public_key=\"{zero}\"}} 1024
+wireguard_peer_rx_bytes_total{{adapter=\"wg\\\"0\",public_key=\"{ones}\"}} 0
+# TYPE wireguard_peer_tx_bytes counter
+# UNIT wireguard_peer_tx_bytes bytes
+# HELP wireguard_peer_tx_bytes Number of bytes transmitted to the peer.
+wireguard_peer_tx_bytes_total{{adapter=\"wg\\\"0\",public_key=\"{zero}\"}} 2048
+wireguard_peer_tx_bytes_total{{adapter=\"wg\\\"0\",public_key=\"{ones}\"}} 0
+# TYPE wireguard_peer_last_handshake_seconds gauge
+# UNIT wireguard_peer_last_handshake_seconds seconds
+# HELP wireguard_peer_last_handshake_seconds Unix time of the last handshake with the peer, or 0 if there has been none.
+wireguard_peer_last_handshake_seconds{{adapter=\"wg\\\"0\",public_key=\"{zero}\"}} 1637625600.5
+wireguard_peer_last_handshake_seconds{{adapter=\"wg\\\"0\",public_key=\"{ones}\"}} 0.0
+# EOF
+",
+            zero = zero,
+            ones = ones
+        );
+        assert_eq!(out, expected);
+    }
+}
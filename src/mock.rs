@@ -0,0 +1,754 @@
+#![allow(unused_unsafe)]
+//In this file we are explicit about unsafe, even in unsafe functions, like in util.rs
+
+use crate::adapter::{encode_config, key_only_peer, InterfaceFlags, PeerFlags};
+use crate::wireguard_nt_raw::{
+    BOOL, DWORD, GUID, LPCWSTR, NET_LUID, WIREGUARD_ADAPTER_HANDLE, WIREGUARD_ADAPTER_LOG_STATE,
+    WIREGUARD_ADAPTER_STATE, WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_DOWN,
+    WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_UP, WIREGUARD_ALLOWED_IP, WIREGUARD_INTERFACE,
+    WIREGUARD_LOGGER_CALLBACK, WIREGUARD_PEER,
+};
+use crate::{DriverVersion, Error, Interface, InterfaceRef, Key, PeerStats, WireGuardApi};
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use widestring::U16CStr;
+use winapi::shared::winerror::{
+    ERROR_ALREADY_EXISTS, ERROR_FILE_NOT_FOUND, ERROR_INVALID_DATA, ERROR_INVALID_HANDLE,
+    ERROR_INVALID_PARAMETER, ERROR_MORE_DATA,
+};
+use winapi::um::errhandlingapi::SetLastError;
+
+/// The driver version that a new [`MockWireGuard`] reports
+const MOCK_DRIVER_VERSION: DriverVersion = DriverVersion {
+    major: 0,
+    minor: 10,
+};
+
+/// A call to [`MockWireGuard`] that set or read the config of an adapter, as returned by
+/// [`MockWireGuard::calls`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MockCall {
+    /// `WireGuardSetConfiguration` was called for the adapter named `adapter`, with `config`
+    /// decoded from the buffer that was passed. Fields that were not sent are `None`
+    SetConfiguration {
+        /// The name the adapter was created with
+        adapter: String,
+        /// The config that was passed
+        config: Interface,
+    },
+
+    /// `WireGuardGetConfiguration` returned the config of the adapter named `adapter`. Calls that
+    /// only asked for the size of the buffer are not recorded
+    GetConfiguration {
+        /// The name the adapter was created with
+        adapter: String,
+    },
+}
+
+/// An adapter created with [`MockWireGuard`]
+struct MockAdapter {
+    name: String,
+    pool: String,
+    luid: u64,
+    state: WIREGUARD_ADAPTER_STATE,
+    config: Interface,
+}
+
+/// An open handle to a [`MockAdapter`]
+struct MockHandle {
+    luid: u64,
+    /// Whether the handle came from `WireGuardCreateAdapter`, in which case closing it removes the
+    /// adapter
+    created: bool,
+}
+
+struct MockState {
+    adapters: Vec<MockAdapter>,
+    handles: HashMap<usize, MockHandle>,
+    /// The last id given to an adapter or handle
+    last_id: usize,
+    driver_version: Option<DriverVersion>,
+    calls: Vec<MockCall>,
+    /// The Win32 error that the next attempt to put an adapter up fails with
+    fail_next_up: Option<u32>,
+}
+
+impl MockState {
+    fn next_id(&mut self) -> usize {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn open_handle(&mut self, luid: u64, created: bool) -> WIREGUARD_ADAPTER_HANDLE {
+        let id = self.next_id();
+        self.handles.insert(id, MockHandle { luid, created });
+        id as WIREGUARD_ADAPTER_HANDLE
+    }
+
+    fn adapter_mut(&mut self, handle: WIREGUARD_ADAPTER_HANDLE) -> Option<&mut MockAdapter> {
+        let luid = self.handles.get(&(handle as usize))?.luid;
+        //The adapter is gone once the handle that created it is closed
+        self.adapters
+            .iter_mut()
+            .find(|adapter| adapter.luid == luid)
+    }
+}
+
+/// An in-memory stand-in for wireguard.dll, for testing code that uses [`crate::Adapter`] without
+/// the driver or Administrator rights.
+///
+/// `MockWireGuard` implements [`WireGuardApi`], so it can be passed to [`crate::Adapter::create`],
+/// [`crate::Adapter::open`] and the driver functions in place of the `Arc<dll>` returned by
+/// [`crate::load`]. It keeps the adapters, their state and their configs in memory:
+/// [`crate::Adapter::set_config`] and the other config functions merge peers, remove peers and
+/// move allowed ips between peers the way the driver does, and [`crate::Adapter::get_config`]
+/// returns the result. Every config that is set or read is recorded, see [`MockWireGuard::calls`].
+///
+/// The listen port and public key are returned as they were set, the mock does not pick a port
+/// or derive keys. The traffic counters and handshakes of the peers stay empty until they are set
+/// with [`MockWireGuard::set_peer_stats`]. Functions that go through IP Helper or the registry,
+/// such as [`crate::Adapter::get_mtu`] or [`crate::Adapter::rename`], still call Windows with the
+/// LUID of the mock adapter. No real interface has that LUID, so they fail. The mock never logs.
+///
+/// Requires the `mock` feature
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use wireguard_nt::{Adapter, Interface, Key, MockWireGuard};
+///
+/// let wireguard = Arc::new(MockWireGuard::new());
+/// let adapter = Adapter::create(&wireguard, "WireGuard", "Test", None).unwrap();
+/// let config = Interface::builder()
+///     .private_key(Key::new([1; 32]))
+///     .build()
+///     .unwrap();
+/// adapter.set_config(&config).unwrap();
+/// assert_eq!(wireguard.config("Test").unwrap().private_key, config.private_key);
+/// ```
+pub struct MockWireGuard {
+    state: Mutex<MockState>,
+}
+
+impl MockWireGuard {
+    /// Creates a mock without adapters, whose driver reports version 0.10
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                adapters: Vec::new(),
+                handles: HashMap::new(),
+                last_id: 0,
+                driver_version: Some(MOCK_DRIVER_VERSION),
+                calls: Vec::new(),
+                fail_next_up: None,
+            }),
+        }
+    }
+
+    /// Sets the version the driver reports. With `None`, the driver is not running, so
+    /// [`crate::get_running_driver_version_typed`] fails and creating adapters fails as if the
+    /// driver could not be installed. [`crate::delete_driver`] also sets it to `None`
+    pub fn set_driver_version(&self, version: Option<DriverVersion>) {
+        self.state().driver_version = version;
+    }
+
+    /// Makes the next call that puts an adapter up fail with the Win32 error `code`, as the driver
+    /// does when it cannot bind the listen port. Later calls succeed again
+    pub fn fail_next_up(&self, code: u32) {
+        self.state().fail_next_up = Some(code);
+    }
+
+    /// Returns the current config of the adapter named `name`, or `None` if there is no such
+    /// adapter. Names are not case sensitive
+    pub fn config(&self, name: &str) -> Option<Interface> {
+        self.state()
+            .adapters
+            .iter()
+            .find(|adapter| adapter.name.eq_ignore_ascii_case(name))
+            .map(|adapter| adapter.config.clone())
+    }
+
+    /// Makes the driver report `stats` for the peer with public key `stats.public_key` of the
+    /// adapter named `name`, as if traffic had been exchanged with it and it had roamed to
+    /// `stats.endpoint`. Returns `false` if there is no such adapter or peer
+    pub fn set_peer_stats(&self, name: &str, stats: &PeerStats) -> bool {
+        let mut state = self.state();
+        let peer = state
+            .adapters
+            .iter_mut()
+            .find(|adapter| adapter.name.eq_ignore_ascii_case(name))
+            .and_then(|adapter| {
+                adapter
+                    .config
+                    .peers
+                    .iter_mut()
+                    .find(|peer| peer.public_key == stats.public_key)
+            });
+        match peer {
+            Some(peer) => {
+                peer.rx_bytes = stats.rx_bytes;
+                peer.tx_bytes = stats.tx_bytes;
+                peer.last_handshake = stats.last_handshake;
+                peer.endpoint = stats.endpoint;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the configs that were set and read so far, oldest first
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state().calls.clone()
+    }
+
+    /// Forgets the calls recorded so far
+    pub fn clear_calls(&self) {
+        self.state().calls.clear();
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for MockWireGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fails a call the way wireguard.dll does, by setting the last error and returning `failed`
+fn fail<T>(code: u32, failed: T) -> T {
+    unsafe { SetLastError(code) };
+    failed
+}
+
+/// Writes the traffic counters and last handshake of each peer of `config` into `buffer`, which
+/// holds `config` as encoded by `encode_config`. They cannot be set with
+/// `WireGuardSetConfiguration`, so `encode_config` leaves them zero
+fn write_peer_stats(buffer: &mut [u8], config: &Interface) {
+    let mut offset = size_of::<WIREGUARD_INTERFACE>();
+    for peer in &config.peers {
+        let raw = buffer[offset..offset + size_of::<WIREGUARD_PEER>()]
+            .as_mut_ptr()
+            .cast::<WIREGUARD_PEER>();
+        //SAFETY: the slice above holds a whole `WIREGUARD_PEER`, the unaligned accesses need no
+        //alignment
+        unsafe {
+            let mut wg_peer = raw.read_unaligned();
+            wg_peer.RxBytes = peer.rx_bytes;
+            wg_peer.TxBytes = peer.tx_bytes;
+            wg_peer.LastHandshake = peer
+                .last_handshake
+                .map_or(0, crate::util::system_time_to_filetime);
+            raw.write_unaligned(wg_peer);
+        }
+        offset += size_of::<WIREGUARD_PEER>()
+            + peer.allowed_ips.len() * size_of::<WIREGUARD_ALLOWED_IP>();
+    }
+}
+
+/// Merges `update`, decoded from a `WireGuardSetConfiguration` buffer with the interface flags
+/// `flags` and the flags `peer_flags` of each peer, into `config`
+fn apply_update(
+    config: &mut Interface,
+    update: Interface,
+    flags: InterfaceFlags,
+    peer_flags: &[PeerFlags],
+) {
+    if flags.contains(InterfaceFlags::HAS_PRIVATE_KEY) {
+        config.private_key = update.private_key;
+    }
+    if flags.contains(InterfaceFlags::HAS_PUBLIC_KEY) {
+        config.public_key = update.public_key;
+    }
+    if flags.contains(InterfaceFlags::HAS_LISTEN_PORT) {
+        config.listen_port = update.listen_port;
+    }
+    if flags.contains(InterfaceFlags::REPLACE_PEERS) {
+        config.peers.clear();
+    }
+
+    for (peer, &flags) in update.peers.into_iter().zip(peer_flags) {
+        let existing = config
+            .peers
+            .iter()
+            .position(|p| p.public_key == peer.public_key);
+        if flags.contains(PeerFlags::REMOVE) {
+            if let Some(index) = existing {
+                config.peers.remove(index);
+            }
+            continue;
+        }
+        let index = match existing {
+            Some(index) => index,
+            //`WIREGUARD_PEER_UPDATE` only changes peers that already exist
+            None if flags.contains(PeerFlags::UPDATE) => continue,
+            None => {
+                config.peers.push(key_only_peer(&peer.public_key));
+                config.peers.len() - 1
+            }
+        };
+        //An allowed ip belongs to one peer at a time, adding it to this one takes it from the
+        //others
+        for other in config.peers.iter_mut() {
+            other
+                .allowed_ips
+                .retain(|allowed_ip| !peer.allowed_ips.contains(allowed_ip));
+        }
+
+        let target = &mut config.peers[index];
+        if flags.contains(PeerFlags::HAS_PRESHARED_KEY) {
+            //Like the driver, a zero key clears the preshared key
+            target.preshared_key = peer.preshared_key.filter(|key| *key != Key::new([0; 32]));
+        }
+        if flags.contains(PeerFlags::HAS_PERSISTENT_KEEPALIVE) {
+            target.persistent_keepalive = peer.persistent_keepalive;
+        }
+        if flags.contains(PeerFlags::HAS_ENDPOINT) {
+            target.endpoint = peer.endpoint;
+        }
+        if flags.contains(PeerFlags::REPLACE_ALLOWED_IPS) {
+            target.allowed_ips.clear();
+        }
+        target.allowed_ips.extend(peer.allowed_ips);
+    }
+}
+
+impl WireGuardApi for MockWireGuard {
+    unsafe fn create_adapter(
+        &self,
+        pool: LPCWSTR,
+        name: LPCWSTR,
+        _guid: *const GUID,
+    ) -> WIREGUARD_ADAPTER_HANDLE {
+        let pool = unsafe { U16CStr::from_ptr_str(pool) }.to_string_lossy();
+        let name = unsafe { U16CStr::from_ptr_str(name) }.to_string_lossy();
+        let mut state = self.state();
+        if state.driver_version.is_none() {
+            return fail(ERROR_FILE_NOT_FOUND, ptr::null_mut());
+        }
+        if state
+            .adapters
+            .iter()
+            .any(|adapter| adapter.name.eq_ignore_ascii_case(&name))
+        {
+            return fail(ERROR_ALREADY_EXISTS, ptr::null_mut());
+        }
+        //The interface type is in the top 16 bits of a LUID, and no interface has type 0
+        let luid = (state.next_id() as u64) << 24;
+        state.adapters.push(MockAdapter {
+            name,
+            pool,
+            luid,
+            state: WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_DOWN,
+            config: Interface::default(),
+        });
+        state.open_handle(luid, true)
+    }
+
+    unsafe fn open_adapter(&self, name: LPCWSTR) -> WIREGUARD_ADAPTER_HANDLE {
+        let name = unsafe { U16CStr::from_ptr_str(name) }.to_string_lossy();
+        let mut state = self.state();
+        let luid = state
+            .adapters
+            .iter()
+            .find(|adapter| adapter.name.eq_ignore_ascii_case(&name))
+            .map(|adapter| adapter.luid);
+        match luid {
+            Some(luid) => state.open_handle(luid, false),
+            None => fail(ERROR_FILE_NOT_FOUND, ptr::null_mut()),
+        }
+    }
+
+    unsafe fn close_adapter(&self, adapter: WIREGUARD_ADAPTER_HANDLE) {
+        let mut state = self.state();
+        if let Some(handle) = state.handles.remove(&(adapter as usize)) {
+            if handle.created {
+                state.adapters.retain(|adapter| adapter.luid != handle.luid);
+            }
+        }
+    }
+
+    unsafe fn get_adapter_luid(&self, adapter: WIREGUARD_ADAPTER_HANDLE, luid: *mut NET_LUID) {
+        let value = self.state().adapter_mut(adapter).map_or(0, |a| a.luid);
+        unsafe { luid.cast::<u64>().write(value) };
+    }
+
+    unsafe fn get_running_driver_version(&self) -> DWORD {
+        match self.state().driver_version {
+            Some(version) => version.raw(),
+            None => fail(ERROR_FILE_NOT_FOUND, 0),
+        }
+    }
+
+    unsafe fn delete_driver(&self) -> BOOL {
+        self.state().driver_version = None;
+        1
+    }
+
+    unsafe fn set_logger(&self, _logger: WIREGUARD_LOGGER_CALLBACK) {}
+
+    unsafe fn set_adapter_logging(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        _state: WIREGUARD_ADAPTER_LOG_STATE,
+    ) -> BOOL {
+        //The mock never logs, so only the handle is checked
+        match self.state().adapter_mut(adapter) {
+            Some(_) => 1,
+            None => fail(ERROR_INVALID_HANDLE, 0),
+        }
+    }
+
+    unsafe fn get_adapter_state(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: *mut WIREGUARD_ADAPTER_STATE,
+    ) -> BOOL {
+        match self.state().adapter_mut(adapter) {
+            Some(adapter) => {
+                unsafe { state.write(adapter.state) };
+                1
+            }
+            None => fail(ERROR_INVALID_HANDLE, 0),
+        }
+    }
+
+    unsafe fn set_adapter_state(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        state: WIREGUARD_ADAPTER_STATE,
+    ) -> BOOL {
+        let mut mock = self.state();
+        let fail_next_up = mock.fail_next_up;
+        let adapter = match mock.adapter_mut(adapter) {
+            Some(adapter) => adapter,
+            None => return fail(ERROR_INVALID_HANDLE, 0),
+        };
+        match fail_next_up {
+            Some(code) if state == WIREGUARD_ADAPTER_STATE_WIREGUARD_ADAPTER_STATE_UP => {
+                mock.fail_next_up = None;
+                fail(code, 0)
+            }
+            _ => {
+                adapter.state = state;
+                1
+            }
+        }
+    }
+
+    unsafe fn get_configuration(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        config: *mut WIREGUARD_INTERFACE,
+        bytes: *mut DWORD,
+    ) -> BOOL {
+        let mut state = self.state();
+        let adapter = match state.adapter_mut(adapter) {
+            Some(adapter) => adapter,
+            None => return fail(ERROR_INVALID_HANDLE, 0),
+        };
+        let writer =
+            match encode_config(&adapter.config, InterfaceFlags::empty(), PeerFlags::empty()) {
+                Ok(writer) => writer,
+                Err(_) => return fail(ERROR_INVALID_DATA, 0),
+            };
+        let len = writer.capacity();
+        if unsafe { *bytes } < len as DWORD {
+            unsafe { *bytes = len as DWORD };
+            return fail(ERROR_MORE_DATA, 0);
+        }
+        //SAFETY: the caller passes a buffer of `*bytes` bytes, which is at least `len`
+        unsafe {
+            ptr::copy_nonoverlapping(writer.ptr(), config.cast::<u8>(), len);
+            *bytes = len as DWORD;
+            write_peer_stats(
+                std::slice::from_raw_parts_mut(config.cast::<u8>(), len),
+                &adapter.config,
+            );
+        }
+        let name = adapter.name.clone();
+        state
+            .calls
+            .push(MockCall::GetConfiguration { adapter: name });
+        1
+    }
+
+    unsafe fn set_configuration(
+        &self,
+        adapter: WIREGUARD_ADAPTER_HANDLE,
+        config: *const WIREGUARD_INTERFACE,
+        bytes: DWORD,
+    ) -> BOOL {
+        //SAFETY: the caller passes a buffer of `bytes` bytes
+        let buffer = unsafe { std::slice::from_raw_parts(config.cast::<u8>(), bytes as usize) };
+        let (update, flags, peer_flags) = match InterfaceRef::parse(buffer) {
+            Ok(update) => (update.to_interface(), update.flags(), update.peer_flags()),
+            Err(_) => return fail(ERROR_INVALID_PARAMETER, 0),
+        };
+        let mut state = self.state();
+        let adapter = match state.adapter_mut(adapter) {
+            Some(adapter) => adapter,
+            None => return fail(ERROR_INVALID_HANDLE, 0),
+        };
+        let call = MockCall::SetConfiguration {
+            adapter: adapter.name.clone(),
+            config: update.clone(),
+        };
+        apply_update(&mut adapter.config, update, flags, &peer_flags);
+        state.calls.push(call);
+        1
+    }
+
+    fn adapters(&self) -> Result<Vec<(String, Option<String>)>, Error> {
+        Ok(self
+            .state()
+            .adapters
+            .iter()
+            .map(|adapter| (adapter.name.clone(), Some(adapter.pool.clone())))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Adapter, AdapterOrigin, AdapterState, AllowedIp, Key, Peer};
+
+    use std::sync::Arc;
+
+    fn peer(byte: u8, allowed_ip: &str) -> Peer {
+        Peer::builder()
+            .public_key(Key::new([byte; 32]))
+            .allowed_ip(allowed_ip.parse::<AllowedIp>().unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn set_and_get_config() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let adapter = Adapter::create(&wireguard, "WireGuard", "Mock", None).unwrap();
+        let config = Interface::builder()
+            .private_key(Key::new([9; 32]))
+            .listen_port(51820)
+            .peer(peer(1, "10.0.0.1/32"))
+            .peer(peer(2, "10.0.0.2/32"))
+            .build()
+            .unwrap();
+        adapter.set_config(&config).unwrap();
+
+        let current = adapter.get_config().unwrap();
+        assert_eq!(current.private_key, config.private_key);
+        assert_eq!(current.listen_port, Some(51820));
+        assert_eq!(current.peers, config.peers);
+        assert_eq!(wireguard.config("mock").unwrap(), current);
+
+        let calls = wireguard.calls();
+        match &calls[0] {
+            MockCall::SetConfiguration {
+                adapter,
+                config: set,
+            } => {
+                assert_eq!(adapter, "Mock");
+                assert_eq!(set.peers, config.peers);
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+        assert!(calls[1..].iter().all(
+            |call| matches!(call, MockCall::GetConfiguration { adapter } if adapter == "Mock")
+        ));
+        wireguard.clear_calls();
+        assert!(wireguard.calls().is_empty());
+    }
+
+    #[test]
+    fn rejects_fwmark() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let adapter = Adapter::create(&wireguard, "WireGuard", "Mark", None).unwrap();
+        let config = Interface::from_wg_quick(
+            "[Interface]\nPrivateKey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=\nFwMark = 51820\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            adapter.set_config(&config),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(!wireguard
+            .calls()
+            .iter()
+            .any(|call| matches!(call, MockCall::SetConfiguration { .. })));
+    }
+
+    #[test]
+    fn merges_like_the_driver() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let adapter = Adapter::create(&wireguard, "WireGuard", "Merge", None).unwrap();
+        adapter.add_peers(&[peer(1, "10.0.0.0/24")]).unwrap();
+        //Adding an allowed ip to one peer takes it from the other
+        adapter
+            .add_peers(&[peer(2, "10.0.0.0/24"), peer(3, "10.0.3.0/24")])
+            .unwrap();
+        let peers = adapter.get_config().unwrap().peers;
+        assert_eq!(peers.len(), 3);
+        assert!(peers[0].allowed_ips.is_empty());
+        assert_eq!(peers[1].allowed_ips, peer(2, "10.0.0.0/24").allowed_ips);
+
+        adapter
+            .set_peer_endpoint(&Key::new([3; 32]), "192.0.2.1:51820".parse().unwrap())
+            .unwrap();
+        //Updates of missing peers are ignored
+        adapter
+            .set_peer_endpoint(&Key::new([4; 32]), "192.0.2.2:51820".parse().unwrap())
+            .unwrap();
+        adapter.remove_peer(&Key::new([1; 32])).unwrap();
+        let peers = adapter.get_config().unwrap().peers;
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[1].endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(peers[1].allowed_ips, peer(3, "10.0.3.0/24").allowed_ips);
+    }
+
+    #[test]
+    fn adapters_and_driver() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let created = Adapter::create(&wireguard, "Pool", "Tunnel", None).unwrap();
+        assert!(created.get_luid().is_ok());
+        assert!(matches!(
+            Adapter::create(&wireguard, "Pool", "tunnel", None),
+            Err(Error::Win32(ERROR_ALREADY_EXISTS))
+        ));
+        let opened = Adapter::open(&wireguard, "Tunnel").unwrap();
+        assert_eq!(opened.pool(), "Pool");
+        assert_eq!(opened.get_luid().unwrap(), created.get_luid().unwrap());
+        created.up().unwrap();
+        assert_eq!(opened.get_state().unwrap(), AdapterState::Up);
+        assert_eq!(
+            Adapter::enumerate(&wireguard, "Pool").unwrap(),
+            vec!["Tunnel".to_owned()]
+        );
+        assert!(matches!(
+            crate::delete_driver(&wireguard),
+            Err(Error::DriverInUse)
+        ));
+
+        //Dropping the creating handle removes the adapter, even while it is open elsewhere
+        drop(created);
+        assert!(matches!(
+            Adapter::open(&wireguard, "Tunnel"),
+            Err(Error::AdapterNotFound)
+        ));
+        assert!(opened.get_config().is_err());
+        drop(opened);
+
+        crate::delete_driver(&wireguard).unwrap();
+        assert!(matches!(
+            crate::ensure_driver_present(&wireguard),
+            Err(Error::DriverNotInstalled)
+        ));
+        assert!(matches!(
+            Adapter::open_or_create(&wireguard, "Pool", "Tunnel", None),
+            Err(Error::DriverNotInstalled)
+        ));
+        wireguard.set_driver_version(Some(DriverVersion { major: 0, minor: 9 }));
+        assert!(!crate::driver_capabilities(&wireguard).adapter_api);
+    }
+
+    #[test]
+    fn raw_bindings_version() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        assert!(crate::check_raw_bindings_version(&wireguard).unwrap());
+        wireguard.set_driver_version(Some(DriverVersion { major: 1, minor: 0 }));
+        assert!(!crate::check_raw_bindings_version(&wireguard).unwrap());
+        wireguard.set_driver_version(None);
+        assert!(crate::check_raw_bindings_version(&wireguard).is_err());
+    }
+
+    #[test]
+    fn explicit_close() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let created = Adapter::create(&wireguard, "Pool", "Closed", None).unwrap();
+        assert_eq!(created.origin(), AdapterOrigin::Created);
+        //Closing an opened handle leaves the adapter installed
+        let opened = Adapter::open_or_create(&wireguard, "Pool", "Closed", None).unwrap();
+        assert_eq!(opened.origin(), AdapterOrigin::Opened);
+        opened.close().unwrap();
+        assert!(Adapter::open(&wireguard, "Closed").is_ok());
+        created.close().unwrap();
+        assert!(matches!(
+            Adapter::open(&wireguard, "Closed"),
+            Err(Error::AdapterNotFound)
+        ));
+    }
+
+    #[test]
+    fn remove_allowed_ip() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let adapter = Adapter::create(&wireguard, "WireGuard", "Revoke", None).unwrap();
+        let mut first = peer(1, "10.0.0.0/24");
+        first.add_allowed_ip("10.0.1.0/24".parse::<AllowedIp>().unwrap());
+        first.endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        adapter.add_peers(&[first, peer(2, "10.0.2.0/24")]).unwrap();
+
+        let key = Key::new([1; 32]);
+        adapter
+            .remove_allowed_ip(&key, "10.0.0.7/24".parse::<AllowedIp>().unwrap())
+            .unwrap();
+        let peers = adapter.get_config().unwrap().peers;
+        assert_eq!(peers[0].allowed_ips, peer(1, "10.0.1.0/24").allowed_ips);
+        assert_eq!(peers[0].endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(peers[1].allowed_ips, peer(2, "10.0.2.0/24").allowed_ips);
+
+        //Removing again, or from a missing peer, sends nothing
+        wireguard.clear_calls();
+        adapter
+            .remove_allowed_ip(&key, "10.0.0.0/24".parse::<AllowedIp>().unwrap())
+            .unwrap();
+        #[cfg(feature = "ipnet")]
+        adapter
+            .remove_allowed_ip(&key, "10.0.0.0/24".parse::<ipnet::IpNet>().unwrap())
+            .unwrap();
+        adapter
+            .remove_allowed_ip(
+                &Key::new([3; 32]),
+                "10.0.0.0/24".parse::<AllowedIp>().unwrap(),
+            )
+            .unwrap();
+        assert!(!wireguard
+            .calls()
+            .iter()
+            .any(|call| matches!(call, MockCall::SetConfiguration { .. })));
+    }
+
+    #[test]
+    fn config_into_buffer() {
+        let wireguard = Arc::new(MockWireGuard::new());
+        let adapter = Adapter::create(&wireguard, "WireGuard", "Poll", None).unwrap();
+        let mut first = peer(1, "10.0.0.0/24");
+        first.endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        adapter.add_peers(&[first, peer(2, "10.0.2.0/24")]).unwrap();
+
+        let mut buf = Vec::new();
+        let config = adapter.get_config_into(&mut buf).unwrap();
+        let owned = adapter.get_config().unwrap();
+        assert_eq!(config.to_interface().peers, owned.peers);
+        let mut peers = config.peers();
+        assert_eq!(peers.len(), 2);
+        let first = peers.next().unwrap();
+        assert_eq!(first.public_key(), &Key::new([1; 32]));
+        assert_eq!(first.endpoint(), owned.peers[0].endpoint);
+        assert!(first
+            .allowed_ips()
+            .eq(owned.peers[0].allowed_ips.iter().copied()));
+
+        //Polling again with the same buffer does not reallocate it
+        let capacity = buf.capacity();
+        let ptr = buf.as_ptr();
+        assert_eq!(adapter.get_config_into(&mut buf).unwrap().peers().len(), 2);
+        assert_eq!((buf.capacity(), buf.as_ptr()), (capacity, ptr));
+    }
+}
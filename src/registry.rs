@@ -0,0 +1,225 @@
+use crate::util::to_wide;
+use crate::Error;
+
+use std::ptr;
+
+use widestring::U16CStr;
+use winapi::shared::minwindef::HKEY;
+use winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, ERROR_MORE_DATA, ERROR_NO_MORE_ITEMS, ERROR_SUCCESS,
+};
+use winapi::um::winnt::{
+    KEY_READ, KEY_SET_VALUE, REG_DWORD, REG_MULTI_SZ, REG_OPTION_NON_VOLATILE, REG_SZ,
+};
+use winapi::um::winreg::{
+    RegCloseKey, RegCreateKeyExW, RegDeleteTreeW, RegEnumKeyExW, RegGetValueW, RegOpenKeyExW,
+    RegSetValueExW, HKEY_LOCAL_MACHINE, REGSAM, RRF_RT_REG_SZ,
+};
+
+/// The longest key name allowed by the registry, including the null terminator
+const MAX_KEY_LENGTH: usize = 256;
+
+/// An open registry key that is closed on drop
+pub(crate) struct RegKey(HKEY);
+
+impl RegKey {
+    /// Opens `path` under `HKEY_LOCAL_MACHINE` for reading.
+    /// Returns `None` if the key does not exist
+    pub(crate) fn open_local_machine(path: &str) -> Result<Option<RegKey>, Error> {
+        Self::open(path, KEY_READ)
+    }
+
+    /// Opens `path` under `HKEY_LOCAL_MACHINE` for reading and setting values.
+    /// Returns `None` if the key does not exist
+    pub(crate) fn open_local_machine_writeable(path: &str) -> Result<Option<RegKey>, Error> {
+        Self::open(path, KEY_READ | KEY_SET_VALUE)
+    }
+
+    /// Opens `path` under `HKEY_LOCAL_MACHINE` for reading and setting values, creating it and
+    /// any missing parent keys first
+    pub(crate) fn create_local_machine(path: &str) -> Result<RegKey, Error> {
+        let path = to_wide(path)?;
+        let mut key: HKEY = ptr::null_mut();
+        //SAFETY: `path` is null terminated and `key` is a valid out pointer. The class, security
+        //attributes and disposition are optional
+        let err = unsafe {
+            RegCreateKeyExW(
+                HKEY_LOCAL_MACHINE,
+                path.as_ptr(),
+                0,
+                ptr::null_mut(),
+                REG_OPTION_NON_VOLATILE,
+                KEY_READ | KEY_SET_VALUE,
+                ptr::null_mut(),
+                &mut key,
+                ptr::null_mut(),
+            )
+        };
+        match err as u32 {
+            ERROR_SUCCESS => Ok(RegKey(key)),
+            err => Err(access_error(err)),
+        }
+    }
+
+    /// Deletes `path` under `HKEY_LOCAL_MACHINE` together with all of its subkeys and values.
+    /// Succeeds if the key does not exist
+    pub(crate) fn delete_local_machine_tree(path: &str) -> Result<(), Error> {
+        let path = to_wide(path)?;
+        //SAFETY: `path` is null terminated
+        let err = unsafe { RegDeleteTreeW(HKEY_LOCAL_MACHINE, path.as_ptr()) };
+        match err as u32 {
+            ERROR_SUCCESS | ERROR_FILE_NOT_FOUND => Ok(()),
+            err => Err(access_error(err)),
+        }
+    }
+
+    fn open(path: &str, access: REGSAM) -> Result<Option<RegKey>, Error> {
+        let path = to_wide(path)?;
+        let mut key: HKEY = ptr::null_mut();
+        //SAFETY: `path` is null terminated and `key` is a valid out pointer
+        let err = unsafe { RegOpenKeyExW(HKEY_LOCAL_MACHINE, path.as_ptr(), 0, access, &mut key) };
+        match err as u32 {
+            ERROR_SUCCESS => Ok(Some(RegKey(key))),
+            ERROR_FILE_NOT_FOUND => Ok(None),
+            err => Err(access_error(err)),
+        }
+    }
+
+    /// Returns the names of the direct subkeys of this key
+    pub(crate) fn subkeys(&self) -> Result<Vec<String>, Error> {
+        let mut subkeys = Vec::new();
+        for index in 0.. {
+            let mut name = [0u16; MAX_KEY_LENGTH];
+            let mut len = name.len() as u32;
+            //SAFETY: `name` is writeable for `len` characters
+            let err = unsafe {
+                RegEnumKeyExW(
+                    self.0,
+                    index,
+                    name.as_mut_ptr(),
+                    &mut len,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            match err as u32 {
+                ERROR_SUCCESS => subkeys.push(String::from_utf16_lossy(&name[..len as usize])),
+                ERROR_NO_MORE_ITEMS => break,
+                err => return Err(Error::Win32(err)),
+            }
+        }
+        Ok(subkeys)
+    }
+
+    /// Reads the string value `value` of `subkey`, relative to this key.
+    /// Returns `None` if the subkey or value does not exist
+    pub(crate) fn get_string(&self, subkey: &str, value: &str) -> Result<Option<String>, Error> {
+        let subkey = to_wide(subkey)?;
+        let value = to_wide(value)?;
+        let mut buf: Vec<u16> = vec![0; MAX_KEY_LENGTH];
+        loop {
+            let mut size = (buf.len() * 2) as u32;
+            //SAFETY: `buf` is writeable for `size` bytes and both strings are null terminated
+            let err = unsafe {
+                RegGetValueW(
+                    self.0,
+                    subkey.as_ptr(),
+                    value.as_ptr(),
+                    RRF_RT_REG_SZ,
+                    ptr::null_mut(),
+                    buf.as_mut_ptr().cast(),
+                    &mut size,
+                )
+            };
+            match err as u32 {
+                ERROR_SUCCESS => {
+                    //RegGetValueW always null terminates strings that it returns
+                    let s = U16CStr::from_slice_with_nul(&buf[..(size as usize / 2)])
+                        .map_err(|_| Error::InvalidConfig("Invalid registry string".to_owned()))?;
+                    return Ok(Some(s.to_string_lossy()));
+                }
+                ERROR_FILE_NOT_FOUND => return Ok(None),
+                //`size` now holds the required size in bytes
+                ERROR_MORE_DATA => buf.resize(size as usize / 2 + 1, 0),
+                err => return Err(Error::Win32(err)),
+            }
+        }
+    }
+
+    /// Sets the string value `value` of this key to `data`, creating the value if needed
+    pub(crate) fn set_string(&self, value: &str, data: &str) -> Result<(), Error> {
+        let data = to_wide(data)?;
+        self.set_value(value, REG_SZ, data.as_ptr().cast(), data.len() * 2)
+    }
+
+    /// Sets the multi-string value `value` of this key to `data`, creating the value if needed
+    pub(crate) fn set_multi_string(&self, value: &str, data: &[&str]) -> Result<(), Error> {
+        let data = multi_string(data)?;
+        self.set_value(value, REG_MULTI_SZ, data.as_ptr().cast(), data.len() * 2)
+    }
+
+    /// Sets the `DWORD` value `value` of this key to `data`, creating the value if needed
+    pub(crate) fn set_dword(&self, value: &str, data: u32) -> Result<(), Error> {
+        self.set_value(value, REG_DWORD, (&data as *const u32).cast(), 4)
+    }
+
+    /// Sets `value` to the `len` bytes at `data`, which must hold a value of type `kind`
+    fn set_value(&self, value: &str, kind: u32, data: *const u8, len: usize) -> Result<(), Error> {
+        let value = to_wide(value)?;
+        //SAFETY: the callers pass a `data` that is readable for `len` bytes, and `value` is null
+        //terminated
+        let err = unsafe { RegSetValueExW(self.0, value.as_ptr(), 0, kind, data, len as u32) };
+        match err as u32 {
+            ERROR_SUCCESS => Ok(()),
+            err => Err(access_error(err)),
+        }
+    }
+}
+
+/// Encodes `strings` as a `REG_MULTI_SZ`: each string is null terminated and the list ends with an
+/// extra null. Empty strings cannot be stored, because they would end the list early
+fn multi_string(strings: &[&str]) -> Result<Vec<u16>, Error> {
+    let mut data = Vec::new();
+    for s in strings {
+        if s.is_empty() {
+            return Err(Error::InvalidConfig(
+                "Empty strings cannot be stored in a registry string list".to_owned(),
+            ));
+        }
+        data.extend_from_slice(&to_wide(s)?);
+    }
+    data.push(0);
+    Ok(data)
+}
+
+/// Builds the error for a failed registry call, using [`Error::AccessDenied`] when the caller
+/// lacks the rights to the key
+fn access_error(err: u32) -> Error {
+    match err {
+        ERROR_ACCESS_DENIED => Error::AccessDenied(std::io::Error::from_raw_os_error(err as i32)),
+        err => Error::Win32(err),
+    }
+}
+
+impl Drop for RegKey {
+    fn drop(&mut self) {
+        //SAFETY: The key was opened by `open` and is only closed here
+        unsafe { RegCloseKey(self.0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_string_layout() {
+        let data = multi_string(&["ab", "c"]).unwrap();
+        let expected: Vec<u16> = "ab\0c\0\0".encode_utf16().collect();
+        assert_eq!(data, expected);
+        assert_eq!(multi_string(&[]).unwrap(), vec![0]);
+        assert!(multi_string(&["a", ""]).is_err());
+    }
+}
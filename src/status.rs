@@ -0,0 +1,340 @@
+use crate::{AdapterState, Interface, Key, Peer};
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// A snapshot of the state, listen port and peers of an adapter, returned by
+/// [`crate::Adapter::status`].
+///
+/// The `Display` output is a summary in the style of `wg show`, with the latest handshake of each
+/// peer relative to the time it is formatted. Private and preshared keys are never shown
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Status {
+    /// The name of the adapter
+    pub name: String,
+
+    /// Whether the adapter is up
+    pub state: AdapterState,
+
+    /// The public key of the interface, or `None` if no private key is set
+    pub public_key: Option<Key>,
+
+    /// The port the interface listens on, or `None` if it has none yet
+    pub listen_port: Option<u16>,
+
+    /// The peers of the adapter, with their allowed ips, traffic counters and latest handshake
+    pub peers: Vec<Peer>,
+}
+
+impl Status {
+    /// Writes the summary for the current time `now`
+    fn fmt_at(&self, f: &mut fmt::Formatter<'_>, now: SystemTime) -> fmt::Result {
+        writeln!(f, "interface: {}", self.name)?;
+        let state = match self.state {
+            AdapterState::Up => "up",
+            AdapterState::Down => "down",
+        };
+        writeln!(f, "  state: {}", state)?;
+        if let Some(public_key) = &self.public_key {
+            writeln!(f, "  public key: {}", public_key)?;
+            writeln!(f, "  private key: (hidden)")?;
+        }
+        if let Some(listen_port) = self.listen_port {
+            writeln!(f, "  listening port: {}", listen_port)?;
+        }
+
+        for peer in &self.peers {
+            writeln!(f)?;
+            writeln!(f, "peer: {}", peer.public_key)?;
+            if peer.preshared_key.is_some() {
+                writeln!(f, "  preshared key: (hidden)")?;
+            }
+            if let Some(endpoint) = peer.endpoint {
+                writeln!(f, "  endpoint: {}", endpoint)?;
+            }
+            write!(f, "  allowed ips: ")?;
+            if peer.allowed_ips.is_empty() {
+                write!(f, "(none)")?;
+            }
+            for (i, allowed_ip) in peer.allowed_ips.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", allowed_ip)?;
+            }
+            writeln!(f)?;
+            if let Some(handshake) = peer.last_handshake {
+                match now.duration_since(handshake) {
+                    Ok(age) if age.as_secs() > 0 => {
+                        writeln!(f, "  latest handshake: {} ago", Span(age))?
+                    }
+                    //A handshake after `now` is seen when the clock was set back
+                    _ => writeln!(f, "  latest handshake: Now")?,
+                }
+            }
+            if peer.rx_bytes != 0 || peer.tx_bytes != 0 {
+                writeln!(
+                    f,
+                    "  transfer: {} received, {} sent",
+                    Bytes(peer.rx_bytes),
+                    Bytes(peer.tx_bytes)
+                )?;
+            }
+            if let Some(keepalive) = peer.persistent_keepalive.filter(|k| k.as_secs() > 0) {
+                writeln!(f, "  persistent keepalive: every {}", Span(keepalive))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_at(f, SystemTime::now())
+    }
+}
+
+/// Writes `config` in the tab separated format of `wg show <interface> dump`: a line with the
+/// private key, public key, listen port and fwmark of the interface, then a line per peer with its
+/// public key, preshared key, endpoint, allowed ips, latest handshake as Unix time, received and
+/// sent bytes and persistent keepalive. Missing values are written as `(none)`, `0` or `off`, as
+/// `wg` writes them
+pub(crate) fn write_dump(out: &mut impl fmt::Write, config: &Interface) -> fmt::Result {
+    let key = |key: &Option<Key>| match key {
+        Some(key) => key.to_base64(),
+        None => "(none)".to_owned(),
+    };
+    //`wg` writes the fwmark in hex, WireGuard NT has none
+    let mark = match config.mark.filter(|&mark| mark != 0) {
+        Some(mark) => format!("0x{:x}", mark),
+        None => "off".to_owned(),
+    };
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}",
+        key(&config.private_key),
+        key(&config.public_key),
+        config.listen_port.unwrap_or(0),
+        mark
+    )?;
+
+    for peer in &config.peers {
+        write!(out, "{}\t{}\t", peer.public_key, key(&peer.preshared_key))?;
+        match peer.endpoint {
+            Some(endpoint) => write!(out, "{}\t", endpoint)?,
+            None => write!(out, "(none)\t")?,
+        }
+        if peer.allowed_ips.is_empty() {
+            write!(out, "(none)")?;
+        }
+        for (i, allowed_ip) in peer.allowed_ips.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "{}", allowed_ip)?;
+        }
+        let handshake = peer
+            .last_handshake
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        write!(
+            out,
+            "\t{}\t{}\t{}\t",
+            handshake, peer.rx_bytes, peer.tx_bytes
+        )?;
+        match peer.persistent_keepalive.filter(|k| k.as_secs() > 0) {
+            Some(keepalive) => writeln!(out, "{}", keepalive.as_secs())?,
+            None => writeln!(out, "off")?,
+        }
+    }
+    Ok(())
+}
+
+/// Formats a duration in whole seconds as `wg` does, such as "1 hour, 2 seconds"
+struct Span(Duration);
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let units = [
+            (secs / (365 * 24 * 60 * 60), "year"),
+            (secs / (24 * 60 * 60) % 365, "day"),
+            (secs / (60 * 60) % 24, "hour"),
+            (secs / 60 % 60, "minute"),
+            (secs % 60, "second"),
+        ];
+        let mut first = true;
+        for &(count, unit) in units.iter().filter(|(count, _)| *count > 0) {
+            if !first {
+                write!(f, ", ")?;
+            }
+            first = false;
+            let plural = if count == 1 { "" } else { "s" };
+            write!(f, "{} {}{}", count, unit, plural)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a byte count with binary prefixes as `wg` does, such as "1.50 KiB"
+struct Bytes(u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const KIB: f64 = 1024.0;
+        let bytes = self.0;
+        let b = bytes as f64;
+        if bytes < 1024 {
+            write!(f, "{} B", bytes)
+        } else if b < KIB * KIB {
+            write!(f, "{:.2} KiB", b / KIB)
+        } else if b < KIB * KIB * KIB {
+            write!(f, "{:.2} MiB", b / (KIB * KIB))
+        } else if b < KIB * KIB * KIB * KIB {
+            write!(f, "{:.2} GiB", b / (KIB * KIB * KIB))
+        } else {
+            write!(f, "{:.2} TiB", b / (KIB * KIB * KIB * KIB))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Formats `status` as if the current time were `now`
+    struct At<'a>(&'a Status, SystemTime);
+
+    impl fmt::Display for At<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt_at(f, self.1)
+        }
+    }
+
+    #[test]
+    fn spans_and_bytes() {
+        assert_eq!(Span(Duration::from_secs(1)).to_string(), "1 second");
+        assert_eq!(
+            Span(Duration::from_secs(62)).to_string(),
+            "1 minute, 2 seconds"
+        );
+        assert_eq!(
+            Span(Duration::from_secs(3600 + 2)).to_string(),
+            "1 hour, 2 seconds"
+        );
+        assert_eq!(
+            Span(Duration::from_secs(366 * 24 * 3600)).to_string(),
+            "1 year, 1 day"
+        );
+        assert_eq!(Bytes(1023).to_string(), "1023 B");
+        assert_eq!(Bytes(1536).to_string(), "1.50 KiB");
+        assert_eq!(Bytes(5 * 1024 * 1024).to_string(), "5.00 MiB");
+        assert_eq!(Bytes(3 << 30).to_string(), "3.00 GiB");
+        assert_eq!(Bytes(2 << 40).to_string(), "2.00 TiB");
+    }
+
+    #[test]
+    fn wg_show_summary() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut connected = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .preshared_key(Key::new([9; 32]))
+            .endpoint("192.0.2.1:51820".parse().unwrap())
+            .allowed_ip("10.0.0.0/24".parse::<crate::AllowedIp>().unwrap())
+            .allowed_ip("fd00::/64".parse::<crate::AllowedIp>().unwrap())
+            .persistent_keepalive(Duration::from_secs(25))
+            .build()
+            .unwrap();
+        connected.last_handshake = Some(now - Duration::from_secs(62));
+        connected.rx_bytes = 1536;
+        connected.tx_bytes = 100;
+        let waiting = Peer::builder()
+            .public_key(Key::new([2; 32]))
+            .build()
+            .unwrap();
+        let status = Status {
+            name: "wg0".to_owned(),
+            state: AdapterState::Up,
+            public_key: Some(Key::new([3; 32])),
+            listen_port: Some(51820),
+            peers: vec![connected, waiting],
+        };
+
+        let expected = format!(
+            "interface: wg0
+  state: up
+  public key: {}
+  private key: (hidden)
+  listening port: 51820
+
+peer: {}
+  preshared key: (hidden)
+  endpoint: 192.0.2.1:51820
+  allowed ips: 10.0.0.0/24, fd00::/64
+  latest handshake: 1 minute, 2 seconds ago
+  transfer: 1.50 KiB received, 100 B sent
+  persistent keepalive: every 25 seconds
+
+peer: {}
+  allowed ips: (none)
+",
+            Key::new([3; 32]),
+            Key::new([1; 32]),
+            Key::new([2; 32])
+        );
+        assert_eq!(At(&status, now).to_string(), expected);
+        //The preshared key itself is never printed
+        assert!(!expected.contains(&Key::new([9; 32]).to_string()));
+    }
+
+    #[test]
+    fn dump() {
+        let mut connected = Peer::builder()
+            .public_key(Key::new([1; 32]))
+            .preshared_key(Key::new([9; 32]))
+            .endpoint("[2001:db8::1]:51820".parse().unwrap())
+            .allowed_ip("10.0.0.0/24".parse::<crate::AllowedIp>().unwrap())
+            .allowed_ip("fd00::/64".parse::<crate::AllowedIp>().unwrap())
+            .persistent_keepalive(Duration::from_secs(25))
+            .build()
+            .unwrap();
+        connected.last_handshake =
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_637_625_600_500));
+        connected.rx_bytes = 1536;
+        connected.tx_bytes = 100;
+        let waiting = Peer::builder()
+            .public_key(Key::new([2; 32]))
+            .build()
+            .unwrap();
+        let config = Interface {
+            listen_port: Some(51820),
+            private_key: Some(Key::new([3; 32])),
+            public_key: Some(Key::new([4; 32])),
+            peers: vec![connected, waiting],
+            ..Default::default()
+        };
+
+        let mut out = String::new();
+        write_dump(&mut out, &config).unwrap();
+        let expected = format!(
+            "{}\t{}\t51820\toff\n\
+             {}\t{}\t[2001:db8::1]:51820\t10.0.0.0/24,fd00::/64\t1637625600\t1536\t100\t25\n\
+             {}\t(none)\t(none)\t(none)\t0\t0\t0\toff\n",
+            Key::new([3; 32]),
+            Key::new([4; 32]),
+            Key::new([1; 32]),
+            Key::new([9; 32]),
+            Key::new([2; 32])
+        );
+        assert_eq!(out, expected);
+
+        let empty = Interface {
+            mark: Some(0x1234),
+            ..Default::default()
+        };
+        out.clear();
+        write_dump(&mut out, &empty).unwrap();
+        assert_eq!(out, "(none)\t(none)\t0\t0x1234\n");
+    }
+}
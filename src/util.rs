@@ -2,20 +2,479 @@
 //In this file we are explicit about unsafe, even in unsafe functions
 //Hopefully one day we won't need this
 
-use crate::wireguard_nt_raw;
+use crate::{Error, WireGuardApi};
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
 use std::{alloc::Layout, sync::Arc};
+use winapi::shared::winerror::ERROR_FILE_NOT_FOUND;
+use winapi::um::errhandlingapi::GetLastError;
 
-/// A wrapper struct that allows a type to be Send and Sync
+/// A wrapper struct that allows a type to be Send and Sync. Only used for handles that are never
+/// dereferenced in Rust
 pub(crate) struct UnsafeHandle<T>(pub T);
 
 /// We never read from the pointer. It only serves as a handle we pass to the kernel or C code that
-/// doesn't have the same mutable aliasing restrictions we have in Rust
+/// doesn't have the same mutable aliasing restrictions we have in Rust. The WireGuard NT functions
+/// that take an adapter handle may be called from any thread, and concurrently
 unsafe impl<T> Send for UnsafeHandle<T> {}
 unsafe impl<T> Sync for UnsafeHandle<T> {}
 
 /// Returns the major and minor version of the wireguard driver
-pub fn get_running_driver_version(wireguard: &Arc<wireguard_nt_raw::wireguard>) -> u32 {
-    unsafe { wireguard.WireGuardGetRunningDriverVersion() }
+#[deprecated(note = "use `get_running_driver_version_typed`, which returns a `DriverVersion`")]
+pub fn get_running_driver_version<A: WireGuardApi>(wireguard: &Arc<A>) -> u32 {
+    unsafe { wireguard.get_running_driver_version() }
+}
+
+/// The version of a WireGuard NT driver. Versions compare by major, then minor version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DriverVersion {
+    /// The major version
+    pub major: u16,
+
+    /// The minor version
+    pub minor: u16,
+}
+
+impl DriverVersion {
+    /// Unpacks a version as returned by `WireGuardGetRunningDriverVersion`, which holds the major
+    /// version in the high 16 bits and the minor version in the low 16 bits
+    pub fn from_raw(raw: u32) -> Self {
+        Self {
+            major: (raw >> 16) as u16,
+            minor: raw as u16,
+        }
+    }
+
+    /// Returns this version packed in the format used by `WireGuardGetRunningDriverVersion`
+    pub fn raw(self) -> u32 {
+        (self.major as u32) << 16 | self.minor as u32
+    }
+}
+
+impl std::fmt::Display for DriverVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Returns the version of the running wireguard driver, or an error if the driver is not loaded
+pub fn get_running_driver_version_typed<A: WireGuardApi>(
+    wireguard: &Arc<A>,
+) -> Result<DriverVersion, Error> {
+    match unsafe { wireguard.get_running_driver_version() } {
+        0 => Err(Error::last_win32()),
+        raw => Ok(DriverVersion::from_raw(raw)),
+    }
+}
+
+/// Checks that the running wireguard driver has at least version `min`, returning
+/// [`Error::DriverVersionUnsupported`] with both versions if it is older. Fails with the error of
+/// [`get_running_driver_version_typed`] if no driver is running.
+///
+/// Programs that rely on features of newer drivers can call this at startup to fail early with an
+/// actionable message
+pub fn require_driver_version<A: WireGuardApi>(
+    wireguard: &Arc<A>,
+    min: DriverVersion,
+) -> Result<(), Error> {
+    check_driver_version(get_running_driver_version_typed(wireguard)?, min)
+}
+
+/// The driver version whose `wireguard.h` the raw bindings of this crate were generated from. The
+/// driver only reports its major and minor version, so this is the minor version of that header
+pub const RAW_BINDINGS_VERSION: DriverVersion = DriverVersion {
+    major: 0,
+    minor: 10,
+};
+
+/// Compares the version of the running wireguard driver with [`RAW_BINDINGS_VERSION`], and returns
+/// whether they match. On a mismatch, a [`crate::LogLevel::Warn`] message naming both versions is
+/// sent to the logger that receives the driver's messages.
+///
+/// The structs passed to wireguard.dll are laid out as in the header the bindings were generated
+/// from, so a driver of another version may read them differently. Creating or opening an adapter
+/// does not check this. The check only reads the driver version, so programs that want the
+/// warning can call it once at startup. Fails with the error of
+/// [`get_running_driver_version_typed`] if no driver is running
+pub fn check_raw_bindings_version<A: WireGuardApi>(wireguard: &Arc<A>) -> Result<bool, Error> {
+    let found = get_running_driver_version_typed(wireguard)?;
+    if found == RAW_BINDINGS_VERSION {
+        return Ok(true);
+    }
+    let message = format!(
+        "The running WireGuard NT driver has version {}, but the bindings of this crate were generated for version {}",
+        found, RAW_BINDINGS_VERSION
+    );
+    crate::log::log_message(crate::LogLevel::Warn, &message);
+    Ok(false)
+}
+
+/// Checks that the WireGuard NT kernel driver is running, returning [`Error::DriverNotInstalled`]
+/// if it is not.
+///
+/// Loading wireguard.dll succeeds whether or not the driver is installed. WireGuard NT installs
+/// and starts the driver when the first adapter is created, and it stays loaded while adapters
+/// exist, so before any adapter was created since boot this also fails. It is most useful after
+/// [`crate::Adapter::create`] failed, to tell a driver that could not be installed from other
+/// errors. [`crate::Adapter::open_or_create`] does this itself
+pub fn ensure_driver_present<A: WireGuardApi>(wireguard: &Arc<A>) -> Result<(), Error> {
+    match unsafe { wireguard.get_running_driver_version() } {
+        0 => Err(driver_error(unsafe { GetLastError() })),
+        _ => Ok(()),
+    }
+}
+
+/// Maps the error code of a failed `WireGuardGetRunningDriverVersion` call. The driver reports
+/// `ERROR_FILE_NOT_FOUND` when it is not loaded
+fn driver_error(code: u32) -> Error {
+    match code {
+        ERROR_FILE_NOT_FOUND => Error::DriverNotInstalled,
+        code => Error::Win32(code),
+    }
+}
+
+/// Returns [`Error::DriverVersionUnsupported`] if `found` is older than `required`
+fn check_driver_version(found: DriverVersion, required: DriverVersion) -> Result<(), Error> {
+    if found < required {
+        Err(Error::DriverVersionUnsupported { found, required })
+    } else {
+        Ok(())
+    }
+}
+
+/// The first driver version with the adapter API that this crate is written against. Earlier
+/// versions changed the API in incompatible ways
+const ADAPTER_API_VERSION: DriverVersion = DriverVersion {
+    major: 0,
+    minor: 10,
+};
+
+/// The first driver version that accepts the `WIREGUARD_PEER_UPDATE` and `WIREGUARD_PEER_REMOVE`
+/// flags. The driver only reports its major and minor version, so this is the first minor
+/// version whose header includes them
+const PEER_UPDATE_VERSION: DriverVersion = DriverVersion {
+    major: 0,
+    minor: 10,
+};
+
+/// The first driver version that can prefix log messages with the adapter id
+const LOG_PREFIX_VERSION: DriverVersion = DriverVersion {
+    major: 0,
+    minor: 10,
+};
+
+/// The optional features of the running WireGuard NT driver, as returned by
+/// [`driver_capabilities`]. More fields are added as newer drivers gain features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DriverCapabilities {
+    /// The version of the running driver, or `None` if no driver is running
+    pub version: Option<DriverVersion>,
+
+    /// The driver has the adapter API this crate uses. Without it, adapters cannot be created or
+    /// configured
+    pub adapter_api: bool,
+
+    /// Single peers can be updated or removed without replacing the others, as done by
+    /// [`crate::Adapter::update_peer`], [`crate::Adapter::set_peer_endpoint`] and
+    /// [`crate::Adapter::remove_peer`]
+    pub peer_update: bool,
+
+    /// Log messages can carry the adapter id, see [`crate::AdapterLoggingLevel::OnWithPrefix`]
+    pub log_with_prefix: bool,
+}
+
+impl DriverCapabilities {
+    /// Returns the capabilities of a driver with version `version`, or of no driver if `None`
+    pub fn from_version(version: Option<DriverVersion>) -> Self {
+        let at_least = |min: DriverVersion| matches!(version, Some(version) if version >= min);
+        Self {
+            version,
+            adapter_api: at_least(ADAPTER_API_VERSION),
+            peer_update: at_least(PEER_UPDATE_VERSION),
+            log_with_prefix: at_least(LOG_PREFIX_VERSION),
+        }
+    }
+}
+
+/// Checks the version of the running wireguard driver and returns which optional features it
+/// supports. If no driver is running, for example because no adapter was created yet since boot,
+/// every capability is `false`
+pub fn driver_capabilities<A: WireGuardApi>(wireguard: &Arc<A>) -> DriverCapabilities {
+    DriverCapabilities::from_version(get_running_driver_version_typed(wireguard).ok())
+}
+
+/// Uninstalls the WireGuard NT kernel driver.
+///
+/// The driver can only be removed once every WireGuard adapter on the system is gone. Adapters are
+/// removed when the [`crate::Adapter`] that created them is dropped, so this returns
+/// [`Error::DriverInUse`] while any adapter, including one owned by another process, still exists.
+/// Callers can retry after closing their adapters
+pub fn delete_driver<A: WireGuardApi>(wireguard: &Arc<A>) -> Result<(), Error> {
+    if !crate::adapter::adapter_names(&**wireguard, None)?.is_empty() {
+        return Err(Error::DriverInUse);
+    }
+    match unsafe { wireguard.delete_driver() } {
+        0 => Err(Error::last_win32()),
+        _ => Ok(()),
+    }
+}
+
+/// Adds the driver package described by the INF file at `inf_path` to the driver store with
+/// `SetupCopyOEMInfW`, so that Plug and Play installs it when an adapter is created.
+///
+/// wireguard.dll contains its own copy of the driver and installs it when the first adapter is
+/// created, so this is only needed when the driver package is shipped separately, for example to
+/// provision it from an installer ahead of time. The `.cat` and `.sys` files the INF refers to
+/// must be in the same directory as the INF. Staging a package that is already in the driver store
+/// succeeds without copying it again. Requires administrator privileges, see [`is_elevated`], and
+/// the `setup` feature
+#[cfg(feature = "setup")]
+pub fn install_driver(inf_path: &std::path::Path) -> Result<(), Error> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::um::setupapi::{SetupCopyOEMInfW, SPOST_PATH};
+
+    //The driver store records where the package came from, which needs an absolute path
+    let path = if inf_path.is_absolute() {
+        inf_path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .map_err(|e| Error::InvalidConfig(format!("{}: {}", inf_path.display(), e)))?
+            .join(inf_path)
+    };
+    let mut wide: Vec<u16> = path.as_os_str().encode_wide().collect();
+    if wide.contains(&0) {
+        return Err(Error::InvalidConfig(format!(
+            "{} contains a NUL character",
+            path.display()
+        )));
+    }
+    wide.push(0);
+    //SAFETY: `wide` is null terminated, the media location may be null for SPOST_PATH, and no
+    //destination buffer is requested
+    let result = unsafe {
+        SetupCopyOEMInfW(
+            wide.as_ptr(),
+            std::ptr::null(),
+            SPOST_PATH,
+            0,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    match result {
+        0 => Err(Error::last_win32()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if the current process runs elevated, for example after being started with "Run as
+/// administrator". Creating adapters and changing network settings requires an elevated process.
+///
+/// Returns false if the process token cannot be queried
+pub fn is_elevated() -> bool {
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+    use winapi::um::securitybaseapi::GetTokenInformation;
+    use winapi::um::winnt::{TokenElevation, HANDLE, TOKEN_ELEVATION, TOKEN_QUERY};
+
+    let mut token: HANDLE = std::ptr::null_mut();
+    //SAFETY: `GetCurrentProcess` returns a pseudo handle that is always valid and `token` is a
+    //valid out pointer
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return false;
+    }
+    let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+    let mut len: DWORD = 0;
+    //SAFETY: `elevation` is writeable for the size we pass, and `token` was opened with
+    //TOKEN_QUERY access above
+    let ok = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            (&mut elevation as *mut TOKEN_ELEVATION).cast(),
+            std::mem::size_of::<TOKEN_ELEVATION>() as DWORD,
+            &mut len,
+        )
+    };
+    //SAFETY: `token` was opened above and is not used after this
+    unsafe { CloseHandle(token) };
+    ok != 0 && elevation.TokenIsElevated != 0
+}
+
+/// How many times [`query_growing_into`] calls its query before giving up
+const MAX_QUERY_ATTEMPTS: usize = 8;
+
+/// Runs a Win32 style query that fills `buf`, growing it while the query fails with
+/// `ERROR_MORE_DATA`. Repeated queries with the same `buf` reuse its allocation.
+///
+/// `query` is called with a buffer and its length in bytes. It returns the Win32 error code of the
+/// call, `ERROR_SUCCESS` on success. On `ERROR_MORE_DATA` it may update the length to the size it
+/// needs, and the next attempt uses that size or double the current one, whichever is larger.
+/// On success the buffer is truncated to the updated length if that is smaller. Gives up with
+/// `ERROR_MORE_DATA` after a few attempts, since the data being queried can keep growing between
+/// calls.
+///
+/// The first attempt uses all of the capacity of `buf`, or `initial` bytes if that is larger.
+/// Everything in `buf` is overwritten with zeroes before the first attempt
+pub(crate) fn query_growing_into(
+    buf: &mut Vec<u8>,
+    initial: usize,
+    mut query: impl FnMut(&mut [u8], &mut u32) -> i32,
+) -> Result<(), Error> {
+    use winapi::shared::winerror::{ERROR_MORE_DATA, ERROR_SUCCESS};
+
+    let len = buf.capacity().max(initial);
+    buf.clear();
+    buf.resize(len, 0);
+    for _ in 0..MAX_QUERY_ATTEMPTS {
+        let mut size = u32::try_from(buf.len()).map_err(|_| Error::Win32(ERROR_MORE_DATA))?;
+        match query(buf, &mut size) as u32 {
+            ERROR_SUCCESS => {
+                buf.truncate(size as usize);
+                return Ok(());
+            }
+            ERROR_MORE_DATA => {
+                let len = (size as usize).max(buf.len().saturating_mul(2)).max(1);
+                buf.resize(len, 0);
+            }
+            err => return Err(Error::Win32(err)),
+        }
+    }
+    Err(Error::Win32(ERROR_MORE_DATA))
+}
+
+/// Encodes `s` as a null terminated UTF-16 string for passing to Windows. Fails with
+/// [`Error::InvalidConfig`] if `s` contains a NUL character, which would cut the string short
+pub(crate) fn to_wide(s: &str) -> Result<Vec<u16>, Error> {
+    if s.contains('\0') {
+        return Err(Error::InvalidConfig(format!(
+            "{:?} contains a NUL character",
+            s
+        )));
+    }
+    let mut wide: Vec<u16> = s.encode_utf16().collect();
+    wide.push(0);
+    Ok(wide)
+}
+
+/// The functions that wireguard.dll exports and this crate loads
+const DLL_EXPORTS: [&str; 12] = [
+    "WireGuardCreateAdapter",
+    "WireGuardOpenAdapter",
+    "WireGuardCloseAdapter",
+    "WireGuardGetAdapterLUID",
+    "WireGuardGetRunningDriverVersion",
+    "WireGuardDeleteDriver",
+    "WireGuardSetLogger",
+    "WireGuardSetAdapterLogging",
+    "WireGuardGetAdapterState",
+    "WireGuardSetAdapterState",
+    "WireGuardGetConfiguration",
+    "WireGuardSetConfiguration",
+];
+
+/// Maps the error of opening a dll file, returning [`Error::DllNotFound`] if the file, or the
+/// directory it is in, does not exist
+pub(crate) fn open_library_error(e: libloading::Error) -> Error {
+    use winapi::shared::winerror::{ERROR_MOD_NOT_FOUND, ERROR_PATH_NOT_FOUND};
+
+    let code = std::error::Error::source(&e)
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .and_then(io::Error::raw_os_error);
+    match code.map(|code| code as u32) {
+        Some(ERROR_FILE_NOT_FOUND) | Some(ERROR_PATH_NOT_FOUND) | Some(ERROR_MOD_NOT_FOUND) => {
+            Error::DllNotFound
+        }
+        _ => Error::Load(e),
+    }
+}
+
+/// Checks that `library` exports every function of wireguard.dll, returning
+/// [`Error::DllMissingSymbol`] with the first one that is missing
+pub(crate) fn check_exports(library: &libloading::Library) -> Result<(), Error> {
+    for name in DLL_EXPORTS.iter() {
+        //SAFETY: the symbol is only looked up, never called through this type
+        if unsafe { library.get::<unsafe extern "C" fn()>(name.as_bytes()) }.is_err() {
+            return Err(Error::DllMissingSymbol(name));
+        }
+    }
+    Ok(())
+}
+
+/// Counts the dlls written by [`write_embedded_dll`], so that each gets its own file name
+static EMBEDDED_DLL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes `bytes` to a new file in `dir`, named after this process, and returns its path.
+///
+/// The file is created with `create_new`, so an existing file, possibly planted by someone else,
+/// is never reused
+pub(crate) fn write_embedded_dll(dir: &Path, bytes: &[u8]) -> Result<PathBuf, Error> {
+    loop {
+        let count = EMBEDDED_DLL_COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("wireguard-{}-{}.dll", std::process::id(), count));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(bytes)?;
+                file.sync_all()?;
+                return Ok(path);
+            }
+            //Left behind by an earlier process with the same id that still has it loaded
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Deletes the dlls that [`write_embedded_dll`] wrote to `dir` earlier. Windows cannot delete a
+/// dll that is still loaded, so the files of processes that are still running are kept
+pub(crate) fn remove_embedded_dlls(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("wireguard-") && name.ends_with(".dll") {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+const UNIX_EPOCH_AS_FILETIME: u64 = 116_444_736_000_000_000;
+
+/// Converts a Windows FILETIME, the number of 100ns intervals since 1601-01-01 UTC, into a
+/// [`SystemTime`]. WireGuard NT uses a zero FILETIME to mean "never", which maps to `None`
+pub(crate) fn filetime_to_system_time(filetime: u64) -> Option<SystemTime> {
+    if filetime == 0 {
+        return None;
+    }
+    if filetime >= UNIX_EPOCH_AS_FILETIME {
+        let since_unix = filetime - UNIX_EPOCH_AS_FILETIME;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_nanos(since_unix) * 100)
+    } else {
+        let before_unix = UNIX_EPOCH_AS_FILETIME - filetime;
+        SystemTime::UNIX_EPOCH.checked_sub(Duration::from_nanos(before_unix) * 100)
+    }
+}
+
+/// Converts a [`SystemTime`] into a Windows FILETIME, the inverse of [`filetime_to_system_time`].
+/// Times before 1601 map to zero, and times too far in the future for a FILETIME to `u64::MAX`
+pub(crate) fn system_time_to_filetime(time: SystemTime) -> u64 {
+    let intervals =
+        |duration: Duration| u64::try_from(duration.as_nanos() / 100).unwrap_or(u64::MAX);
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since_unix) => UNIX_EPOCH_AS_FILETIME.saturating_add(intervals(since_unix)),
+        Err(e) => UNIX_EPOCH_AS_FILETIME.saturating_sub(intervals(e.duration())),
+    }
 }
 
 pub(crate) struct StructWriter {
@@ -25,14 +484,16 @@ pub(crate) struct StructWriter {
 }
 
 impl StructWriter {
-    /// Creates a struct writer that has the given initial capacity `capacity`,
+    /// Creates a zeroed struct writer that has the given initial capacity `capacity`,
     /// and whose allocation is aligned to `align`
     pub fn new(capacity: usize, align: usize) -> Self {
         let layout = Layout::from_size_align(capacity, align).unwrap();
-        let start = unsafe { std::alloc::alloc(layout) };
-        // Safety:
-        // start is writeable for `capacity` bytes because that is the size of the allocation
-        unsafe { start.write_bytes(0, capacity) };
+        //Large allocations get pages from the OS that are already zeroed, which is much faster
+        //than clearing them after allocating
+        let start = unsafe { std::alloc::alloc_zeroed(layout) };
+        if start.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
         Self {
             start,
             offset: 0,
@@ -79,119 +540,303 @@ impl StructWriter {
         self.start
     }
 
+    /// Returns the size of this writer's allocation in bytes
+    pub fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
     /// Returns true if this writer's capacity is full, false otherwise
     pub fn is_full(&self) -> bool {
         self.layout.size() == self.offset
     }
 }
 
+/// Overwrites `len` bytes at `start` with zeroes in a way the compiler cannot optimize away, so that
+/// key material does not linger in freed memory
+///
+/// # Safety
+/// `start` must be valid for writes of `len` bytes
+unsafe fn zero_memory(start: *mut u8, len: usize) {
+    //Clear a word at a time where `start` is aligned for it, which is most of a large config
+    const WORD: usize = std::mem::size_of::<usize>();
+    let head = start.align_offset(WORD).min(len);
+    let words = (len - head) / WORD;
+    for i in 0..head {
+        unsafe { std::ptr::write_volatile(start.add(i), 0) };
+    }
+    let word_start = unsafe { start.add(head) }.cast::<usize>();
+    for i in 0..words {
+        unsafe { std::ptr::write_volatile(word_start.add(i), 0) };
+    }
+    for i in head + words * WORD..len {
+        unsafe { std::ptr::write_volatile(start.add(i), 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Overwrites all of `bytes` with zeroes, see [`zero_memory`]
+pub(crate) fn zero_bytes(bytes: &mut [u8]) {
+    //SAFETY: the slice is valid for writes of its length
+    unsafe { zero_memory(bytes.as_mut_ptr(), bytes.len()) };
+}
+
 impl Drop for StructWriter {
     fn drop(&mut self) {
+        //The buffer holds the private and preshared keys passed to WireGuardSetConfiguration
+        unsafe { zero_memory(self.start, self.layout.size()) };
         unsafe { std::alloc::dealloc(self.start, self.layout) };
     }
 }
 
-pub(crate) struct StructReader {
-    start: *mut u8,
-    offset: usize,
-    layout: Layout,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl StructReader {
-    /// Creates a struct reader that has the given initial capacity `capacity`,
-    /// and whose allocation is aligned to `align`
-    pub fn new(capacity: usize, align: usize) -> Self {
-        let layout = Layout::from_size_align(capacity, align).unwrap();
-        let start = unsafe { std::alloc::alloc(layout) };
-        Self {
-            start,
-            offset: 0,
-            layout,
-        }
+    #[test]
+    fn load_errors() {
+        let missing = unsafe { crate::load_from_path("no-such-directory\\wireguard.dll") };
+        assert!(matches!(missing, Err(Error::DllNotFound)));
+        //Every Windows process has kernel32.dll loaded, and it exports none of the functions
+        let other = unsafe { crate::load_from_path("kernel32.dll") };
+        assert!(matches!(
+            other,
+            Err(Error::DllMissingSymbol("WireGuardCreateAdapter"))
+        ));
     }
 
-    /// Reads a given type from the internal buffer.
-    /// This advances the internal pointer by the size of the read type, such that a given instance of
-    /// the given type can only be read once.
-    ///
-    /// # Safety
-    /// The caller must ensure the internal pointer is aligned suitably for reading a T.
-    /// In most C APIs (like Wireguard NT) the structs are setup in such a way that calling read
-    /// repeatedly to read packed data always yields a struct that is aligned because the
-    /// previous struct was aligned.
-    ///
-    /// # Panics
-    /// 1. If reading a struct of size T would overflow the buffer.
-    /// 2. If the internal pointer does not meet the alignment requirements of T.
-    pub unsafe fn read<T>(&mut self) -> T {
-        let size = std::mem::size_of::<T>();
-        if size + self.offset > self.layout.size() {
-            panic!(
-                "Overflow attempting to read struct of size {}. To allocation size: {}, offset: {}",
-                size,
-                self.layout.size(),
-                self.offset
-            );
-        }
-        // Safety:
-        // ptr is within this allocation by the bounds check above
-        let ptr = unsafe { self.start.add(self.offset) };
-        self.offset += size;
-        assert_eq!(ptr as usize % std::mem::align_of::<T>(), 0);
+    #[test]
+    #[cfg(feature = "setup")]
+    fn install_missing_driver() {
+        assert!(matches!(
+            install_driver(std::path::Path::new("no-such-directory\\wireguard.inf")),
+            Err(Error::Win32(_))
+        ));
+        assert!(matches!(
+            install_driver(std::path::Path::new("wireguard\0.inf")),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
 
-        std::ptr::read(ptr as _)
+    #[test]
+    #[ignore = "requires administrator privileges"]
+    fn elevated() {
+        assert!(is_elevated());
     }
 
-    pub fn ptr(&self) -> *const u8 {
-        self.start
+    #[test]
+    fn query_growing_retries() {
+        use winapi::shared::winerror::{ERROR_MORE_DATA, ERROR_SUCCESS};
+
+        let mut lengths = Vec::new();
+        let mut buf = Vec::new();
+        query_growing_into(&mut buf, 4, |buf, size| {
+            lengths.push(buf.len());
+            if buf.len() < 20 {
+                //Report a smaller size than needed, which is then doubled instead
+                *size = 5;
+                return ERROR_MORE_DATA as i32;
+            }
+            buf[..10].copy_from_slice(&[7; 10]);
+            *size = 10;
+            ERROR_SUCCESS as i32
+        })
+        .unwrap();
+        assert_eq!(buf, vec![7; 10]);
+        assert_eq!(lengths, vec![4, 8, 16, 32]);
+
+        let mut lengths = Vec::new();
+        query_growing_into(&mut Vec::new(), 0, |buf, size| {
+            lengths.push(buf.len());
+            if buf.len() < 100 {
+                *size = 100;
+                return ERROR_MORE_DATA as i32;
+            }
+            ERROR_SUCCESS as i32
+        })
+        .unwrap();
+        assert_eq!(lengths, vec![0, 100]);
     }
 
-    /// Returns true if this reader's capacity is full, false otherwise
-    pub fn is_full(&self) -> bool {
-        self.layout.size() == self.offset
+    #[test]
+    fn query_growing_errors() {
+        use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_MORE_DATA};
+
+        let mut attempts = 0;
+        let result = query_growing_into(&mut Vec::new(), 1, |_, size| {
+            attempts += 1;
+            *size = 0;
+            ERROR_MORE_DATA as i32
+        });
+        assert!(matches!(result, Err(Error::Win32(ERROR_MORE_DATA))));
+        assert_eq!(attempts, MAX_QUERY_ATTEMPTS);
+
+        let result = query_growing_into(&mut Vec::new(), 1, |_, _| ERROR_ACCESS_DENIED as i32);
+        assert!(matches!(result, Err(Error::Win32(ERROR_ACCESS_DENIED))));
     }
-}
 
-impl Drop for StructReader {
-    fn drop(&mut self) {
-        unsafe { std::alloc::dealloc(self.start, self.layout) };
+    #[test]
+    fn query_growing_reuses_buffer() {
+        use winapi::shared::winerror::{ERROR_MORE_DATA, ERROR_SUCCESS};
+
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&[0xAA; 8]);
+        let mut lengths = Vec::new();
+        query_growing_into(&mut buf, 4, |buf, size| {
+            lengths.push(buf.len());
+            assert!(buf.iter().all(|&b| b == 0));
+            buf[..10].copy_from_slice(&[7; 10]);
+            *size = 10;
+            ERROR_SUCCESS as i32
+        })
+        .unwrap();
+        //The whole capacity is used, and the old contents are cleared first
+        assert_eq!(lengths, vec![64]);
+        assert_eq!(buf, vec![7; 10]);
+
+        lengths.clear();
+        query_growing_into(&mut buf, 4, |buf, size| {
+            lengths.push(buf.len());
+            if buf.len() < 100 {
+                *size = 100;
+                return ERROR_MORE_DATA as i32;
+            }
+            ERROR_SUCCESS as i32
+        })
+        .unwrap();
+        assert_eq!(lengths, vec![64, 128]);
+        assert_eq!(buf.len(), 128);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::mem::{align_of_val, size_of_val};
+    #[test]
+    fn required_driver_version() {
+        let required = DriverVersion {
+            major: 0,
+            minor: 10,
+        };
+        assert!(check_driver_version(required, required).is_ok());
+        assert!(check_driver_version(DriverVersion { major: 1, minor: 0 }, required).is_ok());
+
+        let found = DriverVersion { major: 0, minor: 9 };
+        let err = check_driver_version(found, required).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DriverVersionUnsupported { found: f, required: r } if f == found && r == required
+        ));
+        assert_eq!(
+            err.to_string(),
+            "the running WireGuard NT driver version 0.9 is unsupported, version 0.10 or newer is required"
+        );
+    }
 
     #[test]
-    fn reader_basic() {
-        #[derive(Debug)]
-        #[repr(C)]
-        struct Data {
-            field_a: u8,
-            field_b: u32,
+    fn missing_driver() {
+        assert!(matches!(
+            driver_error(ERROR_FILE_NOT_FOUND),
+            Error::DriverNotInstalled
+        ));
+        assert!(matches!(driver_error(5), Error::Win32(5)));
+    }
+
+    #[test]
+    fn capabilities_by_version() {
+        let none = DriverCapabilities::from_version(None);
+        assert!(!none.adapter_api && !none.peer_update && !none.log_with_prefix);
+
+        let old = DriverCapabilities::from_version(Some(DriverVersion { major: 0, minor: 9 }));
+        assert!(!old.adapter_api && !old.peer_update && !old.log_with_prefix);
+
+        for &(major, minor) in &[(0, 10), (0, 11), (1, 0)] {
+            let version = DriverVersion { major, minor };
+            let capabilities = DriverCapabilities::from_version(Some(version));
+            assert_eq!(capabilities.version, Some(version));
+            assert!(capabilities.adapter_api);
+            assert!(capabilities.peer_update);
+            assert!(capabilities.log_with_prefix);
         }
-        let expected_data = Data {
-            field_a: 0b10000001,
-            field_b: 0x00FFFF00,
-        };
-        let mut reader =
-            StructReader::new(size_of_val(&expected_data), align_of_val(&expected_data));
-        let byte_buffer: &mut [u8; 8] = unsafe { std::mem::transmute(reader.ptr()) };
-        byte_buffer[0] = 0b10000001;
-        byte_buffer[4] = 0x0;
-        byte_buffer[5] = 0xFF;
-        byte_buffer[6] = 0xFF;
-        byte_buffer[7] = 0x0;
-        let actual_data: Data = unsafe { reader.read() };
-        assert_eq!(actual_data.field_a, expected_data.field_a);
-        assert_eq!(actual_data.field_b, expected_data.field_b);
     }
 
     #[test]
-    #[should_panic]
-    fn reader_overflow() {
-        unsafe { StructReader::new(1, align_of_val(&1)).read::<u64>() };
+    fn wide_strings() {
+        assert_eq!(to_wide("ab").unwrap(), vec![b'a' as u16, b'b' as u16, 0]);
+        assert_eq!(to_wide("").unwrap(), vec![0]);
+        assert_eq!(to_wide("\u{1F512}").unwrap(), vec![0xD83D, 0xDD12, 0]);
+        assert!(matches!(to_wide("a\0b"), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn embedded_dll_files() {
+        let dir = std::env::temp_dir().join(format!("wireguard-nt-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = write_embedded_dll(&dir, b"first").unwrap();
+        let second = write_embedded_dll(&dir, b"second").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read(&first).unwrap(), b"first");
+        assert_eq!(fs::read(&second).unwrap(), b"second");
+
+        let other = dir.join("other.dll");
+        fs::write(&other, b"other").unwrap();
+        remove_embedded_dlls(&dir);
+        assert!(!first.exists());
+        assert!(!second.exists());
+        //Only files written by `write_embedded_dll` are removed
+        assert!(other.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn driver_version() {
+        let version = DriverVersion::from_raw(0x0000_000a);
+        assert_eq!(
+            version,
+            DriverVersion {
+                major: 0,
+                minor: 10
+            }
+        );
+        assert_eq!(version.to_string(), "0.10");
+        assert_eq!(version.raw(), 0x0000_000a);
+
+        let one = DriverVersion::from_raw(0x0001_0002);
+        assert_eq!(one, DriverVersion { major: 1, minor: 2 });
+        assert!(version < one);
+        assert!(DriverVersion { major: 0, minor: 9 } < version);
+    }
+
+    #[test]
+    fn filetime_zero_is_none() {
+        assert_eq!(filetime_to_system_time(0), None);
+    }
+
+    #[test]
+    fn filetime_round_trip() {
+        assert_eq!(
+            filetime_to_system_time(UNIX_EPOCH_AS_FILETIME),
+            Some(SystemTime::UNIX_EPOCH)
+        );
+
+        //2021-11-23T00:00:00Z, as reported by GetSystemTimeAsFileTime
+        let filetime = 132_820_992_000_000_000;
+        let expected = SystemTime::UNIX_EPOCH + Duration::from_secs(1_637_625_600);
+        let time = filetime_to_system_time(filetime).unwrap();
+        assert_eq!(time, expected);
+
+        //Convert back to make sure no precision was lost
+        assert_eq!(system_time_to_filetime(time), filetime);
+        let before_unix = SystemTime::UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(
+            system_time_to_filetime(before_unix),
+            UNIX_EPOCH_AS_FILETIME - 10_000_000
+        );
+    }
+
+    #[test]
+    fn filetime_before_unix_epoch() {
+        //One second before 1970-01-01
+        let filetime = UNIX_EPOCH_AS_FILETIME - 10_000_000;
+        assert_eq!(
+            filetime_to_system_time(filetime),
+            Some(SystemTime::UNIX_EPOCH - Duration::from_secs(1))
+        );
     }
 
     #[test]
@@ -211,6 +856,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn zeroed_memory() {
+        //Cover starts that are not word aligned and lengths with a partial last word
+        for offset in 0..8 {
+            for len in 0..40 {
+                let mut buf = [0xAAu8; 48];
+                unsafe { zero_memory(buf.as_mut_ptr().add(offset), len) };
+                assert!(buf[..offset].iter().all(|&b| b == 0xAA));
+                assert!(buf[offset..offset + len].iter().all(|&b| b == 0));
+                assert!(buf[offset + len..].iter().all(|&b| b == 0xAA));
+            }
+        }
+    }
+
     #[test]
     #[should_panic]
     fn writer_unaligned() {
@@ -0,0 +1,730 @@
+use crate::{AddressFamilyPreference, AllowedIp, Interface, Key, Peer};
+
+use std::fmt::{self, Write};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// A wg-quick config file, as parsed by [`WgQuickConfig::parse`].
+///
+/// wg-quick configs contain settings that are applied by wg-quick itself rather than by the
+/// WireGuard driver. These are kept next to the [`Interface`] so that callers can apply them, for
+/// example addresses using [`crate::Adapter::set_default_route`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WgQuickConfig {
+    /// The driver config from the `[Interface]` and `[Peer]` sections
+    pub interface: Interface,
+
+    /// The addresses to assign to the adapter, from `Address`
+    pub addresses: Vec<AllowedIp>,
+
+    /// The DNS servers to use while the adapter is up, from `DNS`
+    pub dns: Vec<IpAddr>,
+
+    /// The DNS search domains, given as non ip entries of `DNS`
+    pub dns_search: Vec<String>,
+
+    /// The MTU of the adapter, from `MTU`
+    pub mtu: Option<u16>,
+}
+
+/// Keys that only affect the wg-quick script on Linux, which are accepted but have no effect
+const IGNORED_KEYS: &[&str] = &[
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+enum Section {
+    None,
+    Interface,
+    Peer,
+}
+
+/// A `[Peer]` section whose keys are still being read
+struct PeerSection {
+    line: usize,
+    public_key: Option<Key>,
+    peer: Peer,
+}
+
+impl PeerSection {
+    fn new(line: usize) -> Self {
+        PeerSection {
+            line,
+            public_key: None,
+            peer: Peer {
+                public_key: Key::new([0; 32]),
+                preshared_key: None,
+                persistent_keepalive: None,
+                endpoint: None,
+                allowed_ips: Vec::new(),
+                tx_bytes: 0,
+                rx_bytes: 0,
+                last_handshake: None,
+            },
+        }
+    }
+
+    fn finish(self) -> Result<Peer, ConfigParseError> {
+        let public_key = self.public_key.ok_or(ConfigParseError::MissingKey {
+            line: self.line,
+            key: "PublicKey",
+        })?;
+        Ok(Peer {
+            public_key,
+            ..self.peer
+        })
+    }
+}
+
+impl WgQuickConfig {
+    /// Parses the contents of a wg-quick `.conf` file.
+    ///
+    /// Hostnames in `Endpoint` are resolved while parsing, preferring IPv4 addresses. Shorthand for
+    /// [`WgQuickConfig::parse_with`] with the default [`AddressFamilyPreference`]
+    pub fn parse(text: &str) -> Result<WgQuickConfig, ConfigParseError> {
+        WgQuickConfig::parse_with(text, AddressFamilyPreference::default())
+    }
+
+    /// Parses the contents of a wg-quick `.conf` file, resolving hostnames in `Endpoint` to an
+    /// address chosen by `preference`. A hostname without an address of the required family is
+    /// reported as [`ConfigParseError::InvalidValue`]
+    pub fn parse_with(
+        text: &str,
+        preference: AddressFamilyPreference,
+    ) -> Result<WgQuickConfig, ConfigParseError> {
+        let mut section = Section::None;
+        let mut interface_line = None;
+        let mut private_key = None;
+        let mut listen_port = None;
+        let mut addresses = Vec::new();
+        let mut dns = Vec::new();
+        let mut dns_search = Vec::new();
+        let mut mtu = None;
+        let mut mark = None;
+        let mut peers = Vec::new();
+        let mut current_peer: Option<PeerSection> = None;
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some(peer) = current_peer.take() {
+                    peers.push(peer.finish()?);
+                }
+                let name = line[1..line.len() - 1].trim();
+                section = if name.eq_ignore_ascii_case("interface") {
+                    if interface_line.is_some() {
+                        return Err(ConfigParseError::DuplicateSection {
+                            line: line_number,
+                            name: name.to_owned(),
+                        });
+                    }
+                    interface_line = Some(line_number);
+                    Section::Interface
+                } else if name.eq_ignore_ascii_case("peer") {
+                    current_peer = Some(PeerSection::new(line_number));
+                    Section::Peer
+                } else {
+                    return Err(ConfigParseError::UnknownSection {
+                        line: line_number,
+                        name: name.to_owned(),
+                    });
+                };
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(ConfigParseError::Syntax { line: line_number }),
+            };
+            let lower_key = key.to_ascii_lowercase();
+            let invalid = |reason: String| ConfigParseError::InvalidValue {
+                line: line_number,
+                key: key.to_owned(),
+                reason,
+            };
+
+            match (&section, lower_key.as_str(), current_peer.as_mut()) {
+                (Section::None, _, _) => {
+                    return Err(ConfigParseError::Syntax { line: line_number });
+                }
+                (Section::Interface, "privatekey", _) => {
+                    private_key = Some(parse_key(value).map_err(invalid)?);
+                }
+                (Section::Interface, "listenport", _) => {
+                    listen_port = Some(value.parse().map_err(|e| invalid(format!("{}", e)))?);
+                }
+                (Section::Interface, "address", _) => {
+                    for address in split_list(value) {
+                        addresses.push(address.parse().map_err(|e| invalid(format!("{}", e)))?);
+                    }
+                }
+                (Section::Interface, "dns", _) => {
+                    for entry in split_list(value) {
+                        match entry.parse() {
+                            Ok(address) => dns.push(address),
+                            Err(_) => dns_search.push(entry.to_owned()),
+                        }
+                    }
+                }
+                (Section::Interface, "mtu", _) => {
+                    mtu = Some(value.parse().map_err(|e| invalid(format!("{}", e)))?);
+                }
+                (Section::Interface, "fwmark", _) => {
+                    mark = parse_mark(value).map_err(invalid)?;
+                }
+                (Section::Interface, key, _) if IGNORED_KEYS.contains(&key) => {
+                    log::warn!("Ignoring wg-quick key {} on line {}", key, line_number);
+                }
+                (Section::Peer, "publickey", Some(peer)) => {
+                    peer.public_key = Some(parse_key(value).map_err(invalid)?);
+                }
+                (Section::Peer, "presharedkey", Some(peer)) => {
+                    peer.peer.preshared_key = Some(parse_key(value).map_err(invalid)?);
+                }
+                (Section::Peer, "endpoint", Some(peer)) => {
+                    peer.peer.endpoint = Some(preference.resolve(value).map_err(invalid)?);
+                }
+                (Section::Peer, "allowedips", Some(peer)) => {
+                    for allowed_ip in split_list(value) {
+                        peer.peer
+                            .allowed_ips
+                            .push(allowed_ip.parse().map_err(|e| invalid(format!("{}", e)))?);
+                    }
+                }
+                (Section::Peer, "persistentkeepalive", Some(peer)) => {
+                    peer.peer.persistent_keepalive = if value.eq_ignore_ascii_case("off") {
+                        None
+                    } else {
+                        match value
+                            .parse::<u16>()
+                            .map_err(|e| invalid(format!("{}", e)))?
+                        {
+                            0 => None,
+                            secs => Some(Duration::from_secs(secs.into())),
+                        }
+                    };
+                }
+                _ => {
+                    return Err(ConfigParseError::UnknownKey {
+                        line: line_number,
+                        key: key.to_owned(),
+                    });
+                }
+            }
+        }
+        if let Some(peer) = current_peer.take() {
+            peers.push(peer.finish()?);
+        }
+
+        let interface_line = interface_line.ok_or(ConfigParseError::MissingInterface)?;
+        let private_key = private_key.ok_or(ConfigParseError::MissingKey {
+            line: interface_line,
+            key: "PrivateKey",
+        })?;
+
+        Ok(WgQuickConfig {
+            interface: Interface {
+                listen_port,
+                private_key: Some(private_key),
+                mark,
+                peers,
+                ..Default::default()
+            },
+            addresses,
+            dns,
+            dns_search,
+            mtu,
+        })
+    }
+
+    /// Writes this config in the wg-quick `.conf` format. See [`Interface::to_wg_quick`]
+    pub fn to_wg_quick(&self) -> String {
+        write_wg_quick(
+            &self.interface,
+            &self.addresses,
+            &self.dns,
+            &self.dns_search,
+            self.mtu,
+        )
+    }
+}
+
+impl Interface {
+    /// Parses the contents of a wg-quick `.conf` file into an interface config.
+    ///
+    /// `Address`, `DNS` and `MTU` are not part of the driver config and are dropped. Use
+    /// [`WgQuickConfig::parse`] to keep them
+    pub fn from_wg_quick(text: &str) -> Result<Interface, ConfigParseError> {
+        WgQuickConfig::parse(text).map(|config| config.interface)
+    }
+
+    /// Like [`Interface::from_wg_quick`], resolving hostnames in `Endpoint` as chosen by
+    /// `preference`, see [`WgQuickConfig::parse_with`]
+    pub fn from_wg_quick_with(
+        text: &str,
+        preference: AddressFamilyPreference,
+    ) -> Result<Interface, ConfigParseError> {
+        WgQuickConfig::parse_with(text, preference).map(|config| config.interface)
+    }
+
+    /// Writes this interface config in the wg-quick `.conf` format.
+    ///
+    /// Values that have no wg-quick equivalent, such as the public key of the interface and the
+    /// transfer statistics of peers, are left out. Peers are written in order, so the output of
+    /// the same config is always identical
+    pub fn to_wg_quick(&self) -> String {
+        write_wg_quick(self, &[], &[], &[], None)
+    }
+}
+
+fn write_wg_quick(
+    interface: &Interface,
+    addresses: &[AllowedIp],
+    dns: &[IpAddr],
+    dns_search: &[String],
+    mtu: Option<u16>,
+) -> String {
+    //Writing to a String never fails
+    let mut out = String::new();
+    out.push_str("[Interface]\n");
+    if let Some(private_key) = &interface.private_key {
+        writeln!(out, "PrivateKey = {}", private_key).unwrap();
+    }
+    if let Some(listen_port) = interface.listen_port {
+        writeln!(out, "ListenPort = {}", listen_port).unwrap();
+    }
+    if !addresses.is_empty() {
+        writeln!(out, "Address = {}", join(addresses)).unwrap();
+    }
+    if !dns.is_empty() || !dns_search.is_empty() {
+        let entries: Vec<String> = dns
+            .iter()
+            .map(ToString::to_string)
+            .chain(dns_search.iter().cloned())
+            .collect();
+        writeln!(out, "DNS = {}", entries.join(", ")).unwrap();
+    }
+    if let Some(mtu) = mtu {
+        writeln!(out, "MTU = {}", mtu).unwrap();
+    }
+    if let Some(mark) = interface.mark.filter(|&mark| mark != 0) {
+        writeln!(out, "FwMark = {}", mark).unwrap();
+    }
+
+    for peer in &interface.peers {
+        out.push_str("\n[Peer]\n");
+        writeln!(out, "PublicKey = {}", peer.public_key).unwrap();
+        if let Some(preshared_key) = &peer.preshared_key {
+            writeln!(out, "PresharedKey = {}", preshared_key).unwrap();
+        }
+        if !peer.allowed_ips.is_empty() {
+            writeln!(out, "AllowedIPs = {}", join(&peer.allowed_ips)).unwrap();
+        }
+        if let Some(endpoint) = peer.endpoint {
+            writeln!(out, "Endpoint = {}", endpoint).unwrap();
+        }
+        if let Some(keepalive) = peer.persistent_keepalive {
+            writeln!(out, "PersistentKeepalive = {}", keepalive.as_secs()).unwrap();
+        }
+    }
+    out
+}
+
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+/// Parses a `FwMark`, which is `off`, or a number in decimal or with a `0x` prefix in hex
+fn parse_mark(value: &str) -> Result<Option<u32>, String> {
+    if value.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    let mark = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    mark.map(Some).map_err(|e| e.to_string())
+}
+
+fn parse_key(value: &str) -> Result<Key, String> {
+    Key::from_base64(value).map_err(|e| e.to_string())
+}
+
+/// The error returned when parsing a wg-quick config fails. Line numbers start at 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigParseError {
+    /// The line is not a section header, a comment or a `Key = Value` pair inside a section
+    Syntax {
+        /// The offending line
+        line: usize,
+    },
+
+    /// A section other than `[Interface]` or `[Peer]`
+    UnknownSection {
+        /// The line of the section header
+        line: usize,
+        /// The name of the section
+        name: String,
+    },
+
+    /// A second `[Interface]` section
+    DuplicateSection {
+        /// The line of the section header
+        line: usize,
+        /// The name of the section
+        name: String,
+    },
+
+    /// A key that is not valid in its section
+    UnknownKey {
+        /// The offending line
+        line: usize,
+        /// The key as written in the config
+        key: String,
+    },
+
+    /// The value of a key could not be parsed
+    InvalidValue {
+        /// The offending line
+        line: usize,
+        /// The key as written in the config
+        key: String,
+        /// Why the value is invalid
+        reason: String,
+    },
+
+    /// A section is missing a required key
+    MissingKey {
+        /// The line of the section header
+        line: usize,
+        /// The missing key
+        key: &'static str,
+    },
+
+    /// The config has no `[Interface]` section
+    MissingInterface,
+}
+
+impl fmt::Display for ConfigParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigParseError::Syntax { line } => write!(f, "line {}: invalid syntax", line),
+            ConfigParseError::UnknownSection { line, name } => {
+                write!(f, "line {}: unknown section [{}]", line, name)
+            }
+            ConfigParseError::DuplicateSection { line, name } => {
+                write!(f, "line {}: duplicate section [{}]", line, name)
+            }
+            ConfigParseError::UnknownKey { line, key } => {
+                write!(f, "line {}: unknown key {}", line, key)
+            }
+            ConfigParseError::InvalidValue { line, key, reason } => {
+                write!(f, "line {}: invalid value for {}: {}", line, key, reason)
+            }
+            ConfigParseError::MissingKey { line, key } => {
+                write!(f, "line {}: section is missing {}", line, key)
+            }
+            ConfigParseError::MissingInterface => write!(f, "missing [Interface] section"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    const CONFIG: &str = "
+# Exported from a Linux server
+[Interface]
+PrivateKey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=
+ListenPort = 51820
+Address = 10.0.0.2/24, fd00::2/64
+DNS = 10.0.0.1, example.internal
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+
+[Peer]
+PublicKey = hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=
+PresharedKey = AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+Endpoint = 203.0.113.1:51820
+AllowedIPs = 0.0.0.0/0, ::/0
+PersistentKeepalive = 25
+
+[peer]
+publickey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=
+Endpoint = [2001:db8::1]:51820 # trailing comment
+AllowedIPs = 10.0.1.0/24
+AllowedIPs = 10.0.2.0/24
+PersistentKeepalive = off
+";
+
+    #[test]
+    fn parse() {
+        let config = WgQuickConfig::parse(CONFIG).unwrap();
+        let interface = &config.interface;
+        assert_eq!(
+            interface.private_key,
+            Some(
+                "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo="
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert_eq!(interface.listen_port, Some(51820));
+        assert_eq!(
+            config.addresses,
+            vec![
+                "10.0.0.2/24".parse().unwrap(),
+                "fd00::2/64".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.dns, vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(config.dns_search, vec!["example.internal".to_owned()]);
+        assert_eq!(config.mtu, None);
+
+        assert_eq!(interface.peers.len(), 2);
+        let first = &interface.peers[0];
+        assert_eq!(
+            first.public_key.to_base64(),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo="
+        );
+        assert_eq!(first.preshared_key, Some(Key::new([0; 32])));
+        assert_eq!(first.endpoint, Some("203.0.113.1:51820".parse().unwrap()));
+        assert_eq!(
+            first.allowed_ips,
+            vec![AllowedIp::ALL_V4, AllowedIp::ALL_V6]
+        );
+        assert_eq!(first.persistent_keepalive, Some(Duration::from_secs(25)));
+
+        let second = &interface.peers[1];
+        assert_eq!(second.preshared_key, None);
+        assert_eq!(
+            second.endpoint,
+            Some("[2001:db8::1]:51820".parse().unwrap())
+        );
+        assert_eq!(second.allowed_ips.len(), 2);
+        assert_eq!(second.persistent_keepalive, None);
+    }
+
+    #[test]
+    fn link_local_endpoint() {
+        let config = "[Interface]
+PrivateKey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=
+
+[Peer]
+PublicKey = hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=
+Endpoint = [fe80::1%12]:51820
+";
+        let interface = Interface::from_wg_quick(config).unwrap();
+        match interface.peers[0].endpoint {
+            Some(SocketAddr::V6(v6)) => assert_eq!(v6.scope_id(), 12),
+            other => panic!("expected an IPv6 endpoint, got {:?}", other),
+        }
+        assert!(interface
+            .to_wg_quick()
+            .contains("Endpoint = [fe80::1%12]:51820\n"));
+    }
+
+    #[test]
+    fn errors() {
+        let check = |text: &str, expected: ConfigParseError| {
+            assert_eq!(WgQuickConfig::parse(text).unwrap_err(), expected);
+        };
+        check("ListenPort = 1", ConfigParseError::Syntax { line: 1 });
+        check(
+            "[Interface]\nPrivateKey",
+            ConfigParseError::Syntax { line: 2 },
+        );
+        check(
+            "[Wireguard]",
+            ConfigParseError::UnknownSection {
+                line: 1,
+                name: "Wireguard".to_owned(),
+            },
+        );
+        check(
+            "[Interface]\nEndpoint = 203.0.113.1:51820",
+            ConfigParseError::UnknownKey {
+                line: 2,
+                key: "Endpoint".to_owned(),
+            },
+        );
+        check("", ConfigParseError::MissingInterface);
+        check(
+            "\n[Interface]\nListenPort = 51820",
+            ConfigParseError::MissingKey {
+                line: 2,
+                key: "PrivateKey",
+            },
+        );
+        check(
+            "[Interface]\nPrivateKey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=\n[Peer]\nAllowedIPs = 10.0.0.0/8",
+            ConfigParseError::MissingKey {
+                line: 3,
+                key: "PublicKey",
+            },
+        );
+        assert!(matches!(
+            WgQuickConfig::parse("[Interface]\nListenPort = 70000"),
+            Err(ConfigParseError::InvalidValue { line: 2, .. })
+        ));
+        assert!(matches!(
+            WgQuickConfig::parse("[Interface]\n[Peer]\nAllowedIPs = 10.0.0.0/33"),
+            Err(ConfigParseError::InvalidValue { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn endpoint_family() {
+        let config = "[Interface]
+PrivateKey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=
+[Peer]
+PublicKey = hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=
+Endpoint = 203.0.113.1:51820
+";
+        let interface =
+            Interface::from_wg_quick_with(config, AddressFamilyPreference::V4Only).unwrap();
+        assert_eq!(
+            interface.peers[0].endpoint,
+            Some("203.0.113.1:51820".parse().unwrap())
+        );
+        match WgQuickConfig::parse_with(config, AddressFamilyPreference::V6Only) {
+            Err(ConfigParseError::InvalidValue { line, key, reason }) => {
+                assert_eq!(line, 5);
+                assert_eq!(key, "Endpoint");
+                assert_eq!(reason, "203.0.113.1:51820 has no IPv6 address");
+            }
+            other => panic!("expected an invalid endpoint, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bare_allowed_ips() {
+        let config = "[Interface]
+PrivateKey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=
+[Peer]
+PublicKey = hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=
+AllowedIPs = 10.0.0.5, fd00::5
+";
+        let interface = Interface::from_wg_quick(config).unwrap();
+        let allowed_ips = &interface.peers[0].allowed_ips;
+        assert_eq!(
+            allowed_ips.iter().map(|ip| ip.cidr()).collect::<Vec<_>>(),
+            vec![32, 128]
+        );
+        assert!(interface
+            .to_wg_quick()
+            .contains("AllowedIPs = 10.0.0.5/32, fd00::5/128\n"));
+    }
+
+    #[test]
+    fn fwmark() {
+        let with_mark = |mark: &str| {
+            let config = format!(
+                "[Interface]\nPrivateKey = dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=\nFwMark = {}\n",
+                mark
+            );
+            Interface::from_wg_quick(&config).map(|interface| interface.mark)
+        };
+        assert_eq!(with_mark("51820"), Ok(Some(51820)));
+        assert_eq!(with_mark("0xca6c"), Ok(Some(0xca6c)));
+        assert_eq!(with_mark("off"), Ok(None));
+        assert!(matches!(
+            with_mark("mark"),
+            Err(ConfigParseError::InvalidValue { line: 3, .. })
+        ));
+
+        let interface = Interface {
+            mark: Some(51820),
+            ..Default::default()
+        };
+        assert!(interface.to_wg_quick().contains("FwMark = 51820\n"));
+    }
+
+    #[test]
+    fn from_wg_quick() {
+        let interface = Interface::from_wg_quick(CONFIG).unwrap();
+        assert_eq!(interface.peers.len(), 2);
+    }
+
+    #[test]
+    fn to_wg_quick() {
+        let interface = Interface::builder()
+            .private_key(Key::new([1; 32]))
+            .listen_port(51820)
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([2; 32]))
+                    .preshared_key(Key::new([3; 32]))
+                    .endpoint("[2001:db8::1]:51820".parse().unwrap())
+                    .allowed_ip(AllowedIp::ALL_V4)
+                    .allowed_ip(AllowedIp::ALL_V6)
+                    .persistent_keepalive(Duration::from_secs(25))
+                    .build()
+                    .unwrap(),
+            )
+            .peer(
+                Peer::builder()
+                    .public_key(Key::new([4; 32]))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+
+        let expected = "\
+[Interface]
+PrivateKey = AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=
+ListenPort = 51820
+
+[Peer]
+PublicKey = AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=
+PresharedKey = AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=
+AllowedIPs = 0.0.0.0/0, ::/0
+Endpoint = [2001:db8::1]:51820
+PersistentKeepalive = 25
+
+[Peer]
+PublicKey = BAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQ=
+";
+        assert_eq!(interface.to_wg_quick(), expected);
+        assert_eq!(
+            Interface::from_wg_quick(&interface.to_wg_quick()).unwrap(),
+            interface
+        );
+    }
+
+    #[test]
+    fn wg_quick_round_trip() {
+        let config = WgQuickConfig::parse(CONFIG).unwrap();
+        let text = config.to_wg_quick();
+        assert_eq!(WgQuickConfig::parse(&text).unwrap(), config);
+        assert_eq!(WgQuickConfig::parse(&text).unwrap().to_wg_quick(), text);
+    }
+}